@@ -4,7 +4,8 @@ use chrono::{DateTime, Utc};
 use std::time::Duration;
 
 use crate::index::START_TIME;
-use crate::modules::{user::route::configure_routes, conversion::route::configure_conversion_routes, sync::route::configure_sync_routes, editor::route::configure_editor_routes};
+use crate::services::export_capabilities::{capabilities, ExportCapabilities};
+use crate::modules::{user::route::configure_routes, conversion::route::configure_conversion_routes, sync::route::configure_sync_routes, editor::route::configure_editor_routes, template::route::configure_template_routes};
 
 #[derive(Serialize)]
 struct HealthResponse {
@@ -12,6 +13,7 @@ struct HealthResponse {
     uptime: String,
     timestamp: DateTime<Utc>,
     version: String,
+    exports: Option<ExportCapabilities>,  // Tools and formats found at startup
 }
 
 pub fn config_app(app: &mut web::ServiceConfig) {
@@ -23,6 +25,7 @@ pub fn config_app(app: &mut web::ServiceConfig) {
             .configure(configure_conversion_routes)
             .configure(configure_sync_routes)
             .configure(configure_editor_routes)
+            .configure(configure_template_routes)
     );
 }
 
@@ -35,6 +38,7 @@ async fn health_check() -> Result<HttpResponse> {
         uptime: uptime_str,
         timestamp: Utc::now(),
         version: env!("CARGO_PKG_VERSION").to_string(),
+        exports: capabilities().cloned(),
     };
     
     Ok(HttpResponse::Ok().json(response))
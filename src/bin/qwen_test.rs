@@ -1,6 +1,7 @@
 use serde::{Serialize, Deserialize};
 use std::fs;
 use std::error::Error;
+use base64::{Engine as _, engine::general_purpose};
 
 // Structs for building the JSON request body, matching the OpenAI multimodal format.
 #[derive(Serialize)]
@@ -61,7 +62,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let image_bytes = fs::read(image_path)?;
 
     // 2. Encode the bytes into a Base64 string.
-    let base64_image = base64::encode(&image_bytes);
+    let base64_image = general_purpose::STANDARD.encode(&image_bytes);
 
     // 3. Format as a data URI.
     let image_uri = format!("data:image/png;base64,{}", base64_image);
@@ -98,7 +99,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     if response.status().is_success() {
         let response_body = response.json::<ChatCompletionResponse>().await?;
-        if let Some(choice) = response_body.choices.get(0) {
+        if let Some(choice) = response_body.choices.first() {
             println!("\n--- Qwen Vision Model Response ---");
             println!("{}", choice.message.content);
             println!("------------------------------------");
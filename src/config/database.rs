@@ -4,9 +4,10 @@ use std::sync::Arc;
 use tokio::sync::OnceCell;
 
 use crate::modules::user::model::User;
-use crate::modules::conversion::model::Conversion;
-use crate::modules::sync::model::Project;
+use crate::modules::conversion::model::{CachedExport, Conversion};
+use crate::modules::sync::model::{Project, ProjectExport};
 use crate::modules::editor::model::Preview;
+use crate::modules::template::model::ExportTemplate;
 use crate::config::environment::Config;
 
 static DB_INSTANCE: OnceCell<Arc<Database>> = OnceCell::const_new();
@@ -46,6 +47,13 @@ async fn get_db() -> Arc<Database> {
             .await
             .expect("Failed to create user_id index");
 
+        let user_tags_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1, "tags": 1 })
+            .build();
+        conversion_collection.create_index(user_tags_index)
+            .await
+            .expect("Failed to create user_id/tags index");
+
         // Create indices for projects collection
         let projects_collection: Collection<Project> = db.collection("projects");
         let project_user_id_index = IndexModel::builder()
@@ -55,6 +63,40 @@ async fn get_db() -> Arc<Database> {
             .await
             .expect("Failed to create project user_id index");
 
+        let templates_collection: Collection<ExportTemplate> = db.collection("templates");
+        let template_id_index = IndexModel::builder()
+            .keys(doc! { "template_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        templates_collection.create_index(template_id_index)
+            .await
+            .expect("Failed to create template_id index");
+
+        let template_user_kind_index = IndexModel::builder()
+            .keys(doc! { "user_id": 1, "kind": 1 })
+            .build();
+        templates_collection.create_index(template_user_kind_index)
+            .await
+            .expect("Failed to create template user_id/kind index");
+
+        let project_exports_collection: Collection<ProjectExport> = db.collection("project_exports");
+        let export_id_index = IndexModel::builder()
+            .keys(doc! { "export_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        project_exports_collection.create_index(export_id_index)
+            .await
+            .expect("Failed to create export_id index");
+
+        let export_cache_collection: Collection<CachedExport> = db.collection("export_cache");
+        let export_cache_index = IndexModel::builder()
+            .keys(doc! { "job_id": 1, "cache_key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        export_cache_collection.create_index(export_cache_index)
+            .await
+            .expect("Failed to create export cache index");
+
         Arc::new(db)
     })
     .await
@@ -76,7 +118,22 @@ pub async fn get_projects_collection() -> mongodb::error::Result<Collection<Proj
     Ok(db.collection("projects"))
 }
 
+pub async fn get_project_exports_collection() -> mongodb::error::Result<Collection<ProjectExport>> {
+    let db = get_db().await;
+    Ok(db.collection("project_exports"))
+}
+
 pub async fn get_previews_collection() -> mongodb::error::Result<Collection<Preview>> {
     let db = get_db().await;
     Ok(db.collection("previews"))
-}
\ No newline at end of file
+}
+
+pub async fn get_templates_collection() -> mongodb::error::Result<Collection<ExportTemplate>> {
+    let db = get_db().await;
+    Ok(db.collection("templates"))
+}
+
+pub async fn get_export_cache_collection() -> mongodb::error::Result<Collection<CachedExport>> {
+    let db = get_db().await;
+    Ok(db.collection("export_cache"))
+}
@@ -15,6 +15,15 @@ pub struct Config {
     pub email_password: String,
     pub open_router_api_key: String,
     pub frontend_url: String,
+    pub ocr_model_chain: Vec<String>,
+    pub export_max_concurrency: usize,
+    pub export_max_queued: usize,
+    pub export_queue_wait_secs: u64,
+    pub export_timeout_secs: u64,
+    pub export_memory_limit_mb: u64,
+    pub export_watermark_plans: Vec<String>,
+    pub export_watermark_text: String,
+    pub export_watermark_position: String,
 }
 
 impl Config {
@@ -32,6 +41,31 @@ impl Config {
         let email_user = env::var("EMAIL_USER").unwrap_or_else(|_| "your_email_user".to_string());
         let email_password = env::var("EMAIL_PASSWORD").unwrap_or_else(|_| "your_email_password".to_string());
         let open_router_api_key = env::var("OPENROUTER_API_KEY").unwrap_or_else(|_| "your_open_router_api_key".to_string());
+        // Comma separated list of OpenRouter model ids, tried in order until one succeeds.
+        let ocr_model_chain = env::var("OCR_MODEL_CHAIN")
+            .unwrap_or_else(|_| "qwen/qwen-vl-plus,qwen/qwen-vl-max".to_string())
+            .split(',')
+            .map(|m| m.trim().to_string())
+            .filter(|m| !m.is_empty())
+            .collect();
+
+        // Limits for pandoc/xelatex export jobs.
+        let export_max_concurrency = env_number("EXPORT_MAX_CONCURRENCY", 4);
+        let export_max_queued = env_number("EXPORT_MAX_QUEUED", 16);
+        let export_queue_wait_secs = env_number("EXPORT_QUEUE_WAIT_SECS", 10);
+        let export_timeout_secs = env_number("EXPORT_TIMEOUT_SECS", 60);
+        let export_memory_limit_mb = env_number("EXPORT_MEMORY_LIMIT_MB", 1024);
+
+        // Branding on PDF, DOCX and ODT exports for the listed plans (comma separated
+        // plan names); an empty text turns it off. Position is footer, header or diagonal.
+        let export_watermark_plans = env::var("EXPORT_WATERMARK_PLANS")
+            .unwrap_or_else(|_| "Free".to_string())
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        let export_watermark_text = env::var("EXPORT_WATERMARK_TEXT").unwrap_or_else(|_| "Converted with ImageToText - Free plan".to_string());
+        let export_watermark_position = env::var("EXPORT_WATERMARK_POSITION").unwrap_or_else(|_| "footer".to_string());
 
         Config {
             jwt_secret,
@@ -46,6 +80,15 @@ impl Config {
             email_password,
             open_router_api_key,
             frontend_url,
+            ocr_model_chain,
+            export_max_concurrency,
+            export_max_queued,
+            export_queue_wait_secs,
+            export_timeout_secs,
+            export_memory_limit_mb,
+            export_watermark_plans,
+            export_watermark_text,
+            export_watermark_position,
         }
     }
     #[allow(dead_code)]
@@ -58,6 +101,10 @@ impl Config {
     }
 }
 
+fn env_number<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key).ok().and_then(|v| v.trim().parse().ok()).unwrap_or(default)
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self::new()
@@ -4,7 +4,10 @@ use std::time::Instant;
 use std::sync::Arc;
 
 use crate::app;
+use crate::config::database;
 use crate::config::environment::Config;
+use crate::modules::sync::crud::SyncCRUD;
+use crate::services::export_capabilities;
 
 pub static START_TIME: once_cell::sync::Lazy<Arc<Instant>> = 
     once_cell::sync::Lazy::new(|| Arc::new(Instant::now()));
@@ -14,6 +17,29 @@ pub async fn start_server() -> std::io::Result<()> {
     let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
     let address = format!("0.0.0.0:{}", port);
     
+    // Find the export tools once, so missing ones disable their formats up front
+    // instead of failing every request.
+    let capabilities = export_capabilities::probe().await;
+    for tool in &capabilities.tools {
+        match &tool.version {
+            Some(version) => println!("Export tool {}: {}", tool.name, version),
+            None => println!("Export tool {}: not found", tool.name),
+        }
+    }
+    let unavailable: Vec<&str> = capabilities.formats.iter().filter(|f| !f.available).map(|f| f.format).collect();
+    if !unavailable.is_empty() {
+        println!("WARNING: Export formats disabled for missing tools: {}", unavailable.join(", "));
+    }
+
+    match database::get_project_exports_collection().await {
+        Ok(collection) => match SyncCRUD::fail_interrupted_exports(&collection).await {
+            Ok(0) => {}
+            Ok(count) => println!("Marked {} project exports interrupted by the last shutdown as failed", count),
+            Err(e) => eprintln!("Failed to mark interrupted project exports: {}", e),
+        },
+        Err(e) => eprintln!("Failed to mark interrupted project exports: {}", e),
+    }
+
     println!("Server starting on http://{}", address);
     
     HttpServer::new(move || {
@@ -3,77 +3,94 @@ use actix_web::{HttpResponse, Error, HttpRequest, HttpMessage, web};
 use futures_util::StreamExt;
 use uuid::Uuid;
 use std::time::Instant;
-use serde::{Deserialize, Serialize};
-use base64::{Engine as _, engine::general_purpose};
 use percent_encoding;
 
 use crate::config::database;
 use crate::config::environment::Config;
 use crate::services::cloudinary::CloudinaryService;
-use crate::services::document_converter::DocumentConverter;
+use crate::services::ocr::{OcrService, OcrOutput};
+use crate::services::consensus::ConsensusAligner;
+use crate::services::image_crop::{CropRegion, ImageCropper};
+use crate::services::remote_fetch::{RemoteImageFetcher, FetchError};
+use crate::services::latex_math::{extract_math_segments, render_math_in_text, MathKind, MathOutputFormat};
+use crate::services::math_speech::speak_latex;
+use crate::services::latex_sanitizer::find_blocked_constructs;
+use crate::services::export_cache;
+use crate::services::document_converter::{ConversionResult, DocumentConverter, MergeSection, RenderError, RenderOptions, ReviewPage, TargetFormat, TemplateFiles};
 use crate::modules::conversion::{
-    model::Conversion,
-    schema::{UploadResponse, ErrorResponse, MAX_FILE_SIZE, is_allowed_mime_type, ConversionResultResponse},
+    model::{CachedExport, Conversion},
+    model::{normalize_tags, ConsensusReview, SegmentStatus, RegionResult},
+    schema::{UploadResponse, UploadOptions, ErrorResponse, MAX_FILE_SIZE, is_allowed_mime_type, ConversionResultResponse, UpdateTagsRequest, TagsResponse, TagSuggestionParams, TagSuggestionsResponse, normalize_metadata, validate_tags_and_metadata, MergeExportRequest, MAX_MERGE_CONVERSIONS, ResolveSegmentRequest, RecropRequest, RegionsResponse, MAX_CROP_REGIONS, MAX_REGIONS_FIELD_SIZE, JsonUploadRequest, UrlUploadRequest, sniff_image_mime_type, EquationsResponse, EquationItem, ResultParams, LatexRejectedResponse, LatexCompileErrorResponse, ExportOptionsParams},
     crud::ConversionCRUD,
 };
 use crate::modules::user::crud::UserCRUD;
+use crate::modules::user::model::User;
+use crate::modules::template::controller::TemplateController;
+use crate::modules::editor::schema::MAX_PREVIEW_CONTENT_BYTES;
+use mongodb::bson::oid::ObjectId;
+use base64::{Engine as _, engine::general_purpose};
 
-// --- Structs for Qwen Vision API call ---
-#[derive(Serialize)]
-struct QwenRequestBody<'a> {
-    model: &'a str,
-    messages: Vec<QwenMessage<'a>>,
-    max_tokens: u32,
-}
-
-#[derive(Serialize)]
-struct QwenMessage<'a> {
-    role: &'a str,
-    content: Vec<QwenContentPart<'a>>,
-}
-
-#[derive(Serialize)]
-#[serde(tag = "type")]
-enum QwenContentPart<'a> {
-    #[serde(rename = "text")]
-    Text { text: &'a str },
-    #[serde(rename = "image_url")]
-    ImageUrl { image_url: QwenImageUrl },
+/// An image received through any upload endpoint, ready for the shared pipeline.
+struct IncomingImage {
+    filename: String,
+    mime_type: String,
+    bytes: Vec<u8>,
+    regions: Vec<CropRegion>,
+    sniff_content: bool,  // Check the bytes against the declared type; only for fetched URLs, whose type comes from a remote server
 }
 
-#[derive(Serialize)]
-struct QwenImageUrl {
-    url: String,
+/// Validation shared by every upload path, returning the error response if the
+/// upload is rejected: the declared type must be allowed, the size within limits,
+/// and with `sniff_content` the bytes must actually be that kind of image.
+fn upload_validation_error(mime_type: &str, bytes: &[u8], sniff_content: bool) -> Option<HttpResponse> {
+    if !is_allowed_mime_type(mime_type) {
+        return Some(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+            error: "Invalid file type".to_string(),
+            message: format!("Supported types: JPEG, PNG, GIF. Got: {}", mime_type),
+        }));
+    }
+    if bytes.is_empty() {
+        return Some(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "No file".to_string(),
+            message: "The uploaded file is empty.".to_string(),
+        }));
+    }
+    if bytes.len() as u64 > MAX_FILE_SIZE {
+        return Some(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+            error: "File too large".to_string(),
+            message: format!("Maximum file size is {} bytes", MAX_FILE_SIZE),
+        }));
+    }
+    if sniff_content && sniff_image_mime_type(bytes) != Some(mime_type) {
+        return Some(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+            error: "Invalid file type".to_string(),
+            message: format!("File content does not match the declared type {}.", mime_type),
+        }));
+    }
+    None
 }
 
-#[derive(Deserialize, Debug)]
-struct QwenChatCompletionResponse {
-    choices: Vec<QwenChoice>,
+enum RegionOcrError {
+    InvalidRegion(String),
+    Ocr(String),
 }
 
-#[derive(Deserialize, Debug)]
-struct QwenChoice {
-    message: QwenResponseMessage,
-}
+pub struct ConversionController;
 
-#[derive(Deserialize, Debug)]
-struct QwenResponseMessage {
-    content: String,
+/// Text of a region-mode conversion: each region's text as its own block.
+fn region_text(results: &[RegionResult]) -> String {
+    results.iter().map(|r| r.text.trim()).collect::<Vec<_>>().join("\n\n")
 }
 
-pub struct ConversionController;
-
 impl ConversionController {
-    pub async fn upload_image(mut payload: Multipart, req: HttpRequest) -> Result<HttpResponse, Error> {
-        println!("CONTROLLER: Entered upload_image handler.");
-        let start_time = Instant::now();
-
+    /// Resolves the authenticated user for the upload endpoints.
+    async fn upload_user(req: &HttpRequest) -> Result<(User, ObjectId), HttpResponse> {
         println!("CONTROLLER: Extracting user claims from token.");
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
             Some(claims) => claims,
             None => {
                 println!("CONTROLLER: ERROR - Claims not found in request extensions.");
-                return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                return Err(HttpResponse::Unauthorized().json(ErrorResponse {
                     error: "Unauthorized".to_string(),
                     message: "User authentication data not found in token.".to_string(),
                 }));
@@ -85,14 +102,14 @@ impl ConversionController {
             Ok(Some(user)) => user,
             Ok(None) => {
                 println!("CONTROLLER: ERROR - User from token not found in DB.");
-                return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                return Err(HttpResponse::NotFound().json(ErrorResponse {
                     error: "User not found".to_string(),
                     message: "User associated with this token no longer exists.".to_string(),
                 }));
             }
             Err(_) => {
                 println!("CONTROLLER: ERROR - Database error while fetching user.");
-                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                return Err(HttpResponse::InternalServerError().json(ErrorResponse {
                     error: "Database Error".to_string(),
                     message: "Failed to retrieve user data.".to_string(),
                 }));
@@ -103,126 +120,340 @@ impl ConversionController {
             Some(id) => id,
             None => {
                 println!("CONTROLLER: ERROR - User record is missing DB ID.");
-                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                return Err(HttpResponse::InternalServerError().json(ErrorResponse {
                     error: "Internal Server Error".to_string(),
                     message: "User record is missing a database ID.".to_string(),
                 }));
             }
         };
         println!("CONTROLLER: User authenticated and authorized. User ID: {}", user_id);
+        Ok((user, user_id))
+    }
 
-        let config = Config::new();
-        let cloudinary = CloudinaryService::new(config.clone());
-        
-        // Process the multipart form data
+    pub async fn upload_image(mut payload: Multipart, req: HttpRequest, options: web::Query<UploadOptions>) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered upload_image handler.");
+        let start_time = Instant::now();
+
+        let (user, user_id) = match Self::upload_user(&req).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+
+        // Process the multipart form data: the image itself plus an optional
+        // `regions` text part holding a JSON array of crop rectangles.
         println!("CONTROLLER: Processing multipart form data...");
-        let mut field = match payload.next().await {
-            Some(Ok(field)) => field,
-            _ => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+        let mut upload: Option<(String, String, Vec<u8>)> = None;
+        let mut regions: Vec<CropRegion> = Vec::new();
+        while let Some(item) = payload.next().await {
+            let mut field = item.map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid multipart data: {}", e)))?;
+
+            if field.name() == Some("regions") {
+                let mut raw = Vec::new();
+                while let Some(chunk) = field.next().await {
+                    let data = chunk.map_err(|e| actix_web::error::ErrorBadRequest(format!("Failed to read chunk: {}", e)))?;
+                    if raw.len() + data.len() > MAX_REGIONS_FIELD_SIZE {
+                        return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                            error: "Regions too large".to_string(),
+                            message: format!("The regions field may be at most {} bytes", MAX_REGIONS_FIELD_SIZE),
+                        }));
+                    }
+                    raw.extend_from_slice(&data);
+                }
+                regions = match serde_json::from_slice(&raw) {
+                    Ok(regions) => regions,
+                    Err(e) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid regions".to_string(),
+                        message: format!("Regions must be a JSON array of crop rectangles: {}", e),
+                    })),
+                };
+                continue;
+            }
+
+            // Only the first file part is processed.
+            if upload.is_some() {
+                continue;
+            }
+
+            let content_disposition = field.content_disposition().ok_or_else(|| actix_web::error::ErrorBadRequest("No content disposition"))?;
+            let filename = content_disposition.get_filename().unwrap_or("file").to_string();
+
+            // Validate mime type
+            let mime_type = field.content_type().ok_or_else(|| actix_web::error::ErrorBadRequest("No mime type"))?.to_string();
+            if !is_allowed_mime_type(&mime_type) {
+                return Ok(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+                    error: "Invalid file type".to_string(),
+                    message: format!("Supported types: JPEG, PNG, GIF. Got: {}", mime_type),
+                }));
+            }
+
+            // Write file contents and check size
+            let mut file_bytes = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let data = chunk.map_err(|e| actix_web::error::ErrorBadRequest(format!("Failed to read chunk: {}", e)))?;
+                if (file_bytes.len() + data.len()) as u64 > MAX_FILE_SIZE {
+                    return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                        error: "File too large".to_string(),
+                        message: format!("Maximum file size is {} bytes", MAX_FILE_SIZE),
+                    }));
+                }
+                file_bytes.extend_from_slice(&data);
+            }
+            upload = Some((filename, mime_type, file_bytes));
+        }
+
+        let (filename, mime_type, file_bytes) = match upload {
+            Some(upload) => upload,
+            None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
                 error: "No file".to_string(),
                 message: "No file was uploaded".to_string(),
             })),
         };
-        
-        let content_disposition = field.content_disposition().ok_or_else(|| actix_web::error::ErrorBadRequest("No content disposition"))?;
-        let filename = content_disposition.get_filename().unwrap_or("file").to_string();
-        
-        // Validate mime type
-        let mime_type = field.content_type().ok_or_else(|| actix_web::error::ErrorBadRequest("No mime type"))?.to_string();
-        if !is_allowed_mime_type(&mime_type) {
-            return Ok(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
-                error: "Invalid file type".to_string(),
-                message: format!("Supported types: JPEG, PNG, GIF. Got: {}", mime_type),
+        println!("CONTROLLER: Finished reading file into memory. Size: {} bytes.", file_bytes.len());
+
+        let image = IncomingImage { filename, mime_type, bytes: file_bytes, regions, sniff_content: false };
+        Self::process_upload(user, user_id, image, options.into_inner(), start_time).await
+    }
+
+    pub async fn upload_image_json(
+        req: HttpRequest,
+        body: web::Json<JsonUploadRequest>,
+        options: web::Query<UploadOptions>,
+    ) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered upload_image_json handler.");
+        let start_time = Instant::now();
+
+        let (user, user_id) = match Self::upload_user(&req).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+
+        let body = body.into_inner();
+
+        // Accept either raw base64 or a `data:<mime>;base64,<data>` URI.
+        let (uri_mime, encoded) = match body.data.trim().strip_prefix("data:") {
+            Some(uri) => match uri.split_once(";base64,") {
+                Some((mime, data)) => (Some(mime.to_string()), data.to_string()),
+                None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid data".to_string(),
+                    message: "Data URIs must be base64 encoded.".to_string(),
+                })),
+            },
+            None => (None, body.data.trim().to_string()),
+        };
+        let encoded: String = encoded.chars().filter(|c| !c.is_whitespace()).collect();
+
+        // Reject oversized payloads before spending time decoding them.
+        if (encoded.len() as u64 / 4) * 3 > MAX_FILE_SIZE + 3 {
+            return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                error: "File too large".to_string(),
+                message: format!("Maximum file size is {} bytes", MAX_FILE_SIZE),
             }));
         }
 
-        // Write file contents and check size
-        let mut file_bytes = Vec::new();
-        while let Some(chunk) = field.next().await {
-            let data = chunk.map_err(|e| actix_web::error::ErrorBadRequest(format!("Failed to read chunk: {}", e)))?;
-            if (file_bytes.len() + data.len()) as u64 > MAX_FILE_SIZE {
-                return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
-                    error: "File too large".to_string(),
-                    message: format!("Maximum file size is {} bytes", MAX_FILE_SIZE),
+        let bytes = match general_purpose::STANDARD.decode(&encoded) {
+            Ok(bytes) => bytes,
+            Err(e) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid data".to_string(),
+                message: format!("Failed to decode base64 data: {}", e),
+            })),
+        };
+
+        let mime_type = body.mime_type
+            .or(uri_mime)
+            .or_else(|| sniff_image_mime_type(&bytes).map(str::to_string))
+            .unwrap_or_default()
+            .to_lowercase();
+        let filename = body.filename
+            .map(|f| f.trim().to_string())
+            .filter(|f| !f.is_empty())
+            .unwrap_or_else(|| "upload".to_string());
+
+        let image = IncomingImage { filename, mime_type, bytes, regions: body.regions.unwrap_or_default(), sniff_content: false };
+        Self::process_upload(user, user_id, image, options.into_inner(), start_time).await
+    }
+
+    pub async fn upload_image_url(
+        req: HttpRequest,
+        body: web::Json<UrlUploadRequest>,
+        options: web::Query<UploadOptions>,
+    ) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered upload_image_url handler.");
+        let start_time = Instant::now();
+
+        let (user, user_id) = match Self::upload_user(&req).await {
+            Ok(user) => user,
+            Err(response) => return Ok(response),
+        };
+
+        let body = body.into_inner();
+        let fetcher = RemoteImageFetcher::new(MAX_FILE_SIZE);
+        let fetched = match fetcher.fetch(&body.url, is_allowed_mime_type).await {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                println!("CONTROLLER: Remote fetch failed: {}", e);
+                let mut response = match e {
+                    FetchError::InvalidUrl(_) | FetchError::Blocked(_) => HttpResponse::BadRequest(),
+                    FetchError::TooLarge(_) => HttpResponse::PayloadTooLarge(),
+                    FetchError::UnsupportedType(_) => HttpResponse::UnsupportedMediaType(),
+                    FetchError::Timeout => HttpResponse::GatewayTimeout(),
+                    FetchError::Upstream(_) => HttpResponse::BadGateway(),
+                };
+                return Ok(response.json(ErrorResponse {
+                    error: "Remote fetch failed".to_string(),
+                    message: e.to_string(),
+                }));
+            }
+        };
+
+        let filename = body.filename
+            .map(|f| f.trim().to_string())
+            .filter(|f| !f.is_empty())
+            .or_else(|| {
+                fetched.final_url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .filter(|s| !s.is_empty())
+                    .map(|s| percent_encoding::percent_decode_str(s).decode_utf8_lossy().to_string())
+            })
+            .unwrap_or_else(|| "remote-image".to_string());
+
+        let image = IncomingImage {
+            filename,
+            mime_type: fetched.content_type,
+            bytes: fetched.bytes,
+            regions: body.regions.unwrap_or_default(),
+            sniff_content: true,
+        };
+        Self::process_upload(user, user_id, image, options.into_inner(), start_time).await
+    }
+
+    /// Shared pipeline behind every upload endpoint: validation, OCR, storage
+    /// upload and record creation.
+    async fn process_upload(
+        user: User,
+        user_id: ObjectId,
+        image: IncomingImage,
+        options: UploadOptions,
+        start_time: Instant,
+    ) -> Result<HttpResponse, Error> {
+        let IncomingImage { filename, mime_type, bytes: file_bytes, regions, sniff_content } = image;
+
+        if let Some(response) = upload_validation_error(&mime_type, &file_bytes, sniff_content) {
+            return Ok(response);
+        }
+
+        // Per-request model overrides must be allowed by the user's plan.
+        let requested_model = options.model.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+        let secondary_model = options.secondary_model.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+        for model in requested_model.iter().chain(secondary_model.iter()) {
+            if !user.allowed_ocr_models().contains(&model.as_str()) {
+                return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+                    error: "Model not allowed".to_string(),
+                    message: format!(
+                        "Model '{}' is not available on the {} plan. Allowed: {}",
+                        model,
+                        user.plan,
+                        user.allowed_ocr_models().join(", ")
+                    ),
                 }));
             }
-            file_bytes.extend_from_slice(&data);
         }
+
+        let consensus_mode = match options.mode.as_deref().map(str::trim) {
+            None | Some("") | Some("single") => false,
+            Some("consensus") => true,
+            Some(other) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid mode".to_string(),
+                    message: format!("Supported modes: single, consensus. Got: {}", other),
+                }));
+            }
+        };
+
+        let config = Config::new();
+        let cloudinary = CloudinaryService::new(config.clone());
         let size = file_bytes.len() as u64;
-        println!("CONTROLLER: Finished reading file into memory. Size: {} bytes.", size);
-
-        // --- Qwen OCR Step (Replaces Tesseract) ---
-        let extracted_text = {
-            println!("CONTROLLER: Starting Qwen Vision API call...");
-            let base64_image = general_purpose::STANDARD.encode(&file_bytes);
-            let image_uri = format!("data:{};base64,{}", mime_type, base64_image);
-            
-            let api_key = &config.open_router_api_key;
-            let model_name = "qwen/qwen-vl-plus";
-            let prompt_text = "You are an expert mathematician and a LaTeX specialist. Your task is to accurately transcribe all text and mathematical expressions from the provided image.
-
-            **CRITICAL INSTRUCTIONS:**
-            1.  **Validate and Correct:** Do not just blindly transcribe. Interpret the content. If a mathematical expression seems syntactically incorrect or a symbol is ambiguous, infer the most likely correct version. Your goal is to produce semantically sound mathematics.
-            2.  **Strict Formatting:** Adhere to the following formatting rules without exception.
-                *   **Math Delimiters:** Use ONLY `$...$` for inline math and `$$...$$` for display/block math.
-                *   **No Structural LaTeX:** Do NOT use commands like `\\textbf`, `\\textit`, `\\section`, `\\begin{itemize}`, etc. Use plain text for emphasis and structure.
-                *   **No Code Blocks:** The entire output must be plain text. Do NOT wrap it in Markdown code blocks (```).
-                *   **Plain Lists:** For lists, use simple numbering like `1.`, `2.`, or plain hyphens `-`.
-            
-            **Example of Correct Output:**
-            Here is some text with an inline formula $f(x) = x^2 + 1$.
-            
-            1. A list item with a display formula:
-            $$ \\int_a^b g(t) dt = G(b) - G(a) $$
-            2. Another list item.
-            
-            **Your Goal:** Return ONLY the clean, corrected, and properly formatted transcription from the image.";
-            
-            let request_body = QwenRequestBody {
-                model: model_name,
-                messages: vec![QwenMessage {
-                    role: "user",
-                    content: vec![
-                        QwenContentPart::Text { text: prompt_text },
-                        QwenContentPart::ImageUrl { image_url: QwenImageUrl { url: image_uri } },
-                    ],
-                }],
-                max_tokens: 2048,
-            };
 
-            let client = reqwest::Client::new();
-            let response_result = client
-                .post("https://openrouter.ai/api/v1/chat/completions")
-                .bearer_auth(api_key)
-                .json(&request_body)
-                .send()
-                .await;
-
-            match response_result {
-                Ok(res) if res.status().is_success() => {
-                    match res.json::<QwenChatCompletionResponse>().await {
-                        Ok(res_body) => {
-                            if let Some(choice) = res_body.choices.get(0) {
-                                println!("CONTROLLER: Qwen API call successful.");
-                                Ok(choice.message.content.clone())
-                            } else {
-                                Err("Qwen API returned no choices.".to_string())
-                            }
-                        }
-                        Err(e) => Err(format!("Failed to parse Qwen API response: {}", e)),
-                    }
+        if regions.len() > MAX_CROP_REGIONS {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid regions".to_string(),
+                message: format!("At most {} regions can be given per image.", MAX_CROP_REGIONS),
+            }));
+        }
+        if consensus_mode && !regions.is_empty() {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid mode".to_string(),
+                message: "Consensus mode cannot be combined with crop regions.".to_string(),
+            }));
+        }
+
+        // --- OCR Step: try the requested model, then the configured fallback chain ---
+        let ocr = OcrService::new(config.clone());
+        let models = ocr.model_chain(requested_model.as_deref());
+        let mut consensus = None;
+        let mut region_results = Vec::new();
+        let extracted_text = if !regions.is_empty() {
+            // Region mode: each crop is OCR'd on its own and returned as a separate block.
+            println!("CONTROLLER: Starting region OCR for {} region(s)...", regions.len());
+            match Self::ocr_regions(&ocr, &file_bytes, &regions, &models).await {
+                Ok(results) => {
+                    let output = OcrOutput {
+                        text: region_text(&results),
+                        model: results[0].ocr_model.clone(),
+                        low_quality: results.iter().any(|r| r.low_quality),
+                    };
+                    region_results = results;
+                    Ok(output)
                 }
-                Ok(res) => {
-                    let status = res.status();
-                    let error_text = res.text().await.unwrap_or_else(|_| "Could not read error body".to_string());
-                    Err(format!("Qwen API call failed with status {}: {}", status, error_text))
+                Err(RegionOcrError::InvalidRegion(e)) => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid regions".to_string(),
+                        message: e,
+                    }));
                 }
-                Err(e) => Err(format!("Failed to send request to Qwen API: {}", e)),
+                Err(RegionOcrError::Ocr(e)) => Err(e),
             }
+        } else if consensus_mode {
+            // Consensus mode compares the primary model against a second, distinct model.
+            let Some(primary_model) = models.first().cloned() else {
+                return Ok(HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                    error: "OCR unavailable".to_string(),
+                    message: "No OCR models are configured.".to_string(),
+                }));
+            };
+            // A second model picked from the chain must also be allowed by the plan.
+            let allowed = user.allowed_ocr_models();
+            let fallback_model = || models.iter().find(|m| **m != primary_model && allowed.contains(&m.as_str())).cloned();
+            let secondary_model = match secondary_model.or_else(fallback_model) {
+                Some(model) if model != primary_model => model,
+                _ => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid mode".to_string(),
+                        message: "Consensus mode requires two distinct models.".to_string(),
+                    }));
+                }
+            };
+
+            println!("CONTROLLER: Starting consensus OCR with {} and {}...", primary_model, secondary_model);
+            match ocr.transcribe_pair(&file_bytes, &mime_type, &primary_model, &secondary_model).await {
+                Ok((primary, secondary)) => {
+                    let segments = ConsensusAligner::align(&primary.text, &secondary.text);
+                    let ratio = ConsensusAligner::agreement_ratio(&segments);
+                    let review = ConsensusReview::new(primary.model.clone(), secondary.model, ratio, segments);
+                    let low_quality = primary.low_quality || secondary.low_quality;
+                    let output = OcrOutput { text: review.merged_text(), model: primary.model, low_quality };
+                    consensus = Some(review);
+                    Ok(output)
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            println!("CONTROLLER: Starting OCR with model chain...");
+            ocr.transcribe(&file_bytes, &mime_type, &models).await
         };
-        
-        let extracted_text = match extracted_text {
-            Ok(text) => text,
+
+        let ocr_output = match extracted_text {
+            Ok(output) => output,
             Err(e) => {
                 eprintln!("CONTROLLER: OCR Error: {}", e);
                 return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
@@ -265,7 +496,10 @@ impl ConversionController {
         // --- Database Record Creation ---
         println!("CONTROLLER: Creating database record...");
         let mut conversion = Conversion::new(user_id, filename, size, mime_type);
-        conversion.mark_completed(extracted_text, processing_time_ms, cloudinary_url, public_id.clone());
+        conversion.mark_completed(ocr_output.text, processing_time_ms, cloudinary_url, public_id.clone());
+        conversion.ocr_model = Some(ocr_output.model.clone());
+        conversion.consensus = consensus;
+        conversion.regions = region_results.clone();
 
         let collection = match database::get_conversion_collection().await {
             Ok(coll) => coll,
@@ -289,6 +523,11 @@ impl ConversionController {
                     job_id,
                     message: "Image processed and uploaded successfully.".to_string(),
                     status: "completed".to_string(),
+                    ocr_model: Some(ocr_output.model),
+                    regions: region_results,
+                    warning: ocr_output.low_quality.then(|| {
+                        "No OCR model produced clean output (e.g. unbalanced $ delimiters); review the extracted text.".to_string()
+                    }),
                 }))
             }
             Err(e) => {
@@ -301,9 +540,167 @@ impl ConversionController {
         }
     }
 
-    pub async fn get_conversion_result(req: HttpRequest, job_id: web::Path<String>) -> Result<HttpResponse, Error> {
+    /// Crops each region out of the image and OCRs the crops concurrently.
+    async fn ocr_regions(
+        ocr: &OcrService,
+        image_bytes: &[u8],
+        regions: &[CropRegion],
+        models: &[String],
+    ) -> Result<Vec<RegionResult>, RegionOcrError> {
+        // Decoding and re-encoding a large image is CPU-bound, so keep it off the async workers.
+        let (image_bytes, owned_regions) = (image_bytes.to_vec(), regions.to_vec());
+        let crops = web::block(move || ImageCropper::crop_all(&image_bytes, &owned_regions))
+            .await
+            .map_err(|e| RegionOcrError::Ocr(format!("Cropping failed: {}", e)))?
+            .map_err(RegionOcrError::InvalidRegion)?;
+
+        let outputs = futures::future::join_all(
+            crops.iter().map(|crop| ocr.transcribe(&crop.bytes, &crop.mime_type, models))
+        ).await;
+
+        crops
+            .into_iter()
+            .zip(outputs)
+            .zip(regions.iter().cloned())
+            .enumerate()
+            .map(|(index, ((crop, output), region))| {
+                let output = output.map_err(|e| RegionOcrError::Ocr(format!("Region {}: {}", index, e)))?;
+                Ok(RegionResult {
+                    index,
+                    region,
+                    pixel_rect: crop.rect,
+                    text: output.text,
+                    ocr_model: output.model,
+                    low_quality: output.low_quality,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn recrop_conversion(
+        req: HttpRequest,
+        job_id: web::Path<String>,
+        body: web::Json<RecropRequest>,
+    ) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered recrop_conversion handler for job_id: {}", job_id);
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            })),
+        };
+
+        let user = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) => user,
+            _ => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "Invalid user token.".to_string(),
+            })),
+        };
+        let user_db_id = user.id.unwrap();
+
+        let body = body.into_inner();
+        if body.regions.is_empty() || body.regions.len() > MAX_CROP_REGIONS {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid regions".to_string(),
+                message: format!("Provide between 1 and {} regions.", MAX_CROP_REGIONS),
+            }));
+        }
+
+        let requested_model = body.model.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+        if let Some(model) = &requested_model
+            && !user.allowed_ocr_models().contains(&model.as_str()) {
+            return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+                error: "Model not allowed".to_string(),
+                message: format!("Model '{}' is not available on the {} plan.", model, user.plan),
+            }));
+        }
+
+        let collection = match database::get_conversion_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let conversion = match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
+            Ok(Some(conversion)) if conversion.user_id == user_db_id => conversion,
+            Ok(Some(_)) => return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+                error: "Forbidden".to_string(),
+                message: "You do not have permission to access this resource.".to_string(),
+            })),
+            Ok(None) => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Conversion job with this ID was not found.".to_string(),
+            })),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve conversion data.".to_string(),
+            })),
+        };
+
+        // Re-cropping works from the original image kept in Cloudinary.
+        let config = Config::new();
+        let cloudinary = CloudinaryService::new(config.clone());
+        let image_bytes = match cloudinary.fetch_file(&conversion.cloudinary_url).await {
+            Ok(bytes) => bytes,
+            Err(e) => return Ok(HttpResponse::BadGateway().json(ErrorResponse {
+                error: "Image unavailable".to_string(),
+                message: e,
+            })),
+        };
+
+        let ocr = OcrService::new(config);
+        let models = ocr.model_chain(requested_model.as_deref());
+        let results = match Self::ocr_regions(&ocr, &image_bytes, &body.regions, &models).await {
+            Ok(results) => results,
+            Err(RegionOcrError::InvalidRegion(e)) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid regions".to_string(),
+                message: e,
+            })),
+            Err(RegionOcrError::Ocr(e)) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "OCR failed".to_string(),
+                message: e,
+            })),
+        };
+
+        // The text follows the new regions, as in an upload with regions; any
+        // consensus review was of the old text and no longer applies.
+        let extracted_text = region_text(&results);
+        match ConversionCRUD::update_regions(&job_id, &user_db_id, &results, &extracted_text, &collection).await {
+            Ok(_) => Ok(HttpResponse::Ok().json(RegionsResponse {
+                job_id: job_id.into_inner(),
+                regions: results,
+                extracted_text,
+            })),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to save region results.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn get_conversion_result(
+        req: HttpRequest,
+        job_id: web::Path<String>,
+        params: web::Query<ResultParams>,
+    ) -> Result<HttpResponse, Error> {
         println!("CONTROLLER: Entered get_conversion_result handler for job_id: {}", job_id);
 
+        let math_format = match params.math.as_deref() {
+            None => None,
+            Some(name) => match MathOutputFormat::from_name(name) {
+                Some(format) => Some(format),
+                None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid math format".to_string(),
+                    message: format!("Unsupported math format '{}'. Use 'mathml' or 'unicode'.", name),
+                })),
+            },
+        };
+
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
             Some(claims) => claims,
             None => {
@@ -342,13 +739,23 @@ impl ConversionController {
                     }));
                 }
                 
+                let extracted_text = conversion.extracted_text.unwrap_or_default();
+                let rendered_text = math_format.map(|format| render_math_in_text(&extracted_text, format));
+
                 let response = ConversionResultResponse {
                     job_id: conversion.job_id,
                     status: conversion.status.to_string(),
                     original_filename: conversion.original_filename,
-                    extracted_text: conversion.extracted_text.unwrap_or_default(),
+                    extracted_text,
                     created_at: conversion.created_at.to_string(),
                     processing_time_ms: conversion.processing_time_ms.unwrap_or(0),
+                    ocr_model: conversion.ocr_model,
+                    consensus: conversion.consensus,
+                    regions: conversion.regions,
+                    tags: conversion.tags,
+                    metadata: conversion.metadata,
+                    math_format: math_format.map(|f| f.name().to_string()),
+                    rendered_text,
                 };
 
                 Ok(HttpResponse::Ok().json(response))
@@ -364,8 +771,8 @@ impl ConversionController {
         }
     }
 
-    pub async fn download_word_document(req: HttpRequest, job_id: web::Path<String>) -> Result<HttpResponse, Error> {
-        println!("CONTROLLER: Entered download_word_document handler for job_id: {}", job_id);
+    pub async fn get_equations(req: HttpRequest, job_id: web::Path<String>) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered get_equations handler for job_id: {}", job_id);
 
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
             Some(claims) => claims,
@@ -393,42 +800,28 @@ impl ConversionController {
 
         match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
             Ok(Some(conversion)) if conversion.user_id == user.id.unwrap() => {
-                let latex_content = conversion.extracted_text.unwrap_or_default();
-                if latex_content.is_empty() {
-                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                        error: "No Content".to_string(),
-                        message: "The conversion has no text to export.".to_string(),
-                    }));
-                }
+                let equations: Vec<EquationItem> = extract_math_segments(&conversion.extracted_text.unwrap_or_default())
+                    .into_iter()
+                    .map(|segment| EquationItem { alt_text: speak_latex(&segment.latex), segment })
+                    .collect();
+                let inline_count = equations.iter().filter(|e| e.segment.kind == MathKind::Inline).count();
 
-                match DocumentConverter::latex_to_docx(&latex_content) {
-                    Ok(result) => {
-                        let filename = format!("{}.docx", conversion.original_filename);
-                        let encoded_filename = percent_encoding::percent_encode(
-                            filename.as_bytes(),
-                            percent_encoding::NON_ALPHANUMERIC
-                        ).to_string();
-                        
-                        Ok(HttpResponse::Ok()
-                            .content_type(result.mime_type)
-                            .append_header(("Content-Disposition", format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", filename, encoded_filename)))
-                            .append_header(("Content-Length", result.size.to_string()))
-                            .append_header(("Cache-Control", "no-cache"))
-                            .body(result.content))
-                    }
-                    Err(e) => {
-                        eprintln!("Word conversion failed: {}", e);
-                        Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                            error: "Document Conversion Failed".to_string(),
-                            message: format!("Could not convert LaTeX to DOCX. Error: {}", e),
-                        }))
-                    }
-                }
+                Ok(HttpResponse::Ok().json(EquationsResponse {
+                    job_id: conversion.job_id,
+                    total: equations.len(),
+                    inline_count,
+                    display_count: equations.len() - inline_count,
+                    equations,
+                }))
             }
-            Ok(_) => Ok(HttpResponse::Forbidden().json(ErrorResponse {
+            Ok(Some(_)) => Ok(HttpResponse::Forbidden().json(ErrorResponse {
                 error: "Forbidden".to_string(),
                 message: "You do not have permission to access this resource.".to_string(),
             })),
+            Ok(None) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Conversion job with this ID was not found.".to_string(),
+            })),
             Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                 error: "Database Error".to_string(),
                 message: "Failed to retrieve conversion data.".to_string(),
@@ -436,8 +829,12 @@ impl ConversionController {
         }
     }
 
-    pub async fn download_odt_document(req: HttpRequest, job_id: web::Path<String>) -> Result<HttpResponse, Error> {
-        println!("CONTROLLER: Entered download_odt_document handler for job_id: {}", job_id);
+    pub async fn update_tags(
+        req: HttpRequest,
+        job_id: web::Path<String>,
+        body: web::Json<UpdateTagsRequest>,
+    ) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered update_tags handler for job_id: {}", job_id);
 
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
             Some(claims) => claims,
@@ -454,6 +851,7 @@ impl ConversionController {
                 message: "Invalid user token.".to_string(),
             })),
         };
+        let user_db_id = user.id.unwrap();
 
         let collection = match database::get_conversion_collection().await {
             Ok(coll) => coll,
@@ -463,53 +861,384 @@ impl ConversionController {
             })),
         };
 
-        match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
-            Ok(Some(conversion)) if conversion.user_id == user.id.unwrap() => {
-                let latex_content = conversion.extracted_text.unwrap_or_default();
-                if latex_content.is_empty() {
-                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
-                        error: "No Content".to_string(),
-                        message: "The conversion has no text to export.".to_string(),
-                    }));
-                }
+        let conversion = match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
+            Ok(Some(conversion)) if conversion.user_id == user_db_id => conversion,
+            Ok(Some(_)) => return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+                error: "Forbidden".to_string(),
+                message: "You do not have permission to access this resource.".to_string(),
+            })),
+            Ok(None) => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Conversion job with this ID was not found.".to_string(),
+            })),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve conversion data.".to_string(),
+            })),
+        };
 
-                match DocumentConverter::latex_to_odt(&latex_content) {
-                    Ok(result) => {
-                        let filename = format!("{}.odt", conversion.original_filename);
-                        let encoded_filename = percent_encoding::percent_encode(
-                            filename.as_bytes(),
-                            percent_encoding::NON_ALPHANUMERIC
-                        ).to_string();
-                        
-                        Ok(HttpResponse::Ok()
-                            .content_type(result.mime_type)
-                            .append_header(("Content-Disposition", format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", filename, encoded_filename)))
-                            .append_header(("Content-Length", result.size.to_string()))
-                            .append_header(("Cache-Control", "no-cache"))
-                            .body(result.content))
-                    }
-                    Err(e) => {
-                        eprintln!("ODT conversion failed: {}", e);
-                        Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                            error: "Document Conversion Failed".to_string(),
-                            message: format!("Could not convert LaTeX to ODT. Error: {}", e),
-                        }))
-                    }
-                }
+        // Fields omitted from the body keep their current values.
+        let body = body.into_inner();
+        let tags = match body.tags {
+            Some(tags) => normalize_tags(&tags),
+            None => conversion.tags,
+        };
+        let metadata = match body.metadata.map(normalize_metadata) {
+            Some(Ok(metadata)) => metadata,
+            Some(Err(message)) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid tags or metadata".to_string(),
+                    message,
+                }));
             }
-            Ok(_) => Ok(HttpResponse::Forbidden().json(ErrorResponse {
+            None => conversion.metadata,
+        };
+
+        if let Err(message) = validate_tags_and_metadata(&tags, &metadata) {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid tags or metadata".to_string(),
+                message,
+            }));
+        }
+
+        match ConversionCRUD::update_tags_and_metadata(&job_id, &user_db_id, &tags, &metadata, &collection).await {
+            Ok(_) => Ok(HttpResponse::Ok().json(TagsResponse {
+                job_id: job_id.into_inner(),
+                tags,
+                metadata,
+            })),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to update tags and metadata.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn resolve_consensus_segment(
+        req: HttpRequest,
+        path: web::Path<(String, usize)>,
+        body: web::Json<ResolveSegmentRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let (job_id, segment_index) = path.into_inner();
+        println!("CONTROLLER: Entered resolve_consensus_segment handler for job_id: {}, segment: {}", job_id, segment_index);
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            })),
+        };
+
+        let user = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) => user,
+            _ => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "Invalid user token.".to_string(),
+            })),
+        };
+        let user_db_id = user.id.unwrap();
+
+        let collection = match database::get_conversion_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let conversion = match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
+            Ok(Some(conversion)) if conversion.user_id == user_db_id => conversion,
+            Ok(Some(_)) => return Ok(HttpResponse::Forbidden().json(ErrorResponse {
                 error: "Forbidden".to_string(),
                 message: "You do not have permission to access this resource.".to_string(),
             })),
+            Ok(None) => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Conversion job with this ID was not found.".to_string(),
+            })),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve conversion data.".to_string(),
+            })),
+        };
+
+        let mut review = match conversion.consensus {
+            Some(review) => review,
+            None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Not a consensus conversion".to_string(),
+                message: "This conversion was not processed in consensus mode.".to_string(),
+            })),
+        };
+
+        let segment = match review.segments.get_mut(segment_index) {
+            Some(segment) if segment.status != SegmentStatus::Agreed => segment,
+            Some(_) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid segment".to_string(),
+                message: "Both models agreed on this segment; there is nothing to resolve.".to_string(),
+            })),
+            None => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Segment {} does not exist.", segment_index),
+            })),
+        };
+
+        let body = body.into_inner();
+        let resolved_text = match body.choice.as_str() {
+            "primary" => segment.primary.clone().unwrap_or_default(),
+            "secondary" => segment.secondary.clone().unwrap_or_default(),
+            "custom" => match body.text {
+                // Custom text ends up in `extracted_text`, so it gets the editor's checks.
+                Some(text) if text.len() > MAX_PREVIEW_CONTENT_BYTES => return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                    error: "Content Too Large".to_string(),
+                    message: format!("The custom text must be at most {} MB.", MAX_PREVIEW_CONTENT_BYTES / (1024 * 1024)),
+                })),
+                Some(text) => {
+                    let violations = find_blocked_constructs(&text);
+                    if !violations.is_empty() {
+                        return Ok(HttpResponse::UnprocessableEntity().json(LatexRejectedResponse {
+                            error: "Unsafe LaTeX".to_string(),
+                            message: "The custom text uses commands that are not allowed. Remove them and try again.".to_string(),
+                            violations,
+                        }));
+                    }
+                    text
+                }
+                None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid choice".to_string(),
+                    message: "A 'text' value is required when choice is 'custom'.".to_string(),
+                })),
+            },
+            other => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid choice".to_string(),
+                message: format!("Supported choices: primary, secondary, custom. Got: {}", other),
+            })),
+        };
+        segment.text = resolved_text;
+        segment.status = SegmentStatus::Resolved;
+        if review.merged_text().len() > MAX_PREVIEW_CONTENT_BYTES {
+            return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                error: "Content Too Large".to_string(),
+                message: format!("The resolved text must be at most {} MB.", MAX_PREVIEW_CONTENT_BYTES / (1024 * 1024)),
+            }));
+        }
+
+        match ConversionCRUD::update_consensus(&job_id, &user_db_id, &review, &collection).await {
+            Ok(_) => Ok(HttpResponse::Ok().json(serde_json::json!({
+                "job_id": job_id,
+                "unresolved_segments": review.unresolved_count(),
+                "extracted_text": review.merged_text(),
+                "consensus": review,
+            }))),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to save the resolved segment.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn suggest_tags(req: HttpRequest, query: web::Query<TagSuggestionParams>) -> Result<HttpResponse, Error> {
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            })),
+        };
+
+        let user = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) => user,
+            _ => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "Invalid user token.".to_string(),
+            })),
+        };
+
+        let collection = match database::get_conversion_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let limit = query.limit.unwrap_or(10).clamp(1, 50);
+        match ConversionCRUD::list_user_tags(&user.id.unwrap(), query.prefix.as_deref(), limit, &collection).await {
+            Ok(tags) => Ok(HttpResponse::Ok().json(TagSuggestionsResponse { tags })),
             Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve tags.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn merge_export(req: HttpRequest, body: web::Json<MergeExportRequest>) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered merge_export handler for {} job(s).", body.job_ids.len());
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            })),
+        };
+
+        let user = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) => user,
+            _ => return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "Invalid user token.".to_string(),
+            })),
+        };
+
+        let body = body.into_inner();
+        if body.job_ids.is_empty() || body.job_ids.len() > MAX_MERGE_CONVERSIONS {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid Request".to_string(),
+                message: format!("Provide between 1 and {} job_ids to merge.", MAX_MERGE_CONVERSIONS),
+            }));
+        }
+
+        let format = match TargetFormat::from_name(&body.format) {
+            Some(format) => format,
+            None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid Format".to_string(),
+                message: format!("Supported formats: {}. Got: {}", TargetFormat::supported_names(), body.format),
+            })),
+        };
+
+        let mut export_options = body.options.clone();
+        export_options.title = export_options.title.or_else(|| body.title.clone());
+        let mut options = match export_options.to_render_options(&user, TemplateFiles::default()) {
+            Ok(options) => options,
+            Err(message) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid Export Options".to_string(),
+                message,
+            })),
+        };
+        if let Some(response) = review_format_error(&options, format) {
+            return Ok(response);
+        }
+
+        let collection = match database::get_conversion_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let conversions = match ConversionCRUD::find_many_by_job_ids(&body.job_ids, &user.id.unwrap(), &collection).await {
+            Ok(conversions) => conversions,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                 error: "Database Error".to_string(),
                 message: "Failed to retrieve conversion data.".to_string(),
             })),
+        };
+
+        // Every requested job must be found and owned by the caller; duplicates are allowed.
+        let missing: Vec<&String> = body.job_ids
+            .iter()
+            .filter(|id| !conversions.iter().any(|c| &c.job_id == *id))
+            .collect();
+        if !missing.is_empty() {
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: format!("Conversions not found or not accessible: {}", missing.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ")),
+            }));
+        }
+
+        let sections: Vec<MergeSection> = body.job_ids
+            .iter()
+            .filter_map(|id| conversions.iter().find(|c| &c.job_id == id))
+            .map(|c| MergeSection {
+                heading: c.original_filename.clone(),
+                content: c.extracted_text.clone().unwrap_or_default(),
+            })
+            .collect();
+
+        if sections.iter().all(|s| s.content.trim().is_empty()) {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "No Content".to_string(),
+                message: "The selected conversions have no text to export.".to_string(),
+            }));
+        }
+
+        let merged_latex = DocumentConverter::merge_sections(
+            &sections,
+            body.page_breaks.unwrap_or(true),
+            body.section_headings.unwrap_or(true),
+        );
+
+        // Templates come from the project when every merged conversion shares one.
+        let project_id = conversions[0].project_id.filter(|id| conversions.iter().all(|c| c.project_id == Some(*id)));
+        options.templates = match TemplateController::export_templates(&user.id.unwrap(), project_id, format).await {
+            Ok(templates) => templates,
+            Err(e) => return Ok(Self::template_error_response(e)),
+        };
+
+        let rendered = if options.layout.original_image.is_some() {
+            let ordered: Vec<&Conversion> = body.job_ids
+                .iter()
+                .filter_map(|id| conversions.iter().find(|c| &c.job_id == id))
+                .collect();
+            let pages = review_pages(&ordered, body.section_headings.unwrap_or(true)).await;
+            DocumentConverter::render_review(&pages, format, &options).await
+        } else {
+            DocumentConverter::render(&merged_latex, format, &options).await
+        };
+        match rendered {
+            Ok(result) => {
+                let base_name = options.title
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .unwrap_or("merged-document");
+                let filename = format.file_name(base_name);
+                Ok(attachment_response(result, &filename, None))
+            }
+            Err(e) => {
+                eprintln!("Merged {} conversion failed: {}", format.name(), e);
+                Ok(render_error_response(e, "merged LaTeX", format))
+            }
+        }
+    }
+
+    fn template_error_response(error: String) -> HttpResponse {
+        eprintln!("Failed to load export templates: {}", error);
+        HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Template Error".to_string(),
+            message: "Failed to load the export templates for this document.".to_string(),
+        })
+    }
+
+    /// Cache lookups that fail are treated as misses; the export is rendered instead.
+    async fn find_cached_export(job_id: &str, cache_key: &str) -> Option<CachedExport> {
+        let collection = database::get_export_cache_collection().await.ok()?;
+        match ConversionCRUD::find_cached_export(job_id, cache_key, &collection).await {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Failed to look up cached export for {}: {}", job_id, e);
+                None
+            }
         }
     }
 
-    pub async fn download_pdf_document(req: HttpRequest, job_id: web::Path<String>) -> Result<HttpResponse, Error> {
-        println!("CONTROLLER: Entered download_pdf_document handler for job_id: {}", job_id);
+    pub async fn download_document(
+        req: HttpRequest,
+        path: web::Path<(String, String)>,
+        query: web::Query<ExportOptionsParams>,
+    ) -> Result<HttpResponse, Error> {
+        let (job_id, format_name) = path.into_inner();
+        println!("CONTROLLER: Entered download_document handler for job_id: {} format: {}", job_id, format_name);
+
+        let format = match TargetFormat::from_name(&format_name) {
+            Some(format) => format,
+            None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid Format".to_string(),
+                message: format!("Supported formats: {}. Got: {}", TargetFormat::supported_names(), format_name),
+            })),
+        };
 
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
             Some(claims) => claims,
@@ -535,9 +1264,20 @@ impl ConversionController {
             })),
         };
 
+        let mut options = match query.to_render_options(&user, TemplateFiles::default()) {
+            Ok(options) => options,
+            Err(message) => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid Export Options".to_string(),
+                message,
+            })),
+        };
+        if let Some(response) = review_format_error(&options, format) {
+            return Ok(response);
+        }
+
         match ConversionCRUD::find_by_job_id(&job_id, &collection).await {
             Ok(Some(conversion)) if conversion.user_id == user.id.unwrap() => {
-                let latex_content = conversion.extracted_text.unwrap_or_default();
+                let latex_content = conversion.extracted_text.clone().unwrap_or_default();
                 if latex_content.is_empty() {
                     return Ok(HttpResponse::BadRequest().json(ErrorResponse {
                         error: "No Content".to_string(),
@@ -545,38 +1285,244 @@ impl ConversionController {
                     }));
                 }
 
-                match DocumentConverter::latex_to_pdf(&latex_content) {
+                options.templates = match TemplateController::export_templates(&conversion.user_id, conversion.project_id, format).await {
+                    Ok(templates) => templates,
+                    Err(e) => return Ok(Self::template_error_response(e)),
+                };
+
+                let filename = format.file_name(&conversion.original_filename);
+                let review = options.layout.original_image.is_some();
+                let cache_key = export_cache::cache_key(
+                    &job_id,
+                    &latex_content,
+                    review.then_some(conversion.cloudinary_url.as_str()),
+                    format,
+                    &options,
+                );
+                let if_none_match = req
+                    .headers()
+                    .get(actix_web::http::header::IF_NONE_MATCH)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+
+                if let Some(entry) = Self::find_cached_export(&job_id, &cache_key).await {
+                    if if_none_match.as_deref().is_some_and(|header| export_cache::if_none_match(header, &entry.etag)) {
+                        return Ok(not_modified_response(&entry.etag));
+                    }
+                    match CloudinaryService::new(Config::new()).fetch_authenticated_raw(&entry.storage_public_id).await {
+                        Ok(content) => {
+                            let result = ConversionResult { size: content.len() as u64, content, mime_type: entry.mime_type };
+                            return Ok(attachment_response(result, &filename, Some(&entry.etag)));
+                        }
+                        Err(e) => eprintln!("Cached export {} unavailable, rendering again: {}", entry.cache_key, e),
+                    }
+                }
+
+                // A review export whose original image couldn't be fetched is incomplete, so it isn't cached
+                let (rendered, cacheable) = if review {
+                    let pages = review_pages(&[&conversion], false).await;
+                    let complete = pages.iter().all(|page| page.image.is_some());
+                    (DocumentConverter::render_review(&pages, format, &options).await, complete)
+                } else {
+                    (DocumentConverter::render(&latex_content, format, &options).await, true)
+                };
+                match rendered {
                     Ok(result) => {
-                        let filename = format!("{}.pdf", conversion.original_filename);
-                        let encoded_filename = percent_encoding::percent_encode(
-                            filename.as_bytes(),
-                            percent_encoding::NON_ALPHANUMERIC
-                        ).to_string();
-                        
-                        Ok(HttpResponse::Ok()
-                            .content_type(result.mime_type)
-                            .append_header(("Content-Disposition", format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", filename, encoded_filename)))
-                            .append_header(("Content-Length", result.size.to_string()))
-                            .append_header(("Cache-Control", "no-cache"))
-                            .body(result.content))
+                        let etag = export_cache::content_etag(&result.content);
+                        if cacheable {
+                            spawn_cache_export(CachedExport {
+                                id: None,
+                                job_id: job_id.clone(),
+                                cache_key,
+                                text_hash: export_cache::text_hash(&latex_content),
+                                format: format.name().to_string(),
+                                etag: etag.clone(),
+                                mime_type: result.mime_type.clone(),
+                                file_size: result.size,
+                                storage_url: String::new(),
+                                storage_public_id: String::new(),
+                                created_at: mongodb::bson::DateTime::now(),
+                            }, result.content.clone());
+                        }
+                        if if_none_match.as_deref().is_some_and(|header| export_cache::if_none_match(header, &etag)) {
+                            return Ok(not_modified_response(&etag));
+                        }
+                        Ok(attachment_response(result, &filename, Some(&etag)))
                     }
                     Err(e) => {
-                        eprintln!("PDF conversion failed: {}", e);
-                        Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                            error: "Document Conversion Failed".to_string(),
-                            message: format!("Could not convert LaTeX to PDF. Error: {}", e),
-                        }))
+                        eprintln!("{} conversion failed: {}", format.name(), e);
+                        Ok(render_error_response(e, "LaTeX", format))
                     }
                 }
             }
-            Ok(_) => Ok(HttpResponse::Forbidden().json(ErrorResponse {
+            Ok(Some(_)) => Ok(HttpResponse::Forbidden().json(ErrorResponse {
                 error: "Forbidden".to_string(),
                 message: "You do not have permission to access this resource.".to_string(),
             })),
+            Ok(None) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Conversion job with this ID was not found.".to_string(),
+            })),
             Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                 error: "Database Error".to_string(),
                 message: "Failed to retrieve conversion data.".to_string(),
             })),
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Rejects review mode for formats that cannot show the original image.
+fn review_format_error(options: &RenderOptions, format: TargetFormat) -> Option<HttpResponse> {
+    if options.layout.original_image.is_none() || format.supports_review() {
+        return None;
+    }
+    Some(HttpResponse::BadRequest().json(ErrorResponse {
+        error: "Invalid Export Options".to_string(),
+        message: format!("original_image is only available for PDF and DOCX exports, not {}.", format.name()),
+    }))
+}
+
+/// Fetches each conversion's stored image for a review export. A failed fetch
+/// leaves the page without its image rather than failing the export.
+async fn review_pages(conversions: &[&Conversion], headings: bool) -> Vec<ReviewPage> {
+    let cloudinary = CloudinaryService::new(Config::new());
+    let mut pages = Vec::with_capacity(conversions.len());
+    for conversion in conversions {
+        let image = match cloudinary.fetch_file(&conversion.cloudinary_url).await {
+            Ok(image) => Some(image),
+            Err(e) => {
+                eprintln!("Failed to fetch original image for {}: {}", conversion.job_id, e);
+                None
+            }
+        };
+        pages.push(ReviewPage {
+            heading: headings.then(|| conversion.original_filename.clone()),
+            content: conversion.extracted_text.clone().unwrap_or_default(),
+            image,
+        });
+    }
+    pages
+}
+
+/// Maps a failed render to a response: 503 with `Retry-After` when the export
+/// queue is saturated, 504 when the job ran out of time, 422 listing the blocked
+/// constructs or the located compile errors for bad LaTeX, 500 otherwise.
+fn render_error_response(error: RenderError, source: &str, format: TargetFormat) -> HttpResponse {
+    match error {
+        RenderError::Busy { retry_after_secs } => HttpResponse::ServiceUnavailable()
+            .append_header(("Retry-After", retry_after_secs.to_string()))
+            .json(ErrorResponse {
+                error: "Export Queue Full".to_string(),
+                message: format!("Too many exports are running. Please retry in {} seconds.", retry_after_secs),
+            }),
+        RenderError::TimedOut { limit_secs } => HttpResponse::GatewayTimeout().json(ErrorResponse {
+            error: "Document Conversion Timed Out".to_string(),
+            message: format!("Converting {} to {} took longer than {} seconds.", source, format.name().to_uppercase(), limit_secs),
+        }),
+        RenderError::Rejected(violations) => HttpResponse::UnprocessableEntity().json(LatexRejectedResponse {
+            error: "Unsafe LaTeX".to_string(),
+            message: format!("The {} uses commands that are not allowed in exports. Remove them and try again.", source),
+            violations,
+        }),
+        RenderError::Compile(errors) => HttpResponse::UnprocessableEntity().json(LatexCompileErrorResponse {
+            error: "LaTeX Compilation Failed".to_string(),
+            message: format!("The {} could not be compiled to {}. Fix the errors listed and try again.", source, format.name().to_uppercase()),
+            errors,
+        }),
+        RenderError::Unavailable(name) => HttpResponse::NotImplemented().json(ErrorResponse {
+            error: "Export Format Unavailable".to_string(),
+            message: format!("Exporting to {} is not available on this server. See /api/health for the supported formats.", name),
+        }),
+        RenderError::Failed(e) => HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Document Conversion Failed".to_string(),
+            message: format!("Could not convert {} to {}. Error: {}", source, format.name().to_uppercase(), e),
+        }),
+    }
+}
+
+/// `attachment` disposition for a user-supplied filename. The quoted `filename`
+/// is an ASCII fallback without quotes, backslashes or control characters; the
+/// full name goes in the RFC 5987 `filename*`.
+fn content_disposition(filename: &str) -> String {
+    let filename: String = filename.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = filename
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded_filename = percent_encoding::percent_encode(
+        filename.as_bytes(),
+        percent_encoding::NON_ALPHANUMERIC
+    ).to_string();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded_filename)
+}
+
+/// Builds a download response with an RFC 6266 filename for a rendered document.
+/// `no-cache` makes clients revalidate every time; with an `etag` they send it
+/// back in `If-None-Match` and get a 304 while the export is unchanged.
+pub fn attachment_response(result: ConversionResult, filename: &str, etag: Option<&str>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(result.mime_type)
+        .append_header(("Content-Disposition", content_disposition(filename)))
+        .append_header(("Content-Length", result.size.to_string()))
+        .append_header(("Cache-Control", "no-cache"));
+    if let Some(etag) = etag {
+        response.append_header(("ETag", etag));
+    }
+    response.body(result.content)
+}
+
+/// `attachment_response` for a body streamed in chunks, e.g. from a file on disk.
+pub fn streamed_attachment_response<S>(body: S, size: u64, mime_type: &str, filename: &str) -> HttpResponse
+where
+    S: futures_util::Stream<Item = Result<web::Bytes, std::io::Error>> + 'static,
+{
+    HttpResponse::Ok()
+        .content_type(mime_type)
+        .append_header(("Content-Disposition", content_disposition(filename)))
+        .append_header(("Cache-Control", "no-cache"))
+        .body(actix_web::body::SizedStream::new(size, body))
+}
+
+fn not_modified_response(etag: &str) -> HttpResponse {
+    HttpResponse::NotModified()
+        .append_header(("ETag", etag))
+        .append_header(("Cache-Control", "no-cache"))
+        .finish()
+}
+
+/// Exports kept per conversion for the current text, e.g. one per format.
+const CACHED_EXPORTS_PER_CONVERSION: usize = 8;
+
+/// Uploads a rendered export and records it in the cache, then deletes the
+/// files of the entries it superseded. Runs after the response is sent; a
+/// failure only means the next download renders again.
+fn spawn_cache_export(mut entry: CachedExport, content: Vec<u8>) {
+    actix_web::rt::spawn(async move {
+        let cloudinary = CloudinaryService::new(Config::new());
+        let public_id = format!("export_cache/{}", entry.cache_key);
+        let extension = TargetFormat::from_name(&entry.format).map(|f| f.extension()).unwrap_or("bin");
+        // Exports are users' documents, so they are never given a public URL
+        entry.storage_url = match cloudinary.upload_raw_authenticated(content, &public_id, &format!("export.{}", extension)).await {
+            Ok(url) => url,
+            Err(e) => return eprintln!("Failed to cache export {}: {}", entry.cache_key, e),
+        };
+        entry.storage_public_id = public_id;
+
+        let stale = match database::get_export_cache_collection().await {
+            Ok(collection) => ConversionCRUD::store_cached_export(&entry, CACHED_EXPORTS_PER_CONVERSION, &collection).await,
+            Err(e) => Err(e),
+        };
+        match stale {
+            Ok(public_ids) => {
+                for public_id in public_ids.iter().filter(|id| **id != entry.storage_public_id) {
+                    if let Err(e) = cloudinary.delete_authenticated_raw(public_id).await {
+                        eprintln!("Failed to delete stale cached export {}: {}", public_id, e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to record cached export {}: {}", entry.cache_key, e),
+        }
+    });
+}
+ 
\ No newline at end of file
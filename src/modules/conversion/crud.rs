@@ -1,5 +1,8 @@
-use mongodb::{Collection, bson::{doc, oid::ObjectId}};
-use crate::modules::conversion::model::Conversion;
+use mongodb::{Collection, bson::{doc, oid::ObjectId, Bson, Regex}};
+use std::collections::{BTreeMap, HashMap};
+use futures::TryStreamExt;
+use crate::modules::conversion::model::{CachedExport, Conversion, ConsensusReview, RegionResult};
+use mongodb::options::FindOptions;
 
 pub struct ConversionCRUD;
 
@@ -12,4 +15,207 @@ impl ConversionCRUD {
     pub async fn find_by_job_id(job_id: &str, collection: &Collection<Conversion>) -> mongodb::error::Result<Option<Conversion>> {
         collection.find_one(doc! { "job_id": job_id }).await
     }
-} 
\ No newline at end of file
+
+    /// Fetches a user's conversions by job_id, preserving the order of `job_ids`.
+    /// Ids that don't exist or belong to another user are simply absent from the result.
+    pub async fn find_many_by_job_ids(
+        job_ids: &[String],
+        user_id: &ObjectId,
+        collection: &Collection<Conversion>,
+    ) -> mongodb::error::Result<Vec<Conversion>> {
+        let mut cursor = collection
+            .find(doc! { "user_id": user_id, "job_id": { "$in": job_ids } })
+            .await?;
+
+        let mut found: HashMap<String, Conversion> = HashMap::new();
+        while let Some(conversion) = cursor.try_next().await? {
+            found.insert(conversion.job_id.clone(), conversion);
+        }
+
+        Ok(job_ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    pub async fn update_tags_and_metadata(
+        job_id: &str,
+        user_id: &ObjectId,
+        tags: &[String],
+        metadata: &HashMap<String, String>,
+        collection: &Collection<Conversion>,
+    ) -> mongodb::error::Result<bool> {
+        let metadata_doc: mongodb::bson::Document = metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), Bson::String(v.clone())))
+            .collect();
+
+        let result = collection
+            .update_one(
+                doc! { "job_id": job_id, "user_id": user_id },
+                doc! {
+                    "$set": {
+                        "tags": tags,
+                        "metadata": metadata_doc,
+                        "updated_at": mongodb::bson::DateTime::now()
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn update_consensus(
+        job_id: &str,
+        user_id: &ObjectId,
+        review: &ConsensusReview,
+        collection: &Collection<Conversion>,
+    ) -> mongodb::error::Result<bool> {
+        let review_bson = mongodb::bson::to_bson(review)?;
+        let result = collection
+            .update_one(
+                doc! { "job_id": job_id, "user_id": user_id },
+                doc! {
+                    "$set": {
+                        "consensus": review_bson,
+                        "extracted_text": review.merged_text(),
+                        "updated_at": mongodb::bson::DateTime::now()
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    /// Replaces the region results and the text joined from them, and drops any
+    /// consensus review, which was made of the previous text.
+    pub async fn update_regions(
+        job_id: &str,
+        user_id: &ObjectId,
+        regions: &[RegionResult],
+        extracted_text: &str,
+        collection: &Collection<Conversion>,
+    ) -> mongodb::error::Result<bool> {
+        let regions_bson = mongodb::bson::to_bson(regions)?;
+        let result = collection
+            .update_one(
+                doc! { "job_id": job_id, "user_id": user_id },
+                doc! {
+                    "$set": {
+                        "regions": regions_bson,
+                        "extracted_text": extracted_text,
+                        "ocr_model": regions.first().map(|r| r.ocr_model.as_str()),
+                        "consensus": mongodb::bson::Bson::Null,
+                        "updated_at": mongodb::bson::DateTime::now()
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    /// Returns the distinct tags a user has applied, optionally filtered by prefix, for autocomplete.
+    pub async fn list_user_tags(
+        user_id: &ObjectId,
+        prefix: Option<&str>,
+        limit: usize,
+        collection: &Collection<Conversion>,
+    ) -> mongodb::error::Result<Vec<String>> {
+        let mut filter = doc! { "user_id": user_id };
+        if let Some(prefix) = prefix.map(|p| p.trim().to_lowercase()).filter(|p| !p.is_empty()) {
+            filter.insert("tags", Regex {
+                pattern: format!("^{}", escape_regex(&prefix)),
+                options: String::new(),
+            });
+        }
+
+        let values = collection.distinct("tags", filter).await?;
+        let prefix = prefix.unwrap_or_default().trim().to_lowercase();
+        let mut tags: Vec<String> = values
+            .into_iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .filter(|t| t.starts_with(&prefix))
+            .collect();
+        tags.sort();
+        tags.truncate(limit);
+        Ok(tags)
+    }
+
+    pub async fn find_cached_export(
+        job_id: &str,
+        cache_key: &str,
+        collection: &Collection<CachedExport>,
+    ) -> mongodb::error::Result<Option<CachedExport>> {
+        collection.find_one(doc! { "job_id": job_id, "cache_key": cache_key }).await
+    }
+
+    /// Size of the newest cached export in each format made from the text
+    /// hashed as `text_hash`, by format name.
+    pub async fn cached_export_sizes(
+        job_id: &str,
+        text_hash: &str,
+        collection: &Collection<CachedExport>,
+    ) -> mongodb::error::Result<BTreeMap<String, u64>> {
+        let entries: Vec<CachedExport> = collection
+            .find(doc! { "job_id": job_id, "text_hash": text_hash })
+            .with_options(FindOptions::builder().sort(doc! { "created_at": 1 }).build())
+            .await?
+            .try_collect()
+            .await?;
+        Ok(entries.into_iter().map(|entry| (entry.format, entry.file_size)).collect())
+    }
+
+    /// Stores a rendered export and drops the entries it supersedes: those made
+    /// from an older text, and the oldest beyond `keep` for the conversion.
+    /// Returns the storage ids of the dropped entries so their files can be deleted.
+    pub async fn store_cached_export(
+        entry: &CachedExport,
+        keep: usize,
+        collection: &Collection<CachedExport>,
+    ) -> mongodb::error::Result<Vec<String>> {
+        collection
+            .update_one(
+                doc! { "job_id": &entry.job_id, "cache_key": &entry.cache_key },
+                doc! { "$setOnInsert": mongodb::bson::to_document(entry)? },
+            )
+            .upsert(true)
+            .await?;
+
+        let mut stale: Vec<CachedExport> = collection
+            .find(doc! { "job_id": &entry.job_id, "text_hash": { "$ne": &entry.text_hash } })
+            .await?
+            .try_collect()
+            .await?;
+        let current: Vec<CachedExport> = collection
+            .find(doc! { "job_id": &entry.job_id, "text_hash": &entry.text_hash })
+            .with_options(FindOptions::builder().sort(doc! { "created_at": -1 }).skip(keep as u64).build())
+            .await?
+            .try_collect()
+            .await?;
+        stale.extend(current);
+
+        let ids: Vec<ObjectId> = stale.iter().filter_map(|e| e.id).collect();
+        if !ids.is_empty() {
+            collection.delete_many(doc! { "_id": { "$in": ids } }).await?;
+        }
+        Ok(stale.into_iter().map(|e| e.storage_public_id).collect())
+    }
+}
+
+/// Adds a `$all` tag constraint to a conversion filter when tags were requested.
+pub fn apply_tag_filter(filter: &mut mongodb::bson::Document, tags: &[String]) {
+    if !tags.is_empty() {
+        filter.insert("tags", doc! { "$all": tags });
+    }
+}
+
+fn escape_regex(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
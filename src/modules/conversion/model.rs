@@ -1,8 +1,12 @@
 use serde::{Deserialize, Serialize};
 use mongodb::bson::{DateTime, oid::ObjectId};
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::fmt;
 
+use crate::services::consensus::AlignedSegment;
+use crate::services::image_crop::{CropRegion, PixelRect};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Conversion {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
@@ -21,15 +25,105 @@ pub struct Conversion {
     pub created_at: DateTime,
     pub updated_at: DateTime,
     pub completed_at: Option<DateTime>,
+    #[serde(default)]
+    pub project_id: Option<ObjectId>,  // Set when the conversion is assigned to a project
+    #[serde(default)]
+    pub ocr_model: Option<String>,  // Model that actually produced extracted_text
+    #[serde(default)]
+    pub consensus: Option<ConsensusReview>,  // Present for conversions made in consensus mode
+    #[serde(default)]
+    pub regions: Vec<RegionResult>,  // Per-region OCR results when crop rectangles were given
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub enum ConversionStatus {
+    #[default]
     Processing,
     Completed,
     Failed,
 }
 
+/// OCR output for one crop rectangle. Both the requested region and the
+/// resolved pixel rectangle are kept so the editor can draw the crop.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegionResult {
+    pub index: usize,
+    pub region: CropRegion,
+    pub pixel_rect: PixelRect,
+    pub text: String,
+    pub ocr_model: String,
+    #[serde(default)]
+    pub low_quality: bool,  // No model gave clean output for this region
+}
+
+/// Side-by-side result of running one image through two OCR models.
+/// `extracted_text` is always the concatenation of the segments' `text`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConsensusReview {
+    pub primary_model: String,
+    pub secondary_model: String,
+    pub agreement_ratio: f64,
+    pub segments: Vec<ConsensusSegment>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConsensusSegment {
+    pub index: usize,
+    pub status: SegmentStatus,
+    pub text: String,                // Accepted text; the primary candidate until resolved
+    pub primary: Option<String>,     // Only set for conflicting segments
+    pub secondary: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum SegmentStatus {
+    #[serde(rename = "agreed")]
+    Agreed,
+    #[serde(rename = "conflict")]
+    Conflict,
+    #[serde(rename = "resolved")]
+    Resolved,
+}
+
+impl ConsensusReview {
+    pub fn new(primary_model: String, secondary_model: String, agreement_ratio: f64, segments: Vec<AlignedSegment>) -> Self {
+        let segments = segments
+            .into_iter()
+            .enumerate()
+            .map(|(index, segment)| match segment {
+                AlignedSegment::Agreed(text) => ConsensusSegment {
+                    index,
+                    status: SegmentStatus::Agreed,
+                    text,
+                    primary: None,
+                    secondary: None,
+                },
+                AlignedSegment::Conflict { primary, secondary } => ConsensusSegment {
+                    index,
+                    status: SegmentStatus::Conflict,
+                    text: primary.clone(),
+                    primary: Some(primary),
+                    secondary: Some(secondary),
+                },
+            })
+            .collect();
+
+        Self { primary_model, secondary_model, agreement_ratio, segments }
+    }
+
+    pub fn unresolved_count(&self) -> usize {
+        self.segments.iter().filter(|s| s.status == SegmentStatus::Conflict).count()
+    }
+
+    pub fn merged_text(&self) -> String {
+        self.segments.iter().map(|s| s.text.as_str()).collect()
+    }
+}
+
 impl fmt::Display for ConversionStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -63,6 +157,12 @@ impl Conversion {
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
             completed_at: None,
+            project_id: None,
+            ocr_model: None,
+            consensus: None,
+            regions: Vec::new(),
+            tags: Vec::new(),
+            metadata: HashMap::new(),
         }
     }
 
@@ -83,8 +183,33 @@ impl Conversion {
     }
 }
 
-impl Default for ConversionStatus {
-    fn default() -> Self {
-        ConversionStatus::Processing
+/// A rendered export kept in storage so repeated downloads of an unchanged
+/// conversion skip the render. See `services::export_cache` for the key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CachedExport {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub job_id: String,
+    pub cache_key: String,
+    pub text_hash: String,        // Entries with another hash were rendered from an older text
+    pub format: String,
+    pub etag: String,             // Strong ETag of the stored bytes
+    pub mime_type: String,
+    pub file_size: u64,
+    pub storage_url: String,      // Authenticated asset; only reachable through a signed URL
+    pub storage_public_id: String,
+    pub created_at: DateTime,
+}
+
+/// Trims, lowercases and de-duplicates user supplied tags, dropping empty ones.
+/// Order of first appearance is preserved so the UI shows tags as entered.
+pub fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
     }
+    normalized
 } 
\ No newline at end of file
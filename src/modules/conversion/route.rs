@@ -1,16 +1,27 @@
 use actix_web::web;
 use crate::modules::conversion::controller::ConversionController;
 use crate::middleware::user_auth::Authentication;
+use crate::modules::conversion::schema::MAX_JSON_UPLOAD_BODY;
 
 pub fn configure_conversion_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/conversion")
             .wrap(Authentication::new())  // Require authentication for all conversion routes
             .route("/upload", web::post().to(ConversionController::upload_image))
+            .service(
+                web::resource("/upload/json")
+                    .app_data(web::JsonConfig::default().limit(MAX_JSON_UPLOAD_BODY))
+                    .route(web::post().to(ConversionController::upload_image_json))
+            )
+            .route("/upload/url", web::post().to(ConversionController::upload_image_url))
+            .route("/tags", web::get().to(ConversionController::suggest_tags))
+            .route("/merge", web::post().to(ConversionController::merge_export))
             .route("/{job_id}", web::get().to(ConversionController::get_conversion_result))
-            .route("/{job_id}/download/word", web::get().to(ConversionController::download_word_document))
-            .route("/{job_id}/download/odt", web::get().to(ConversionController::download_odt_document))
-            .route("/{job_id}/download/pdf", web::get().to(ConversionController::download_pdf_document))
+            .route("/{job_id}/equations", web::get().to(ConversionController::get_equations))
+            .route("/{job_id}/tags", web::put().to(ConversionController::update_tags))
+            .route("/{job_id}/regions", web::post().to(ConversionController::recrop_conversion))
+            .route("/{job_id}/consensus/{segment}", web::put().to(ConversionController::resolve_consensus_segment))
+            .route("/{job_id}/download/{format}", web::get().to(ConversionController::download_document))
     );
 }
 
@@ -18,7 +29,22 @@ pub fn configure_conversion_routes(cfg: &mut web::ServiceConfig) {
 Route Structure:
 
 1. Protected Routes (Auth Required):
-   - POST /api/conversion/upload
+   - POST /api/conversion/upload?model=&mode=single|consensus&secondary_model=
+     (multipart: image file plus an optional "regions" JSON array of crop rectangles)
+   - POST /api/conversion/upload/json  (base64 or data URI body, same query options)
+   - POST /api/conversion/upload/url   (remote image fetched with size, time and SSRF limits)
+   - GET /api/conversion/tags?prefix=&limit=
+   - POST /api/conversion/merge  (body may carry an "options" object, see below)
+   - GET /api/conversion/{job_id}?math=mathml|unicode
+   - GET /api/conversion/{job_id}/equations
+   - PUT /api/conversion/{job_id}/tags
+   - POST /api/conversion/{job_id}/regions
+   - PUT /api/conversion/{job_id}/consensus/{segment}
+   - GET /api/conversion/{job_id}/download/{format}
+     (docx|word, odt, pdf, pdfa, html, md, tex, epub, rtf, txt, ipynb; pdfa is PDF/A-2b,
+      validated with veraPDF or qpdf before it is returned)
+     ?paper_size=&margin=&font=&font_size=&columns=&line_spacing=&number_equations=&toc=
+      &title=&author=&date=  (layout applies to pdf, pdfa and tex; author defaults to full name)
    - GET /api/conversion/status/{job_id}
    - GET /api/conversion/history
    - DELETE /api/conversion/{job_id}
@@ -1,13 +1,59 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::modules::conversion::model::{ConsensusReview, RegionResult};
+use crate::modules::user::model::User;
+use crate::services::image_crop::CropRegion;
+use crate::services::document_converter::{parse_margin, DocumentFont, DocumentLayout, ImagePlacement, PaperSize, RenderOptions, TemplateFiles, Watermark, FONT_SIZES_PT, LINE_SPACINGS};
+use crate::services::latex_math::MathSegment;
+use crate::services::latex_log::LatexError;
+use crate::services::latex_sanitizer::LatexViolation;
 
 #[derive(Debug, Serialize)]
 pub struct UploadResponse {
     pub job_id: String,
     pub message: String,
     pub status: String,
+    pub ocr_model: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<RegionResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,  // Set when the text was kept despite failing the quality check
+}
+
+/// Query parameters accepted by the upload endpoint.
+#[derive(Debug, Deserialize, Default)]
+pub struct UploadOptions {
+    pub model: Option<String>,  // Must be in the user's plan allow-list
+    pub mode: Option<String>,   // "single" (default) or "consensus"
+    pub secondary_model: Option<String>,  // Second opinion for consensus mode
+}
+
+// Region-of-interest constraints
+pub const MAX_CROP_REGIONS: usize = 10;
+pub const MAX_REGIONS_FIELD_SIZE: usize = 16 * 1024; // 16 KB of JSON
+
+#[derive(Debug, Deserialize)]
+pub struct RecropRequest {
+    pub regions: Vec<CropRegion>,
+    pub model: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
+pub struct RegionsResponse {
+    pub job_id: String,
+    pub regions: Vec<RegionResult>,
+    pub extracted_text: String,  // The conversion's new text, joined from the regions
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveSegmentRequest {
+    pub choice: String,        // "primary", "secondary" or "custom"
+    pub text: Option<String>,  // Required when choice is "custom"
+}
+
+#[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct ConversionStatusResponse {
     pub job_id: String,
     pub status: String,
@@ -20,6 +66,7 @@ pub struct ConversionStatusResponse {
 }
 
 #[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct ConversionHistoryResponse {
     pub conversions: Vec<ConversionListItem>,
     pub total: i64,
@@ -28,6 +75,7 @@ pub struct ConversionHistoryResponse {
 }
 
 #[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct ConversionListItem {
     pub job_id: String,
     pub original_filename: String,
@@ -43,7 +91,24 @@ pub struct ErrorResponse {
     pub message: String,
 }
 
+/// Returned when LaTeX is refused by the sanitiser before export.
+#[derive(Debug, Serialize)]
+pub struct LatexRejectedResponse {
+    pub error: String,
+    pub message: String,
+    pub violations: Vec<LatexViolation>,
+}
+
+/// Returned when LaTeX fails to compile, with errors located in the user's text.
 #[derive(Debug, Serialize)]
+pub struct LatexCompileErrorResponse {
+    pub error: String,
+    pub message: String,
+    pub errors: Vec<LatexError>,
+}
+
+#[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct SuccessResponse {
     pub message: String,
     pub data: Option<serde_json::Value>,
@@ -51,6 +116,7 @@ pub struct SuccessResponse {
 
 // Validation error response
 #[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct ValidationErrorResponse {
     pub error: String,
     pub field_errors: std::collections::HashMap<String, Vec<String>>,
@@ -70,6 +136,34 @@ pub fn is_allowed_mime_type(mime: &str) -> bool {
     matches!(mime, "image/jpeg" | "image/png" | "image/gif")
 }
 
+/// Detects the image type from its magic bytes, for uploads whose declared type can't be trusted.
+pub fn sniff_image_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    match image::guess_format(bytes).ok()? {
+        image::ImageFormat::Jpeg => Some("image/jpeg"),
+        image::ImageFormat::Png => Some("image/png"),
+        image::ImageFormat::Gif => Some("image/gif"),
+        _ => None,
+    }
+}
+
+// JSON upload bodies carry base64, which is ~4/3 the size of the file itself.
+pub const MAX_JSON_UPLOAD_BODY: usize = (MAX_FILE_SIZE as usize / 3) * 4 + 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct JsonUploadRequest {
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,  // Falls back to the data URI type, then content sniffing
+    pub data: String,               // Base64 or a data URI
+    pub regions: Option<Vec<CropRegion>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UrlUploadRequest {
+    pub url: String,
+    pub filename: Option<String>,
+    pub regions: Option<Vec<CropRegion>>,
+}
+
 #[derive(Serialize)]
 pub struct ConversionResultResponse {
     pub job_id: String,
@@ -78,4 +172,257 @@ pub struct ConversionResultResponse {
     pub extracted_text: String,
     pub created_at: String,
     pub processing_time_ms: u64,
-} 
\ No newline at end of file
+    pub ocr_model: Option<String>,
+    pub consensus: Option<ConsensusReview>,
+    pub regions: Vec<RegionResult>,
+    pub tags: Vec<String>,
+    pub metadata: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub math_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rendered_text: Option<String>,  // extracted_text with math converted to math_format
+}
+
+/// Query parameters accepted by the result endpoint.
+#[derive(Debug, Deserialize)]
+pub struct ResultParams {
+    pub math: Option<String>,  // "mathml" or "unicode"
+}
+
+// Tag and metadata constraints
+pub const MAX_TAGS_PER_CONVERSION: usize = 20;
+pub const MAX_TAG_LENGTH: usize = 50;
+pub const MAX_METADATA_ENTRIES: usize = 20;
+pub const MAX_METADATA_KEY_LENGTH: usize = 50;
+pub const MAX_METADATA_VALUE_LENGTH: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTagsRequest {
+    pub tags: Option<Vec<String>>,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagsResponse {
+    pub job_id: String,
+    pub tags: Vec<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagSuggestionParams {
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagSuggestionsResponse {
+    pub tags: Vec<String>,
+}
+
+/// Query parameters accepted by conversion list endpoints to filter by tag.
+/// `tags` is a comma separated list; a conversion must carry all of them.
+#[derive(Debug, Deserialize)]
+pub struct TagFilterParams {
+    pub tags: Option<String>,
+}
+
+impl TagFilterParams {
+    pub fn tag_list(&self) -> Vec<String> {
+        match &self.tags {
+            Some(tags) => crate::modules::conversion::model::normalize_tags(
+                &tags.split(',').map(|t| t.to_string()).collect::<Vec<_>>()
+            ),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Checks tag and metadata limits, returning a user facing message on failure.
+/// Trims metadata keys and values. Keys that are only distinct before
+/// trimming, such as "a" and "a ", are rejected rather than merged.
+pub fn normalize_metadata(metadata: HashMap<String, String>) -> Result<HashMap<String, String>, String> {
+    let mut normalized = HashMap::with_capacity(metadata.len());
+    for (key, value) in metadata {
+        let key = key.trim().to_string();
+        if normalized.insert(key.clone(), value.trim().to_string()).is_some() {
+            return Err(format!("Metadata key '{}' is given more than once.", key));
+        }
+    }
+    Ok(normalized)
+}
+
+pub fn validate_tags_and_metadata(
+    tags: &[String],
+    metadata: &HashMap<String, String>,
+) -> Result<(), String> {
+    if tags.len() > MAX_TAGS_PER_CONVERSION {
+        return Err(format!("A conversion can have at most {} tags.", MAX_TAGS_PER_CONVERSION));
+    }
+    if let Some(tag) = tags.iter().find(|t| t.chars().count() > MAX_TAG_LENGTH) {
+        return Err(format!("Tag '{}' exceeds {} characters.", tag, MAX_TAG_LENGTH));
+    }
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err(format!("A conversion can have at most {} metadata entries.", MAX_METADATA_ENTRIES));
+    }
+    for (key, value) in metadata {
+        if key.trim().is_empty() || key.chars().count() > MAX_METADATA_KEY_LENGTH {
+            return Err(format!("Metadata keys must be 1-{} characters.", MAX_METADATA_KEY_LENGTH));
+        }
+        if key.starts_with('$') || key.contains('.') {
+            return Err(format!("Metadata key '{}' may not start with '$' or contain '.'.", key));
+        }
+        if value.chars().count() > MAX_METADATA_VALUE_LENGTH {
+            return Err(format!("Metadata value for '{}' exceeds {} characters.", key, MAX_METADATA_VALUE_LENGTH));
+        }
+    }
+    Ok(())
+}
+
+// Merge export constraints
+pub const MAX_MERGE_CONVERSIONS: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct MergeExportRequest {
+    pub job_ids: Vec<String>,      // Output order follows this list
+    pub format: String,            // Any TargetFormat name, e.g. "docx", "pdf" or "epub"
+    pub page_breaks: Option<bool>, // Defaults to true
+    pub section_headings: Option<bool>, // Defaults to true, headings come from filenames
+    pub title: Option<String>,
+    #[serde(default)]
+    pub options: ExportOptionsParams,  // Layout and metadata; `options.title` overrides `title`
+}
+
+pub const MAX_EXPORT_METADATA_LENGTH: usize = 200;
+
+/// Layout and metadata options for the export endpoints, as query parameters
+/// or a JSON object. Every value is checked against an allow-list.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ExportOptionsParams {
+    pub paper_size: Option<String>,      // a4, a5, letter or legal
+    pub margin: Option<String>,          // e.g. 2cm, 20mm or 1in, between 0.5cm and 5cm
+    pub font: Option<String>,            // latin_modern, times, palatino, helvetica or schoolbook
+    pub font_size: Option<u8>,           // 10, 11 or 12 (points)
+    pub columns: Option<u8>,             // 1 or 2
+    pub line_spacing: Option<f32>,       // 1, 1.15, 1.5 or 2
+    pub number_equations: Option<bool>,
+    pub toc: Option<bool>,
+    pub title: Option<String>,
+    pub author: Option<String>,          // Defaults to the user's full name
+    pub date: Option<String>,
+    pub original_image: Option<String>,  // beside or above: review pages for pdf and docx
+}
+
+impl ExportOptionsParams {
+    /// Validates the options into render settings for `user`'s export, returning
+    /// a user facing message for the first value outside its allow-list.
+    pub fn to_render_options(&self, user: &User, templates: TemplateFiles) -> Result<RenderOptions, String> {
+        let paper_size = match self.paper_size.as_deref() {
+            Some(name) => Some(PaperSize::from_name(name).ok_or("paper_size must be one of a4, a5, letter or legal.")?),
+            None => None,
+        };
+        let margin = match self.margin.as_deref() {
+            Some(margin) => Some(parse_margin(margin).ok_or("margin must be a length such as 2cm, 20mm or 1in, between 0.5cm and 5cm.")?),
+            None => None,
+        };
+        let font = match self.font.as_deref() {
+            Some(name) => Some(DocumentFont::from_name(name).ok_or("font must be one of latin_modern, times, palatino, helvetica or schoolbook.")?),
+            None => None,
+        };
+        if let Some(size) = self.font_size.filter(|s| !FONT_SIZES_PT.contains(s)) {
+            return Err(format!("font_size must be 10, 11 or 12, got {}.", size));
+        }
+        if let Some(columns) = self.columns.filter(|c| !(1..=2).contains(c)) {
+            return Err(format!("columns must be 1 or 2, got {}.", columns));
+        }
+        if let Some(spacing) = self.line_spacing.filter(|s| !LINE_SPACINGS.contains(s)) {
+            return Err(format!("line_spacing must be 1, 1.15, 1.5 or 2, got {}.", spacing));
+        }
+        let original_image = match self.original_image.as_deref() {
+            Some(name) => Some(ImagePlacement::from_name(name).ok_or("original_image must be beside or above.")?),
+            None => None,
+        };
+
+        let metadata = |value: &Option<String>, field: &str| -> Result<Option<String>, String> {
+            match value.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+                Some(v) if v.chars().count() > MAX_EXPORT_METADATA_LENGTH => {
+                    Err(format!("{} must be at most {} characters.", field, MAX_EXPORT_METADATA_LENGTH))
+                }
+                v => Ok(v.map(str::to_string)),
+            }
+        };
+
+        Ok(RenderOptions {
+            title: metadata(&self.title, "title")?,
+            author: metadata(&self.author, "author")?.or_else(|| Some(user.full_name.clone()).filter(|a| !a.trim().is_empty())),
+            date: metadata(&self.date, "date")?,
+            layout: DocumentLayout {
+                paper_size,
+                margin,
+                font,
+                font_size_pt: self.font_size,
+                two_columns: self.columns == Some(2),
+                line_spacing: self.line_spacing,
+                number_equations: self.number_equations.unwrap_or(false),
+                table_of_contents: self.toc.unwrap_or(false),
+                original_image,
+            },
+            templates,
+            watermark: Watermark::for_plan(&user.plan.to_string()),
+            archival: false,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EquationsResponse {
+    pub job_id: String,
+    pub total: usize,
+    pub inline_count: usize,
+    pub display_count: usize,
+    pub equations: Vec<EquationItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EquationItem {
+    #[serde(flatten)]
+    pub segment: MathSegment,
+    pub alt_text: Option<String>,  // Spoken form for screen readers, None if the LaTeX did not parse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::conversion::model::normalize_tags;
+
+    fn metadata(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_normalize_tags_trims_lowercases_and_dedupes_in_order() {
+        let tags = ["  Physics ", "", "math", "PHYSICS", "  "].map(String::from);
+        assert_eq!(normalize_tags(&tags), vec!["physics", "math"]);
+    }
+
+    #[test]
+    fn test_normalize_metadata_rejects_keys_equal_after_trimming() {
+        let normalized = normalize_metadata(metadata(&[(" course ", " MATH 101 ")])).unwrap();
+        assert_eq!(normalized, metadata(&[("course", "MATH 101")]));
+
+        assert!(normalize_metadata(metadata(&[("a", "1"), ("a ", "2")])).is_err());
+    }
+
+    #[test]
+    fn test_validate_tags_and_metadata_limits() {
+        assert!(validate_tags_and_metadata(&[], &metadata(&[("course", "MATH 101")])).is_ok());
+        assert!(validate_tags_and_metadata(&[], &metadata(&[("", "x")])).is_err());
+        assert!(validate_tags_and_metadata(&[], &metadata(&[("$set", "x")])).is_err());
+        assert!(validate_tags_and_metadata(&[], &metadata(&[("a.b", "x")])).is_err());
+        assert!(validate_tags_and_metadata(&[], &metadata(&[("k", &"v".repeat(MAX_METADATA_VALUE_LENGTH + 1))])).is_err());
+
+        let too_many: Vec<String> = (0..=MAX_TAGS_PER_CONVERSION).map(|i| format!("t{}", i)).collect();
+        assert!(validate_tags_and_metadata(&too_many, &HashMap::new()).is_err());
+        assert!(validate_tags_and_metadata(&["x".repeat(MAX_TAG_LENGTH + 1)], &HashMap::new()).is_err());
+    }
+}
@@ -1,13 +1,23 @@
 use actix_web::{web, HttpResponse, Error, HttpRequest, HttpMessage};
-use mongodb::Collection;
+use mongodb::{Collection, bson::oid::ObjectId};
+use std::collections::BTreeMap;
 use crate::config::database;
 use crate::modules::user::crud::UserCRUD;
 use crate::modules::conversion::crud::ConversionCRUD;
-use crate::services::document_converter::DocumentConverter;
+use crate::modules::conversion::model::Conversion;
+use crate::services::document_converter::{DocumentConverter, PaperSize, RenderError, RenderOptions, TargetFormat};
+use crate::services::document_stats::document_stats;
+use crate::services::export_cache;
+use crate::services::export_capabilities::{format_renderer, BUILTIN_RENDERER};
+use crate::services::latex_sanitizer::describe_violations;
+use crate::services::native_renderer::{count_text, display_equations, split_sections};
 use crate::modules::editor::{
     crud::EditorCRUD,
-    model::Preview,
-    schema::{PreviewResponse, DocumentMetadata, ErrorResponse, PreviewListResponse, PreviewSummary},
+    model::{ContentFormat, Preview, PreviewStatus, RefreshMode, Staleness},
+    schema::{
+        PreviewResponse, DocumentMetadata, ErrorResponse, EquationAnchor, PreviewConflictResponse, PreviewErrorResponse, PreviewListResponse, PreviewMetadataResponse, PreviewSummary,
+        SectionOutlineResponse, SectionResponse, SectionSummary, UpdatePreviewRequest, MAX_PREVIEW_CONTENT_BYTES,
+    },
 };
 
 #[derive(serde::Deserialize)]
@@ -16,14 +26,44 @@ pub struct PaginationParams {
     limit: Option<i64>,
 }
 
+#[derive(serde::Deserialize)]
+pub struct MetadataParams {
+    paper_size: Option<String>,  // a4 (default), a5, letter or legal
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreviewParams {
+    refresh: Option<String>,  // How to rebuild a stale preview: background (default) or sync
+}
+
 pub struct EditorController;
 
 impl EditorController {
+    /// Returns the preview, generating it on first open. A preview built from
+    /// conversion text that has changed since is rebuilt: in the background by
+    /// default, returning the old copy flagged `stale`, or before responding
+    /// with `?refresh=sync`. Edited previews are flagged but never replaced,
+    /// and a text whose refresh failed is retried only after a while (see
+    /// `Preview::staleness`). Previews stored before source hashes were kept
+    /// are rebuilt once, on their first GET.
     pub async fn get_preview(
         req: HttpRequest,
         path: web::Path<String>,
+        query: web::Query<PreviewParams>,
     ) -> Result<HttpResponse, Error> {
         let conversion_id = path.into_inner();
+        let refresh_mode = match query.refresh.as_deref() {
+            None => RefreshMode::default(),
+            Some(name) => match RefreshMode::from_name(name) {
+                Some(mode) => mode,
+                None => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid Refresh Mode".to_string(),
+                        message: format!("refresh must be sync or background, got {}.", name),
+                    }));
+                }
+            },
+        };
 
         // Get user from token
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
@@ -77,91 +117,91 @@ impl EditorController {
         // Check if preview exists
         match EditorCRUD::get_preview(&user_id, &conversion_id, &previews_collection).await {
             Ok(Some(preview)) => {
-                let response = PreviewResponse {
-                    html_content: preview.html_content,
-                    metadata: DocumentMetadata {
-                        total_pages: preview.total_pages,
-                        word_count: preview.word_count,
-                        last_modified: preview.updated_at,
-                        format: preview.format.to_string(),
-                        file_size: None,  // We don't have the size for existing previews
-                        download_filename: Some(format!("{}.html", preview.original_filename)),
-                    },
-                    conversion_id: preview.conversion_id,
-                    status: preview.preview_status.to_string(),
+                let conversion = match owned_conversion(&user_id, &conversion_id).await {
+                    Ok(conversion) => conversion,
+                    Err(response) => return Ok(response),
                 };
-                Ok(HttpResponse::Ok().json(response))
-            }
-            Ok(None) => {
-                // If preview doesn't exist, try to generate it from conversion
-                let conversions_collection = match database::get_conversion_collection().await {
-                    Ok(collection) => collection,
+                // A deleted conversion leaves the last preview as it is.
+                let Some(conversion) = conversion else {
+                    return Ok(HttpResponse::Ok().json(preview_response(preview, false)));
+                };
+                let text_content = conversion.extracted_text.unwrap_or_default();
+                let source_hash = Preview::hash_source(&text_content);
+                match preview.staleness(&source_hash, mongodb::bson::DateTime::now().timestamp_millis()) {
+                    Staleness::Current => return Ok(HttpResponse::Ok().json(preview_response(preview, false))),
+                    Staleness::Stale => return Ok(HttpResponse::Ok().json(preview_response(preview, true))),
+                    Staleness::NeedsRefresh => {}
+                }
+
+                // Claim the next version so a concurrent edit or refresh cannot interleave.
+                let claimed = match EditorCRUD::begin_update(&user_id, &conversion_id, preview.version, &previews_collection).await {
+                    Ok(Some(claimed)) => claimed,
+                    Ok(None) => return Ok(HttpResponse::Ok().json(preview_response(preview, true))),
                     Err(_) => {
                         return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                             error: "Database Error".to_string(),
-                            message: "Failed to connect to database.".to_string(),
+                            message: "Failed to update preview.".to_string(),
                         }));
                     }
                 };
+                if refresh_mode == RefreshMode::Sync {
+                    // In its own task, like edits, so a disconnect can't strand it in Generating
+                    let refresh = actix_web::rt::spawn(async move {
+                        refresh_preview(claimed, text_content, source_hash, &previews_collection).await
+                    });
+                    return match refresh.await {
+                        Ok(Ok(preview)) => Ok(HttpResponse::Ok().json(preview_response(preview, false))),
+                        Ok(Err(response)) => Ok(response),
+                        Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                            error: "Preview Refresh Failed".to_string(),
+                            message: "The preview refresh was interrupted.".to_string(),
+                        })),
+                    };
+                }
 
-                match ConversionCRUD::find_by_job_id(&conversion_id, &conversions_collection).await {
-                    Ok(Some(conversion)) => {
-                        let text_content = conversion.extracted_text.unwrap_or_default();
-                        // Generate HTML preview
-                        match DocumentConverter::latex_to_html_preview(&text_content) {
-                            Ok(result) => {
-                                // Create preview
-                                match EditorCRUD::create_preview(
-                                    &user_id,
-                                    &conversion_id,
-                                    &String::from_utf8_lossy(&result.content),
-                                    &conversion.original_filename,
-                                    &previews_collection,
-                                ).await {
-                                    Ok(preview) => {
-                                        let response = PreviewResponse {
-                                            html_content: preview.html_content,
-                                            metadata: DocumentMetadata {
-                                                total_pages: preview.total_pages,
-                                                word_count: preview.word_count,
-                                                last_modified: preview.updated_at,
-                                                format: preview.format.to_string(),
-                                                file_size: Some(result.size),
-                                                download_filename: Some(format!("{}.html", preview.original_filename)),
-                                            },
-                                            conversion_id: preview.conversion_id,
-                                            status: preview.preview_status.to_string(),
-                                        };
-                                        Ok(HttpResponse::Ok().json(response))
-                                    }
-                                    Err(_) => {
-                                        Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                                            error: "Preview Creation Failed".to_string(),
-                                            message: "Failed to save preview.".to_string(),
-                                        }))
-                                    }
-                                }
-                            }
+                let response = preview_response(claimed.clone(), true);
+                actix_web::rt::spawn(async move {
+                    let _ = refresh_preview(claimed, text_content, source_hash, &previews_collection).await;
+                });
+                Ok(HttpResponse::Ok().json(response))
+            }
+            Ok(None) => {
+                // If preview doesn't exist, generate it from the user's conversion;
+                // other users' conversions are reported as missing.
+                let conversion = match owned_conversion(&user_id, &conversion_id).await {
+                    Ok(Some(conversion)) => conversion,
+                    Ok(None) => {
+                        return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                            error: "Conversion not found".to_string(),
+                            message: "The specified conversion does not exist.".to_string(),
+                        }));
+                    }
+                    Err(response) => return Ok(response),
+                };
+                let text_content = conversion.extracted_text.unwrap_or_default();
+                // Generate HTML preview
+                match DocumentConverter::latex_to_html_preview(&text_content).await {
+                    Ok(html_content) => {
+                        // Create preview
+                        match EditorCRUD::create_preview(
+                            &user_id,
+                            &conversion_id,
+                            &html_content,
+                            &conversion.original_filename,
+                            &text_content,
+                            &document_stats(&text_content),
+                            &previews_collection,
+                        ).await {
+                            Ok(preview) => Ok(HttpResponse::Ok().json(preview_response(preview, false))),
                             Err(_) => {
                                 Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                                    error: "Preview Generation Failed".to_string(),
-                                    message: "Failed to generate HTML preview.".to_string(),
+                                    error: "Preview Creation Failed".to_string(),
+                                    message: "Failed to save preview.".to_string(),
                                 }))
                             }
                         }
                     }
-                    Ok(None) => {
-                        Ok(HttpResponse::NotFound().json(ErrorResponse {
-                            error: "Conversion not found".to_string(),
-                            message: "The specified conversion does not exist.".to_string(),
-                        }))
-                    }
-                    Err(_) => {
-                        Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                            error: "Database Error".to_string(),
-                            message: "Failed to retrieve conversion data.".to_string(),
-                        }))
-                    }
+                    Err(e) => Ok(preview_render_error(e, None)),
                 }
             }
             Err(_) => {
@@ -242,10 +282,7 @@ impl EditorController {
             }
         };
 
-        let total = match EditorCRUD::count_user_previews(&user_id, &previews_collection).await {
-            Ok(count) => count,
-            Err(_) => 0,
-        };
+        let total = EditorCRUD::count_user_previews(&user_id, &previews_collection).await.unwrap_or_default();
 
         // Convert to response format
         let preview_summaries: Vec<PreviewSummary> = previews
@@ -256,7 +293,7 @@ impl EditorController {
                 preview_status: preview.preview_status.to_string(),
                 created_at: preview.created_at,
                 last_modified: preview.updated_at,
-                file_size: None,  // We don't store file size for existing previews
+                file_size: Some(preview.html_content.len() as u64),
             })
             .collect();
 
@@ -268,4 +305,538 @@ impl EditorController {
             total_pages: (total as f64 / limit as f64).ceil() as i64,
         }))
     }
-}
\ No newline at end of file
+
+    /// Re-renders a preview from edited LaTeX or Markdown. The request carries
+    /// the version it was based on; if the preview has moved on since, nothing
+    /// is changed and 409 is returned with the current version. A failed render
+    /// still uses up a version, which the error reports as `current_version`.
+    pub async fn update_preview(
+        req: HttpRequest,
+        path: web::Path<String>,
+        body: web::Json<UpdatePreviewRequest>,
+    ) -> Result<HttpResponse, Error> {
+        let conversion_id = path.into_inner();
+        let body = body.into_inner();
+
+        let user_id = match authenticated_user_id(&req).await {
+            Ok(user_id) => user_id,
+            Err(response) => return Ok(response),
+        };
+
+        let content_format = match body.content_format.as_deref() {
+            None => ContentFormat::Latex,
+            Some(name) => match ContentFormat::from_name(name) {
+                Some(format) => format,
+                None => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid Content Format".to_string(),
+                        message: format!("content_format must be latex or markdown, got {}.", name),
+                    }));
+                }
+            },
+        };
+        if body.content.trim().is_empty() {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Empty Content".to_string(),
+                message: "The edited content is empty.".to_string(),
+            }));
+        }
+        if body.content.len() > MAX_PREVIEW_CONTENT_BYTES {
+            return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                error: "Content Too Large".to_string(),
+                message: format!("The edited content must be at most {} MB.", MAX_PREVIEW_CONTENT_BYTES / (1024 * 1024)),
+            }));
+        }
+
+        let previews_collection: Collection<Preview> = match database::get_previews_collection().await {
+            Ok(collection) => collection,
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+
+        // Claim the next version, which also moves the preview to Generating.
+        let preview = match EditorCRUD::begin_update(&user_id, &conversion_id, body.version, &previews_collection).await {
+            Ok(Some(preview)) => preview,
+            Ok(None) => return Ok(version_conflict(&user_id, &conversion_id, &previews_collection).await),
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to update preview.".to_string(),
+                }));
+            }
+        };
+
+        // The render runs in its own task so that a client disconnecting mid-render
+        // doesn't cancel it and leave the preview in Generating.
+        let update = actix_web::rt::spawn(apply_update(preview, body.content, content_format, previews_collection));
+        let preview = match update.await {
+            Ok(Ok(preview)) => preview,
+            Ok(Err(response)) => return Ok(response),
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Preview Update Failed".to_string(),
+                    message: "The preview update was interrupted.".to_string(),
+                }));
+            }
+        };
+
+        // Edits are kept over later changes to the conversion, which only flag them.
+        let stale = match owned_conversion(&user_id, &conversion_id).await {
+            Ok(Some(conversion)) => {
+                preview.source_hash != Some(Preview::hash_source(conversion.extracted_text.as_deref().unwrap_or_default()))
+            }
+            _ => false,
+        };
+        Ok(HttpResponse::Ok().json(preview_response(preview, stale)))
+    }
+
+    /// Word and equation counts, page estimates, reading time and export sizes
+    /// of a preview. Export sizes cover the formats rendered in-process, plus
+    /// the others once the current text has been exported to them; they are
+    /// null while the preview is being rendered.
+    pub async fn get_metadata(
+        req: HttpRequest,
+        path: web::Path<String>,
+        query: web::Query<MetadataParams>,
+    ) -> Result<HttpResponse, Error> {
+        let conversion_id = path.into_inner();
+        let user_id = match authenticated_user_id(&req).await {
+            Ok(user_id) => user_id,
+            Err(response) => return Ok(response),
+        };
+
+        let paper_size = match query.paper_size.as_deref() {
+            None => PaperSize::A4,
+            Some(name) => match PaperSize::from_name(name) {
+                Some(paper_size) => paper_size,
+                None => {
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: "Invalid Paper Size".to_string(),
+                        message: format!("paper_size must be a4, a5, letter or legal, got {}.", name),
+                    }));
+                }
+            },
+        };
+
+        let previews_collection: Collection<Preview> = match database::get_previews_collection().await {
+            Ok(collection) => collection,
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+        let mut preview = match EditorCRUD::get_preview(&user_id, &conversion_id, &previews_collection).await {
+            Ok(Some(preview)) => preview,
+            Ok(None) => {
+                return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                    error: "Preview not found".to_string(),
+                    message: "Open the preview before requesting its metadata.".to_string(),
+                }));
+            }
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to retrieve preview data.".to_string(),
+                }));
+            }
+        };
+
+        // Previews stored before statistics were kept have no figures, and sizes
+        // are cleared by every edit; both are worked out from the source and stored
+        // the first time they are asked for.
+        let ready = matches!(preview.preview_status, PreviewStatus::Ready);
+        if preview.estimated_pages.is_empty() || (ready && preview.export_sizes.is_none()) {
+            let source = match preview.source_content.clone() {
+                Some(content) => content,
+                None => match preview_source(&user_id, &conversion_id).await {
+                    Ok(source) => source.content,
+                    Err(response) => return Ok(response),
+                },
+            };
+            if preview.estimated_pages.is_empty() {
+                preview.set_stats(&preview.content_format.stats(&source));
+                if let Err(e) = EditorCRUD::set_stats(&preview, &previews_collection).await {
+                    eprintln!("Failed to store statistics of {}: {}", conversion_id, e);
+                }
+            }
+            if ready && preview.export_sizes.is_none() {
+                let sizes = in_process_export_sizes(&preview, &source).await;
+                if let Err(e) = EditorCRUD::set_export_sizes(&user_id, &conversion_id, preview.version, &sizes, &previews_collection).await {
+                    eprintln!("Failed to store export sizes of {}: {}", conversion_id, e);
+                }
+                preview.export_sizes = Some(sizes);
+            }
+        }
+        if preview.export_sizes.is_some() {
+            let exported = exported_sizes(&preview).await;
+            if let Some(sizes) = preview.export_sizes.as_mut() {
+                for (format, size) in exported {
+                    sizes.entry(format).or_insert(size);
+                }
+            }
+        }
+
+        Ok(HttpResponse::Ok().json(PreviewMetadataResponse::new(preview, paper_size)))
+    }
+
+    /// Outline of the preview: its sections with headings, word counts and
+    /// display equation anchors, so the editor can load sections lazily.
+    pub async fn get_sections(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+        let conversion_id = path.into_inner();
+        let user_id = match authenticated_user_id(&req).await {
+            Ok(user_id) => user_id,
+            Err(response) => return Ok(response),
+        };
+        let source = match preview_source(&user_id, &conversion_id).await {
+            Ok(source) => source,
+            Err(response) => return Ok(response),
+        };
+
+        let mut next_equation = 1;
+        let sections = split_sections(&source.content)
+            .into_iter()
+            .enumerate()
+            .map(|(index, section)| {
+                let equations = equation_anchors(next_equation, display_equations(&section.content));
+                next_equation += equations.len();
+                SectionSummary {
+                    index,
+                    heading: section.heading,
+                    level: section.level,
+                    word_count: count_text(&section.content).words,
+                    equations,
+                }
+            })
+            .collect();
+
+        Ok(HttpResponse::Ok().json(SectionOutlineResponse {
+            conversion_id,
+            version: source.version,
+            sections,
+        }))
+    }
+
+    /// HTML of one section from the outline, with equation ids that match it.
+    pub async fn get_section(req: HttpRequest, path: web::Path<(String, usize)>) -> Result<HttpResponse, Error> {
+        let (conversion_id, index) = path.into_inner();
+        let user_id = match authenticated_user_id(&req).await {
+            Ok(user_id) => user_id,
+            Err(response) => return Ok(response),
+        };
+        let source = match preview_source(&user_id, &conversion_id).await {
+            Ok(source) => source,
+            Err(response) => return Ok(response),
+        };
+
+        let mut sections = split_sections(&source.content);
+        let total_sections = sections.len();
+        if index >= total_sections {
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Section not found".to_string(),
+                message: format!("The document has {} sections, numbered from 0.", total_sections),
+            }));
+        }
+
+        // Equation anchors are numbered across the document, so count the earlier ones.
+        let first_equation = 1 + sections[..index]
+            .iter()
+            .map(|section| count_text(&section.content).display_equations)
+            .sum::<usize>();
+        let section = sections.swap_remove(index);
+        let (html_content, equations) = match DocumentConverter::latex_to_html_section(&section.content, first_equation).await {
+            Ok(rendered) => rendered,
+            Err(e) => return Ok(preview_render_error(e, None)),
+        };
+
+        Ok(HttpResponse::Ok().json(SectionResponse {
+            conversion_id,
+            version: source.version,
+            index,
+            total_sections,
+            heading: section.heading,
+            html_content,
+            equations: equation_anchors(first_equation, equations),
+        }))
+    }
+}
+
+/// The signed-in user's id, or the response to send when it is unavailable.
+async fn authenticated_user_id(req: &HttpRequest) -> Result<ObjectId, HttpResponse> {
+    let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+        Some(claims) => claims,
+        None => {
+            return Err(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            }));
+        }
+    };
+
+    match UserCRUD::find_by_uuid(&claims.sub).await {
+        Ok(Some(user)) => user.id.ok_or_else(|| {
+            HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Invalid User".to_string(),
+                message: "User record is missing required data.".to_string(),
+            })
+        }),
+        Ok(None) => Err(HttpResponse::NotFound().json(ErrorResponse {
+            error: "User not found".to_string(),
+            message: "User associated with this token no longer exists.".to_string(),
+        })),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to retrieve user data.".to_string(),
+        })),
+    }
+}
+
+fn preview_response(preview: Preview, stale: bool) -> PreviewResponse {
+    PreviewResponse {
+        metadata: DocumentMetadata {
+            total_pages: preview.total_pages,
+            word_count: preview.word_count,
+            last_modified: preview.updated_at,
+            format: preview.format.to_string(),
+            file_size: Some(preview.html_content.len() as u64),
+            download_filename: Some(format!("{}.html", preview.original_filename)),
+        },
+        html_content: preview.html_content,
+        conversion_id: preview.conversion_id,
+        status: preview.preview_status.to_string(),
+        version: preview.version,
+        stale,
+    }
+}
+
+/// The user's conversion, or None when it does not exist or belongs to someone else.
+async fn owned_conversion(user_id: &ObjectId, conversion_id: &str) -> Result<Option<Conversion>, HttpResponse> {
+    let database_error = || {
+        HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to retrieve conversion data.".to_string(),
+        })
+    };
+    let conversions_collection = database::get_conversion_collection().await.map_err(|_| database_error())?;
+    match ConversionCRUD::find_by_job_id(conversion_id, &conversions_collection).await {
+        Ok(conversion) => Ok(conversion.filter(|conversion| conversion.user_id == *user_id)),
+        Err(_) => Err(database_error()),
+    }
+}
+
+/// Renders edited content into a preview claimed with `EditorCRUD::begin_update`
+/// and stores it. On failure the preview keeps its old HTML and is marked as
+/// failed, and the error carries the claimed version for the next edit.
+async fn apply_update(
+    mut preview: Preview,
+    content: String,
+    content_format: ContentFormat,
+    collection: Collection<Preview>,
+) -> Result<Preview, HttpResponse> {
+    let rendered = match content_format {
+        ContentFormat::Latex => DocumentConverter::latex_to_html_preview(&content).await,
+        ContentFormat::Markdown => DocumentConverter::markdown_to_html_preview(&content).await,
+    };
+    let html_content = match rendered {
+        Ok(html_content) => html_content,
+        Err(e) => {
+            eprintln!("Preview update for {} failed: {}", preview.conversion_id, e);
+            let _ = EditorCRUD::update_preview_status(&preview.user_id, &preview.conversion_id, preview.version, PreviewStatus::Error, &collection).await;
+            return Err(preview_render_error(e, Some(preview.version)));
+        }
+    };
+
+    preview.set_stats(&content_format.stats(&content));
+    preview.html_content = html_content;
+    preview.source_content = Some(content);
+    preview.content_format = content_format;
+    preview.preview_status = PreviewStatus::Ready;
+    preview.updated_at = mongodb::bson::DateTime::now();
+    match EditorCRUD::complete_update(&preview, &collection).await {
+        Ok(true) => Ok(preview),
+        Ok(false) => Err(version_conflict(&preview.user_id, &preview.conversion_id, &collection).await),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to save preview.".to_string(),
+        })),
+    }
+}
+
+/// Rebuilds a preview claimed with `EditorCRUD::begin_update` from the
+/// conversion's current text. On failure the preview keeps its old HTML and
+/// is marked as failed along with the text's hash, so GETs back off instead of
+/// claiming a new version each time.
+async fn refresh_preview(
+    mut preview: Preview,
+    text_content: String,
+    source_hash: String,
+    collection: &Collection<Preview>,
+) -> Result<Preview, HttpResponse> {
+    let html_content = match DocumentConverter::latex_to_html_preview(&text_content).await {
+        Ok(html_content) => html_content,
+        Err(e) => {
+            eprintln!("Refreshing preview {} failed: {}", preview.conversion_id, e);
+            let _ = EditorCRUD::fail_refresh(&preview.user_id, &preview.conversion_id, preview.version, &source_hash, collection).await;
+            return Err(preview_render_error(e, Some(preview.version)));
+        }
+    };
+
+    preview.set_stats(&document_stats(&text_content));
+    preview.html_content = html_content;
+    preview.source_hash = Some(source_hash);
+    preview.preview_status = PreviewStatus::Ready;
+    preview.updated_at = mongodb::bson::DateTime::now();
+    match EditorCRUD::complete_update(&preview, collection).await {
+        Ok(true) => Ok(preview),
+        Ok(false) => Err(version_conflict(&preview.user_id, &preview.conversion_id, collection).await),
+        Err(_) => Err(HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to save preview.".to_string(),
+        })),
+    }
+}
+
+/// The LaTeX a preview shows and the preview version it belongs to.
+struct PreviewSource {
+    content: String,
+    version: i64,
+}
+
+/// Reads the preview's edited LaTeX, or the conversion's extracted text when it
+/// has not been edited (or not opened) yet.
+async fn preview_source(user_id: &ObjectId, conversion_id: &str) -> Result<PreviewSource, HttpResponse> {
+    let database_error = || {
+        HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to retrieve preview data.".to_string(),
+        })
+    };
+    let previews_collection = database::get_previews_collection().await.map_err(|_| database_error())?;
+    let preview = EditorCRUD::get_preview(user_id, conversion_id, &previews_collection).await.map_err(|_| database_error())?;
+
+    let version = preview.as_ref().map(|p| p.version).unwrap_or_default();
+    if let Some(Preview { source_content: Some(content), content_format, .. }) = preview {
+        if content_format == ContentFormat::Markdown {
+            return Err(HttpResponse::UnprocessableEntity().json(ErrorResponse {
+                error: "Sections Unavailable".to_string(),
+                message: "Sections are only available for LaTeX content; this preview was last edited as Markdown.".to_string(),
+            }));
+        }
+        return Ok(PreviewSource { content, version });
+    }
+
+    let conversions_collection = database::get_conversion_collection().await.map_err(|_| database_error())?;
+    match ConversionCRUD::find_by_job_id(conversion_id, &conversions_collection).await {
+        Ok(Some(conversion)) if conversion.user_id == *user_id => Ok(PreviewSource {
+            content: conversion.extracted_text.unwrap_or_default(),
+            version,
+        }),
+        Ok(_) => Err(HttpResponse::NotFound().json(ErrorResponse {
+            error: "Conversion not found".to_string(),
+            message: "The specified conversion does not exist.".to_string(),
+        })),
+        Err(_) => Err(database_error()),
+    }
+}
+
+fn equation_anchors(first_equation: usize, equations: Vec<String>) -> Vec<EquationAnchor> {
+    equations
+        .into_iter()
+        .enumerate()
+        .map(|(i, latex)| EquationAnchor { anchor: format!("eq-{}", first_equation + i), latex })
+        .collect()
+}
+
+/// 409 with the preview's current version, or 404 when there is no preview.
+async fn version_conflict(user_id: &ObjectId, conversion_id: &str, collection: &Collection<Preview>) -> HttpResponse {
+    match EditorCRUD::get_preview(user_id, conversion_id, collection).await {
+        Ok(Some(current)) => HttpResponse::Conflict().json(PreviewConflictResponse {
+            error: "Preview Changed".to_string(),
+            message: "The preview was updated since this edit started. Reload it and apply the edit again.".to_string(),
+            current_version: current.version,
+        }),
+        Ok(None) => HttpResponse::NotFound().json(ErrorResponse {
+            error: "Preview not found".to_string(),
+            message: "Open the preview before editing it.".to_string(),
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(ErrorResponse {
+            error: "Database Error".to_string(),
+            message: "Failed to retrieve preview data.".to_string(),
+        }),
+    }
+}
+
+fn preview_render_error(error: RenderError, current_version: Option<i64>) -> HttpResponse {
+    let (mut response, error, message) = match error {
+        RenderError::Busy { retry_after_secs } => {
+            let mut response = HttpResponse::ServiceUnavailable();
+            response.append_header(("Retry-After", retry_after_secs.to_string()));
+            (response, "Preview Queue Full", "Too many documents are being rendered. Please retry shortly.".to_string())
+        }
+        RenderError::Rejected(violations) => (
+            HttpResponse::UnprocessableEntity(),
+            "Unsafe LaTeX",
+            format!("The document uses commands that are not allowed: {}", describe_violations(&violations)),
+        ),
+        RenderError::TimedOut { limit_secs } => (
+            HttpResponse::GatewayTimeout(),
+            "Preview Generation Timed Out",
+            format!("Rendering the preview took longer than {} seconds.", limit_secs),
+        ),
+        RenderError::Unavailable(format) => (
+            HttpResponse::NotImplemented(),
+            "Preview Unavailable",
+            format!("Previews of {} are not available on this server.", format),
+        ),
+        _ => (HttpResponse::InternalServerError(), "Preview Generation Failed", "Failed to generate HTML preview.".to_string()),
+    };
+    response.json(PreviewErrorResponse { error: error.to_string(), message, current_version })
+}
+
+/// Sizes of the exports rendered in-process, which cost little more than counting
+/// the text. Formats that need pandoc or TeX are left to `exported_sizes`, so
+/// nothing is rendered just to be measured. Markdown edits are not exported
+/// from, so only their own text and HTML are measured.
+async fn in_process_export_sizes(preview: &Preview, content: &str) -> BTreeMap<String, u64> {
+    let mut sizes = BTreeMap::new();
+    if preview.content_format == ContentFormat::Markdown {
+        sizes.insert(TargetFormat::Markdown.name().to_string(), content.len() as u64);
+        sizes.insert(TargetFormat::Html.name().to_string(), preview.html_content.len() as u64);
+        return sizes;
+    }
+
+    let options = RenderOptions { title: Some(preview.original_filename.clone()), ..RenderOptions::default() };
+    for format in TargetFormat::ALL.into_iter().filter(|format| format_renderer(*format) == Some(BUILTIN_RENDERER)) {
+        match DocumentConverter::render(content, format, &options).await {
+            Ok(result) => {
+                sizes.insert(format.name().to_string(), result.size);
+            }
+            Err(e) => eprintln!("Measuring {} export of {} failed: {}", format.name(), preview.conversion_id, e),
+        }
+    }
+    sizes
+}
+
+/// Sizes of the preview's text as it was last exported to each format, from
+/// the export cache; formats not exported since the text changed are missing.
+async fn exported_sizes(preview: &Preview) -> BTreeMap<String, u64> {
+    // Exports are made from the conversion text, which `source_hash` identifies
+    let text_hash = match &preview.source_content {
+        Some(content) => Some(export_cache::text_hash(content)),
+        None => preview.source_hash.clone(),
+    };
+    let Some(text_hash) = text_hash else {
+        return BTreeMap::new();
+    };
+    match database::get_export_cache_collection().await {
+        Ok(collection) => ConversionCRUD::cached_export_sizes(&preview.conversion_id, &text_hash, &collection)
+            .await
+            .unwrap_or_default(),
+        Err(_) => BTreeMap::new(),
+    }
+}
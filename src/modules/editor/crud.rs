@@ -1,6 +1,8 @@
-use mongodb::{Collection, bson::{doc, oid::ObjectId}, options::FindOptions};
-use crate::modules::editor::model::{Preview, PreviewStatus};
+use mongodb::{Collection, bson::{doc, oid::ObjectId, to_bson}, options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument}};
+use crate::modules::editor::model::{ContentFormat, Preview, PreviewStatus};
+use crate::services::document_stats::DocumentStats;
 use chrono::Utc;
+use std::collections::BTreeMap;
 
 pub struct EditorCRUD;
 
@@ -10,23 +12,36 @@ impl EditorCRUD {
         conversion_id: &str,
         html_content: &str,
         original_filename: &str,
+        source_text: &str,
+        stats: &DocumentStats,
         collection: &Collection<Preview>,
     ) -> mongodb::error::Result<Preview> {
         let now = mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis());
         
-        let preview = Preview {
+        let mut preview = Preview {
             id: None,
             conversion_id: conversion_id.to_string(),
-            user_id: user_id.clone(),
+            user_id: *user_id,
             html_content: html_content.to_string(),
             original_filename: original_filename.to_string(),
             preview_status: PreviewStatus::Ready,
-            total_pages: 0, // Will be updated after processing
-            word_count: 0,  // Will be updated after processing
+            total_pages: 0,
+            word_count: 0,
             format: crate::modules::editor::model::DocumentFormat::Docx,
+            source_content: None,
+            content_format: ContentFormat::Latex,
+            version: 0,
+            source_hash: Some(Preview::hash_source(source_text)),
+            failed_source_hash: None,
+            inline_equations: 0,
+            display_equations: 0,
+            estimated_pages: Default::default(),
+            reading_time_minutes: 0,
+            export_sizes: None,
             created_at: now,
             updated_at: now,
         };
+        preview.set_stats(stats);
 
         let insert_result = collection.insert_one(&preview).await?;
         let id = insert_result.inserted_id.as_object_id().unwrap();
@@ -81,10 +96,158 @@ impl EditorCRUD {
         Ok(count as i64)
     }
 
-    #[allow(dead_code)]
+    /// Claims the next version of a preview for an update, moving it to
+    /// `Generating`. Returns None when the preview is not at `expected_version`,
+    /// i.e. someone else updated it first.
+    pub async fn begin_update(
+        user_id: &ObjectId,
+        conversion_id: &str,
+        expected_version: i64,
+        collection: &Collection<Preview>,
+    ) -> mongodb::error::Result<Option<Preview>> {
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        collection
+            .find_one_and_update(
+                doc! {
+                    "user_id": user_id,
+                    "conversion_id": conversion_id,
+                    "version": version_filter(expected_version)
+                },
+                doc! {
+                    "$set": {
+                        "preview_status": PreviewStatus::Generating.to_string(),
+                        "export_sizes": mongodb::bson::Bson::Null,
+                        "updated_at": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis())
+                    },
+                    "$inc": { "version": 1_i64 }
+                },
+            )
+            .with_options(options)
+            .await
+    }
+
+    /// Stores the re-rendered HTML, source text, source hash and statistics of `preview`
+    /// if it is still at `preview.version`. Returns false when a newer update
+    /// took over.
+    pub async fn complete_update(
+        preview: &Preview,
+        collection: &Collection<Preview>,
+    ) -> mongodb::error::Result<bool> {
+        let result = collection
+            .update_one(
+                doc! {
+                    "user_id": preview.user_id,
+                    "conversion_id": &preview.conversion_id,
+                    "version": preview.version
+                },
+                doc! {
+                    "$set": {
+                        "html_content": &preview.html_content,
+                        "source_content": preview.source_content.as_deref(),
+                        "content_format": preview.content_format.to_string(),
+                        "source_hash": preview.source_hash.as_deref(),
+                        "failed_source_hash": mongodb::bson::Bson::Null,
+                        "total_pages": preview.total_pages,
+                        "word_count": preview.word_count,
+                        "inline_equations": preview.inline_equations,
+                        "display_equations": preview.display_equations,
+                        "estimated_pages": to_bson(&preview.estimated_pages)?,
+                        "reading_time_minutes": preview.reading_time_minutes,
+                        "preview_status": PreviewStatus::Ready.to_string(),
+                        "updated_at": preview.updated_at
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Stores the statistics of `preview` if it is still at `preview.version`,
+    /// for previews kept from before statistics were stored.
+    pub async fn set_stats(
+        preview: &Preview,
+        collection: &Collection<Preview>,
+    ) -> mongodb::error::Result<bool> {
+        let result = collection
+            .update_one(
+                doc! {
+                    "user_id": preview.user_id,
+                    "conversion_id": &preview.conversion_id,
+                    "version": version_filter(preview.version)
+                },
+                doc! {
+                    "$set": {
+                        "total_pages": preview.total_pages,
+                        "word_count": preview.word_count,
+                        "inline_equations": preview.inline_equations,
+                        "display_equations": preview.display_equations,
+                        "estimated_pages": to_bson(&preview.estimated_pages)?,
+                        "reading_time_minutes": preview.reading_time_minutes
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Stores the measured export sizes of a preview that is still at `version`.
+    pub async fn set_export_sizes(
+        user_id: &ObjectId,
+        conversion_id: &str,
+        version: i64,
+        export_sizes: &BTreeMap<String, u64>,
+        collection: &Collection<Preview>,
+    ) -> mongodb::error::Result<bool> {
+        let result = collection
+            .update_one(
+                doc! {
+                    "user_id": user_id,
+                    "conversion_id": conversion_id,
+                    "version": version_filter(version)
+                },
+                doc! { "$set": { "export_sizes": to_bson(export_sizes)? } },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Marks a refresh of a preview still at `version` as failed, recording the
+    /// hash of the conversion text it was built from so it is not retried at once.
+    pub async fn fail_refresh(
+        user_id: &ObjectId,
+        conversion_id: &str,
+        version: i64,
+        source_hash: &str,
+        collection: &Collection<Preview>,
+    ) -> mongodb::error::Result<bool> {
+        let result = collection
+            .update_one(
+                doc! {
+                    "user_id": user_id,
+                    "conversion_id": conversion_id,
+                    "version": version
+                },
+                doc! {
+                    "$set": {
+                        "preview_status": PreviewStatus::Error.to_string(),
+                        "failed_source_hash": source_hash,
+                        "updated_at": mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis())
+                    }
+                },
+            )
+            .await?;
+
+        Ok(result.modified_count > 0)
+    }
+
+    /// Sets the status of a preview that is still at `version`.
     pub async fn update_preview_status(
         user_id: &ObjectId,
         conversion_id: &str,
+        version: i64,
         status: PreviewStatus,
         collection: &Collection<Preview>,
     ) -> mongodb::error::Result<bool> {
@@ -93,7 +256,8 @@ impl EditorCRUD {
             .update_one(
                 doc! {
                     "user_id": user_id,
-                    "conversion_id": conversion_id
+                    "conversion_id": conversion_id,
+                    "version": version
                 },
                 doc! {
                     "$set": {
@@ -123,3 +287,13 @@ impl EditorCRUD {
         Ok(result.deleted_count > 0)
     }
 }
+
+/// Matches `version`; previews created before versioning have no field, which
+/// counts as version 0.
+fn version_filter(version: i64) -> mongodb::bson::Document {
+    if version == 0 {
+        doc! { "$in": [0_i64, mongodb::bson::Bson::Null] }
+    } else {
+        doc! { "$eq": version }
+    }
+}
@@ -1,8 +1,12 @@
 use mongodb::bson::{DateTime, oid::ObjectId};
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
 use std::fmt;
+use crate::services::document_converter::PaperSize;
+use crate::services::document_stats::{document_stats, markdown_stats, DocumentStats};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Preview {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
@@ -11,14 +15,147 @@ pub struct Preview {
     pub html_content: String,
     pub original_filename: String,
     pub preview_status: PreviewStatus,
-    pub total_pages: i32,
-    pub word_count: i32,
+    pub total_pages: i32,  // Estimated A4 pages
+    pub word_count: i32,   // Words of running text, math excluded
     pub format: DocumentFormat,
+    #[serde(default)]
+    pub source_content: Option<String>,  // Edited text the HTML was rendered from; None until first edited
+    #[serde(default)]
+    pub content_format: ContentFormat,
+    #[serde(default)]
+    pub version: i64,  // Bumped on every update, for optimistic concurrency
+    #[serde(default)]
+    pub source_hash: Option<String>,  // SHA-1 of the conversion text the preview was built from; None for old previews
+    #[serde(default)]
+    pub failed_source_hash: Option<String>,  // SHA-1 of the conversion text whose last refresh failed
+    #[serde(default)]
+    pub inline_equations: i32,
+    #[serde(default)]
+    pub display_equations: i32,
+    #[serde(default)]
+    pub estimated_pages: BTreeMap<String, i32>,  // Paper size name to page count; empty before stats were stored
+    #[serde(default)]
+    pub reading_time_minutes: i32,
+    #[serde(default)]
+    pub export_sizes: Option<BTreeMap<String, u64>>,  // Bytes of the in-process exports by format name; None until measured
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Markup of the text a preview is rendered from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum ContentFormat {
+    #[default]
+    #[serde(rename = "latex")]
+    Latex,
+    #[serde(rename = "markdown")]
+    Markdown,
+}
+
+impl ContentFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "latex" | "tex" => Some(ContentFormat::Latex),
+            "markdown" | "md" => Some(ContentFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    /// Statistics of `content` written in this markup.
+    pub fn stats(self, content: &str) -> DocumentStats {
+        match self {
+            ContentFormat::Latex => document_stats(content),
+            ContentFormat::Markdown => markdown_stats(content),
+        }
+    }
+}
+
+/// How a GET rebuilds a stale preview: after responding with the old copy, or
+/// before responding.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RefreshMode {
+    #[default]
+    Background,
+    Sync,
+}
+
+impl RefreshMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "background" => Some(RefreshMode::Background),
+            "sync" => Some(RefreshMode::Sync),
+            _ => None,
+        }
+    }
+}
+
+/// A preview left in `Generating` for longer than this is taken to be abandoned,
+/// e.g. by a restart during a background refresh, and may be refreshed again.
+const REFRESH_TIMEOUT_MILLIS: i64 = 5 * 60 * 1000;
+
+/// How long a refresh that failed waits before the same conversion text is tried again.
+const REFRESH_RETRY_MILLIS: i64 = 10 * 60 * 1000;
+
+/// How a preview compares with the conversion text it should show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Staleness {
+    Current,       // Built from the current text
+    Stale,         // Out of date, but returned as it is for now
+    NeedsRefresh,  // Out of date and should be rebuilt
+}
+
+impl Preview {
+    /// Compares the preview with the conversion text hashed as `source_hash`,
+    /// at `now_millis`. Edited previews are never replaced, a running refresh is
+    /// not started twice, and a text whose refresh failed is only retried after
+    /// `REFRESH_RETRY_MILLIS`, since every refresh claims a new version.
+    /// Previews stored before source hashes were kept have none, so each is
+    /// rebuilt once, on its first GET, unless it was edited.
+    pub fn staleness(&self, source_hash: &str, now_millis: i64) -> Staleness {
+        let age = now_millis - self.updated_at.timestamp_millis();
+        if self.source_hash.as_deref() == Some(source_hash) {
+            return Staleness::Current;
+        }
+        let refreshing = matches!(self.preview_status, PreviewStatus::Generating) && age < REFRESH_TIMEOUT_MILLIS;
+        let backing_off = matches!(self.preview_status, PreviewStatus::Error)
+            && self.failed_source_hash.as_deref() == Some(source_hash)
+            && age < REFRESH_RETRY_MILLIS;
+        if self.source_content.is_some() || refreshing || backing_off {
+            Staleness::Stale
+        } else {
+            Staleness::NeedsRefresh
+        }
+    }
+
+    /// Hex SHA-1 of a conversion's text, compared with `source_hash` to tell
+    /// whether the preview is out of date.
+    pub fn hash_source(text: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(text);
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Copies document statistics into the preview; `total_pages` is the A4 estimate.
+    pub fn set_stats(&mut self, stats: &DocumentStats) {
+        self.word_count = stats.word_count as i32;
+        self.inline_equations = stats.inline_equations as i32;
+        self.display_equations = stats.display_equations as i32;
+        self.estimated_pages = stats.estimated_pages.iter().map(|(paper, pages)| (paper.clone(), *pages as i32)).collect();
+        self.total_pages = self.estimated_pages.get(PaperSize::A4.name()).copied().unwrap_or_default();
+        self.reading_time_minutes = stats.reading_time_minutes as i32;
+    }
+}
+
+impl fmt::Display for ContentFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentFormat::Latex => write!(f, "latex"),
+            ContentFormat::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum PreviewStatus {
     #[serde(rename = "pending")]
     Pending,
@@ -41,22 +178,89 @@ impl fmt::Display for PreviewStatus {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum DocumentFormat {
     #[serde(rename = "docx")]
     Docx,
     #[serde(rename = "pdf")]
-    PDF,
+    Pdf,
     #[serde(rename = "odt")]
-    ODT,
+    Odt,
 }
 
 impl fmt::Display for DocumentFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             DocumentFormat::Docx => write!(f, "docx"),
-            DocumentFormat::PDF => write!(f, "pdf"),
-            DocumentFormat::ODT => write!(f, "odt"),
+            DocumentFormat::Pdf => write!(f, "pdf"),
+            DocumentFormat::Odt => write!(f, "odt"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: i64 = 60 * 1000;
+
+    fn preview(status: PreviewStatus, source_hash: Option<&str>, failed_source_hash: Option<&str>) -> Preview {
+        Preview {
+            id: None,
+            conversion_id: "job".to_string(),
+            user_id: ObjectId::new(),
+            html_content: String::new(),
+            original_filename: "notes.pdf".to_string(),
+            preview_status: status,
+            total_pages: 0,
+            word_count: 0,
+            format: DocumentFormat::Docx,
+            source_content: None,
+            content_format: ContentFormat::Latex,
+            version: 3,
+            source_hash: source_hash.map(str::to_string),
+            failed_source_hash: failed_source_hash.map(str::to_string),
+            inline_equations: 0,
+            display_equations: 0,
+            estimated_pages: BTreeMap::new(),
+            reading_time_minutes: 0,
+            export_sizes: None,
+            created_at: DateTime::from_millis(0),
+            updated_at: DateTime::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn test_staleness_compares_source_hashes() {
+        let ready = preview(PreviewStatus::Ready, Some("old"), None);
+        assert_eq!(ready.staleness("old", MINUTE), Staleness::Current);
+        assert_eq!(ready.staleness("new", MINUTE), Staleness::NeedsRefresh);
+
+        // Previews from before source hashes are rebuilt once
+        assert_eq!(preview(PreviewStatus::Ready, None, None).staleness("new", MINUTE), Staleness::NeedsRefresh);
+
+        let edited = Preview { source_content: Some("Edited".to_string()), ..ready };
+        assert_eq!(edited.staleness("new", MINUTE), Staleness::Stale);
+    }
+
+    #[test]
+    fn test_staleness_waits_for_running_and_failed_refreshes() {
+        let refreshing = preview(PreviewStatus::Generating, Some("old"), None);
+        assert_eq!(refreshing.staleness("new", MINUTE), Staleness::Stale);
+        assert_eq!(refreshing.staleness("new", REFRESH_TIMEOUT_MILLIS), Staleness::NeedsRefresh);
+
+        let failed = preview(PreviewStatus::Error, Some("old"), Some("new"));
+        assert_eq!(failed.staleness("new", MINUTE), Staleness::Stale);
+        assert_eq!(failed.staleness("new", REFRESH_RETRY_MILLIS), Staleness::NeedsRefresh);
+        // A newer text is tried at once
+        assert_eq!(failed.staleness("newer", MINUTE), Staleness::NeedsRefresh);
+    }
+
+    #[test]
+    fn test_refresh_mode_names() {
+        assert_eq!(RefreshMode::default(), RefreshMode::Background);
+        assert_eq!(RefreshMode::from_name("background"), Some(RefreshMode::Background));
+        assert_eq!(RefreshMode::from_name(" Sync "), Some(RefreshMode::Sync));
+        assert_eq!(RefreshMode::from_name("later"), None);
+    }
+}
@@ -1,12 +1,19 @@
 use actix_web::web;
 use crate::modules::editor::controller::EditorController;
 use crate::middleware::user_auth::Authentication;
+use crate::modules::editor::schema::MAX_PREVIEW_CONTENT_BYTES;
 
 pub fn configure_editor_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/editor")
             .wrap(Authentication::new())  // Protect all editor routes
+            // Edited documents are larger than the default 32 KB JSON limit.
+            .app_data(web::JsonConfig::default().limit(MAX_PREVIEW_CONTENT_BYTES + 64 * 1024))
             .route("/preview/{conversion_id}", web::get().to(EditorController::get_preview))
+            .route("/preview/{conversion_id}", web::put().to(EditorController::update_preview))
+            .route("/preview/{conversion_id}/metadata", web::get().to(EditorController::get_metadata))
+            .route("/preview/{conversion_id}/sections", web::get().to(EditorController::get_sections))
+            .route("/preview/{conversion_id}/sections/{index}", web::get().to(EditorController::get_section))
             .route("/previews", web::get().to(EditorController::list_previews))
     );
 }
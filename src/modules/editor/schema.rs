@@ -1,5 +1,18 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use mongodb::bson::DateTime;
+use std::collections::BTreeMap;
+use crate::modules::editor::model::Preview;
+use crate::services::document_converter::PaperSize;
+
+/// Largest edited document accepted by the update endpoint.
+pub const MAX_PREVIEW_CONTENT_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePreviewRequest {
+    pub content: String,
+    pub content_format: Option<String>,  // latex (default) or markdown
+    pub version: i64,                    // Version the edit was based on, from the last preview response
+}
 
 #[derive(Debug, Serialize)]
 pub struct PreviewResponse {
@@ -7,6 +20,8 @@ pub struct PreviewResponse {
     pub metadata: DocumentMetadata,
     pub conversion_id: String,
     pub status: String,
+    pub version: i64,
+    pub stale: bool,  // The conversion's text changed after this preview was built from it
 }
 
 #[derive(Debug, Serialize)]
@@ -19,6 +34,79 @@ pub struct DocumentMetadata {
     pub download_filename: Option<String>,  // Suggested download filename
 }
 
+/// Statistics of a preview's document. Pages are estimated from the text,
+/// not from a typeset PDF.
+#[derive(Debug, Serialize)]
+pub struct PreviewMetadataResponse {
+    pub conversion_id: String,
+    pub version: i64,
+    pub status: String,
+    pub word_count: i32,  // Math excluded
+    pub inline_equations: i32,
+    pub display_equations: i32,
+    pub paper_size: String,
+    pub estimated_pages: i32,  // For `paper_size`
+    pub pages_by_paper_size: BTreeMap<String, i32>,
+    pub reading_time_minutes: i32,
+    pub html_size: u64,
+    pub export_sizes: Option<BTreeMap<String, u64>>,  // Format name to bytes; formats needing pandoc or TeX only once exported
+}
+
+impl PreviewMetadataResponse {
+    /// Statistics stored on `preview`, with the page estimate for `paper_size`.
+    pub fn new(preview: Preview, paper_size: PaperSize) -> Self {
+        PreviewMetadataResponse {
+            version: preview.version,
+            status: preview.preview_status.to_string(),
+            word_count: preview.word_count,
+            inline_equations: preview.inline_equations,
+            display_equations: preview.display_equations,
+            paper_size: paper_size.name().to_string(),
+            estimated_pages: preview.estimated_pages.get(paper_size.name()).copied().unwrap_or_default(),
+            pages_by_paper_size: preview.estimated_pages,
+            reading_time_minutes: preview.reading_time_minutes,
+            html_size: preview.html_content.len() as u64,
+            export_sizes: preview.export_sizes,
+            conversion_id: preview.conversion_id,
+        }
+    }
+}
+
+/// Outline of a preview for lazy loading: one entry per section.
+#[derive(Debug, Serialize)]
+pub struct SectionOutlineResponse {
+    pub conversion_id: String,
+    pub version: i64,
+    pub sections: Vec<SectionSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SectionSummary {
+    pub index: usize,              // Use with GET .../sections/{index}
+    pub heading: Option<String>,   // None for text before the first heading
+    pub level: usize,              // 1 (chapter) to 4 (subsubsection); 0 without a heading
+    pub word_count: usize,
+    pub equations: Vec<EquationAnchor>,
+}
+
+/// A display equation and the id of its element in the section HTML.
+#[derive(Debug, Serialize)]
+pub struct EquationAnchor {
+    pub anchor: String,  // e.g. "eq-3"; numbered across the whole document
+    pub latex: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SectionResponse {
+    pub conversion_id: String,
+    pub version: i64,
+    pub index: usize,
+    pub total_sections: usize,
+    pub heading: Option<String>,
+    pub html_content: String,  // HTML fragment, without <html> or <body>
+    pub equations: Vec<EquationAnchor>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PreviewListResponse {
     pub previews: Vec<PreviewSummary>,
@@ -44,7 +132,26 @@ pub struct ErrorResponse {
     pub message: String,
 }
 
+/// Returned when rendering a preview fails. After an edit or refresh claimed a
+/// new version, `current_version` is that version, which the next edit must send.
+#[derive(Debug, Serialize)]
+pub struct PreviewErrorResponse {
+    pub error: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_version: Option<i64>,
+}
+
+/// Returned when an update was based on an outdated version of the preview.
+#[derive(Debug, Serialize)]
+pub struct PreviewConflictResponse {
+    pub error: String,
+    pub message: String,
+    pub current_version: i64,
+}
+
 #[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct DownloadMetadata {
     pub filename: String,
     pub file_size: u64,
@@ -53,7 +160,54 @@ pub struct DownloadMetadata {
 }
 
 #[derive(Debug, Serialize)]
+#[allow(dead_code)]
 pub struct DownloadResponse {
     pub metadata: DownloadMetadata,
     pub download_url: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::editor::model::ContentFormat;
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    /// A preview as stored before versions and statistics were kept.
+    fn stored_preview() -> Preview {
+        let now = DateTime::now();
+        mongodb::bson::from_document(doc! {
+            "conversion_id": "job",
+            "user_id": ObjectId::new(),
+            "html_content": "<p>Energy</p>",
+            "original_filename": "notes.pdf",
+            "preview_status": "ready",
+            "total_pages": 0,
+            "word_count": 0,
+            "format": "docx",
+            "created_at": now,
+            "updated_at": now,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_metadata_of_backfilled_markdown_preview() {
+        let mut preview = stored_preview();
+        assert!(preview.estimated_pages.is_empty() && preview.export_sizes.is_none());
+        assert_eq!(preview.version, 0);
+
+        preview.content_format = ContentFormat::Markdown;
+        preview.set_stats(&preview.content_format.stats("# Energy\n\n- Mass $m$\n\n$$E = mc^2$$\n"));
+        let metadata = PreviewMetadataResponse::new(preview, PaperSize::A5);
+
+        assert_eq!(metadata.conversion_id, "job");
+        assert_eq!(metadata.status, "ready");
+        assert_eq!(metadata.word_count, 2);
+        assert_eq!((metadata.inline_equations, metadata.display_equations), (1, 1));
+        assert_eq!(metadata.paper_size, "a5");
+        assert_eq!(metadata.estimated_pages, 1);
+        assert_eq!(metadata.pages_by_paper_size.len(), PaperSize::ALL.len());
+        assert_eq!(metadata.html_size, 13);
+        assert!(metadata.export_sizes.is_none());
+    }
+}
@@ -5,6 +5,7 @@ pub mod user;
 pub mod conversion;
 pub mod sync;
 pub mod editor;
+pub mod template;
 
 // Re-export key components for convenience
 pub use user::crud::UserCRUD;
@@ -1,14 +1,28 @@
 use actix_web::{web, HttpResponse, Error, HttpRequest, HttpMessage};
 use mongodb::bson::doc;
 use crate::config::database;
+use crate::config::environment::Config;
 use crate::modules::sync::{
     crud::SyncCRUD,
-    schema::{ProjectListResponse, ProjectListResult, ErrorResponse, CreateProjectRequest, AssignConversionsRequest, ProjectConversionsResponse, ConversionListItem, SuccessResponse},
-    model::Project,
+    schema::{ProjectListResponse, ProjectListResult, ErrorResponse, CreateProjectRequest, AssignConversionsRequest, ProjectConversionsResponse, ConversionListItem, SuccessResponse, ProjectExportParams, ProjectExportResponse, BundleManifest, BundleManifestConversion, INLINE_BUNDLE_MAX_FILES},
+    model::{Project, ProjectExport},
 };
 use crate::modules::user::crud::UserCRUD;
+use crate::modules::user::model::User;
 use crate::modules::conversion::model::Conversion;
+use crate::modules::conversion::crud::apply_tag_filter;
+use crate::modules::conversion::schema::{ExportOptionsParams, TagFilterParams};
+use crate::modules::conversion::controller::{attachment_response, streamed_attachment_response};
+use crate::modules::template::controller::TemplateController;
+use crate::services::cloudinary::CloudinaryService;
+use crate::services::document_converter::{ConversionResult, DocumentConverter, RenderError, RenderOptions, TargetFormat, TemplateFiles};
+use crate::services::latex_project::{main_tex, section_tex, ProjectSection, LATEXMKRC};
+use crate::services::zip_archive::{archive_file_stem, build_zip, ArchiveEntry, TempArchive};
+use crate::modules::template::crud::TemplateCRUD;
+use crate::modules::template::schema::{ProjectTemplatesRequest, ProjectTemplatesResponse, TemplateResponse};
 use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
 use mongodb::{Collection, bson::oid::ObjectId};
 
 #[derive(Deserialize)]
@@ -196,10 +210,8 @@ impl SyncController {
             }
         };
 
-        let total = match SyncCRUD::count_user_projects(&user_id, &collection).await {
-            Ok(count) => count,
-            Err(_) => 0, // Default to 0 if count fails
-        };
+        // Default to 0 if count fails
+        let total = SyncCRUD::count_user_projects(&user_id, &collection).await.unwrap_or_default();
 
         // Convert projects to response format
         let project_responses: Vec<ProjectListResponse> = projects
@@ -228,8 +240,10 @@ impl SyncController {
     pub async fn list_project_conversions(
         req: HttpRequest,
         path: web::Path<(String, i64, i64)>,
+        query: web::Query<TagFilterParams>,
     ) -> Result<HttpResponse, Error> {
         let (project_id, page, limit) = path.into_inner();
+        let tags = query.tag_list();
         let project_id = match ObjectId::parse_str(&project_id) {
             Ok(id) => id,
             Err(_) => {
@@ -315,6 +329,7 @@ impl SyncController {
             &user_id,
             skip,
             limit,
+            &tags,
             &conversions_collection
         ).await {
             Ok(conversions) => conversions,
@@ -337,15 +352,17 @@ impl SyncController {
                 status: conv.status.to_string(),
                 created_at: conv.created_at.to_string(),
                 completed_at: conv.completed_at.map(|dt| dt.to_string()),
+                tags: conv.tags,
             })
             .collect();
 
-        let total = match conversions_collection.count_documents(
-            doc! {
-                "user_id": user_id,
-                "project_id": project_id
-            }
-        ).await {
+        let mut count_filter = doc! {
+            "user_id": user_id,
+            "project_id": project_id
+        };
+        apply_tag_filter(&mut count_filter, &tags);
+
+        let total = match conversions_collection.count_documents(count_filter).await {
             Ok(count) => count as i64,
             Err(_) => 0,
         };
@@ -362,8 +379,10 @@ impl SyncController {
     pub async fn list_unassigned_conversions(
         req: HttpRequest,
         path: web::Path<(i64, i64)>,
+        query: web::Query<TagFilterParams>,
     ) -> Result<HttpResponse, Error> {
         let (page, limit) = path.into_inner();
+        let tags = query.tag_list();
 
         // Get user from token
         let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
@@ -421,6 +440,7 @@ impl SyncController {
             &user_id,
             skip,
             limit,
+            &tags,
             &conversions_collection
         ).await {
             Ok(conversions) => conversions,
@@ -443,15 +463,17 @@ impl SyncController {
                 status: conv.status.to_string(),
                 created_at: conv.created_at.to_string(),
                 completed_at: conv.completed_at.map(|dt| dt.to_string()),
+                tags: conv.tags,
             })
             .collect();
 
-        let total = match conversions_collection.count_documents(
-            doc! {
-                "user_id": user_id,
-                "project_id": null
-            }
-        ).await {
+        let mut count_filter = doc! {
+            "user_id": user_id,
+            "project_id": null
+        };
+        apply_tag_filter(&mut count_filter, &tags);
+
+        let total = match conversions_collection.count_documents(count_filter).await {
             Ok(count) => count as i64,
             Err(_) => 0,
         };
@@ -470,7 +492,7 @@ impl SyncController {
         path: web::Path<String>,
         data: web::Json<AssignConversionsRequest>,
     ) -> Result<HttpResponse, Error> {
-        let project_id = match ObjectId::parse_str(&path.into_inner()) {
+        let project_id = match ObjectId::parse_str(path.into_inner()) {
             Ok(id) => id,
             Err(_) => {
                 return Ok(HttpResponse::BadRequest().json(ErrorResponse {
@@ -565,4 +587,605 @@ impl SyncController {
             })),
         }
     }
-}
\ No newline at end of file
+
+    pub async fn get_project_templates(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+        Self::project_templates(req, path.into_inner(), None).await
+    }
+
+    pub async fn set_project_templates(
+        req: HttpRequest,
+        path: web::Path<String>,
+        data: web::Json<ProjectTemplatesRequest>,
+    ) -> Result<HttpResponse, Error> {
+        Self::project_templates(req, path.into_inner(), Some(data.into_inner().template_ids)).await
+    }
+
+    /// Shared body of the project template endpoints: replaces the project's
+    /// default templates when `template_ids` is given, then returns them.
+    async fn project_templates(
+        req: HttpRequest,
+        project_id: String,
+        template_ids: Option<Vec<String>>,
+    ) -> Result<HttpResponse, Error> {
+        let project_id = match ObjectId::parse_str(&project_id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid Project ID".to_string(),
+                    message: "The provided project ID is not valid.".to_string(),
+                }));
+            }
+        };
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => {
+                return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "User authentication data not found.".to_string(),
+                }));
+            }
+        };
+
+        let user_id = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) if user.id.is_some() => user.id.unwrap(),
+            _ => {
+                return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "Invalid user token.".to_string(),
+                }));
+            }
+        };
+
+        let (projects_collection, templates_collection) = match (
+            database::get_projects_collection().await,
+            database::get_templates_collection().await,
+        ) {
+            (Ok(projects), Ok(templates)) => (projects, templates),
+            _ => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+
+        let project = match SyncCRUD::find_project(&project_id, &user_id, &projects_collection).await {
+            Ok(Some(project)) => project,
+            Ok(None) => {
+                return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                    error: "Project not found".to_string(),
+                    message: "The specified project does not exist or does not belong to you.".to_string(),
+                }));
+            }
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to retrieve project.".to_string(),
+                }));
+            }
+        };
+
+        let template_ids = template_ids.unwrap_or(project.default_template_ids);
+        let templates = match TemplateCRUD::find_many(&template_ids, &user_id, &templates_collection).await {
+            Ok(templates) => templates,
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to retrieve templates.".to_string(),
+                }));
+            }
+        };
+
+        if let Some(missing) = template_ids.iter().find(|id| !templates.iter().any(|t| &t.template_id == *id)) {
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Template not found".to_string(),
+                message: format!("Template {} does not exist or does not belong to you.", missing),
+            }));
+        }
+        if let Some(duplicate) = templates.iter().find(|t| templates.iter().filter(|o| o.kind == t.kind).count() > 1) {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Duplicate template kind".to_string(),
+                message: format!("A project can have only one {} template.", duplicate.kind),
+            }));
+        }
+
+        if let Err(_) | Ok(false) = SyncCRUD::set_project_templates(&project_id, &user_id, &template_ids, &projects_collection).await {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to update project templates.".to_string(),
+            }));
+        }
+
+        Ok(HttpResponse::Ok().json(ProjectTemplatesResponse {
+            project_id: project_id.to_hex(),
+            templates: templates.into_iter().map(TemplateResponse::from).collect(),
+        }))
+    }
+
+    /// Exports a whole project as a ZIP of rendered documents, original images
+    /// and a `manifest.json`. Small projects are returned directly; larger ones
+    /// are built in the background and reported through `get_project_export`.
+    pub async fn export_project(
+        req: HttpRequest,
+        path: web::Path<String>,
+        query: web::Query<ProjectExportParams>,
+        export_options: web::Query<ExportOptionsParams>,
+    ) -> Result<HttpResponse, Error> {
+        let project_id = path.into_inner();
+        println!("CONTROLLER: Entered export_project handler for project_id: {}", project_id);
+
+        let formats = match parse_export_formats(query.formats.as_deref().unwrap_or("tex")) {
+            Ok(formats) => formats,
+            Err(message) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid Format".to_string(),
+                    message,
+                }));
+            }
+        };
+
+        let (user, project, conversions) = match Self::export_source(&req, &project_id).await {
+            Ok(source) => source,
+            Err(response) => return Ok(response),
+        };
+
+        let options = match export_options.to_render_options(&user, TemplateFiles::default()) {
+            Ok(options) => options,
+            Err(message) => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid Export Options".to_string(),
+                    message,
+                }));
+            }
+        };
+
+        if conversions.len() * formats.len() <= INLINE_BUNDLE_MAX_FILES {
+            let filename = format!("{}.zip", archive_file_stem(&project.name));
+            let response = match Self::build_project_bundle(&project, &conversions, &formats, &options, false).await {
+                Ok((archive, size)) => archive_response(archive, size, &filename),
+                Err(e) => Err(e),
+            };
+            return match response {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    eprintln!("Project export for {} failed: {}", project.name, e);
+                    Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                        error: "Export Failed".to_string(),
+                        message: "Failed to build the project bundle.".to_string(),
+                    }))
+                }
+            };
+        }
+
+        let exports_collection = match database::get_project_exports_collection().await {
+            Ok(collection) => collection,
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+
+        let export = ProjectExport::new(project.id.unwrap(), project.user_id, formats.iter().map(|f| f.name().to_string()).collect());
+        if SyncCRUD::create_export(&export, &exports_collection).await.is_err() {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to start the project export.".to_string(),
+            }));
+        }
+
+        let export_id = export.export_id.clone();
+        actix_web::rt::spawn(async move {
+            Self::run_background_export(export_id, project, conversions, formats, options, exports_collection).await;
+        });
+        Ok(HttpResponse::Accepted().json(ProjectExportResponse::from(export)))
+    }
+
+    /// Exports a project as an Overleaf-ready LaTeX project: `main.tex` with one
+    /// `\input` per conversion, the original images under `figures/` and the
+    /// preamble and class from the project's or user's default templates.
+    pub async fn export_overleaf(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+        let project_id = path.into_inner();
+        println!("CONTROLLER: Entered export_overleaf handler for project_id: {}", project_id);
+
+        let (_, project, conversions) = match Self::export_source(&req, &project_id).await {
+            Ok(source) => source,
+            Err(response) => return Ok(response),
+        };
+
+        let templates = match TemplateController::export_templates(&project.user_id, project.id, TargetFormat::Tex).await {
+            Ok(templates) => templates,
+            Err(e) => {
+                eprintln!("Failed to load export templates: {}", e);
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Template Error".to_string(),
+                    message: "Failed to load the export templates for this project.".to_string(),
+                }));
+            }
+        };
+
+        let cloudinary = CloudinaryService::new(Config::new());
+        let mut entries = Vec::new();
+        let mut sections = Vec::with_capacity(conversions.len());
+        for (index, conversion) in conversions.iter().enumerate() {
+            let file_stem = format!("{:03}-{}", index + 1, archive_file_stem(&conversion.original_filename));
+            // A missing image only loses the figure; the text is still exported.
+            let figure = match cloudinary.fetch_file(&conversion.cloudinary_url).await {
+                Ok(image) => {
+                    let filename = format!("{}.{}", file_stem, image_extension(&conversion.mime_type));
+                    entries.push(ArchiveEntry::new(format!("figures/{}", filename), image));
+                    Some(filename)
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch image for {}: {}", conversion.job_id, e);
+                    None
+                }
+            };
+            sections.push(ProjectSection {
+                file_stem,
+                heading: conversion.original_filename.clone(),
+                content: conversion.extracted_text.clone().unwrap_or_default(),
+                figure,
+            });
+        }
+
+        let class_name = templates.latex_class.as_ref().map(|class| class.name.as_str());
+        entries.push(ArchiveEntry::new("main.tex", main_tex(&project.name, class_name, templates.latex_preamble.is_some(), &sections)));
+        entries.push(ArchiveEntry::new("latexmkrc", LATEXMKRC));
+        if let Some(preamble) = &templates.latex_preamble {
+            entries.push(ArchiveEntry::new("preamble.tex", preamble.as_str()));
+        }
+        if let Some(class) = &templates.latex_class {
+            entries.push(ArchiveEntry::new(format!("{}.cls", class.name), class.source.clone()));
+        }
+        for section in &sections {
+            entries.push(ArchiveEntry::new(format!("sections/{}.tex", section.file_stem), section_tex(section)));
+        }
+
+        let archive = web::block(move || build_zip(&entries))
+            .await
+            .map_err(|e| format!("Archive task failed: {}", e))
+            .and_then(|built| built);
+        match archive {
+            Ok(archive) => {
+                let result = ConversionResult {
+                    size: archive.len() as u64,
+                    content: archive,
+                    mime_type: "application/zip".to_string(),
+                };
+                Ok(attachment_response(result, &format!("{}-overleaf.zip", archive_file_stem(&project.name)), None))
+            }
+            Err(e) => {
+                eprintln!("Overleaf export for {} failed: {}", project.name, e);
+                Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Export Failed".to_string(),
+                    message: "Failed to build the Overleaf project.".to_string(),
+                }))
+            }
+        }
+    }
+
+    pub async fn get_project_export(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+        let export_id = path.into_inner();
+        println!("CONTROLLER: Entered get_project_export handler for export_id: {}", export_id);
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => {
+                return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "User authentication data not found.".to_string(),
+                }));
+            }
+        };
+
+        let user_id = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) if user.id.is_some() => user.id.unwrap(),
+            _ => {
+                return Ok(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "Invalid user token.".to_string(),
+                }));
+            }
+        };
+
+        let collection = match database::get_project_exports_collection().await {
+            Ok(collection) => collection,
+            Err(_) => {
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+
+        match SyncCRUD::find_export(&export_id, &user_id, &collection).await {
+            Ok(Some(export)) => Ok(HttpResponse::Ok().json(ProjectExportResponse::from(export))),
+            Ok(None) => Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Export not found".to_string(),
+                message: "The specified export does not exist or does not belong to you.".to_string(),
+            })),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve export.".to_string(),
+            })),
+        }
+    }
+
+    /// Authenticates the caller and loads one of their projects with all of its
+    /// conversions, for the export endpoints.
+    async fn export_source(req: &HttpRequest, project_id: &str) -> Result<(User, Project, Vec<Conversion>), HttpResponse> {
+        let project_id = match ObjectId::parse_str(project_id) {
+            Ok(id) => id,
+            Err(_) => {
+                return Err(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Invalid Project ID".to_string(),
+                    message: "The provided project ID is not valid.".to_string(),
+                }));
+            }
+        };
+
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => {
+                return Err(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "User authentication data not found.".to_string(),
+                }));
+            }
+        };
+
+        let user = match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) if user.id.is_some() => user,
+            _ => {
+                return Err(HttpResponse::Unauthorized().json(ErrorResponse {
+                    error: "Unauthorized".to_string(),
+                    message: "Invalid user token.".to_string(),
+                }));
+            }
+        };
+        let user_id = user.id.unwrap();
+
+        let (projects_collection, conversions_collection) = match (
+            database::get_projects_collection().await,
+            database::get_conversion_collection().await,
+        ) {
+            (Ok(projects), Ok(conversions)) => (projects, conversions),
+            _ => {
+                return Err(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to connect to database.".to_string(),
+                }));
+            }
+        };
+
+        let project = match SyncCRUD::find_project(&project_id, &user_id, &projects_collection).await {
+            Ok(Some(project)) => project,
+            Ok(None) => {
+                return Err(HttpResponse::NotFound().json(ErrorResponse {
+                    error: "Project not found".to_string(),
+                    message: "The specified project does not exist or does not belong to you.".to_string(),
+                }));
+            }
+            Err(_) => {
+                return Err(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to retrieve project.".to_string(),
+                }));
+            }
+        };
+
+        let conversions = match SyncCRUD::list_all_project_conversions(&project_id, &user_id, &conversions_collection).await {
+            Ok(conversions) if !conversions.is_empty() => conversions,
+            Ok(_) => {
+                return Err(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Empty Project".to_string(),
+                    message: "The project has no conversions to export.".to_string(),
+                }));
+            }
+            Err(_) => {
+                return Err(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to retrieve project conversions.".to_string(),
+                }));
+            }
+        };
+
+        Ok((user, project, conversions))
+    }
+
+    /// Builds a bundle after the request has returned, uploads it to storage and
+    /// records the download link or the failure on the export.
+    async fn run_background_export(
+        export_id: String,
+        project: Project,
+        conversions: Vec<Conversion>,
+        formats: Vec<TargetFormat>,
+        options: RenderOptions,
+        collection: Collection<ProjectExport>,
+    ) {
+        let uploaded = match Self::build_project_bundle(&project, &conversions, &formats, &options, true).await {
+            Ok((archive, size)) => {
+                let public_id = format!("{}/exports/{}.zip", project.cloudinary_folder, export_id);
+                let filename = format!("{}.zip", archive_file_stem(&project.name));
+                CloudinaryService::new(Config::new())
+                    .upload_raw_file(archive.path(), &public_id, &filename)
+                    .await
+                    .map(|url| (url, public_id, size))
+            }
+            Err(e) => Err(e),
+        };
+
+        let recorded = match uploaded {
+            Ok((url, public_id, size)) => SyncCRUD::complete_export(&export_id, &url, &public_id, size, &collection).await,
+            Err(e) => {
+                eprintln!("Project export {} failed: {}", export_id, e);
+                SyncCRUD::fail_export(&export_id, &e, &collection).await
+            }
+        };
+        if let Err(e) = recorded {
+            eprintln!("Failed to record the result of project export {}: {}", export_id, e);
+        }
+    }
+
+    /// Renders every conversion in each format and zips the results with the
+    /// original images into a temp file, adding each file as soon as it is
+    /// made. Files that cannot be produced are listed under `errors` in the
+    /// manifest instead of failing the whole bundle. With `wait_for_queue`, as
+    /// in background exports, a full export queue is waited out rather than
+    /// recorded as an error. Returns the finished archive and its size.
+    async fn build_project_bundle(
+        project: &Project,
+        conversions: &[Conversion],
+        formats: &[TargetFormat],
+        options: &RenderOptions,
+        wait_for_queue: bool,
+    ) -> Result<(TempArchive, u64), String> {
+        let mut templates = Vec::with_capacity(formats.len());
+        for format in formats {
+            templates.push(TemplateController::export_templates(&project.user_id, project.id, *format).await?);
+        }
+
+        let cloudinary = CloudinaryService::new(Config::new());
+        let mut archive = TempArchive::create()?;
+        let mut manifest_conversions = Vec::with_capacity(conversions.len());
+
+        for (index, conversion) in conversions.iter().enumerate() {
+            let stem = format!("{:03}-{}", index + 1, archive_file_stem(&conversion.original_filename));
+            let mut item = BundleManifestConversion {
+                job_id: conversion.job_id.clone(),
+                original_filename: conversion.original_filename.clone(),
+                status: conversion.status.to_string(),
+                created_at: conversion.created_at.to_string(),
+                ocr_model: conversion.ocr_model.clone(),
+                tags: conversion.tags.clone(),
+                metadata: conversion.metadata.clone(),
+                image: None,
+                documents: Default::default(),
+                errors: Default::default(),
+            };
+
+            match cloudinary.fetch_file(&conversion.cloudinary_url).await {
+                Ok(image) => {
+                    let path = format!("images/{}.{}", stem, image_extension(&conversion.mime_type));
+                    archive = add_to_archive(archive, path.clone(), image).await?;
+                    item.image = Some(path);
+                }
+                Err(e) => {
+                    item.errors.insert("image".to_string(), e);
+                }
+            }
+
+            let text = conversion.extracted_text.as_deref().unwrap_or_default();
+            for (format, templates) in formats.iter().zip(&templates) {
+                if text.trim().is_empty() {
+                    item.errors.insert(format.name().to_string(), "The conversion has no extracted text.".to_string());
+                    continue;
+                }
+                let options = RenderOptions { templates: templates.clone(), ..options.clone() };
+                match render_for_bundle(text, *format, &options, wait_for_queue).await {
+                    Ok(result) => {
+                        let path = format!("documents/{}", format.file_name(&stem));
+                        archive = add_to_archive(archive, path.clone(), result.content).await?;
+                        item.documents.insert(format.name().to_string(), path);
+                    }
+                    Err(e) => {
+                        item.errors.insert(format.name().to_string(), e.to_string());
+                    }
+                }
+            }
+            manifest_conversions.push(item);
+        }
+
+        let manifest = BundleManifest {
+            project_id: project.id.map(|id| id.to_hex()).unwrap_or_default(),
+            project_name: project.name.clone(),
+            description: project.description.clone(),
+            exported_at: mongodb::bson::DateTime::now().to_string(),
+            formats: formats.iter().map(|f| f.name().to_string()).collect(),
+            conversions: manifest_conversions,
+        };
+        let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| format!("Failed to write manifest: {}", e))?;
+        let mut archive = add_to_archive(archive, "manifest.json".to_string(), manifest).await?;
+
+        web::block(move || archive.finish().map(|size| (archive, size)))
+            .await
+            .map_err(|e| format!("Archive task failed: {}", e))?
+    }
+}
+
+/// How long a background export waits out a full export queue per file
+/// before recording the file as failed.
+const BACKGROUND_QUEUE_WAIT: Duration = Duration::from_secs(30 * 60);
+
+async fn render_for_bundle(text: &str, format: TargetFormat, options: &RenderOptions, wait_for_queue: bool) -> Result<ConversionResult, RenderError> {
+    let deadline = Instant::now() + BACKGROUND_QUEUE_WAIT;
+    loop {
+        match DocumentConverter::render(text, format, options).await {
+            Err(RenderError::Busy { retry_after_secs }) if wait_for_queue && Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_secs(retry_after_secs)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Compression is CPU bound, so each file is added off the async workers.
+async fn add_to_archive(mut archive: TempArchive, path: String, content: Vec<u8>) -> Result<TempArchive, String> {
+    web::block(move || archive.add(&path, &content).map(|_| archive))
+        .await
+        .map_err(|e| format!("Archive task failed: {}", e))?
+}
+
+/// Streams a finished archive from disk in chunks. The file is unlinked as
+/// soon as it is open, so it disappears once the response is done or dropped.
+fn archive_response(archive: TempArchive, size: u64, filename: &str) -> Result<HttpResponse, String> {
+    let file = std::fs::File::open(archive.path()).map_err(|e| format!("Failed to open archive: {}", e))?;
+    drop(archive);
+
+    let body = futures::stream::unfold(Some(tokio::fs::File::from_std(file)), |file| async move {
+        let mut file = file?;
+        let mut chunk = vec![0; ARCHIVE_CHUNK_BYTES];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(web::Bytes::from(chunk)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(streamed_attachment_response(body, size, "application/zip", filename))
+}
+
+const ARCHIVE_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Parses a comma separated list of export formats, dropping duplicates.
+fn parse_export_formats(list: &str) -> Result<Vec<TargetFormat>, String> {
+    let mut formats = Vec::new();
+    for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match TargetFormat::from_name(name) {
+            Some(format) if !formats.contains(&format) => formats.push(format),
+            Some(_) => {}
+            None => return Err(format!("Supported formats: {}. Got: {}", TargetFormat::supported_names(), name)),
+        }
+    }
+    if formats.is_empty() {
+        return Err(format!("Choose at least one of: {}", TargetFormat::supported_names()));
+    }
+    Ok(formats)
+}
+
+fn image_extension(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "image/tiff" => "tiff",
+        _ => "bin",
+    }
+}
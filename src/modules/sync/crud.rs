@@ -1,7 +1,8 @@
 use mongodb::{Collection, bson::{doc, oid::ObjectId}};
 use futures::TryStreamExt;
-use crate::modules::sync::model::Project;
+use crate::modules::sync::model::{Project, ProjectExport, ProjectExportStatus};
 use crate::modules::conversion::model::Conversion;
+use crate::modules::conversion::crud::apply_tag_filter;
 use mongodb::options::FindOptions;
 
 pub struct SyncCRUD;
@@ -13,7 +14,7 @@ impl SyncCRUD {
         description: Option<String>,
         collection: &Collection<Project>
     ) -> mongodb::error::Result<Project> {
-        let project = Project::new(user_id.clone(), name, description);
+        let project = Project::new(*user_id, name, description);
         let result = collection.insert_one(&project).await?;
         
         Ok(Project {
@@ -34,7 +35,7 @@ impl SyncCRUD {
         
         let find_options = FindOptions::builder()
             .skip(skip as u64)
-            .limit(limit as i64)
+            .limit(limit)
             .sort(doc! { "created_at": -1 })  // Sort by newest first
             .build();
         
@@ -76,16 +77,18 @@ impl SyncCRUD {
         user_id: &ObjectId,
         skip: i64,
         limit: i64,
+        tags: &[String],
         collection: &Collection<Conversion>
     ) -> mongodb::error::Result<Vec<Conversion>> {
-        let filter = doc! {
+        let mut filter = doc! {
             "user_id": user_id,
             "project_id": project_id
         };
+        apply_tag_filter(&mut filter, tags);
         
         let find_options = FindOptions::builder()
             .skip(skip as u64)
-            .limit(limit as i64)
+            .limit(limit)
             .sort(doc! { "created_at": -1 })
             .build();
         
@@ -103,16 +106,18 @@ impl SyncCRUD {
         user_id: &ObjectId,
         skip: i64,
         limit: i64,
+        tags: &[String],
         collection: &Collection<Conversion>
     ) -> mongodb::error::Result<Vec<Conversion>> {
-        let filter = doc! {
+        let mut filter = doc! {
             "user_id": user_id,
             "project_id": null
         };
+        apply_tag_filter(&mut filter, tags);
         
         let find_options = FindOptions::builder()
             .skip(skip as u64)
-            .limit(limit as i64)
+            .limit(limit)
             .sort(doc! { "created_at": -1 })
             .build();
         
@@ -175,4 +180,139 @@ impl SyncCRUD {
 
         Ok(())
     }
+
+    pub async fn set_project_templates(
+        project_id: &ObjectId,
+        user_id: &ObjectId,
+        template_ids: &[String],
+        collection: &Collection<Project>
+    ) -> mongodb::error::Result<bool> {
+        let filter = doc! {
+            "_id": project_id,
+            "user_id": user_id
+        };
+        let update = doc! {
+            "$set": {
+                "default_template_ids": template_ids,
+                "updated_at": mongodb::bson::DateTime::now()
+            }
+        };
+        let result = collection.update_one(filter, update).await?;
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn remove_template_from_projects(
+        template_id: &str,
+        user_id: &ObjectId,
+        collection: &Collection<Project>
+    ) -> mongodb::error::Result<()> {
+        let filter = doc! {
+            "user_id": user_id,
+            "default_template_ids": template_id
+        };
+        let update = doc! {
+            "$pull": { "default_template_ids": template_id }
+        };
+        collection.update_many(filter, update).await?;
+        Ok(())
+    }
+
+    /// Every conversion in a project, oldest first, for bundle exports.
+    pub async fn list_all_project_conversions(
+        project_id: &ObjectId,
+        user_id: &ObjectId,
+        collection: &Collection<Conversion>
+    ) -> mongodb::error::Result<Vec<Conversion>> {
+        let filter = doc! {
+            "user_id": user_id,
+            "project_id": project_id
+        };
+        let find_options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .build();
+
+        let mut cursor = collection.find(filter).with_options(find_options).await?;
+        let mut conversions = Vec::new();
+        while let Some(conversion) = cursor.try_next().await? {
+            conversions.push(conversion);
+        }
+        Ok(conversions)
+    }
+
+    pub async fn create_export(
+        export: &ProjectExport,
+        collection: &Collection<ProjectExport>
+    ) -> mongodb::error::Result<()> {
+        collection.insert_one(export).await?;
+        Ok(())
+    }
+
+    pub async fn find_export(
+        export_id: &str,
+        user_id: &ObjectId,
+        collection: &Collection<ProjectExport>
+    ) -> mongodb::error::Result<Option<ProjectExport>> {
+        collection.find_one(doc! { "export_id": export_id, "user_id": user_id }).await
+    }
+
+    pub async fn complete_export(
+        export_id: &str,
+        download_url: &str,
+        storage_public_id: &str,
+        file_size: u64,
+        collection: &Collection<ProjectExport>
+    ) -> mongodb::error::Result<()> {
+        let now = mongodb::bson::DateTime::now();
+        let update = doc! {
+            "$set": {
+                "status": mongodb::bson::to_bson(&ProjectExportStatus::Completed)?,
+                "download_url": download_url,
+                "storage_public_id": storage_public_id,
+                "file_size": file_size as i64,
+                "updated_at": now,
+                "completed_at": now
+            }
+        };
+        collection.update_one(doc! { "export_id": export_id }, update).await?;
+        Ok(())
+    }
+
+    pub async fn fail_export(
+        export_id: &str,
+        error_message: &str,
+        collection: &Collection<ProjectExport>
+    ) -> mongodb::error::Result<()> {
+        let now = mongodb::bson::DateTime::now();
+        let update = doc! {
+            "$set": {
+                "status": mongodb::bson::to_bson(&ProjectExportStatus::Failed)?,
+                "error_message": error_message,
+                "updated_at": now,
+                "completed_at": now
+            }
+        };
+        collection.update_one(doc! { "export_id": export_id }, update).await?;
+        Ok(())
+    }
+
+    /// Marks exports still processing as failed. Background exports run inside
+    /// the server process, so at startup any left in that state were cut off
+    /// by a restart and will never finish. Returns how many were marked.
+    pub async fn fail_interrupted_exports(collection: &Collection<ProjectExport>) -> mongodb::error::Result<u64> {
+        let now = mongodb::bson::DateTime::now();
+        let result = collection
+            .update_many(
+                doc! { "status": mongodb::bson::to_bson(&ProjectExportStatus::Processing)? },
+                doc! {
+                    "$set": {
+                        "status": mongodb::bson::to_bson(&ProjectExportStatus::Failed)?,
+                        "error_message": "The server restarted before the export finished. Please start it again.",
+                        "updated_at": now,
+                        "completed_at": now
+                    }
+                },
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
 }
@@ -1,5 +1,7 @@
 use mongodb::bson::{DateTime, oid::ObjectId};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Project {
@@ -11,6 +13,8 @@ pub struct Project {
     pub cloudinary_folder: String,  // Cloudinary folder path
     pub conversion_count: i32,
     pub total_storage_bytes: u64,
+    #[serde(default)]
+    pub default_template_ids: Vec<String>,  // Export templates applied to this project's conversions
     pub created_at: DateTime,
     pub updated_at: DateTime,
 }
@@ -28,9 +32,71 @@ impl Project {
             cloudinary_folder,
             conversion_count: 0,
             total_storage_bytes: 0,
+            default_template_ids: Vec::new(),
             created_at: now,
             updated_at: now,
         }
     }
 }
 
+
+/// A project bundle built in the background because it was too large to
+/// return from the export request itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectExport {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub export_id: String,
+    pub project_id: ObjectId,
+    pub user_id: ObjectId,
+    pub formats: Vec<String>,
+    pub status: ProjectExportStatus,
+    pub download_url: Option<String>,
+    pub storage_public_id: Option<String>,
+    pub file_size: Option<u64>,
+    pub error_message: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+    pub completed_at: Option<DateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ProjectExportStatus {
+    #[serde(rename = "processing")]
+    Processing,
+    #[serde(rename = "completed")]
+    Completed,
+    #[serde(rename = "failed")]
+    Failed,
+}
+
+impl fmt::Display for ProjectExportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProjectExportStatus::Processing => write!(f, "processing"),
+            ProjectExportStatus::Completed => write!(f, "completed"),
+            ProjectExportStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl ProjectExport {
+    pub fn new(project_id: ObjectId, user_id: ObjectId, formats: Vec<String>) -> Self {
+        let now = DateTime::now();
+        Self {
+            id: None,
+            export_id: Uuid::new_v4().to_string(),
+            project_id,
+            user_id,
+            formats,
+            status: ProjectExportStatus::Processing,
+            download_url: None,
+            storage_public_id: None,
+            file_size: None,
+            error_message: None,
+            created_at: now,
+            updated_at: now,
+            completed_at: None,
+        }
+    }
+}
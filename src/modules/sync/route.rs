@@ -11,6 +11,11 @@ pub fn configure_sync_routes(cfg: &mut web::ServiceConfig) {
             .route("/projects/{page}/{limit}", web::get().to(SyncController::list_projects_paginated))
             .route("/projects/{project_id}/conversions/{page}/{limit}", web::get().to(SyncController::list_project_conversions))
             .route("/projects/{project_id}/assign", web::post().to(SyncController::assign_conversions))
+            .route("/projects/{project_id}/templates", web::get().to(SyncController::get_project_templates))
+            .route("/projects/{project_id}/templates", web::put().to(SyncController::set_project_templates))
+            .route("/projects/{project_id}/export", web::get().to(SyncController::export_project))  // ?formats=pdf,docx,tex
+            .route("/projects/{project_id}/export/overleaf", web::get().to(SyncController::export_overleaf))
+            .route("/exports/{export_id}", web::get().to(SyncController::get_project_export))
             .route("/conversions/unassigned/{page}/{limit}", web::get().to(SyncController::list_unassigned_conversions))
     );
 }
@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::modules::sync::model::ProjectExport;
 
 #[derive(Debug, Deserialize)]
 pub struct CreateProjectRequest {
@@ -50,6 +53,7 @@ pub struct ConversionListItem {
     pub status: String,
     pub created_at: String,
     pub completed_at: Option<String>,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -63,3 +67,68 @@ pub struct SuccessResponse {
     pub message: String,
     pub data: Option<serde_json::Value>,
 }
+
+/// Bundles that render more files than this (conversions × formats) are built
+/// in the background instead of in the export request.
+pub const INLINE_BUNDLE_MAX_FILES: usize = 20;
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectExportParams {
+    pub formats: Option<String>,  // Comma separated, e.g. `pdf,docx,tex`; defaults to `tex`
+}
+
+/// Status of a background project bundle.
+#[derive(Debug, Serialize)]
+pub struct ProjectExportResponse {
+    pub export_id: String,
+    pub project_id: String,
+    pub status: String,
+    pub formats: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+    pub created_at: String,
+}
+
+impl From<ProjectExport> for ProjectExportResponse {
+    fn from(export: ProjectExport) -> Self {
+        Self {
+            export_id: export.export_id,
+            project_id: export.project_id.to_hex(),
+            status: export.status.to_string(),
+            formats: export.formats,
+            download_url: export.download_url,
+            file_size: export.file_size,
+            error_message: export.error_message,
+            created_at: export.created_at.to_string(),
+        }
+    }
+}
+
+/// `manifest.json` at the root of a project bundle.
+#[derive(Debug, Serialize)]
+pub struct BundleManifest {
+    pub project_id: String,
+    pub project_name: String,
+    pub description: Option<String>,
+    pub exported_at: String,
+    pub formats: Vec<String>,
+    pub conversions: Vec<BundleManifestConversion>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BundleManifestConversion {
+    pub job_id: String,
+    pub original_filename: String,
+    pub status: String,
+    pub created_at: String,
+    pub ocr_model: Option<String>,
+    pub tags: Vec<String>,
+    pub metadata: HashMap<String, String>,
+    pub image: Option<String>,                // Path of the original image in the bundle
+    pub documents: BTreeMap<String, String>,  // Format -> path in the bundle
+    pub errors: BTreeMap<String, String>,     // Format (or `image`) -> why it is missing
+}
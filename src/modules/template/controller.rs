@@ -0,0 +1,337 @@
+use actix_multipart::Multipart;
+use actix_web::{HttpResponse, Error, HttpRequest, HttpMessage, web};
+use futures_util::StreamExt;
+use mongodb::bson::oid::ObjectId;
+
+use crate::config::database;
+use crate::config::environment::Config;
+use crate::services::cloudinary::CloudinaryService;
+use crate::services::document_converter::{LatexClass, TargetFormat, TemplateFiles};
+use crate::services::latex_sanitizer::{find_blocked_class_constructs, find_blocked_constructs};
+use crate::modules::template::{
+    model::{ExportTemplate, TemplateKind},
+    schema::{UploadTemplateParams, TemplateResponse, TemplateListResponse, MAX_TEMPLATE_SIZE, MAX_TEMPLATES_PER_USER},
+    crud::TemplateCRUD,
+};
+use crate::modules::sync::crud::SyncCRUD;
+use crate::modules::user::crud::UserCRUD;
+use crate::modules::user::schema::ErrorResponse;
+use crate::modules::conversion::schema::LatexRejectedResponse;
+
+pub struct TemplateController;
+
+impl TemplateController {
+    async fn authenticated_user_id(req: &HttpRequest) -> Result<ObjectId, HttpResponse> {
+        let claims = match req.extensions().get::<crate::services::jwt::Claims>().cloned() {
+            Some(claims) => claims,
+            None => return Err(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "User authentication data not found.".to_string(),
+            })),
+        };
+
+        match UserCRUD::find_by_uuid(&claims.sub).await {
+            Ok(Some(user)) if user.id.is_some() => Ok(user.id.unwrap()),
+            _ => Err(HttpResponse::Unauthorized().json(ErrorResponse {
+                error: "Unauthorized".to_string(),
+                message: "Invalid user token.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn upload_template(
+        mut payload: Multipart,
+        req: HttpRequest,
+        params: web::Query<UploadTemplateParams>,
+    ) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered upload_template handler.");
+
+        let user_id = match Self::authenticated_user_id(&req).await {
+            Ok(id) => id,
+            Err(response) => return Ok(response),
+        };
+
+        // Only the first file part is processed.
+        let mut upload: Option<(String, Vec<u8>)> = None;
+        while let Some(item) = payload.next().await {
+            let mut field = item.map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid multipart data: {}", e)))?;
+            if upload.is_some() {
+                continue;
+            }
+
+            let filename = field
+                .content_disposition()
+                .and_then(|cd| cd.get_filename())
+                .unwrap_or("template")
+                .to_string();
+
+            let mut file_bytes = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let data = chunk.map_err(|e| actix_web::error::ErrorBadRequest(format!("Failed to read chunk: {}", e)))?;
+                if file_bytes.len() + data.len() > MAX_TEMPLATE_SIZE {
+                    return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
+                        error: "File too large".to_string(),
+                        message: format!("Maximum template size is {} bytes", MAX_TEMPLATE_SIZE),
+                    }));
+                }
+                file_bytes.extend_from_slice(&data);
+            }
+            upload = Some((filename, file_bytes));
+        }
+
+        let (filename, file_bytes) = match upload {
+            Some(upload) if !upload.1.is_empty() => upload,
+            _ => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "No file".to_string(),
+                message: "No template file was uploaded".to_string(),
+            })),
+        };
+
+        let kind = match params.kind.as_deref() {
+            Some(kind) => TemplateKind::from_name(kind),
+            None => TemplateKind::from_filename(&filename),
+        };
+        let kind = match kind {
+            Some(kind) => kind,
+            None => return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Invalid template kind".to_string(),
+                message: "Upload a .docx, .odt/.ott, .tex/.sty or .cls file, or set kind to reference_docx, odt_template, latex_preamble or latex_class.".to_string(),
+            })),
+        };
+
+        // DOCX and ODT files are ZIP packages; LaTeX sources must be text.
+        let valid_content = if kind.is_binary() {
+            file_bytes.starts_with(b"PK\x03\x04")
+        } else {
+            std::str::from_utf8(&file_bytes).is_ok()
+        };
+        if !valid_content {
+            return Ok(HttpResponse::UnsupportedMediaType().json(ErrorResponse {
+                error: "Invalid template file".to_string(),
+                message: format!("The uploaded file is not a valid {} template.", kind),
+            }));
+        }
+
+        // Preambles are spliced into every export, so they face the same checks as
+        // document text. Class files may retokenize but lose shell and file access.
+        let (violations, source) = match kind {
+            TemplateKind::LatexPreamble => (find_blocked_constructs(&String::from_utf8_lossy(&file_bytes)), "preamble"),
+            TemplateKind::LatexClass => (find_blocked_class_constructs(&String::from_utf8_lossy(&file_bytes)), "class file"),
+            _ => (Vec::new(), "template"),
+        };
+        if !violations.is_empty() {
+            return Ok(HttpResponse::UnprocessableEntity().json(LatexRejectedResponse {
+                error: "Unsafe LaTeX".to_string(),
+                message: format!("The {} uses commands that are not allowed in exports.", source),
+                violations,
+            }));
+        }
+
+        let collection = match database::get_templates_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        match TemplateCRUD::count_for_user(&user_id, &collection).await {
+            Ok(count) if count >= MAX_TEMPLATES_PER_USER => {
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: "Template limit reached".to_string(),
+                    message: format!("You can store at most {} templates. Delete one to upload another.", MAX_TEMPLATES_PER_USER),
+                }));
+            }
+            Ok(_) => {}
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to count existing templates.".to_string(),
+            })),
+        }
+
+        // Raw assets keep their extension in the public id so downloads are recognisable.
+        let extension = filename.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+        let public_id = format!("templates/{}/{}.{}", user_id.to_hex(), uuid::Uuid::new_v4(), extension);
+        let file_size = file_bytes.len() as u64;
+
+        let cloudinary = CloudinaryService::new(Config::new());
+        let storage_url = match cloudinary.upload_raw(file_bytes, &public_id, &filename).await {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("Template upload failed: {}", e);
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Storage Error".to_string(),
+                    message: "Failed to store the template file.".to_string(),
+                }));
+            }
+        };
+
+        let name = params.name.as_deref().map(str::trim).filter(|n| !n.is_empty()).unwrap_or(&filename).to_string();
+        let template = ExportTemplate::new(user_id, name, kind, filename, storage_url, public_id.clone(), file_size);
+
+        match TemplateCRUD::create(template, &collection).await {
+            Ok(template) => Ok(HttpResponse::Created().json(TemplateResponse::from(template))),
+            Err(_) => {
+                let _ = cloudinary.delete_raw(&public_id).await;
+                Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Database Error".to_string(),
+                    message: "Failed to save the template.".to_string(),
+                }))
+            }
+        }
+    }
+
+    pub async fn list_templates(req: HttpRequest) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered list_templates handler.");
+
+        let user_id = match Self::authenticated_user_id(&req).await {
+            Ok(id) => id,
+            Err(response) => return Ok(response),
+        };
+
+        let collection = match database::get_templates_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        match TemplateCRUD::list_for_user(&user_id, &collection).await {
+            Ok(templates) => Ok(HttpResponse::Ok().json(TemplateListResponse {
+                templates: templates.into_iter().map(TemplateResponse::from).collect(),
+            })),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve templates.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn set_default_template(req: HttpRequest, template_id: web::Path<String>) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered set_default_template handler for template_id: {}", template_id);
+
+        let user_id = match Self::authenticated_user_id(&req).await {
+            Ok(id) => id,
+            Err(response) => return Ok(response),
+        };
+
+        let collection = match database::get_templates_collection().await {
+            Ok(coll) => coll,
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let template = match TemplateCRUD::find_by_template_id(&template_id, &user_id, &collection).await {
+            Ok(Some(template)) => template,
+            Ok(None) => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Template with this ID was not found.".to_string(),
+            })),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve the template.".to_string(),
+            })),
+        };
+
+        match TemplateCRUD::set_default(&template, &collection).await {
+            Ok(()) => Ok(HttpResponse::Ok().json(TemplateResponse::from(ExportTemplate { is_default: true, ..template }))),
+            Err(_) => Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to update the default template.".to_string(),
+            })),
+        }
+    }
+
+    pub async fn delete_template(req: HttpRequest, template_id: web::Path<String>) -> Result<HttpResponse, Error> {
+        println!("CONTROLLER: Entered delete_template handler for template_id: {}", template_id);
+
+        let user_id = match Self::authenticated_user_id(&req).await {
+            Ok(id) => id,
+            Err(response) => return Ok(response),
+        };
+
+        let (collection, projects_collection) = match (
+            database::get_templates_collection().await,
+            database::get_projects_collection().await,
+        ) {
+            (Ok(templates), Ok(projects)) => (templates, projects),
+            _ => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to connect to database.".to_string(),
+            })),
+        };
+
+        let template = match TemplateCRUD::find_by_template_id(&template_id, &user_id, &collection).await {
+            Ok(Some(template)) => template,
+            Ok(None) => return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "Not Found".to_string(),
+                message: "Template with this ID was not found.".to_string(),
+            })),
+            Err(_) => return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to retrieve the template.".to_string(),
+            })),
+        };
+
+        let deleted = TemplateCRUD::delete(&template.template_id, &user_id, &collection).await;
+        let unlinked = SyncCRUD::remove_template_from_projects(&template.template_id, &user_id, &projects_collection).await;
+        if deleted.is_err() || unlinked.is_err() {
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database Error".to_string(),
+                message: "Failed to delete the template.".to_string(),
+            }));
+        }
+
+        // The record is gone either way; a leftover asset is only wasted storage.
+        let cloudinary = CloudinaryService::new(Config::new());
+        if let Err(e) = cloudinary.delete_raw(&template.storage_public_id).await {
+            eprintln!("Failed to delete template asset {}: {}", template.storage_public_id, e);
+        }
+
+        Ok(HttpResponse::NoContent().finish())
+    }
+
+    /// Downloads the templates that apply to an export in `format`: the
+    /// project's defaults when the content belongs to a project, otherwise the
+    /// user's own defaults.
+    pub async fn export_templates(
+        user_id: &ObjectId,
+        project_id: Option<ObjectId>,
+        format: TargetFormat,
+    ) -> Result<TemplateFiles, String> {
+        let project_template_ids = match project_id {
+            Some(project_id) => {
+                let projects = database::get_projects_collection().await.map_err(|e| e.to_string())?;
+                SyncCRUD::find_project(&project_id, user_id, &projects)
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .map(|project| project.default_template_ids)
+                    .unwrap_or_default()
+            }
+            None => Vec::new(),
+        };
+
+        let collection = database::get_templates_collection().await.map_err(|e| e.to_string())?;
+        let templates = TemplateCRUD::resolve_for_export(user_id, &project_template_ids, &collection)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let cloudinary = CloudinaryService::new(Config::new());
+        let mut files = TemplateFiles::default();
+        for template in templates.into_iter().filter(|t| t.kind.applies_to(format)) {
+            let content = cloudinary.fetch_file(&template.storage_url).await?;
+            match template.kind {
+                TemplateKind::ReferenceDocx => files.reference_docx = Some(content),
+                TemplateKind::OdtTemplate => files.reference_odt = Some(content),
+                TemplateKind::LatexPreamble => files.latex_preamble = Some(String::from_utf8_lossy(&content).into_owned()),
+                TemplateKind::LatexClass => {
+                    files.latex_class = Some(LatexClass { name: template.latex_class_name(), source: content })
+                }
+            }
+        }
+        Ok(files)
+    }
+}
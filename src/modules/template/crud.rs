@@ -0,0 +1,103 @@
+use mongodb::{Collection, bson::{doc, oid::ObjectId}, options::FindOptions};
+use futures::TryStreamExt;
+use crate::modules::template::model::ExportTemplate;
+
+pub struct TemplateCRUD;
+
+impl TemplateCRUD {
+    pub async fn create(
+        template: ExportTemplate,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<ExportTemplate> {
+        let result = collection.insert_one(&template).await?;
+        Ok(ExportTemplate {
+            id: result.inserted_id.as_object_id(),
+            ..template
+        })
+    }
+
+    pub async fn list_for_user(
+        user_id: &ObjectId,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<Vec<ExportTemplate>> {
+        let find_options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .build();
+        let cursor = collection.find(doc! { "user_id": user_id }).with_options(find_options).await?;
+        cursor.try_collect().await
+    }
+
+    pub async fn count_for_user(
+        user_id: &ObjectId,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<u64> {
+        collection.count_documents(doc! { "user_id": user_id }).await
+    }
+
+    pub async fn find_by_template_id(
+        template_id: &str,
+        user_id: &ObjectId,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<Option<ExportTemplate>> {
+        collection.find_one(doc! { "template_id": template_id, "user_id": user_id }).await
+    }
+
+    pub async fn find_many(
+        template_ids: &[String],
+        user_id: &ObjectId,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<Vec<ExportTemplate>> {
+        let filter = doc! { "template_id": { "$in": template_ids }, "user_id": user_id };
+        let cursor = collection.find(filter).await?;
+        cursor.try_collect().await
+    }
+
+    pub async fn delete(
+        template_id: &str,
+        user_id: &ObjectId,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<bool> {
+        let result = collection.delete_one(doc! { "template_id": template_id, "user_id": user_id }).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Makes `template` the user's default for its kind, clearing any previous default.
+    pub async fn set_default(
+        template: &ExportTemplate,
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<()> {
+        let now = mongodb::bson::DateTime::now();
+        collection.update_many(
+            doc! { "user_id": template.user_id, "kind": template.kind.to_string(), "is_default": true },
+            doc! { "$set": { "is_default": false, "updated_at": now } },
+        ).await?;
+        collection.update_one(
+            doc! { "template_id": &template.template_id, "user_id": template.user_id },
+            doc! { "$set": { "is_default": true, "updated_at": now } },
+        ).await?;
+        Ok(())
+    }
+
+    /// Templates to apply to an export: the project's defaults, with the user's
+    /// defaults filling in any kind the project does not set.
+    pub async fn resolve_for_export(
+        user_id: &ObjectId,
+        project_template_ids: &[String],
+        collection: &Collection<ExportTemplate>,
+    ) -> mongodb::error::Result<Vec<ExportTemplate>> {
+        let mut templates = if project_template_ids.is_empty() {
+            Vec::new()
+        } else {
+            Self::find_many(project_template_ids, user_id, collection).await?
+        };
+
+        let cursor = collection.find(doc! { "user_id": user_id, "is_default": true }).await?;
+        let user_defaults: Vec<ExportTemplate> = cursor.try_collect().await?;
+        for template in user_defaults {
+            if !templates.iter().any(|t| t.kind == template.kind) {
+                templates.push(template);
+            }
+        }
+        Ok(templates)
+    }
+}
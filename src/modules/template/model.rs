@@ -0,0 +1,123 @@
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+use crate::services::document_converter::TargetFormat;
+
+/// A user supplied house-style file applied to exports. The file itself lives
+/// in Cloudinary as a raw asset; this record holds its metadata.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportTemplate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub template_id: String,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub kind: TemplateKind,
+    pub original_filename: String,
+    pub storage_url: String,
+    pub storage_public_id: String,
+    pub file_size: u64,
+    pub is_default: bool,  // User-wide default for its kind, used outside projects with their own default
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl ExportTemplate {
+    pub fn new(
+        user_id: ObjectId,
+        name: String,
+        kind: TemplateKind,
+        original_filename: String,
+        storage_url: String,
+        storage_public_id: String,
+        file_size: u64,
+    ) -> Self {
+        let now = DateTime::now();
+        Self {
+            id: None,
+            template_id: Uuid::new_v4().to_string(),
+            user_id,
+            name,
+            kind,
+            original_filename,
+            storage_url,
+            storage_public_id,
+            file_size,
+            is_default: false,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Class name used in `\documentclass`, taken from the uploaded `.cls` filename.
+    pub fn latex_class_name(&self) -> String {
+        let stem = self.original_filename.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&self.original_filename);
+        let name: String = stem.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_').collect();
+        if name.is_empty() { "custom".to_string() } else { name }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TemplateKind {
+    #[serde(rename = "reference_docx")]
+    ReferenceDocx,
+    #[serde(rename = "odt_template")]
+    OdtTemplate,
+    #[serde(rename = "latex_preamble")]
+    LatexPreamble,
+    #[serde(rename = "latex_class")]
+    LatexClass,
+}
+
+impl TemplateKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "reference_docx" | "docx" => Some(TemplateKind::ReferenceDocx),
+            "odt_template" | "odt" => Some(TemplateKind::OdtTemplate),
+            "latex_preamble" | "preamble" => Some(TemplateKind::LatexPreamble),
+            "latex_class" | "class" => Some(TemplateKind::LatexClass),
+            _ => None,
+        }
+    }
+
+    /// Infers the kind from a filename extension.
+    pub fn from_filename(filename: &str) -> Option<Self> {
+        let extension = filename.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "docx" | "dotx" => Some(TemplateKind::ReferenceDocx),
+            "odt" | "ott" => Some(TemplateKind::OdtTemplate),
+            "tex" | "sty" => Some(TemplateKind::LatexPreamble),
+            "cls" => Some(TemplateKind::LatexClass),
+            _ => None,
+        }
+    }
+
+    /// Whether the template changes exports in `format`.
+    pub fn applies_to(&self, format: TargetFormat) -> bool {
+        match self {
+            TemplateKind::ReferenceDocx => format == TargetFormat::Docx,
+            TemplateKind::OdtTemplate => format == TargetFormat::Odt,
+            // Macros defined in a preamble are expanded by pandoc for every format.
+            TemplateKind::LatexPreamble => format != TargetFormat::Txt,
+            TemplateKind::LatexClass => matches!(format, TargetFormat::Pdf | TargetFormat::Pdfa | TargetFormat::Tex),
+        }
+    }
+
+    /// Office templates are ZIP packages; LaTeX templates must be UTF-8 text.
+    pub fn is_binary(&self) -> bool {
+        matches!(self, TemplateKind::ReferenceDocx | TemplateKind::OdtTemplate)
+    }
+}
+
+impl fmt::Display for TemplateKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateKind::ReferenceDocx => write!(f, "reference_docx"),
+            TemplateKind::OdtTemplate => write!(f, "odt_template"),
+            TemplateKind::LatexPreamble => write!(f, "latex_preamble"),
+            TemplateKind::LatexClass => write!(f, "latex_class"),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use actix_web::web;
+use crate::modules::template::controller::TemplateController;
+use crate::middleware::user_auth::Authentication;
+
+pub fn configure_template_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/templates")
+            .wrap(Authentication::new())  // Protect all template routes
+            .route("", web::get().to(TemplateController::list_templates))
+            .route("", web::post().to(TemplateController::upload_template))
+            .route("/{template_id}/default", web::put().to(TemplateController::set_default_template))
+            .route("/{template_id}", web::delete().to(TemplateController::delete_template))
+    );
+}
+
+/*
+Route Structure (Auth Required):
+   - GET /api/templates
+   - POST /api/templates?name=&kind=reference_docx|odt_template|latex_preamble|latex_class
+     (multipart: the template file; kind is inferred from the extension when omitted)
+   - PUT /api/templates/{template_id}/default   (user-wide default for the template's kind)
+   - DELETE /api/templates/{template_id}
+
+Project defaults are set through PUT /api/sync/projects/{project_id}/templates.
+*/
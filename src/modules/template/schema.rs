@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+use crate::modules::template::model::ExportTemplate;
+
+pub const MAX_TEMPLATE_SIZE: usize = 5 * 1024 * 1024; // 5MB
+pub const MAX_TEMPLATES_PER_USER: u64 = 50;
+
+/// Query parameters accepted by the template upload endpoint.
+#[derive(Debug, Deserialize)]
+pub struct UploadTemplateParams {
+    pub name: Option<String>,  // Defaults to the uploaded filename
+    pub kind: Option<String>,  // Inferred from the file extension when omitted
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectTemplatesRequest {
+    pub template_ids: Vec<String>,  // At most one template per kind
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateResponse {
+    pub template_id: String,
+    pub name: String,
+    pub kind: String,
+    pub original_filename: String,
+    pub file_size: u64,
+    pub is_default: bool,
+    pub created_at: String,
+}
+
+impl From<ExportTemplate> for TemplateResponse {
+    fn from(template: ExportTemplate) -> Self {
+        Self {
+            template_id: template.template_id,
+            name: template.name,
+            kind: template.kind.to_string(),
+            original_filename: template.original_filename,
+            file_size: template.file_size,
+            is_default: template.is_default,
+            created_at: template.created_at.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplateListResponse {
+    pub templates: Vec<TemplateResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProjectTemplatesResponse {
+    pub project_id: String,
+    pub templates: Vec<TemplateResponse>,
+}
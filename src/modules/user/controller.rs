@@ -170,7 +170,7 @@ impl UserController {
                 let refresh_token = Uuid::new_v4().to_string();
                 
                 // Update refresh token in database
-                if let Err(_) = UserCRUD::update_refresh_token(&user.uuid, Some(refresh_token.clone())).await {
+                if UserCRUD::update_refresh_token(&user.uuid, Some(refresh_token.clone())).await.is_err() {
                     return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                         error: "Database error".to_string(),
                         message: "Failed to generate refresh token.".to_string(),
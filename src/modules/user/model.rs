@@ -25,8 +25,9 @@ pub struct User {
     pub refresh_token_expires_at: Option<DateTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub enum AccountType {
+    #[default]
     Individual,
     Student,
     Business,
@@ -39,14 +40,31 @@ impl fmt::Display for AccountType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub enum Plan {
+    #[default]
     Free,
     Starter,
     Professional,
     Enterprise,
 }
 
+impl Plan {
+    pub fn allowed_ocr_models(&self) -> &'static [&'static str] {
+        match self {
+            Plan::Free => &["qwen/qwen-vl-plus"],
+            Plan::Starter => &["qwen/qwen-vl-plus", "qwen/qwen-vl-max"],
+            Plan::Professional | Plan::Enterprise => &[
+                "qwen/qwen-vl-plus",
+                "qwen/qwen-vl-max",
+                "google/gemini-2.0-flash-001",
+                "openai/gpt-4o-mini",
+                "openai/gpt-4o",
+            ],
+        }
+    }
+}
+
 impl fmt::Display for Plan {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -95,6 +113,11 @@ impl User {
         }
     }
 
+    /// OCR models this user may explicitly request for a conversion.
+    pub fn allowed_ocr_models(&self) -> &'static [&'static str] {
+        self.plan.allowed_ocr_models()
+    }
+
     pub fn set_verification_code(&mut self, code: String) {
         self.verification_code = Some(code);
         let expires_at = DateTime::now().timestamp_millis() + (30 * 60 * 1000);
@@ -103,14 +126,13 @@ impl User {
     }
 
     pub fn verify_code(&mut self, code: &str) -> bool {
-        if let (Some(stored_code), Some(expires_at)) = (&self.verification_code, self.verification_code_expires_at) {
-            if stored_code == code && expires_at > DateTime::now() {
-                self.is_verified = true;
-                self.verification_code = None;
-                self.verification_code_expires_at = None;
-                self.updated_at = DateTime::now();
-                return true;
-            }
+        if let (Some(stored_code), Some(expires_at)) = (&self.verification_code, self.verification_code_expires_at)
+            && stored_code == code && expires_at > DateTime::now() {
+            self.is_verified = true;
+            self.verification_code = None;
+            self.verification_code_expires_at = None;
+            self.updated_at = DateTime::now();
+            return true;
         }
         false
     }
@@ -136,16 +158,4 @@ impl User {
             false
         }
     }
-}
-
-impl Default for AccountType {
-    fn default() -> Self {
-        AccountType::Individual
-    }
-}
-
-impl Default for Plan {
-    fn default() -> Self {
-        Plan::Free
-    }
 }
\ No newline at end of file
@@ -47,20 +47,15 @@ pub struct UserResponse {
     pub created_at: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub enum AccountTypeRequest {
+    #[default]
     Individual,
     Student,
     Business,
     Enterprise,
 }
 
-impl Default for AccountTypeRequest {
-    fn default() -> Self {
-        AccountTypeRequest::Individual
-    }
-}
-
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     pub error: String,
@@ -4,6 +4,13 @@ use std::time::Instant;
 use uuid::Uuid;
 use cloudinary::upload::{Upload, Source, OptionalParameters};
 use std::collections::BTreeSet;
+use sha1::{Digest, Sha1};
+use base64::{Engine as _, engine::general_purpose};
+
+/// Delivery types of uploaded assets: `upload` assets are served at public
+/// URLs, `authenticated` ones only at signed URLs.
+const PUBLIC_DELIVERY: &str = "upload";
+const AUTHENTICATED_DELIVERY: &str = "authenticated";
 
 pub struct CloudinaryService {
     upload: Upload,
@@ -45,6 +52,145 @@ impl CloudinaryService {
         }
     }
 
+    /// Uploads a non-image file, such as an export template, as a raw asset.
+    /// The `cloudinary` crate only targets the image endpoint, so this signs
+    /// the request itself.
+    pub async fn upload_raw(&self, bytes: Vec<u8>, public_id: &str, filename: &str) -> Result<String, String> {
+        self.upload_raw_part(reqwest::multipart::Part::bytes(bytes), public_id, filename, PUBLIC_DELIVERY).await
+    }
+
+    /// Uploads a private file, such as a cached export of a user's document, as
+    /// an authenticated raw asset. It has no public delivery URL and is read
+    /// back with `fetch_authenticated_raw`.
+    pub async fn upload_raw_authenticated(&self, bytes: Vec<u8>, public_id: &str, filename: &str) -> Result<String, String> {
+        self.upload_raw_part(reqwest::multipart::Part::bytes(bytes), public_id, filename, AUTHENTICATED_DELIVERY).await
+    }
+
+    /// Uploads a file from disk as a raw asset, streaming it instead of reading
+    /// it into memory first.
+    pub async fn upload_raw_file(&self, path: &Path, public_id: &str, filename: &str) -> Result<String, String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let size = file
+            .metadata()
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?
+            .len();
+        let part = reqwest::multipart::Part::stream_with_length(reqwest::Body::from(file), size);
+        self.upload_raw_part(part, public_id, filename, PUBLIC_DELIVERY).await
+    }
+
+    async fn upload_raw_part(&self, part: reqwest::multipart::Part, public_id: &str, filename: &str, delivery: &str) -> Result<String, String> {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = self.sign(&[("public_id", public_id), ("timestamp", &timestamp), ("type", delivery)]);
+
+        let form = reqwest::multipart::Form::new()
+            .part("file", part.file_name(filename.to_string()))
+            .text("public_id", public_id.to_string())
+            .text("timestamp", timestamp)
+            .text("type", delivery.to_string())
+            .text("api_key", self.config.cloudinary_api_key.clone())
+            .text("signature", signature);
+
+        let url = format!("https://api.cloudinary.com/v1_1/{}/raw/upload", self.config.cloudinary_cloud_name);
+        let response = reqwest::Client::new()
+            .post(&url)
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to upload file: {}", e))?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Failed to upload file: status {} {}", status, body));
+        }
+
+        Ok(format!(
+            "https://res.cloudinary.com/{}/raw/{}/{}",
+            self.config.cloudinary_cloud_name,
+            delivery,
+            public_id
+        ))
+    }
+
+    pub async fn delete_raw(&self, public_id: &str) -> Result<(), String> {
+        self.delete_raw_asset(public_id, PUBLIC_DELIVERY).await
+    }
+
+    /// Deletes a file uploaded with `upload_raw_authenticated`.
+    pub async fn delete_authenticated_raw(&self, public_id: &str) -> Result<(), String> {
+        self.delete_raw_asset(public_id, AUTHENTICATED_DELIVERY).await
+    }
+
+    async fn delete_raw_asset(&self, public_id: &str, delivery: &str) -> Result<(), String> {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = self.sign(&[("public_id", public_id), ("timestamp", &timestamp), ("type", delivery)]);
+
+        let url = format!("https://api.cloudinary.com/v1_1/{}/raw/destroy", self.config.cloudinary_cloud_name);
+        let response = reqwest::Client::new()
+            .post(&url)
+            .form(&[
+                ("public_id", public_id),
+                ("timestamp", timestamp.as_str()),
+                ("type", delivery),
+                ("api_key", self.config.cloudinary_api_key.as_str()),
+                ("signature", signature.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| format!("Failed to delete file: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Failed to delete file: status {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// Downloads a previously uploaded asset, e.g. to re-run OCR on part of an image.
+    pub async fn fetch_file(&self, url: &str) -> Result<Vec<u8>, String> {
+        let response = reqwest::get(url)
+            .await
+            .map_err(|e| format!("Failed to fetch file: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("Failed to fetch file: status {}", response.status()));
+        }
+        response
+            .bytes()
+            .await
+            .map(|b| b.to_vec())
+            .map_err(|e| format!("Failed to read file body: {}", e))
+    }
+
+    /// Downloads a file uploaded with `upload_raw_authenticated` through a
+    /// signed delivery URL.
+    pub async fn fetch_authenticated_raw(&self, public_id: &str) -> Result<Vec<u8>, String> {
+        self.fetch_file(&self.authenticated_raw_url(public_id)).await
+    }
+
+    /// Delivery URL of an authenticated raw asset: its path signed with the
+    /// first eight characters of the URL-safe Base64 SHA-1 of the path and the API secret.
+    fn authenticated_raw_url(&self, public_id: &str) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{}{}", public_id, self.config.cloudinary_api_secret));
+        let digest = general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize());
+        format!(
+            "https://res.cloudinary.com/{}/raw/{}/s--{}--/{}",
+            self.config.cloudinary_cloud_name,
+            AUTHENTICATED_DELIVERY,
+            &digest[..8],
+            public_id
+        )
+    }
+
+    /// Cloudinary request signature: SHA-1 of the sorted parameters followed by the API secret.
+    fn sign(&self, params: &[(&str, &str)]) -> String {
+        let mut pairs: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        pairs.sort();
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{}{}", pairs.join("&"), self.config.cloudinary_api_secret));
+        hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
     pub async fn delete_image(&self, public_id: &str) -> Result<(), String> {
         match self.upload.destroy(public_id).await {
             Ok(_) => Ok(()),
@@ -0,0 +1,126 @@
+/// A run of the aligned transcriptions: either text both providers agree on,
+/// or a span where they differ along with each provider's candidate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlignedSegment {
+    Agreed(String),
+    Conflict { primary: String, secondary: String },
+}
+
+/// Word-level alignment of two transcriptions.
+pub struct ConsensusAligner;
+
+impl ConsensusAligner {
+    /// Aligns `primary` and `secondary` with a longest-common-subsequence over
+    /// whitespace separated tokens. Tokens keep their trailing whitespace, so
+    /// concatenating the primary side of every segment reproduces `primary`.
+    pub fn align(primary: &str, secondary: &str) -> Vec<AlignedSegment> {
+        let a = tokenize(primary);
+        let b = tokenize(secondary);
+
+        // lcs[i][j] = length of the LCS of a[i..] and b[j..]
+        let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i].word == b[j].word {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+
+        let mut segments: Vec<AlignedSegment> = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        let mut agreed = String::new();
+        let mut conflict_a = String::new();
+        let mut conflict_b = String::new();
+
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i].word == b[j].word {
+                flush_conflict(&mut segments, &mut conflict_a, &mut conflict_b);
+                agreed.push_str(a[i].raw);
+                i += 1;
+                j += 1;
+            } else {
+                if !agreed.is_empty() {
+                    segments.push(AlignedSegment::Agreed(std::mem::take(&mut agreed)));
+                }
+                if j >= b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                    conflict_a.push_str(a[i].raw);
+                    i += 1;
+                } else {
+                    conflict_b.push_str(b[j].raw);
+                    j += 1;
+                }
+            }
+        }
+
+        flush_conflict(&mut segments, &mut conflict_a, &mut conflict_b);
+        if !agreed.is_empty() {
+            segments.push(AlignedSegment::Agreed(agreed));
+        }
+        segments
+    }
+
+    /// Bytes in agreed segments over all bytes, from 0.0 to 1.0. A conflict
+    /// counts as the longer of its two readings; no text at all counts as 1.0.
+    pub fn agreement_ratio(segments: &[AlignedSegment]) -> f64 {
+        let (agreed, total) = segments.iter().fold((0usize, 0usize), |(agreed, total), segment| match segment {
+            AlignedSegment::Agreed(text) => (agreed + text.len(), total + text.len()),
+            AlignedSegment::Conflict { primary, secondary } => (agreed, total + primary.len().max(secondary.len())),
+        });
+        if total == 0 { 1.0 } else { agreed as f64 / total as f64 }
+    }
+}
+
+struct Token<'a> {
+    word: &'a str,
+    raw: &'a str,  // The word plus its trailing whitespace
+}
+
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let after_word = &rest[word_end..];
+        let ws_len = after_word.len() - after_word.trim_start().len();
+        tokens.push(Token { word: &rest[..word_end], raw: &rest[..word_end + ws_len] });
+        rest = &rest[word_end + ws_len..];
+    }
+    tokens
+}
+
+fn flush_conflict(segments: &mut Vec<AlignedSegment>, primary: &mut String, secondary: &mut String) {
+    if !primary.is_empty() || !secondary.is_empty() {
+        segments.push(AlignedSegment::Conflict {
+            primary: std::mem::take(primary),
+            secondary: std::mem::take(secondary),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_align_marks_conflicting_words() {
+        let segments = ConsensusAligner::align("Let $x = 2$ be fixed.", "Let $x = 3$ be fixed.");
+        assert_eq!(segments, vec![
+            AlignedSegment::Agreed("Let $x = ".to_string()),
+            AlignedSegment::Conflict { primary: "2$ ".to_string(), secondary: "3$ ".to_string() },
+            AlignedSegment::Agreed("be fixed.".to_string()),
+        ]);
+
+        // 18 agreed bytes against a 3-byte conflict.
+        assert_eq!(ConsensusAligner::agreement_ratio(&segments), 18.0 / 21.0);
+    }
+
+    #[test]
+    fn test_identical_transcriptions_fully_agree() {
+        let segments = ConsensusAligner::align("same text", "same  text");
+        assert_eq!(segments, vec![AlignedSegment::Agreed("same text".to_string())]);
+        assert_eq!(ConsensusAligner::agreement_ratio(&segments), 1.0);
+    }
+}
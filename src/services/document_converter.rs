@@ -1,8 +1,25 @@
-use std::process::Command;
+use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::io::Cursor;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use image::ImageFormat;
+use once_cell::sync::Lazy;
+use tokio::process::Command;
+use tokio::sync::{Semaphore, SemaphorePermit};
 use uuid::Uuid;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::config::environment::Config;
+use crate::services::export_capabilities::{format_renderer, tool_available, BUILTIN_RENDERER};
+use crate::services::latex_math::{escape_xml, extract_math_segments, MathKind};
+use crate::services::latex_log::{parse_latex_log, LatexError};
+use crate::services::latex_sanitizer::{describe_violations, find_blocked_class_constructs, find_blocked_constructs, LatexViolation};
+use crate::services::math_speech;
+use crate::services::office_watermark::watermark_package;
+use crate::services::native_renderer::{display_equations, render_html_fragment, render_native, NativeFormat};
 
 #[derive(Debug)]
 pub struct ConversionResult {
@@ -11,206 +28,1701 @@ pub struct ConversionResult {
     pub mime_type: String,
 }
 
-pub struct DocumentConverter;
+/// Why an export could not be rendered.
+#[derive(Debug)]
+pub enum RenderError {
+    /// Every export slot is taken and the queue is full or the wait ran out.
+    Busy { retry_after_secs: u64 },
+    /// pandoc (or the LaTeX engine it started) ran past the wall-clock limit and was killed.
+    TimedOut { limit_secs: u64 },
+    /// The document or its preamble template uses constructs the sanitiser blocks.
+    Rejected(Vec<LatexViolation>),
+    /// Every PDF engine failed and the TeX log located errors in the document.
+    Compile(Vec<LatexError>),
+    /// The programs the format needs were not found by the startup probe.
+    Unavailable(&'static str),
+    Failed(String),
+}
 
-impl DocumentConverter {
-    /// Converts a string of LaTeX into a Word document (.docx) byte vector.
-    /// This function requires `pandoc` to be installed and available in the system's PATH.
-    pub fn latex_to_docx(latex_content: &str) -> Result<ConversionResult, Box<dyn Error>> {
-        // Generate unique filenames in the system's temp directory to avoid conflicts.
-        let unique_id = Uuid::new_v4().to_string();
-        let temp_dir = std::env::temp_dir();
-        let tex_path = temp_dir.join(format!("{}.tex", unique_id));
-        let docx_path = temp_dir.join(format!("{}.docx", unique_id));
-
-        // 1. Write LaTeX content to a temporary .tex file.
-        let mut file = fs::File::create(&tex_path)?;
-        let full_latex_doc = format!(
-            "\\documentclass{{article}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n\\begin{{document}}\n{}\n\\end{{document}}",
-            latex_content
-        );
-        file.write_all(full_latex_doc.as_bytes())?;
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Busy { retry_after_secs } => write!(f, "export queue is full, retry in {}s", retry_after_secs),
+            RenderError::TimedOut { limit_secs } => write!(f, "export exceeded the {}s time limit", limit_secs),
+            RenderError::Rejected(violations) => write!(f, "blocked LaTeX: {}", describe_violations(violations)),
+            RenderError::Compile(errors) => write!(f, "LaTeX compilation failed with {} error(s)", errors.len()),
+            RenderError::Unavailable(format) => write!(f, "{} export is not available on this server", format),
+            RenderError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
 
-        // 2. Execute the pandoc command-line tool.
-        let output = Command::new("pandoc")
-            .arg(tex_path.to_str().unwrap())
-            .arg("-o")
-            .arg(docx_path.to_str().unwrap())
-            .output()?;
+impl Error for RenderError {}
 
-        // Ensure temporary files are cleaned up regardless of pandoc's success.
-        let _ = fs::remove_file(&tex_path);
+impl From<std::io::Error> for RenderError {
+    fn from(e: std::io::Error) -> Self {
+        RenderError::Failed(e.to_string())
+    }
+}
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("Pandoc execution failed: {}", stderr).into());
+/// Process-wide bounds on pandoc/xelatex jobs, read once from the environment.
+struct ExportLimits {
+    slots: Semaphore,
+    queued: AtomicUsize,
+    max_queued: usize,
+    queue_wait: Duration,
+    timeout: Duration,
+    memory_limit_mb: u64,
+}
+
+static EXPORT_LIMITS: Lazy<ExportLimits> = Lazy::new(|| {
+    let config = Config::new();
+    ExportLimits {
+        slots: Semaphore::new(config.export_max_concurrency.max(1)),
+        queued: AtomicUsize::new(0),
+        max_queued: config.export_max_queued,
+        queue_wait: Duration::from_secs(config.export_queue_wait_secs),
+        timeout: Duration::from_secs(config.export_timeout_secs.max(1)),
+        memory_limit_mb: config.export_memory_limit_mb,
+    }
+});
+
+impl ExportLimits {
+    /// Takes a free export slot, waiting in the bounded queue when all are busy.
+    async fn acquire(&self) -> Result<SemaphorePermit<'_>, RenderError> {
+        if let Ok(permit) = self.slots.try_acquire() {
+            return Ok(permit);
         }
 
-        // 3. Read the generated .docx file into bytes.
-        let docx_bytes = fs::read(&docx_path)?;
-        let file_size = docx_bytes.len() as u64;
+        let busy = RenderError::Busy { retry_after_secs: self.queue_wait.as_secs().max(1) };
+        let _ticket = match QueueTicket::take(&self.queued, self.max_queued) {
+            Some(ticket) => ticket,
+            None => return Err(busy),
+        };
+        match tokio::time::timeout(self.queue_wait, self.slots.acquire()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(busy),
+        }
+    }
+}
 
-        // 4. Clean up the final temporary file.
-        let _ = fs::remove_file(&docx_path);
+/// A place in the export queue, given back on drop so cancelled requests free it too.
+struct QueueTicket<'a>(&'a AtomicUsize);
 
-        Ok(ConversionResult {
-            content: docx_bytes,
-            size: file_size,
-            mime_type: "application/vnd.openxmlformats-officedocument.wordprocessingml.document".to_string(),
-        })
+impl<'a> QueueTicket<'a> {
+    fn take(queued: &'a AtomicUsize, max_queued: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max_queued).then_some(n + 1))
+            .ok()
+            .map(|_| QueueTicket(queued))
     }
+}
 
-    /// Converts a string of LaTeX into an ODT document (.odt) byte vector.
-    /// This function requires `pandoc` to be installed and available in the system's PATH.
-    pub fn latex_to_odt(latex_content: &str) -> Result<ConversionResult, Box<dyn Error>> {
-        // Generate unique filenames in the system's temp directory to avoid conflicts.
-        let unique_id = Uuid::new_v4().to_string();
-        let temp_dir = std::env::temp_dir();
-        let tex_path = temp_dir.join(format!("{}.tex", unique_id));
-        let odt_path = temp_dir.join(format!("{}.odt", unique_id));
-
-        // 1. Write LaTeX content to a temporary .tex file.
-        let mut file = fs::File::create(&tex_path)?;
-        let full_latex_doc = format!(
-            "\\documentclass{{article}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n\\begin{{document}}\n{}\n\\end{{document}}",
-            latex_content
-        );
-        file.write_all(full_latex_doc.as_bytes())?;
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-        // 2. Execute the pandoc command-line tool.
-        let output = Command::new("pandoc")
-            .arg(tex_path.to_str().unwrap())
-            .arg("-o")
-            .arg(odt_path.to_str().unwrap())
-            .output()?;
+/// Kills pandoc's whole process group on drop, so a timed out or cancelled export
+/// also takes down the xelatex run pandoc started.
+struct ProcessGroupGuard(Option<u32>);
 
-        // Ensure temporary files are cleaned up regardless of pandoc's success.
-        let _ = fs::remove_file(&tex_path);
+impl ProcessGroupGuard {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("Pandoc execution failed: {}", stderr).into());
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            // SAFETY: kill(2) with a negative pid signals the group pandoc leads; it touches no memory.
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
         }
+    }
+}
 
-        // 3. Read the generated .odt file into bytes.
-        let odt_bytes = fs::read(&odt_path)?;
-        let file_size = odt_bytes.len() as u64;
+/// A per-job working directory, removed on drop whether the export finished,
+/// failed or was cancelled.
+struct JobDir(PathBuf);
 
-        // 4. Clean up the final temporary file.
-        let _ = fs::remove_file(&odt_path);
+impl JobDir {
+    /// Creates a fresh directory in the system temp dir, which is also the
+    /// working directory of every process the job runs.
+    fn create() -> Result<Self, RenderError> {
+        let job_dir = JobDir(std::env::temp_dir().join(format!("export-{}", Uuid::new_v4())));
+        fs::create_dir_all(&job_dir.0)?;
+        Ok(job_dir)
+    }
+}
+
+impl Drop for JobDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Every export format, with its route name, file extension, MIME type and pandoc writer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetFormat {
+    Docx,
+    Odt,
+    Pdf,
+    Pdfa,  // PDF/A-2b for archiving, validated before it is returned
+    Html,
+    Markdown,
+    Tex,
+    Epub,
+    Rtf,
+    Txt,
+    Ipynb,
+}
+
+impl TargetFormat {
+    pub const ALL: [TargetFormat; 11] = [
+        TargetFormat::Docx,
+        TargetFormat::Odt,
+        TargetFormat::Pdf,
+        TargetFormat::Pdfa,
+        TargetFormat::Html,
+        TargetFormat::Markdown,
+        TargetFormat::Tex,
+        TargetFormat::Epub,
+        TargetFormat::Rtf,
+        TargetFormat::Txt,
+        TargetFormat::Ipynb,
+    ];
+
+    /// Looks a format up by name or extension; `word` is kept for the old download route.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "docx" | "word" => Some(TargetFormat::Docx),
+            "odt" => Some(TargetFormat::Odt),
+            "pdf" => Some(TargetFormat::Pdf),
+            "pdfa" | "pdf-a" | "pdf/a" => Some(TargetFormat::Pdfa),
+            "html" => Some(TargetFormat::Html),
+            "md" | "markdown" => Some(TargetFormat::Markdown),
+            "tex" | "latex" => Some(TargetFormat::Tex),
+            "epub" => Some(TargetFormat::Epub),
+            "rtf" => Some(TargetFormat::Rtf),
+            "txt" | "text" => Some(TargetFormat::Txt),
+            "ipynb" | "jupyter" => Some(TargetFormat::Ipynb),
+            _ => None,
+        }
+    }
+
+    /// Comma separated list of format names, for error messages.
+    pub fn supported_names() -> String {
+        Self::ALL.iter().map(|f| f.name()).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The format's route name; the same as its extension except for PDF/A.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TargetFormat::Pdfa => "pdfa",
+            _ => self.extension(),
+        }
+    }
+
+    /// Filename for a document named `stem` in this format. PDF/A files get a
+    /// `-pdfa` suffix so they can sit next to a plain PDF of the same document.
+    pub fn file_name(&self, stem: &str) -> String {
+        match self {
+            TargetFormat::Pdfa => format!("{}-pdfa.pdf", stem),
+            _ => format!("{}.{}", stem, self.extension()),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            TargetFormat::Docx => "docx",
+            TargetFormat::Odt => "odt",
+            TargetFormat::Pdf | TargetFormat::Pdfa => "pdf",
+            TargetFormat::Html => "html",
+            TargetFormat::Markdown => "md",
+            TargetFormat::Tex => "tex",
+            TargetFormat::Epub => "epub",
+            TargetFormat::Rtf => "rtf",
+            TargetFormat::Txt => "txt",
+            TargetFormat::Ipynb => "ipynb",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            TargetFormat::Docx => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+            TargetFormat::Odt => "application/vnd.oasis.opendocument.text",
+            TargetFormat::Pdf | TargetFormat::Pdfa => "application/pdf",
+            TargetFormat::Html => "text/html",
+            TargetFormat::Markdown => "text/markdown",
+            TargetFormat::Tex => "application/x-tex",
+            TargetFormat::Epub => "application/epub+zip",
+            TargetFormat::Rtf => "application/rtf",
+            TargetFormat::Txt => "text/plain",
+            TargetFormat::Ipynb => "application/x-ipynb+json",
+        }
+    }
+
+    /// Formats that carry the plan watermark.
+    pub fn supports_watermark(&self) -> bool {
+        matches!(self, TargetFormat::Pdf | TargetFormat::Pdfa | TargetFormat::Docx | TargetFormat::Odt)
+    }
+
+    /// Formats that can show original images next to their transcription.
+    pub fn supports_review(&self) -> bool {
+        matches!(self, TargetFormat::Pdf | TargetFormat::Pdfa | TargetFormat::Docx)
+    }
+
+    /// Writer arguments passed to pandoc after the input and output paths.
+    fn pandoc_args(&self) -> Vec<&'static str> {
+        match self {
+            TargetFormat::Docx => vec!["-t", "docx"],
+            TargetFormat::Odt => vec!["-t", "odt"],
+            // Last resort of the PDF engine chain; xelatex and pdflatex are run directly.
+            TargetFormat::Pdf => vec!["-t", "html5", "--mathml", "--pdf-engine=weasyprint"],
+            TargetFormat::Pdfa => vec!["-t", "html5", "--mathml", "--pdf-engine=weasyprint", "--pdf-engine-opt=--pdf-variant=pdf/a-2b"],
+            TargetFormat::Html => vec![
+                "-s",  // Standalone HTML with header
+                "-t", "html5",
+                "--mathjax",  // Use MathJax for equations (better browser support than MathML)
+                "--highlight-style=tango",
+                "--variable=colorlinks:true",
+            ],
+            // `$...$` and `$$...$$` math, which KaTeX auto-render understands.
+            TargetFormat::Markdown => vec!["-t", "markdown+tex_math_dollars-raw_tex", "--wrap=none"],
+            TargetFormat::Epub => vec!["-t", "epub3", "--mathml"],
+            TargetFormat::Rtf => vec!["-s", "-t", "rtf"],
+            TargetFormat::Ipynb => vec!["-t", "ipynb"],
+            TargetFormat::Tex | TargetFormat::Txt => Vec::new(),  // Rendered in-process
+        }
+    }
+}
+
+/// Per-request settings shared by every format.
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    pub title: Option<String>,  // Document title metadata (HTML <title>, EPUB title page, PDF properties)
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub layout: DocumentLayout,
+    pub templates: TemplateFiles,
+    pub watermark: Option<Watermark>,  // Set from the user's plan, see `Watermark::for_plan`
+    pub archival: bool,                // PDF/A-2b output; set by `run_export` for `TargetFormat::Pdfa`
+}
+
+/// Page layout and typography. PDF and `.tex` output get all of it; pandoc
+/// formats only get the table of contents and equation numbers.
+#[derive(Debug, Clone, Default)]
+pub struct DocumentLayout {
+    pub paper_size: Option<PaperSize>,
+    pub margin: Option<String>,      // TeX length checked by `parse_margin`
+    pub font: Option<DocumentFont>,
+    pub font_size_pt: Option<u8>,    // One of FONT_SIZES_PT
+    pub two_columns: bool,
+    pub line_spacing: Option<f32>,   // One of LINE_SPACINGS
+    pub number_equations: bool,
+    pub table_of_contents: bool,
+    pub original_image: Option<ImagePlacement>,  // Review mode, see `render_review`
+}
+
+/// Where review exports put the original image relative to its transcription.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImagePlacement {
+    Beside,
+    Above,
+}
+
+impl ImagePlacement {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "beside" | "side_by_side" => Some(ImagePlacement::Beside),
+            "above" => Some(ImagePlacement::Above),
+            _ => None,
+        }
+    }
+}
+
+/// Branding text put on PDF, DOCX and ODT exports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watermark {
+    pub text: String,
+    pub position: WatermarkPosition,
+}
+
+/// Where the watermark goes on every page: in the footer or header, or large
+/// and pale diagonally across the page behind the text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatermarkPosition {
+    Footer,
+    Header,
+    Diagonal,
+}
+
+impl WatermarkPosition {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "footer" | "bottom" => Some(WatermarkPosition::Footer),
+            "header" | "top" => Some(WatermarkPosition::Header),
+            "diagonal" => Some(WatermarkPosition::Diagonal),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            WatermarkPosition::Footer => "footer",
+            WatermarkPosition::Header => "header",
+            WatermarkPosition::Diagonal => "diagonal",
+        }
+    }
+}
+
+/// The configured watermark and the plans it applies to, read once from the environment.
+struct PlanWatermark {
+    plans: Vec<String>,
+    watermark: Option<Watermark>,
+}
+
+static PLAN_WATERMARK: Lazy<PlanWatermark> = Lazy::new(|| {
+    let config = Config::new();
+    let text = config.export_watermark_text.trim().to_string();
+    let position = WatermarkPosition::from_name(&config.export_watermark_position).unwrap_or_else(|| {
+        println!("WARNING: Unknown EXPORT_WATERMARK_POSITION '{}', using footer", config.export_watermark_position);
+        WatermarkPosition::Footer
+    });
+    PlanWatermark {
+        plans: config.export_watermark_plans,
+        watermark: (!text.is_empty()).then_some(Watermark { text, position }),
+    }
+});
+
+impl Watermark {
+    /// The watermark for exports made on `plan` (the plan's name, e.g. `Free`), or
+    /// None when the plan is not listed in `EXPORT_WATERMARK_PLANS`.
+    pub fn for_plan(plan: &str) -> Option<Watermark> {
+        let settings = &*PLAN_WATERMARK;
+        if settings.plans.iter().any(|p| p.eq_ignore_ascii_case(plan)) {
+            settings.watermark.clone()
+        } else {
+            None
+        }
+    }
+
+    /// Preamble lines drawing the text on every page when it is shipped out.
+    /// Coordinates start at the top left corner of the page.
+    fn latex_setup(&self) -> String {
+        let text = escape_latex_text(&self.text);
+        let mark = match self.position {
+            WatermarkPosition::Footer => format!(
+                "\\put(\\dimexpr0.5\\paperwidth\\relax,\\dimexpr-\\paperheight+0.6cm\\relax){{\\makebox[0pt]{{\\footnotesize\\color{{gray}}{}}}}}",
+                text
+            ),
+            WatermarkPosition::Header => format!(
+                "\\put(\\dimexpr0.5\\paperwidth\\relax,\\dimexpr-0.9cm\\relax){{\\makebox[0pt]{{\\footnotesize\\color{{gray}}{}}}}}",
+                text
+            ),
+            WatermarkPosition::Diagonal => format!(
+                "\\put(\\dimexpr0.5\\paperwidth\\relax,\\dimexpr-0.5\\paperheight\\relax){{\\makebox(0,0){{\\rotatebox{{45}}{{\\fontsize{{40}}{{48}}\\selectfont\\color{{black!10}}{}}}}}}}",
+                text
+            ),
+        };
+        format!("\\usepackage{{graphicx}}\n\\usepackage{{xcolor}}\n\\AddToHook{{shipout/foreground}}{{{}}}\n", mark)
+    }
+}
+
+/// One page of a review export: an original image and its transcription.
+pub struct ReviewPage {
+    pub heading: Option<String>,  // Plain text, e.g. the uploaded filename
+    pub content: String,
+    pub image: Option<Vec<u8>>,   // Stored image bytes; any format the `image` crate reads
+}
+
+pub const FONT_SIZES_PT: [u8; 3] = [10, 11, 12];
+pub const LINE_SPACINGS: [f32; 4] = [1.0, 1.15, 1.5, 2.0];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaperSize {
+    A4,
+    A5,
+    Letter,
+    Legal,
+}
+
+impl PaperSize {
+    pub const ALL: [PaperSize; 4] = [PaperSize::A4, PaperSize::A5, PaperSize::Letter, PaperSize::Legal];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PaperSize::A4 => "a4",
+            PaperSize::A5 => "a5",
+            PaperSize::Letter => "letter",
+            PaperSize::Legal => "legal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "a4" => Some(PaperSize::A4),
+            "a5" => Some(PaperSize::A5),
+            "letter" => Some(PaperSize::Letter),
+            "legal" => Some(PaperSize::Legal),
+            _ => None,
+        }
+    }
+
+    fn latex_option(&self) -> &'static str {
+        match self {
+            PaperSize::A4 => "a4paper",
+            PaperSize::A5 => "a5paper",
+            PaperSize::Letter => "letterpaper",
+            PaperSize::Legal => "legalpaper",
+        }
+    }
+}
+
+/// Base fonts, all shipped with TeX Live so both XeLaTeX and pdfLaTeX find them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocumentFont {
+    LatinModern,
+    Termes,     // Times
+    Pagella,    // Palatino
+    Heros,      // Helvetica
+    Schola,     // New Century Schoolbook
+}
+
+impl DocumentFont {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "latin_modern" | "lmodern" => Some(DocumentFont::LatinModern),
+            "times" | "termes" => Some(DocumentFont::Termes),
+            "palatino" | "pagella" => Some(DocumentFont::Pagella),
+            "helvetica" | "heros" => Some(DocumentFont::Heros),
+            "schoolbook" | "schola" => Some(DocumentFont::Schola),
+            _ => None,
+        }
+    }
+
+    /// Preamble line selecting the font under either engine.
+    fn latex_setup(&self) -> String {
+        let (family, package, sans) = match self {
+            DocumentFont::LatinModern => ("Latin Modern Roman", "lmodern", false),
+            DocumentFont::Termes => ("TeX Gyre Termes", "tgtermes", false),
+            DocumentFont::Pagella => ("TeX Gyre Pagella", "tgpagella", false),
+            DocumentFont::Heros => ("TeX Gyre Heros", "tgheros", true),
+            DocumentFont::Schola => ("TeX Gyre Schola", "tgschola", false),
+        };
+        let pdftex_default = if sans { "\\renewcommand{\\familydefault}{\\sfdefault}" } else { "" };
+        format!(
+            "\\usepackage{{iftex}}\n\\ifXeTeX\\usepackage{{fontspec}}\\setmainfont{{{}}}\\else\\usepackage{{{}}}{}\\fi\n",
+            family, package, pdftex_default
+        )
+    }
+}
+
+/// Accepts a margin such as `2cm`, `20mm`, `0.75in` or `54pt` between 0.5cm
+/// and 5cm, returning it normalised for `geometry`.
+pub fn parse_margin(margin: &str) -> Option<String> {
+    let margin = margin.trim().to_lowercase();
+    let split = margin.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (value, unit) = margin.split_at(split);
+    let value: f64 = value.parse().ok()?;
+    let cm = match unit {
+        "mm" => value / 10.0,
+        "cm" => value,
+        "in" => value * 2.54,
+        "pt" => value * 2.54 / 72.27,
+        _ => return None,
+    };
+    (0.5..=5.0).contains(&cm).then(|| format!("{}{}", value, unit))
+}
+
+/// House-style files applied on top of the built-in document setup.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateFiles {
+    pub reference_docx: Option<Vec<u8>>,  // Styles for DOCX output
+    pub reference_odt: Option<Vec<u8>>,   // Styles for ODT output
+    pub latex_preamble: Option<String>,   // Added after the built-in packages
+    pub latex_class: Option<LatexClass>,  // Replaces `article` for PDF and .tex output
+}
+
+#[derive(Debug, Clone)]
+pub struct LatexClass {
+    pub name: String,
+    pub source: Vec<u8>,
+}
+
+/// One conversion's text within a merged export, in output order.
+pub struct MergeSection {
+    pub heading: String,
+    pub content: String,
+}
+
+/// Pandoc Lua filter that turns LaTeX page breaks into native DOCX/ODT page breaks.
+/// Without it pandoc silently drops `\newpage` when writing word processor formats.
+const PAGEBREAK_LUA_FILTER: &str = r#"
+local function is_pagebreak(text)
+  return text:match("^%s*\\newpage%s*$") or text:match("^%s*\\clearpage%s*$")
+end
+
+function RawBlock(el)
+  if (el.format == "latex" or el.format == "tex") and is_pagebreak(el.text) then
+    if FORMAT == "docx" then
+      return pandoc.RawBlock("openxml", '<w:p><w:r><w:br w:type="page"/></w:r></w:p>')
+    elseif FORMAT == "odt" then
+      return pandoc.RawBlock("opendocument", '<text:p text:style-name="Pagebreak"/>')
+    end
+  end
+end
+"#;
+
+/// Pandoc Lua filter body that attaches spoken alt text to math. The `ALT_TEXT`
+/// table mapping trimmed LaTeX to speech is generated per document and prepended.
+/// HTML and EPUB get an ARIA label on the math span; in DOCX, where equations cannot
+/// carry alt text, the equation is wrapped in a content control titled with the speech.
+const MATH_ALT_LUA_FILTER: &str = r#"
+function Math(el)
+  local alt = ALT_TEXT[el.text:match("^%s*(.-)%s*$")]
+  if not alt then
+    return nil
+  end
+  if FORMAT:match("html") or FORMAT:match("epub") then
+    return pandoc.Span({el}, {role = "math", ["aria-label"] = alt})
+  elseif FORMAT == "docx" then
+    local open = DOCX_ALT_OPEN[el.text:match("^%s*(.-)%s*$")]
+    return {pandoc.RawInline("openxml", open), el, pandoc.RawInline("openxml", DOCX_ALT_CLOSE)}
+  end
+end
+"#;
+
+/// Pandoc Lua filter that numbers display equations, for formats without
+/// LaTeX's `equation` numbering. Runs after the alt text filter so the ARIA
+/// labels are looked up by the original LaTeX.
+const EQUATION_NUMBER_LUA_FILTER: &str = r#"
+local count = 0
+
+function Math(el)
+  if el.mathtype == "DisplayMath" then
+    count = count + 1
+    el.text = el.text .. " \\qquad (" .. count .. ")"
+    return el
+  end
+end
+"#;
+
+/// Wraps display math in the `<span class="equation" id="eq-N">` anchors the
+/// in-process renderer writes, counting from the `first-equation` metadata.
+const EQUATION_ANCHOR_LUA_FILTER: &str = r#"
+function Pandoc(doc)
+  local number = tonumber(pandoc.utils.stringify(doc.meta["first-equation"] or "1")) or 1
+  return doc:walk {
+    Math = function(el)
+      if el.mathtype == "DisplayMath" then
+        local span = pandoc.Span({el}, {id = "eq-" .. number, class = "equation"})
+        number = number + 1
+        return span
+      end
+    end
+  }
+end
+"#;
+
+/// Adds the watermark passed as `watermark` / `watermark-position` metadata to
+/// the HTML that WeasyPrint turns into a PDF: a page margin box, or a fixed
+/// overlay repeated on every page for `diagonal`. DOCX and ODT get theirs from
+/// `office_watermark` after pandoc has written them.
+const WATERMARK_LUA_FILTER: &str = r#"
+local function xml_escape(s)
+  return (s:gsub('&', '&amp;'):gsub('<', '&lt;'):gsub('>', '&gt;'):gsub('"', '&quot;'))
+end
+
+local function css_string(s)
+  return '"' .. s:gsub('\\', '\\\\'):gsub('"', '\\"'):gsub('<', '\\3c ') .. '"'
+end
+
+local function watermark_block(text, position)
+  if position == 'diagonal' then
+    return pandoc.RawBlock('html', '<div style="position: fixed; top: 45%; left: 0; width: 100%; text-align: center; transform: rotate(-45deg); font-size: 40pt; color: rgba(0, 0, 0, 0.08)">' .. xml_escape(text) .. '</div>')
+  end
+  local margin = position == 'header' and 'top-center' or 'bottom-center'
+  return pandoc.RawBlock('html', '<style>@page { @' .. margin .. ' { content: ' .. css_string(text) .. '; font-size: 8pt; color: #999 } }</style>')
+end
+
+function Pandoc(doc)
+  local text = doc.meta.watermark and pandoc.utils.stringify(doc.meta.watermark) or ''
+  if text == '' or not FORMAT:match('html') then
+    return nil
+  end
+  local position = doc.meta['watermark-position'] and pandoc.utils.stringify(doc.meta['watermark-position']) or 'footer'
+  doc.meta.watermark = nil
+  doc.meta['watermark-position'] = nil
+
+  table.insert(doc.blocks, 1, watermark_block(text, position))
+  return doc
+end
+"#;
+
+pub struct DocumentConverter;
+
+impl DocumentConverter {
+    /// Joins several conversions into one LaTeX body, optionally headed by each
+    /// section's heading and separated by page breaks. A title for the merged
+    /// document goes in `RenderOptions::title`, which typesets it once.
+    pub fn merge_sections(sections: &[MergeSection], page_breaks: bool, section_headings: bool) -> String {
+        let mut merged = String::new();
+        for (index, section) in sections.iter().enumerate() {
+            if index > 0 {
+                merged.push_str(if page_breaks { "\n\n\\newpage\n\n" } else { "\n\n" });
+            }
+            if section_headings {
+                merged.push_str(&format!("\\section*{{{}}}\n\n", escape_latex_text(&section.heading)));
+            }
+            merged.push_str(section.content.trim());
+        }
+        merged
+    }
+
+    /// Renders LaTeX content into the requested format. Plain text and
+    /// standalone `.tex` are produced in-process, as are HTML and Markdown when
+    /// pandoc is not installed; PDF goes through the engine chain and every
+    /// other format through `pandoc`. Formats whose programs the startup probe
+    /// did not find fail with `Unavailable`. External runs are bounded by the
+    /// `EXPORT_*` concurrency, time and memory limits.
+    pub async fn render(latex_content: &str, format: TargetFormat, options: &RenderOptions) -> Result<ConversionResult, RenderError> {
+        let renderer = format_renderer(format).ok_or(RenderError::Unavailable(format.name()))?;
+        let builtin = renderer == BUILTIN_RENDERER;
+        let content = match format {
+            // The `.tex` source is the user's own text and is never watermarked.
+            TargetFormat::Tex => latex_document(&escape_stray_specials(latex_content), &RenderOptions { watermark: None, ..options.clone() }, true).into_bytes(),
+            TargetFormat::Txt => render_native(latex_content, NativeFormat::Text, None).into_bytes(),
+            TargetFormat::Html if builtin => render_native(latex_content, NativeFormat::Html, options.title.as_deref()).into_bytes(),
+            TargetFormat::Markdown if builtin => render_native(latex_content, NativeFormat::Markdown, None).into_bytes(),
+            _ => {
+                check_latex(&[latex_content], options)?;
+                run_export(latex_content, &[], format, options).await?
+            }
+        };
 
         Ok(ConversionResult {
-            content: odt_bytes,
-            size: file_size,
-            mime_type: "application/vnd.oasis.opendocument.text".to_string(),
+            size: content.len() as u64,
+            content,
+            mime_type: format.mime_type().to_string(),
         })
     }
 
-    /// Converts a string of LaTeX into a PDF document (.pdf) byte vector.
-    /// This function requires `pandoc` to be installed and available in the system's PATH.
-    pub fn latex_to_pdf(latex_content: &str) -> Result<ConversionResult, Box<dyn Error>> {
-        // Generate unique filenames in the system's temp directory to avoid conflicts.
-        let unique_id = Uuid::new_v4().to_string();
-        let temp_dir = std::env::temp_dir();
-        let tex_path = temp_dir.join(format!("{}.tex", unique_id));
-        let pdf_path = temp_dir.join(format!("{}.pdf", unique_id));
-
-        // 1. Write LaTeX content to a temporary .tex file.
-        let mut file = fs::File::create(&tex_path)?;
-        let full_latex_doc = format!(
-            "\\documentclass{{article}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n\\begin{{document}}\n{}\n\\end{{document}}",
-            latex_content
-        );
-        file.write_all(full_latex_doc.as_bytes())?;
+    /// Renders a review document: one page per `ReviewPage`, with the original
+    /// image beside or above its transcription as `options.layout.original_image`
+    /// says. Only formats where `supports_review` holds are accepted.
+    pub async fn render_review(pages: &[ReviewPage], format: TargetFormat, options: &RenderOptions) -> Result<ConversionResult, RenderError> {
+        if !format.supports_review() {
+            return Err(RenderError::Failed(format!("{} cannot show original images", format.extension())));
+        }
+        if format_renderer(format).is_none() {
+            return Err(RenderError::Unavailable(format.name()));
+        }
+        let texts: Vec<&str> = pages.iter().map(|page| page.content.as_str()).collect();
+        check_latex(&texts, options)?;
+
+        let placement = options.layout.original_image.unwrap_or(ImagePlacement::Beside);
+        let (latex_content, attachments) = review_document(pages, placement, format);
+        let content = run_export(&latex_content, &attachments, format, options).await?;
+        Ok(ConversionResult {
+            size: content.len() as u64,
+            content,
+            mime_type: format.mime_type().to_string(),
+        })
+    }
+
+    /// Standalone HTML for the editor preview of a LaTeX document.
+    pub async fn latex_to_html_preview(latex_content: &str) -> Result<String, RenderError> {
+        let result = Self::render(latex_content, TargetFormat::Html, &RenderOptions::default()).await?;
+        Ok(String::from_utf8_lossy(&result.content).into_owned())
+    }
 
-        // 2. Execute the pandoc command-line tool with PDF output.
-        let output = Command::new("pandoc")
-            .arg(tex_path.to_str().unwrap())
-            .arg("-o")
-            .arg(pdf_path.to_str().unwrap())
-            .arg("--pdf-engine=xelatex")  // Use xelatex for better Unicode support
-            .output()?;
+    /// HTML fragment of one section of a LaTeX document, rendered the way
+    /// `latex_to_html_preview` renders the whole document. Display equations are
+    /// wrapped in `<span class="equation" id="eq-N">`, numbered from `first_equation`;
+    /// their LaTeX is returned alongside in the same order.
+    pub async fn latex_to_html_section(latex_content: &str, first_equation: usize) -> Result<(String, Vec<String>), RenderError> {
+        let renderer = format_renderer(TargetFormat::Html).ok_or(RenderError::Unavailable(TargetFormat::Html.name()))?;
+        if renderer == BUILTIN_RENDERER {
+            return Ok(render_html_fragment(latex_content, first_equation));
+        }
+        let options = RenderOptions::default();
+        check_latex(&[latex_content], &options)?;
 
-        // Ensure temporary files are cleaned up regardless of pandoc's success.
-        let _ = fs::remove_file(&tex_path);
+        let limits = &*EXPORT_LIMITS;
+        let _slot = limits.acquire().await?;
+        let job_dir = JobDir::create()?;
+        fs::write(job_dir.0.join("input.tex"), latex_document(latex_content, &options, false))?;
+        let anchor_filter = job_dir.0.join("equation-anchors.lua");
+        fs::write(&anchor_filter, EQUATION_ANCHOR_LUA_FILTER)?;
+        let filter_paths = [
+            write_pagebreak_filter(&job_dir.0)?,
+            write_math_alt_filter(&job_dir.0, latex_content)?,
+            anchor_filter,
+        ];
 
+        let mut command = Command::new("pandoc");
+        command
+            .arg("+RTS")
+            .arg(format!("-M{}m", limits.memory_limit_mb))
+            .arg("-RTS")
+            .args(["input.tex", "-f", "latex", "-o", "output.html"])
+            // A fragment: the preview's arguments without the standalone page around it
+            .args(TargetFormat::Html.pandoc_args().into_iter().filter(|arg| *arg != "-s"))
+            .arg(format!("--metadata=first-equation:{}", first_equation));
+        for filter_path in &filter_paths {
+            command.arg(format!("--lua-filter={}", filter_path.display()));
+        }
+        let output = run_bounded(command, &job_dir.0, limits).await?;
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("Pandoc execution failed: {}", stderr).into());
+            return Err(RenderError::Failed(format!("Pandoc execution failed: {}", stderr)));
         }
+        let html = tokio::fs::read(job_dir.0.join("output.html")).await?;
+        Ok((String::from_utf8_lossy(&html).into_owned(), display_equations(latex_content)))
+    }
 
-        // 3. Read the generated .pdf file into bytes.
-        let pdf_bytes = fs::read(&pdf_path)?;
-        let file_size = pdf_bytes.len() as u64;
+    /// Standalone HTML for the editor preview of Markdown with `$...$` math,
+    /// such as the Markdown export after editing. Needs pandoc.
+    pub async fn markdown_to_html_preview(markdown: &str) -> Result<String, RenderError> {
+        if !tool_available("pandoc") {
+            return Err(RenderError::Unavailable("markdown"));
+        }
+        check_latex(&[markdown], &RenderOptions::default())?;
 
-        // 4. Clean up the final temporary file.
-        let _ = fs::remove_file(&pdf_path);
+        let limits = &*EXPORT_LIMITS;
+        let _slot = limits.acquire().await?;
+        let job_dir = JobDir::create()?;
+        fs::write(job_dir.0.join("input.md"), markdown)?;
 
-        Ok(ConversionResult {
-            content: pdf_bytes,
-            size: file_size,
-            mime_type: "application/pdf".to_string(),
-        })
+        let mut command = Command::new("pandoc");
+        command
+            .arg("+RTS")
+            .arg(format!("-M{}m", limits.memory_limit_mb))
+            .arg("-RTS")
+            // Raw HTML in the Markdown would reach the preview page unescaped
+            .args(["input.md", "-f", "markdown+tex_math_dollars-raw_html", "-o", "output.html"])
+            .args(TargetFormat::Html.pandoc_args())
+            .arg("--metadata=pagetitle:Preview");
+        let output = run_bounded(command, &job_dir.0, limits).await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(RenderError::Failed(format!("Pandoc execution failed: {}", stderr)));
+        }
+        let html = tokio::fs::read(job_dir.0.join("output.html")).await?;
+        Ok(String::from_utf8_lossy(&html).into_owned())
+    }
+}
+
+/// Builds the LaTeX for a review export and the image files it references.
+/// PDF puts the two halves in minipages; DOCX uses a two-column table, which
+/// pandoc turns into a Word table, since it drops minipage layout.
+fn review_document(pages: &[ReviewPage], placement: ImagePlacement, format: TargetFormat) -> (String, Vec<(String, Vec<u8>)>) {
+    let mut latex = String::new();
+    let mut attachments = Vec::new();
+    for (index, page) in pages.iter().enumerate() {
+        if index > 0 {
+            latex.push_str("\n\n\\newpage\n\n");
+        }
+        if let Some(heading) = &page.heading {
+            latex.push_str(&format!("\\section*{{{}}}\n\n", escape_latex_text(heading)));
+        }
+
+        let height = if placement == ImagePlacement::Above { "0.45" } else { "0.8" };
+        let graphic = match page.image.as_deref().and_then(review_image) {
+            Some((extension, bytes)) => {
+                let filename = format!("original-{:03}.{}", index + 1, extension);
+                let graphic = format!("\\includegraphics[width=\\linewidth,height={}\\textheight,keepaspectratio]{{{}}}", height, filename);
+                attachments.push((filename, bytes));
+                graphic
+            }
+            None => "\\emph{Original image unavailable.}".to_string(),
+        };
+        let content = page.content.trim();
+
+        match (placement, format) {
+            (ImagePlacement::Above, _) => {
+                latex.push_str(&format!("\\begin{{center}}\n{}\n\\end{{center}}\n\n{}", graphic, content));
+            }
+            (ImagePlacement::Beside, TargetFormat::Docx) => {
+                latex.push_str(&format!(
+                    "\\begin{{tabular}}{{p{{0.48\\linewidth}}p{{0.48\\linewidth}}}}\n{} &\n{}\n\\end{{tabular}}",
+                    graphic, content
+                ));
+            }
+            (ImagePlacement::Beside, _) => {
+                latex.push_str(&format!(
+                    "\\noindent\\begin{{minipage}}[t]{{0.48\\linewidth}}\\vspace{{0pt}}\n{}\n\\end{{minipage}}\\hfill\n\\begin{{minipage}}[t]{{0.48\\linewidth}}\\vspace{{0pt}}\n{}\n\\end{{minipage}}",
+                    graphic, content
+                ));
+            }
+        }
     }
+    (latex, attachments)
+}
+
+/// Returns an image TeX and pandoc can embed: PNG and JPEG as they are, other
+/// formats re-encoded as PNG. `None` when the bytes are not a readable image.
+fn review_image(bytes: &[u8]) -> Option<(&'static str, Vec<u8>)> {
+    match image::guess_format(bytes).ok()? {
+        ImageFormat::Png => Some(("png", bytes.to_vec())),
+        ImageFormat::Jpeg => Some(("jpg", bytes.to_vec())),
+        _ => {
+            let decoded = image::load_from_memory(bytes).ok()?;
+            let mut png = Vec::new();
+            decoded.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).ok()?;
+            Some(("png", png))
+        }
+    }
+}
+
+/// Wraps a LaTeX fragment in the document every export starts from, using the
+/// template's class and preamble when one is set. With `typeset`, the layout
+/// and metadata are applied too; pandoc is given the bare document instead,
+/// since its LaTeX reader would turn the font and spacing setup into text.
+fn latex_document(latex_content: &str, options: &RenderOptions, typeset: bool) -> String {
+    let body = if options.layout.number_equations {
+        number_display_math(latex_content)
+    } else {
+        latex_content.to_string()
+    };
+    format!("{}{}\n\\end{{document}}\n", document_prefix(options, typeset), body)
+}
 
-    /// Converts a string of LaTeX into HTML for preview purposes.
-    /// This function requires `pandoc` to be installed and available in the system's PATH.
-    /// Returns both the HTML content and any embedded CSS for styling.
-    pub fn latex_to_html_preview(latex_content: &str) -> Result<ConversionResult, Box<dyn Error>> {
-        // Generate unique filenames in the system's temp directory to avoid conflicts.
-        let unique_id = Uuid::new_v4().to_string();
-        let temp_dir = std::env::temp_dir();
-        let tex_path = temp_dir.join(format!("{}.tex", unique_id));
-        let html_path = temp_dir.join(format!("{}.html", unique_id));
-
-        // 1. Write LaTeX content to a temporary .tex file.
-        let mut file = fs::File::create(&tex_path)?;
-        let full_latex_doc = format!(
-            "\\documentclass{{article}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n\\begin{{document}}\n{}\n\\end{{document}}",
-            latex_content
+/// Everything `latex_document` puts before the user's text; its line count is
+/// the offset between TeX's line numbers and the user's.
+fn document_prefix(options: &RenderOptions, typeset: bool) -> String {
+    let templates = &options.templates;
+    let layout = &options.layout;
+    let class = templates.latex_class.as_ref().map(|c| c.name.as_str()).unwrap_or("article");
+    let preamble = templates.latex_preamble.as_deref().map(|p| format!("{}\n", p.trim())).unwrap_or_default();
+    if !typeset {
+        return format!(
+            "\\documentclass{{{}}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n{}\\begin{{document}}\n",
+            class, preamble
         );
-        file.write_all(full_latex_doc.as_bytes())?;
-
-        // 2. Execute pandoc with HTML5 output and MathJax for math rendering
-        let output = Command::new("pandoc")
-            .arg(tex_path.to_str().unwrap())
-            .arg("-o")
-            .arg(html_path.to_str().unwrap())
-            .arg("-s")  // Standalone HTML with header
-            .arg("--mathjax")  // Use MathJax for equations (better browser support than MathML)
-            .arg("-t")
-            .arg("html5")  // HTML5 output
-            .arg("--highlight-style=tango")  // Add syntax highlighting
-            .arg("--variable=colorlinks:true")  // Color links for better visibility
-            .output()?;
-
-        // Clean up the tex file
-        let _ = fs::remove_file(&tex_path);
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            eprintln!("Pandoc error: {}", stderr);  // Log the error for debugging
-            return Err(format!("Pandoc execution failed: {}", stderr).into());
+    let mut class_options = Vec::new();
+    if let Some(paper) = layout.paper_size {
+        class_options.push(paper.latex_option().to_string());
+    }
+    if let Some(size) = layout.font_size_pt {
+        class_options.push(format!("{}pt", size));
+    }
+    if layout.two_columns {
+        class_options.push("twocolumn".to_string());
+    }
+    let class_options = if class_options.is_empty() { String::new() } else { format!("[{}]", class_options.join(",")) };
+
+    // PDF/A needs the metadata declared before the class; LaTeX then embeds the
+    // colour profile and writes the XMP metadata from the hyperref settings.
+    let mut prefix = if options.archival { "\\DocumentMetadata{pdfstandard=A-2b}\n".to_string() } else { String::new() };
+    // graphicx is for review exports, which show the original image with `\includegraphics`
+    prefix.push_str(&format!("\\documentclass{}{{{}}}\n\\usepackage{{amsmath}}\n\\usepackage{{amssymb}}\n\\usepackage{{graphicx}}\n", class_options, class));
+    if let Some(font) = layout.font {
+        prefix.push_str(&font.latex_setup());
+    }
+
+    // Options go to the packages before the template preamble runs, so a
+    // preamble that loads geometry or hyperref itself doesn't cause an option
+    // clash; the packages are loaded after it, where a repeat load is a no-op.
+    let title = options.title.as_deref().map(escape_latex_text);
+    let author = options.author.as_deref().map(escape_latex_text);
+    let hyperref = title.is_some() || author.is_some();
+    if let Some(margin) = &layout.margin {
+        prefix.push_str(&format!("\\PassOptionsToPackage{{margin={}}}{{geometry}}\n", margin));
+    }
+    if hyperref {
+        prefix.push_str("\\PassOptionsToPackage{hidelinks}{hyperref}\n");
+    }
+    prefix.push_str(&preamble);
+
+    if layout.margin.is_some() {
+        prefix.push_str("\\usepackage{geometry}\n");
+    }
+    if let Some(spacing) = layout.line_spacing.filter(|s| *s != 1.0) {
+        prefix.push_str(&format!("\\usepackage{{setspace}}\\setstretch{{{}}}\n", spacing));
+    }
+    if hyperref {
+        prefix.push_str(&format!(
+            "\\usepackage{{hyperref}}\n\\hypersetup{{pdftitle={{{}}},pdfauthor={{{}}}}}\n",
+            title.as_deref().unwrap_or_default(),
+            author.as_deref().unwrap_or_default()
+        ));
+    }
+    for (command, value) in [("title", title), ("author", author), ("date", options.date.as_deref().map(escape_latex_text))] {
+        if let Some(value) = value {
+            prefix.push_str(&format!("\\{}{{{}}}\n", command, value));
         }
+    }
+
+    if let Some(watermark) = &options.watermark {
+        prefix.push_str(&watermark.latex_setup());
+    }
+
+    prefix.push_str("\\begin{document}\n");
+    // pandoc's template does the same: a title block whenever there is a title
+    if options.title.as_deref().is_some_and(|title| !title.trim().is_empty()) {
+        prefix.push_str("\\maketitle\n");
+    }
+    if layout.table_of_contents {
+        prefix.push_str("\\tableofcontents\n");
+    }
+    prefix
+}
+
+/// Turns `$$...$$` and `\[...\]` into numbered `equation` environments without
+/// changing the line count, so TeX error lines still map to the user's text.
+fn number_display_math(latex_content: &str) -> String {
+    let chars: Vec<char> = latex_content.chars().collect();
+    let mut numbered = String::with_capacity(latex_content.len());
+    let mut position = 0;
+    for segment in extract_math_segments(latex_content).into_iter().filter(|s| s.kind == MathKind::Display) {
+        numbered.extend(&chars[position..segment.start]);
+        // Both display delimiters, `$$` and `\[`, are two characters. The body is
+        // copied as written, since `segment.latex` is trimmed and loses its newlines.
+        let body: String = chars[segment.start + 2..segment.end - 2].iter().collect();
+        numbered.push_str(&format!("\\begin{{equation}}{}\\end{{equation}}", body));
+        position = segment.end;
+    }
+    numbered.extend(&chars[position..]);
+    numbered
+}
+
+/// PDF engines in the order they are tried; each runs when the previous one fails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PdfEngine {
+    Xelatex,
+    Pdflatex,
+    Html,  // pandoc's HTML writer printed by WeasyPrint, for documents no TeX engine accepts
+}
+
+impl PdfEngine {
+    const CHAIN: [PdfEngine; 3] = [PdfEngine::Xelatex, PdfEngine::Pdflatex, PdfEngine::Html];
+
+    /// Whether the programs the engine runs were found at startup.
+    fn installed(&self) -> bool {
+        match self {
+            PdfEngine::Html => tool_available("pandoc") && tool_available("weasyprint"),
+            _ => tool_available(self.name()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PdfEngine::Xelatex => "xelatex",
+            PdfEngine::Pdflatex => "pdflatex",
+            PdfEngine::Html => "weasyprint",
+        }
+    }
+}
+
+/// Runs the sanitiser over user-supplied LaTeX and the templates.
+/// pandoc's LaTeX reader follows `\input` too, so every external format is checked.
+/// Class files legitimately use `\catcode` and friends, so they get the lighter
+/// class checks and rely on the engine restrictions in `run_bounded` for the rest.
+/// Templates are checked again here because those stored before a check was
+/// added never went through it.
+fn check_latex(texts: &[&str], options: &RenderOptions) -> Result<(), RenderError> {
+    let mut violations: Vec<LatexViolation> = texts.iter().flat_map(|text| find_blocked_constructs(text)).collect();
+    if let Some(preamble) = &options.templates.latex_preamble {
+        violations.extend(find_blocked_constructs(preamble));
+    }
+    if let Some(class) = &options.templates.latex_class {
+        violations.extend(find_blocked_class_constructs(&String::from_utf8_lossy(&class.source)));
+    }
+    if violations.is_empty() { Ok(()) } else { Err(RenderError::Rejected(violations)) }
+}
+
+/// Waits for an export slot and renders checked LaTeX as `format` in a fresh
+/// job directory, alongside any `attachments` the LaTeX references by filename.
+async fn run_export(latex_content: &str, attachments: &[(String, Vec<u8>)], format: TargetFormat, options: &RenderOptions) -> Result<Vec<u8>, RenderError> {
+    let limits = &*EXPORT_LIMITS;
+    let _slot = limits.acquire().await?;
 
-        // 3. Read the generated HTML file
-        let html_content = match fs::read_to_string(&html_path) {
-            Ok(content) => content.into_bytes(),
+    let job_dir = JobDir::create()?;
+    for (filename, content) in attachments {
+        fs::write(job_dir.0.join(filename), content)?;
+    }
+
+    match format {
+        TargetFormat::Pdf => compile_pdf(latex_content, format, options, &job_dir.0, limits).await,
+        TargetFormat::Pdfa => {
+            let options = RenderOptions { archival: true, ..options.clone() };
+            let pdf = compile_pdf(latex_content, format, &options, &job_dir.0, limits).await?;
+            validate_pdfa(&pdf, &job_dir.0, limits).await?;
+            Ok(pdf)
+        }
+        _ => run_pandoc(latex_content, format, options, &job_dir.0, limits).await,
+    }
+}
+
+/// Runs the PDF engine chain. Timeouts end the chain; otherwise the next engine
+/// is tried, and if all fail the LaTeX errors from the first TeX engine that
+/// reported any are returned.
+async fn compile_pdf(latex_content: &str, format: TargetFormat, options: &RenderOptions, job_dir: &Path, limits: &ExportLimits) -> Result<Vec<u8>, RenderError> {
+    let mut reported: Option<RenderError> = None;
+    for engine in PdfEngine::CHAIN.into_iter().filter(PdfEngine::installed) {
+        let result = match engine {
+            PdfEngine::Html => run_pandoc(latex_content, format, options, job_dir, limits).await,
+            _ => run_latex_engine(engine, latex_content, options, job_dir, limits).await,
+        };
+        match result {
+            Ok(pdf) => return Ok(pdf),
+            Err(e @ RenderError::TimedOut { .. }) => return Err(e),
             Err(e) => {
-                eprintln!("Failed to read HTML file: {}", e);  // Log the error for debugging
-                return Err(e.into());
+                eprintln!("PDF engine {} failed: {}", engine.name(), e);
+                if !matches!(reported, Some(RenderError::Compile(_))) {
+                    reported = Some(e);
+                }
             }
+        }
+    }
+    Err(reported.unwrap_or_else(|| RenderError::Failed("No PDF engine is available".to_string())))
+}
+
+/// Checks a PDF/A export before it is returned, with veraPDF when it is
+/// found by the startup probe and otherwise with qpdf's structure check plus `pdfa_problems`.
+/// Nothing is returned unvalidated: with neither tool the export fails.
+async fn validate_pdfa(pdf: &[u8], job_dir: &Path, limits: &ExportLimits) -> Result<(), RenderError> {
+    fs::write(job_dir.join("archive.pdf"), pdf)?;
+
+    if tool_available("verapdf") {
+        // veraPDF is a JVM program; keep its heap inside the process memory limit.
+        let mut verapdf = Command::new("verapdf");
+        verapdf
+            .args(["--flavour", "2b", "--format", "text", "archive.pdf"])
+            .env("JAVA_TOOL_OPTIONS", format!("-Xmx{}m", (limits.memory_limit_mb / 2).max(256)));
+        let output = run_bounded(verapdf, job_dir, limits).await?;
+        let report = String::from_utf8_lossy(&output.stdout);
+        if report.lines().any(|line| line.starts_with("PASS")) {
+            return Ok(());
+        }
+        return Err(RenderError::Failed(format!("The PDF is not valid PDF/A-2b: {}", report.trim())));
+    }
+    if !tool_available("qpdf") {
+        return Err(RenderError::Failed("No PDF/A validator (verapdf or qpdf) is installed".to_string()));
+    }
+
+    let mut check = Command::new("qpdf");
+    check.args(["--check", "archive.pdf"]);
+    let output = run_bounded(check, job_dir, limits).await?;
+    if !output.status.success() {
+        return Err(RenderError::Failed(format!("qpdf rejected the PDF: {}", String::from_utf8_lossy(&output.stdout).trim())));
+    }
+
+    // Object streams hide font and metadata dictionaries, so scan an expanded copy.
+    let mut expand = Command::new("qpdf");
+    expand.args(["--object-streams=disable", "archive.pdf", "expanded.pdf"]);
+    let output = run_bounded(expand, job_dir, limits).await?;
+    if !output.status.success() {
+        return Err(RenderError::Failed(format!("qpdf could not expand the PDF: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    let problems = pdfa_problems(&fs::read(job_dir.join("expanded.pdf"))?);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(RenderError::Failed(format!("The PDF is not valid PDF/A-2b: {}", problems.join("; "))))
+    }
+}
+
+/// The PDF/A-2b requirements that can be seen in an uncompressed PDF: the
+/// identification in the XMP metadata, an output intent, and a font file for
+/// every font descriptor.
+fn pdfa_problems(pdf: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(pdf);
+    let mut problems = Vec::new();
+    let part_2 = ["pdfaid:part>2<", "pdfaid:part=\"2\"", "pdfaid:part='2'"].iter().any(|m| text.contains(m));
+    let conformance_b = ["pdfaid:conformance>B<", "pdfaid:conformance=\"B\"", "pdfaid:conformance='B'"].iter().any(|m| text.contains(m));
+    if !part_2 || !conformance_b {
+        problems.push("the XMP metadata does not identify the file as PDF/A-2b".to_string());
+    }
+    if !text.contains("/OutputIntents") {
+        problems.push("no output intent (colour profile) is set".to_string());
+    }
+    let descriptors = text.matches("/Type /FontDescriptor").count();
+    let embedded = text.matches("/FontFile").count();
+    if embedded < descriptors {
+        problems.push(format!("{} of {} fonts are not embedded", descriptors - embedded, descriptors));
+    }
+    problems
+}
+
+/// Compiles the document directly with a TeX engine, so log line numbers map
+/// straight back to the user's text.
+async fn run_latex_engine(engine: PdfEngine, latex_content: &str, options: &RenderOptions, job_dir: &Path, limits: &ExportLimits) -> Result<Vec<u8>, RenderError> {
+    if let Some(class) = &options.templates.latex_class {
+        // TeX looks in its working directory first.
+        fs::write(job_dir.join(format!("{}.cls", class.name)), &class.source)?;
+    }
+    fs::write(job_dir.join("input.tex"), latex_document(&escape_stray_specials(latex_content), options, true))?;
+
+    let mut command = Command::new(engine.name());
+    command.args(["-interaction=nonstopmode", "-halt-on-error", "-no-shell-escape", "input.tex"]);
+    let output = run_bounded(command, job_dir, limits).await?;
+    if output.status.success() {
+        return Ok(tokio::fs::read(job_dir.join("input.pdf")).await?);
+    }
+
+    let log = fs::read(job_dir.join("input.log")).unwrap_or_default();
+    let content_start = document_prefix(options, true).matches('\n').count() + 1;
+    let errors = parse_latex_log(&String::from_utf8_lossy(&log), content_start, latex_content.lines().count().max(1));
+    if errors.is_empty() {
+        return Err(RenderError::Failed(format!("{} exited with {}", engine.name(), output.status)));
+    }
+    Err(RenderError::Compile(errors))
+}
+
+async fn run_pandoc(latex_content: &str, format: TargetFormat, options: &RenderOptions, job_dir: &Path, limits: &ExportLimits) -> Result<Vec<u8>, RenderError> {
+    let tex_path = job_dir.join("input.tex");
+    let output_path = job_dir.join(format!("output.{}", format.extension()));
+
+    // 1. Write any templates, the LaTeX document and the Lua filters to the job directory.
+    // pandoc's own heap is capped through the GHC runtime so it fails cleanly instead of
+    // hitting the hard limit in `run_bounded`.
+    let mut command = Command::new("pandoc");
+    command.arg("+RTS").arg(format!("-M{}m", limits.memory_limit_mb)).arg("-RTS");
+    apply_templates(&mut command, format, &options.templates, job_dir)?;
+
+    fs::write(&tex_path, latex_document(latex_content, options, false))?;
+    let mut filter_paths = vec![
+        write_pagebreak_filter(job_dir)?,
+        write_math_alt_filter(job_dir, latex_content)?,
+    ];
+    if options.layout.number_equations {
+        let filter_path = job_dir.join("equation-numbers.lua");
+        fs::write(&filter_path, EQUATION_NUMBER_LUA_FILTER)?;
+        filter_paths.push(filter_path);
+    }
+    let watermark = options.watermark.as_ref().filter(|_| format.supports_watermark());
+    let office_watermark = watermark.filter(|_| matches!(format, TargetFormat::Docx | TargetFormat::Odt));
+    if let Some(watermark) = watermark.filter(|_| office_watermark.is_none()) {
+        let filter_path = job_dir.join("watermark.lua");
+        fs::write(&filter_path, WATERMARK_LUA_FILTER)?;
+        filter_paths.push(filter_path);
+        command
+            .arg(format!("--metadata=watermark:{}", watermark.text))
+            .arg(format!("--metadata=watermark-position:{}", watermark.position.name()));
+    }
+
+    // 2. Execute the pandoc command-line tool.
+    command
+        .arg(&tex_path)
+        .arg("-f")
+        .arg("latex")
+        .arg("-o")
+        .arg(&output_path)
+        .args(format.pandoc_args());
+    for filter_path in &filter_paths {
+        command.arg(format!("--lua-filter={}", filter_path.display()));
+    }
+    for (key, value) in [("title", &options.title), ("author", &options.author), ("date", &options.date)] {
+        if let Some(value) = value.as_deref().map(str::trim).filter(|v| !v.is_empty()) {
+            command.arg(format!("--metadata={}:{}", key, value));
+        }
+    }
+    if options.layout.table_of_contents {
+        command.arg("--toc");
+    }
+    let output = run_bounded(command, job_dir, limits).await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(RenderError::Failed(format!("Pandoc execution failed: {}", stderr)));
+    }
+
+    // 3. Read the generated file into bytes, putting the watermark into the page
+    // headers or footers of office documents; the job directory is removed by the caller.
+    let content = tokio::fs::read(&output_path).await?;
+    match office_watermark {
+        Some(watermark) => watermark_package(&content, format, watermark).map_err(RenderError::Failed),
+        None => Ok(content),
+    }
+}
+
+/// Runs an export process in the job directory and its own process group, under
+/// the wall-clock limit and a data segment limit its children inherit. The
+/// kpathsea settings stop TeX from running shell commands or touching files
+/// outside its working tree.
+async fn run_bounded(mut command: Command, job_dir: &Path, limits: &ExportLimits) -> Result<std::process::Output, RenderError> {
+    command
+        .current_dir(job_dir)
+        .env("shell_escape", "f")
+        .env("openin_any", "p")
+        .env("openout_any", "p")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .process_group(0);
+    let memory_limit_bytes = limits.memory_limit_mb.saturating_mul(1024 * 1024) as libc::rlim_t;
+    // SAFETY: only async-signal-safe setrlimit(2) runs between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit { rlim_cur: memory_limit_bytes, rlim_max: memory_limit_bytes };
+            if libc::setrlimit(libc::RLIMIT_DATA, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    let mut group = ProcessGroupGuard(child.id());
+    let output = match tokio::time::timeout(limits.timeout, child.wait_with_output()).await {
+        Ok(output) => output?,
+        Err(_) => return Err(RenderError::TimedOut { limit_secs: limits.timeout.as_secs() }),
+    };
+    group.disarm();
+    Ok(output)
+}
+
+/// Writes the reference document for `format`, if the templates include one,
+/// and points pandoc at it.
+fn apply_templates(command: &mut Command, format: TargetFormat, templates: &TemplateFiles, job_dir: &Path) -> Result<(), RenderError> {
+    let reference = match format {
+        TargetFormat::Docx => templates.reference_docx.as_ref().map(|doc| (doc, "reference.docx")),
+        TargetFormat::Odt => templates.reference_odt.as_ref().map(|doc| (doc, "reference.odt")),
+        _ => None,
+    };
+    if let Some((content, filename)) = reference {
+        let path = job_dir.join(filename);
+        fs::write(&path, content)?;
+        command.arg(format!("--reference-doc={}", path.display()));
+    }
+    Ok(())
+}
+
+fn write_pagebreak_filter(job_dir: &Path) -> Result<PathBuf, RenderError> {
+    let filter_path = job_dir.join("pagebreak.lua");
+    fs::write(&filter_path, PAGEBREAK_LUA_FILTER)?;
+    Ok(filter_path)
+}
+
+fn write_math_alt_filter(job_dir: &Path, latex_content: &str) -> Result<PathBuf, RenderError> {
+    let filter_path = job_dir.join("math-alt.lua");
+    fs::write(&filter_path, math_alt_filter(latex_content))?;
+    Ok(filter_path)
+}
+
+/// The alt text filter with its lookup tables for the math in `latex_content`.
+fn math_alt_filter(latex_content: &str) -> String {
+    let alt_texts = math_speech::alt_texts(latex_content);
+    let mut filter = String::from("local ALT_TEXT = {\n");
+    for (latex, alt) in &alt_texts {
+        filter.push_str(&format!("  [{}] = {},\n", lua_string(latex), lua_string(alt)));
+    }
+    filter.push_str("}\nlocal DOCX_ALT_OPEN = {\n");
+    for (latex, alt) in &alt_texts {
+        filter.push_str(&format!("  [{}] = {},\n", lua_string(latex), lua_string(&docx_alt_open(alt))));
+    }
+    filter.push_str(&format!("}}\nlocal DOCX_ALT_CLOSE = {}\n", lua_string(DOCX_ALT_CLOSE)));
+    filter.push_str(MATH_ALT_LUA_FILTER);
+    filter
+}
+
+/// Opens an inline content control around an equation in DOCX. Its title
+/// carries the spoken alt text, which screen readers announce on entering the
+/// control; hidden text, by contrast, is skipped.
+fn docx_alt_open(alt: &str) -> String {
+    format!(
+        "<w:sdt><w:sdtPr><w:alias w:val=\"{}\"/><w:tag w:val=\"math-alt\"/></w:sdtPr><w:sdtContent>",
+        escape_xml(alt)
+    )
+}
+
+const DOCX_ALT_CLOSE: &str = "</w:sdtContent></w:sdt>";
+
+/// Quotes a string as a Lua string literal.
+fn lua_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\{:03}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Environments whose bodies use `&`, `_` and `^` as syntax: math, alignments
+/// and verbatim text.
+const SYNTAX_ENVIRONMENTS: &[&str] = &[
+    "equation", "equation*", "align", "align*", "alignat", "alignat*", "flalign", "flalign*",
+    "gather", "gather*", "multline", "multline*", "eqnarray", "eqnarray*", "math", "displaymath",
+    "tabular", "tabular*", "tabularx", "longtable", "array", "verbatim", "lstlisting", "minted",
+];
+
+/// Commands whose argument is a name (a label, a file, an environment) rather than text.
+const NAME_ARGUMENT_COMMANDS: &[&str] = &[
+    "label", "ref", "eqref", "pageref", "autoref", "cref", "cite", "citep", "citet",
+    "url", "href", "includegraphics", "usepackage", "documentclass",
+];
+
+/// Commands that define macros; their bodies are copied as they are, since
+/// `#1` there is a parameter rather than text.
+const DEFINITION_COMMANDS: &[&str] = &["newcommand", "renewcommand", "providecommand", "def", "gdef", "edef", "xdef"];
+
+/// Escapes `&`, `#`, `_` and `^` that OCR text uses as plain characters, e.g.
+/// "R&D" or "file_name". pandoc's reader accepts them as text but TeX stops
+/// with an error, so the TeX engines get this version. Math, alignment and
+/// verbatim environments, name arguments such as `\label{eq_1}` and macro
+/// definitions are left alone, as are characters already escaped. Lines are
+/// never added or removed, so compile errors still map to the user's text.
+pub fn escape_stray_specials(latex: &str) -> String {
+    let chars: Vec<char> = latex.chars().collect();
+    let n = chars.len();
+    let segments = extract_math_segments(latex);
+    let mut math = segments.iter().map(|segment| (segment.start, segment.end)).peekable();
+    let mut escaped = String::with_capacity(latex.len());
+    let mut environment: Option<String> = None;
+    let mut i = 0;
+
+    let copy_group = |escaped: &mut String, from: usize, open: char, close: char| -> usize {
+        let mut j = from;
+        while j < n && chars[j].is_whitespace() && chars[j] != '\n' {
+            j += 1;
+        }
+        if j >= n || chars[j] != open {
+            return from;
+        }
+        let mut depth = 0;
+        while j < n {
+            match chars[j] {
+                '\\' if j + 1 < n => j += 1,
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        j += 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            j += 1;
+        }
+        let j = j.min(n);
+        escaped.extend(&chars[from..j]);
+        j
+    };
+
+    while i < n {
+        while math.peek().is_some_and(|(start, _)| *start < i) {
+            math.next();
+        }
+        if let Some(&(start, end)) = math.peek().filter(|(start, _)| *start == i && environment.is_none()) {
+            escaped.extend(&chars[start..end]);
+            i = end;
+            continue;
+        }
+
+        match chars[i] {
+            '%' => {
+                while i < n && chars[i] != '\n' {
+                    escaped.push(chars[i]);
+                    i += 1;
+                }
+                continue;
+            }
+            '\\' if i + 1 < n && chars[i + 1].is_ascii_alphabetic() => {
+                let start = i + 1;
+                let mut end = start;
+                while end < n && chars[end].is_ascii_alphabetic() {
+                    end += 1;
+                }
+                let name: String = chars[start..end].iter().collect();
+                escaped.extend(&chars[i..end]);
+                i = end;
+
+                if name == "begin" || name == "end" {
+                    let group_start = escaped.len();
+                    i = copy_group(&mut escaped, i, '{', '}');
+                    let group = escaped[group_start..].trim();
+                    let env = group.trim_start_matches('{').trim_end_matches('}').trim().to_string();
+                    match (name.as_str(), &environment) {
+                        ("begin", None) if SYNTAX_ENVIRONMENTS.contains(&env.as_str()) => environment = Some(env),
+                        ("end", Some(current)) if *current == env => environment = None,
+                        _ => {}
+                    }
+                } else if environment.is_none() && NAME_ARGUMENT_COMMANDS.contains(&name.as_str()) {
+                    i = copy_group(&mut escaped, i, '[', ']');
+                    i = copy_group(&mut escaped, i, '{', '}');
+                } else if environment.is_none() && DEFINITION_COMMANDS.contains(&name.as_str()) {
+                    // The name and parameter text, e.g. `{\f}[1][x]` or `\f#1#2`, then the body
+                    while i < n && chars[i] != '{' && chars[i] != '[' && chars[i] != '\n' {
+                        escaped.push(chars[i]);
+                        i += 1;
+                    }
+                    if name.ends_with("command") {
+                        i = copy_group(&mut escaped, i, '{', '}');
+                        i = copy_group(&mut escaped, i, '[', ']');
+                        i = copy_group(&mut escaped, i, '[', ']');
+                    }
+                    i = copy_group(&mut escaped, i, '{', '}');
+                }
+                continue;
+            }
+            '\\' => {
+                escaped.extend(&chars[i..(i + 2).min(n)]);
+                i += 2;
+                continue;
+            }
+            '&' | '_' if environment.is_none() => {
+                escaped.push('\\');
+                escaped.push(chars[i]);
+            }
+            '#' if environment.is_none() => escaped.push_str("\\#"),
+            '^' if environment.is_none() => escaped.push_str("\\textasciicircum{}"),
+            c => escaped.push(c),
+        }
+        i += 1;
+    }
+    escaped
+}
+
+/// Escapes LaTeX special characters so plain text (e.g. filenames) can be used in headings.
+pub fn escape_latex_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '{' | '}' | '$' | '&' | '#' | '_' | '%' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_sections_with_headings_and_page_breaks() {
+        let sections = vec![
+            MergeSection { heading: "page_1.png".to_string(), content: "First $x$".to_string() },
+            MergeSection { heading: "page_2.png".to_string(), content: "Second".to_string() },
+        ];
+        let merged = DocumentConverter::merge_sections(&sections, true, true);
+        assert_eq!(
+            merged,
+            "\\section*{page\\_1.png}\n\nFirst $x$\n\n\\newpage\n\n\\section*{page\\_2.png}\n\nSecond"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_titled_merge_shows_the_title_once() {
+        let sections = vec![MergeSection { heading: "page_1.png".to_string(), content: "First".to_string() }];
+        let merged = DocumentConverter::merge_sections(&sections, true, true);
+        let options = RenderOptions { title: Some("Lecture Notes".to_string()), ..RenderOptions::default() };
+        let tex = DocumentConverter::render(&merged, TargetFormat::Tex, &options).await.unwrap();
+        let tex = String::from_utf8(tex.content).unwrap();
+
+        // Typeset by \maketitle only; the other mention is the PDF metadata
+        let (preamble, body) = tex.split_once("\\begin{document}").unwrap();
+        assert_eq!(preamble.matches("\\title{Lecture Notes}").count(), 1);
+        assert!(preamble.contains("pdftitle={Lecture Notes}"));
+        assert_eq!(tex.matches("Lecture Notes").count(), 2);
+        assert!(body.starts_with("\n\\maketitle\n") && !body.contains("Lecture Notes"));
+    }
+
+    #[test]
+    fn test_docx_math_alt_text_is_a_content_control_title() {
+        let filter = math_alt_filter("Area $a < b$ here.");
+        let open = docx_alt_open("a is less than b & c");
+        assert_eq!(open, "<w:sdt><w:sdtPr><w:alias w:val=\"a is less than b &amp; c\"/><w:tag w:val=\"math-alt\"/></w:sdtPr><w:sdtContent>");
+        assert!(filter.contains("local DOCX_ALT_OPEN = {\n  [\"a < b\"] = \"<w:sdt><w:sdtPr><w:alias w:val=\\\"a is less than b\\\"/>"));
+        assert!(filter.contains(&format!("local DOCX_ALT_CLOSE = {}", lua_string(DOCX_ALT_CLOSE))));
+        assert!(!filter.contains("w:vanish"));
+    }
+
+    #[test]
+    fn test_escape_latex_text() {
+        assert_eq!(escape_latex_text("50% of a_b & {c}"), "50\\% of a\\_b \\& \\{c\\}");
+        assert_eq!(escape_latex_text("~^\\"), "\\textasciitilde{}\\textasciicircum{}\\textbackslash{}");
+    }
+
+    #[test]
+    fn test_escape_stray_specials_outside_math() {
+        let text = "R&D costs_total #3 and 2^5 % raw & kept\n$a_1 & b^2$ \\label{eq_1} \\& done\n\\begin{align}x_1 &= y\\end{align}\n\\newcommand{\\f}[1]{#1} \\def\\g#1{#1_x}";
+        let escaped = escape_stray_specials(text);
+        assert_eq!(
+            escaped,
+            "R\\&D costs\\_total \\#3 and 2\\textasciicircum{}5 % raw & kept\n$a_1 & b^2$ \\label{eq_1} \\& done\n\\begin{align}x_1 &= y\\end{align}\n\\newcommand{\\f}[1]{#1} \\def\\g#1{#1_x}"
+        );
+        assert_eq!(escaped.lines().count(), text.lines().count());
+        assert_eq!(escape_stray_specials(&escaped), escaped);
+    }
+
+    #[tokio::test]
+    async fn test_tex_output_escapes_plain_text_and_shows_the_title() {
+        let options = RenderOptions { title: Some("Notes".to_string()), ..RenderOptions::default() };
+        let tex = DocumentConverter::render("Q&A on file_name with $x_1$", TargetFormat::Tex, &options).await.unwrap();
+        let tex = String::from_utf8(tex.content).unwrap();
+        assert!(tex.contains("\\begin{document}\n\\maketitle\nQ\\&A on file\\_name with $x_1$\n"));
+    }
+
+    #[test]
+    fn test_queue_ticket_is_bounded_and_released_on_drop() {
+        let queued = AtomicUsize::new(0);
+        let first = QueueTicket::take(&queued, 2).unwrap();
+        let _second = QueueTicket::take(&queued, 2).unwrap();
+        assert!(QueueTicket::take(&queued, 2).is_none());
+        drop(first);
+        assert!(QueueTicket::take(&queued, 2).is_some());
+        assert_eq!(queued.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_in_process_formats_render_without_pandoc() {
+        assert_eq!(TargetFormat::from_name("word"), Some(TargetFormat::Docx));
+        assert_eq!(TargetFormat::from_name("MD"), Some(TargetFormat::Markdown));
+        assert_eq!(TargetFormat::from_name("xlsx"), None);
+
+        let options = RenderOptions::default();
+        let txt = DocumentConverter::render("Area $\\pi r^2$", TargetFormat::Txt, &options).await.unwrap();
+        assert_eq!(String::from_utf8(txt.content).unwrap(), "Area πr²");
+        assert_eq!(txt.mime_type, "text/plain");
+
+        let tex = DocumentConverter::render("$x$", TargetFormat::Tex, &options).await.unwrap();
+        assert!(String::from_utf8(tex.content).unwrap().contains("\\begin{document}\n$x$\n\\end{document}"));
+    }
+
+    #[tokio::test]
+    async fn test_tex_output_applies_layout_and_numbers_equations() {
+        let options = RenderOptions {
+            author: Some("Ada Lovelace".to_string()),
+            layout: DocumentLayout {
+                paper_size: Some(PaperSize::A4),
+                margin: parse_margin("20mm"),
+                font_size_pt: Some(11),
+                two_columns: true,
+                number_equations: true,
+                table_of_contents: true,
+                ..DocumentLayout::default()
+            },
+            ..RenderOptions::default()
         };
+        let tex = DocumentConverter::render("See $$E = mc^2$$ and $x$\n\\[\n a \\\\\n b\n\\]", TargetFormat::Tex, &options).await.unwrap();
+        let tex = String::from_utf8(tex.content).unwrap();
+        assert!(tex.starts_with("\\documentclass[a4paper,11pt,twocolumn]{article}\n"));
+        assert!(tex.contains("\\PassOptionsToPackage{margin=20mm}{geometry}\n"));
+        assert!(tex.contains("\\usepackage{geometry}\n"));
+        assert!(tex.contains("\\author{Ada Lovelace}\n\\begin{document}\n\\tableofcontents\n"));
+        assert!(tex.contains("See \\begin{equation}E = mc^2\\end{equation} and $x$"));
+        // The numbered copy keeps every line, so compile errors still map back
+        assert!(tex.contains("\\begin{equation}\n a \\\\\n b\n\\end{equation}\n\\end{document}"));
 
-        let file_size = html_content.len() as u64;
+        assert_eq!(parse_margin("0.75in").as_deref(), Some("0.75in"));
+        assert_eq!(parse_margin("10cm"), None);
+        assert_eq!(parse_margin("2furlongs"), None);
+    }
 
-        // 4. Clean up the HTML file
-        let _ = fs::remove_file(&html_path);
+    #[test]
+    fn test_review_document_lays_out_image_and_transcription() {
+        let png = b"\x89PNG\r\n\x1a\n rest".to_vec();
+        let pages = vec![
+            ReviewPage { heading: Some("scan_1.png".to_string()), content: "$x$".to_string(), image: Some(png) },
+            ReviewPage { heading: None, content: "Second".to_string(), image: Some(b"not an image".to_vec()) },
+        ];
 
-        Ok(ConversionResult {
-            content: html_content,
-            size: file_size,
-            mime_type: "text/html".to_string(),
-        })
+        let (latex, attachments) = review_document(&pages, ImagePlacement::Beside, TargetFormat::Pdf);
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].0, "original-001.png");
+        assert!(latex.contains("\\newpage"));
+        assert!(latex.contains("\\emph{Original image unavailable.}"));
+
+        // What the TeX engines compile: graphicx has to be loaded for the image
+        let document = latex_document(&latex, &RenderOptions::default(), true);
+        let (preamble, body) = document.split_once("\\begin{document}\n").unwrap();
+        assert!(preamble.lines().any(|line| line == "\\usepackage{graphicx}"));
+        assert!(body.starts_with("\\section*{scan\\_1.png}\n\n\\noindent\\begin{minipage}"));
+        assert!(body.contains("\\includegraphics[width=\\linewidth,height=0.8\\textheight,keepaspectratio]{original-001.png}"));
+
+        let (latex, _) = review_document(&pages, ImagePlacement::Beside, TargetFormat::Docx);
+        assert!(latex.contains("\\begin{tabular}"));
+        assert!(!latex.contains("minipage"));
+    }
+
+    #[tokio::test]
+    async fn test_tex_output_uses_template_class_and_preamble() {
+        let options = RenderOptions {
+            templates: TemplateFiles {
+                latex_preamble: Some("\\usepackage{xcolor}\n".to_string()),
+                latex_class: Some(LatexClass { name: "thesis".to_string(), source: Vec::new() }),
+                ..TemplateFiles::default()
+            },
+            ..RenderOptions::default()
+        };
+        let tex = DocumentConverter::render("body", TargetFormat::Tex, &options).await.unwrap();
+        let tex = String::from_utf8(tex.content).unwrap();
+        assert!(tex.starts_with("\\documentclass{thesis}\n"));
+        assert!(tex.contains("\\usepackage{graphicx}\n\\usepackage{xcolor}\n\\begin{document}"));
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_package_options_precede_the_template_preamble() {
+        let options = RenderOptions {
+            title: Some("Notes".to_string()),
+            author: Some("Ada".to_string()),
+            layout: DocumentLayout { margin: parse_margin("1in"), ..DocumentLayout::default() },
+            templates: TemplateFiles {
+                latex_preamble: Some("\\usepackage[a4paper]{geometry}\n\\usepackage{hyperref}\n".to_string()),
+                ..TemplateFiles::default()
+            },
+            ..RenderOptions::default()
+        };
+        let tex = DocumentConverter::render("body", TargetFormat::Tex, &options).await.unwrap();
+        let tex = String::from_utf8(tex.content).unwrap();
+        assert!(tex.contains(
+            "\\PassOptionsToPackage{margin=1in}{geometry}\n\\PassOptionsToPackage{hidelinks}{hyperref}\n\\usepackage[a4paper]{geometry}\n\\usepackage{hyperref}\n\\usepackage{geometry}\n\\usepackage{hyperref}\n"
+        ));
+        assert!(tex.contains("\\title{Notes}\n\\author{Ada}\n\\begin{document}\n\\maketitle\nbody\n"));
+    }
+
+    #[tokio::test]
+    async fn test_watermark_is_typeset_but_left_out_of_tex_source() {
+        let options = RenderOptions {
+            watermark: Some(Watermark { text: "Made with 100% OCR".to_string(), position: WatermarkPosition::Footer }),
+            ..RenderOptions::default()
+        };
+        let document = latex_document("body", &options, true);
+        assert!(document.contains("\\AddToHook{shipout/foreground}"));
+        assert!(document.contains("\\color{gray}Made with 100\\% OCR}}}\n\\begin{document}"));
+
+        let tex = DocumentConverter::render("body", TargetFormat::Tex, &options).await.unwrap();
+        assert!(!String::from_utf8(tex.content).unwrap().contains("AddToHook"));
+        assert!(TargetFormat::Odt.supports_watermark() && !TargetFormat::Html.supports_watermark());
+    }
+
+    #[test]
+    fn test_pdfa_document_and_checks() {
+        let options = RenderOptions { archival: true, ..RenderOptions::default() };
+        assert!(latex_document("body", &options, true).starts_with("\\DocumentMetadata{pdfstandard=A-2b}\n\\documentclass{article}"));
+        assert_eq!(TargetFormat::from_name("pdf/a"), Some(TargetFormat::Pdfa));
+        assert_eq!(TargetFormat::Pdfa.file_name("notes"), "notes-pdfa.pdf");
+
+        let valid = "<< /Type /Catalog /OutputIntents [ 5 0 R ] >> <pdfaid:part>2</pdfaid:part><pdfaid:conformance>B</pdfaid:conformance> \
+            << /Type /FontDescriptor /FontFile 9 0 R >>";
+        assert!(pdfa_problems(valid.as_bytes()).is_empty());
+        let problems = pdfa_problems(b"<< /Type /FontDescriptor /FontName /Foo >>");
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[2], "1 of 1 fonts are not embedded");
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use crate::services::document_converter::PaperSize;
+use crate::services::native_renderer::{TextCounts, count_text, is_page_break};
+
+/// Room a display equation takes on the page, in words of running text.
+const WORDS_PER_DISPLAY_EQUATION: usize = 30;
+
+/// Reading speed for technical prose, in words per minute.
+const WORDS_PER_MINUTE: usize = 200;
+
+/// Extra reading time per equation, in seconds.
+const SECONDS_PER_INLINE_EQUATION: usize = 3;
+const SECONDS_PER_DISPLAY_EQUATION: usize = 15;
+
+/// Figures shown next to a preview. Page estimates assume the default 11pt
+/// article layout the exports use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocumentStats {
+    pub word_count: usize,  // Words of running text, math excluded
+    pub inline_equations: usize,
+    pub display_equations: usize,
+    pub estimated_pages: BTreeMap<String, usize>,  // Paper size name to page count
+    pub reading_time_minutes: usize,
+}
+
+/// Words of running text that fit on one page of `paper_size`.
+fn words_per_page(paper_size: PaperSize) -> usize {
+    match paper_size {
+        PaperSize::A4 => 500,
+        PaperSize::A5 => 230,
+        PaperSize::Letter => 480,
+        PaperSize::Legal => 620,
+    }
+}
+
+pub fn document_stats(latex_content: &str) -> DocumentStats {
+    // Explicit page breaks start a new page, so each chunk is estimated on its own
+    let mut chunks = vec![String::new()];
+    for line in latex_content.lines() {
+        if is_page_break(line) {
+            chunks.push(String::new());
+        } else if let Some(chunk) = chunks.last_mut() {
+            chunk.push_str(line);
+            chunk.push('\n');
+        }
+    }
+    let counts: Vec<TextCounts> = chunks.iter().map(|chunk| count_text(chunk)).collect();
+
+    let estimated_pages = PaperSize::ALL
+        .iter()
+        .map(|paper_size| {
+            let capacity = words_per_page(*paper_size);
+            let pages = counts
+                .iter()
+                .map(|count| count.words + count.display_equations * WORDS_PER_DISPLAY_EQUATION)
+                .filter(|load| *load > 0)
+                .map(|load| load.div_ceil(capacity))
+                .sum();
+            (paper_size.name().to_string(), pages)
+        })
+        .collect();
+
+    let word_count = counts.iter().map(|count| count.words).sum();
+    let inline_equations = counts.iter().map(|count| count.inline_equations).sum();
+    let display_equations = counts.iter().map(|count| count.display_equations).sum();
+    let seconds = word_count * 60 / WORDS_PER_MINUTE
+        + inline_equations * SECONDS_PER_INLINE_EQUATION
+        + display_equations * SECONDS_PER_DISPLAY_EQUATION;
+
+    DocumentStats {
+        word_count,
+        inline_equations,
+        display_equations,
+        estimated_pages,
+        reading_time_minutes: seconds.div_ceil(60),
+    }
+}
+
+/// Statistics of Markdown with `$...$` math, such as an edited Markdown export.
+/// Block markup (heading hashes, quote and list markers, rules, table pipes and
+/// code fences) is not counted as words, and `%` is text, not a LaTeX comment.
+pub fn markdown_stats(markdown: &str) -> DocumentStats {
+    let text: String = markdown.lines().map(|line| markdown_line_text(line) + "\n").collect();
+    document_stats(&text)
+}
+
+/// The running text of one Markdown line, as LaTeX `count_text` reads.
+fn markdown_line_text(line: &str) -> String {
+    let mut text = line.trim();
+    if text.starts_with("```") || text.starts_with("~~~") || is_markdown_rule(text) {
+        return String::new();
+    }
+    while let Some(rest) = text.strip_prefix('>') {
+        text = rest.trim_start();
+    }
+
+    let hashes = text.len() - text.trim_start_matches('#').len();
+    let ordered = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if (1..=6).contains(&hashes) && (text.len() == hashes || text[hashes..].starts_with([' ', '\t'])) {
+        text = text[hashes..].trim().trim_end_matches('#');
+    } else if let Some(rest) = text.strip_prefix(['-', '*', '+']).filter(|rest| rest.starts_with([' ', '\t'])) {
+        text = rest;
+    } else if let Some(rest) = text[ordered..].strip_prefix(['.', ')']).filter(|rest| ordered > 0 && rest.starts_with([' ', '\t'])) {
+        text = rest;
+    }
+
+    if text.starts_with('|') && text.chars().all(|c| matches!(c, '|' | '-' | ':' | ' ')) {
+        return String::new();  // Table header separator
+    }
+    text.replace('|', " ").replace('%', "\\%")
+}
+
+/// A thematic break: three or more `-`, `*` or `_`, optionally spaced.
+fn is_markdown_rule(text: &str) -> bool {
+    let marks: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    marks.len() >= 3 && ['-', '*', '_'].iter().any(|mark| marks.iter().all(|c| c == mark))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document_stats_excludes_math_and_counts_page_breaks() {
+        let latex = "\\section{Energy}\nMass $m$ and energy $E$ are related:\n\\[E = mc^2\\]\n\\newpage\nThe end.\n";
+        let stats = document_stats(latex);
+
+        assert_eq!(stats.word_count, 8);
+        assert_eq!(stats.inline_equations, 2);
+        assert_eq!(stats.display_equations, 1);
+        assert_eq!(stats.estimated_pages["a4"], 2);
+        assert_eq!(stats.estimated_pages["a5"], 2);
+        assert_eq!(stats.reading_time_minutes, 1);
+
+        let empty = document_stats("");
+        assert_eq!(empty.estimated_pages["letter"], 0);
+        assert_eq!(empty.reading_time_minutes, 0);
+    }
+
+    #[test]
+    fn test_markdown_stats_skip_block_markup() {
+        let markdown = "# Energy\n\n> Mass $m$ and energy:\n\n$$E = mc^2$$\n\n- 50% of it\n2. is lost\n\n---\n\n| a | b |\n|---|:-:|\n```\n";
+        let stats = markdown_stats(markdown);
+
+        assert_eq!(stats.word_count, 11);
+        assert_eq!(stats.inline_equations, 1);
+        assert_eq!(stats.display_equations, 1);
+        assert_eq!(document_stats("# Energy\n").word_count, 2);
+        assert_eq!(markdown_stats("# Energy\n").word_count, 1);
+    }
+}
@@ -0,0 +1,110 @@
+use sha1::{Digest, Sha1};
+
+use crate::services::document_converter::{RenderOptions, TargetFormat, TemplateFiles};
+
+/// Bump when renderer or storage changes should make previously cached exports stale.
+const CACHE_VERSION: &str = "2";
+
+/// Identifies one rendered export: the same key always renders to the same file.
+/// Covers the conversion, its text (and original image for review exports),
+/// the format, every export option and the bytes of the templates in use, so
+/// editing the text or replacing a template yields a new key.
+pub fn cache_key(job_id: &str, source: &str, image_url: Option<&str>, format: TargetFormat, options: &RenderOptions) -> String {
+    let mut hasher = Sha1::new();
+    for part in [CACHE_VERSION, job_id, format.name(), image_url.unwrap_or("")] {
+        hash_field(&mut hasher, part.as_bytes());
+    }
+    hash_field(&mut hasher, text_hash(source).as_bytes());
+
+    // Template bytes are hashed below; their Debug output would be both huge and lossy
+    let settings = RenderOptions { templates: TemplateFiles::default(), ..options.clone() };
+    hash_field(&mut hasher, format!("{:?}", settings).as_bytes());
+
+    let templates = &options.templates;
+    hash_field(&mut hasher, templates.reference_docx.as_deref().unwrap_or_default());
+    hash_field(&mut hasher, templates.reference_odt.as_deref().unwrap_or_default());
+    hash_field(&mut hasher, templates.latex_preamble.as_deref().unwrap_or_default().as_bytes());
+    match &templates.latex_class {
+        Some(class) => {
+            hash_field(&mut hasher, class.name.as_bytes());
+            hash_field(&mut hasher, &class.source);
+        }
+        None => hash_field(&mut hasher, b""),
+    }
+    hex(hasher)
+}
+
+/// Hash of the exported text, stored with each cache entry so entries made from
+/// an older text can be dropped once the text changes.
+pub fn text_hash(source: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(source.as_bytes());
+    hex(hasher)
+}
+
+/// Strong entity tag for a rendered file: the quoted SHA-1 of its bytes.
+pub fn content_etag(content: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(content);
+    format!("\"{}\"", hex(hasher))
+}
+
+/// Whether an `If-None-Match` header matches `etag`. The header lists entity
+/// tags separated by commas, or is `*`; as RFC 9110 requires, the comparison
+/// is weak, so `W/"x"` matches `"x"`.
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// Length-prefixes each field so adjacent fields can't run into each other.
+fn hash_field(hasher: &mut Sha1, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+fn hex(hasher: Sha1) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::document_converter::LatexClass;
+
+    #[test]
+    fn test_cache_key_changes_with_text_format_options_and_templates() {
+        let options = RenderOptions::default();
+        let key = cache_key("job", "$x$", None, TargetFormat::Pdf, &options);
+        assert_eq!(key, cache_key("job", "$x$", None, TargetFormat::Pdf, &options));
+
+        assert_ne!(key, cache_key("job", "$y$", None, TargetFormat::Pdf, &options));
+        assert_ne!(key, cache_key("other", "$x$", None, TargetFormat::Pdf, &options));
+        assert_ne!(key, cache_key("job", "$x$", None, TargetFormat::Docx, &options));
+        assert_ne!(key, cache_key("job", "$x$", Some("https://img"), TargetFormat::Pdf, &options));
+
+        let titled = RenderOptions { title: Some("Notes".to_string()), ..RenderOptions::default() };
+        assert_ne!(key, cache_key("job", "$x$", None, TargetFormat::Pdf, &titled));
+
+        let mut templated = RenderOptions::default();
+        templated.templates.latex_class = Some(LatexClass { name: "notes".to_string(), source: b"v1".to_vec() });
+        let first = cache_key("job", "$x$", None, TargetFormat::Pdf, &templated);
+        templated.templates.latex_class = Some(LatexClass { name: "notes".to_string(), source: b"v2".to_vec() });
+        assert_ne!(first, cache_key("job", "$x$", None, TargetFormat::Pdf, &templated));
+        assert_ne!(key, first);
+    }
+
+    #[test]
+    fn test_if_none_match_accepts_lists_wildcards_and_weak_tags() {
+        let etag = content_etag(b"file");
+        assert!(etag.starts_with('"') && etag.ends_with('"') && etag.len() == 42);
+
+        assert!(if_none_match(&etag, &etag));
+        assert!(if_none_match(&format!("\"other\", W/{}", etag), &etag));
+        assert!(if_none_match("*", &etag));
+        assert!(!if_none_match("\"other\"", &etag));
+        assert!(!if_none_match("", &etag));
+    }
+}
@@ -0,0 +1,145 @@
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::services::document_converter::TargetFormat;
+
+/// External programs exports can use. Each is asked for its version once at startup.
+const TOOLS: [&str; 6] = ["pandoc", "xelatex", "pdflatex", "weasyprint", "verapdf", "qpdf"];
+
+/// How long a program may take to print its version; veraPDF starts a JVM.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Renderer name for formats produced inside the server.
+pub const BUILTIN_RENDERER: &str = "builtin";
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ToolStatus {
+    pub name: &'static str,
+    pub available: bool,
+    pub version: Option<String>,  // First line of the program's version output
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FormatStatus {
+    pub format: &'static str,
+    pub available: bool,
+    pub renderer: Option<&'static str>,  // Program that produces it, or `builtin`
+}
+
+/// What the startup probe found, as reported by `/api/health`.
+#[derive(Debug, Serialize, Clone)]
+pub struct ExportCapabilities {
+    pub tools: Vec<ToolStatus>,
+    pub formats: Vec<FormatStatus>,
+}
+
+static CAPABILITIES: OnceCell<ExportCapabilities> = OnceCell::new();
+
+impl ExportCapabilities {
+    fn from_tools(tools: Vec<ToolStatus>) -> Self {
+        let installed = |name: &str| tools.iter().any(|tool| tool.name == name && tool.available);
+        let formats = TargetFormat::ALL
+            .iter()
+            .map(|format| {
+                let renderer = renderer_for(*format, installed);
+                FormatStatus { format: format.name(), available: renderer.is_some(), renderer }
+            })
+            .collect();
+        ExportCapabilities { tools, formats }
+    }
+}
+
+/// Runs every tool in `TOOLS` with `--version` and records the result for the
+/// rest of the process. Later calls return the first result.
+pub async fn probe() -> &'static ExportCapabilities {
+    if let Some(capabilities) = CAPABILITIES.get() {
+        return capabilities;
+    }
+    // Each probe can take up to PROBE_TIMEOUT, so they run side by side
+    let tools = futures::future::join_all(TOOLS.iter().map(|name| probe_tool(name))).await;
+    CAPABILITIES.get_or_init(|| ExportCapabilities::from_tools(tools))
+}
+
+async fn probe_tool(name: &'static str) -> ToolStatus {
+    let mut command = Command::new(name);
+    command
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let version = match tokio::time::timeout(PROBE_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Some(stdout.lines().chain(stderr.lines()).map(str::trim).find(|line| !line.is_empty()).unwrap_or_default().to_string())
+        }
+        _ => None,
+    };
+    ToolStatus { name, available: version.is_some(), version }
+}
+
+/// The probe result, or None before `probe` has finished.
+pub fn capabilities() -> Option<&'static ExportCapabilities> {
+    CAPABILITIES.get()
+}
+
+/// Whether `tool` was found by the probe. Before the probe has run every tool
+/// is assumed to be installed, which is how exports behaved without it.
+pub fn tool_available(tool: &str) -> bool {
+    match CAPABILITIES.get() {
+        Some(capabilities) => capabilities.tools.iter().any(|t| t.name == tool && t.available),
+        None => true,
+    }
+}
+
+/// The renderer that produces `format` here, or None when it cannot be exported.
+pub fn format_renderer(format: TargetFormat) -> Option<&'static str> {
+    renderer_for(format, tool_available)
+}
+
+/// Picks the renderer for `format` given which tools are installed, in the
+/// order the export pipeline tries them.
+fn renderer_for(format: TargetFormat, installed: impl Fn(&str) -> bool) -> Option<&'static str> {
+    let pdf_engine = || {
+        ["xelatex", "pdflatex"]
+            .into_iter()
+            .find(|engine| installed(engine))
+            .or_else(|| (installed("pandoc") && installed("weasyprint")).then_some("weasyprint"))
+    };
+    match format {
+        TargetFormat::Tex | TargetFormat::Txt => Some(BUILTIN_RENDERER),
+        TargetFormat::Html | TargetFormat::Markdown => Some(if installed("pandoc") { "pandoc" } else { BUILTIN_RENDERER }),
+        TargetFormat::Pdf => pdf_engine(),
+        TargetFormat::Pdfa => pdf_engine().filter(|_| installed("verapdf") || installed("qpdf")),
+        TargetFormat::Docx | TargetFormat::Odt | TargetFormat::Epub | TargetFormat::Rtf | TargetFormat::Ipynb => {
+            installed("pandoc").then_some("pandoc")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renderer_for_falls_back_and_disables_formats() {
+        let nothing = |_: &str| false;
+        assert_eq!(renderer_for(TargetFormat::Html, nothing), Some(BUILTIN_RENDERER));
+        assert_eq!(renderer_for(TargetFormat::Markdown, nothing), Some(BUILTIN_RENDERER));
+        assert_eq!(renderer_for(TargetFormat::Docx, nothing), None);
+        assert_eq!(renderer_for(TargetFormat::Pdf, nothing), None);
+
+        let pandoc_only = |tool: &str| tool == "pandoc";
+        assert_eq!(renderer_for(TargetFormat::Html, pandoc_only), Some("pandoc"));
+        assert_eq!(renderer_for(TargetFormat::Pdf, pandoc_only), None);
+
+        let tex = |tool: &str| tool == "pdflatex" || tool == "qpdf";
+        assert_eq!(renderer_for(TargetFormat::Pdf, tex), Some("pdflatex"));
+        assert_eq!(renderer_for(TargetFormat::Pdfa, tex), Some("pdflatex"));
+        assert_eq!(renderer_for(TargetFormat::Pdfa, |tool: &str| tool == "xelatex"), None);
+    }
+}
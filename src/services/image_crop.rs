@@ -0,0 +1,128 @@
+use image::{GenericImageView, ImageFormat};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// A user supplied crop rectangle, either in pixels or as fractions of the image size.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CropRegion {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub unit: CropUnit,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub enum CropUnit {
+    #[default]
+    #[serde(rename = "px")]
+    Pixels,
+    #[serde(rename = "fraction")]
+    Fraction,
+}
+
+/// A crop rectangle resolved to whole pixels within the image bounds.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct PixelRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug)]
+pub struct CroppedImage {
+    pub bytes: Vec<u8>,
+    pub mime_type: String,
+    pub rect: PixelRect,
+}
+
+pub struct ImageCropper;
+
+impl ImageCropper {
+    /// Crops every region out of the encoded image. Crops are re-encoded as PNG
+    /// so they can be sent to the OCR models regardless of the source format.
+    pub fn crop_all(image_bytes: &[u8], regions: &[CropRegion]) -> Result<Vec<CroppedImage>, String> {
+        let image = image::load_from_memory(image_bytes)
+            .map_err(|e| format!("Failed to decode image: {}", e))?;
+        let (image_width, image_height) = image.dimensions();
+
+        regions
+            .iter()
+            .enumerate()
+            .map(|(index, region)| {
+                let rect = Self::resolve(region, image_width, image_height)
+                    .map_err(|e| format!("Region {}: {}", index, e))?;
+
+                let mut bytes = Vec::new();
+                image
+                    .crop_imm(rect.x, rect.y, rect.width, rect.height)
+                    .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+                    .map_err(|e| format!("Region {}: failed to encode crop: {}", index, e))?;
+
+                Ok(CroppedImage { bytes, mime_type: "image/png".to_string(), rect })
+            })
+            .collect()
+    }
+
+    /// Converts a region to pixel coordinates, clamping it to the image bounds.
+    pub fn resolve(region: &CropRegion, image_width: u32, image_height: u32) -> Result<PixelRect, String> {
+        let values = [region.x, region.y, region.width, region.height];
+        if values.iter().any(|v| !v.is_finite() || *v < 0.0) {
+            return Err("coordinates must be non-negative numbers".to_string());
+        }
+
+        let (x, y, width, height) = match region.unit {
+            CropUnit::Pixels => (region.x, region.y, region.width, region.height),
+            CropUnit::Fraction => {
+                if values.iter().any(|v| *v > 1.0) {
+                    return Err("fractional coordinates must be between 0 and 1".to_string());
+                }
+                (
+                    region.x * image_width as f64,
+                    region.y * image_height as f64,
+                    region.width * image_width as f64,
+                    region.height * image_height as f64,
+                )
+            }
+        };
+
+        let left = (x.floor() as u32).min(image_width);
+        let top = (y.floor() as u32).min(image_height);
+        let right = ((x + width).ceil() as u32).min(image_width);
+        let bottom = ((y + height).ceil() as u32).min(image_height);
+
+        if right <= left || bottom <= top {
+            return Err("region is empty or lies outside the image".to_string());
+        }
+
+        Ok(PixelRect { x: left, y: top, width: right - left, height: bottom - top })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: f64, y: f64, width: f64, height: f64, unit: CropUnit) -> CropRegion {
+        CropRegion { x, y, width, height, unit, label: None }
+    }
+
+    #[test]
+    fn test_resolve_fraction_and_clamp() {
+        let rect = ImageCropper::resolve(&region(0.5, 0.25, 0.5, 0.5, CropUnit::Fraction), 200, 100).unwrap();
+        assert_eq!(rect, PixelRect { x: 100, y: 25, width: 100, height: 50 });
+
+        let clamped = ImageCropper::resolve(&region(150.0, 0.0, 500.0, 40.0, CropUnit::Pixels), 200, 100).unwrap();
+        assert_eq!(clamped, PixelRect { x: 150, y: 0, width: 50, height: 40 });
+    }
+
+    #[test]
+    fn test_resolve_rejects_invalid_regions() {
+        assert!(ImageCropper::resolve(&region(250.0, 0.0, 10.0, 10.0, CropUnit::Pixels), 200, 100).is_err());
+        assert!(ImageCropper::resolve(&region(0.0, 0.0, 1.5, 0.5, CropUnit::Fraction), 200, 100).is_err());
+        assert!(ImageCropper::resolve(&region(-1.0, 0.0, 10.0, 10.0, CropUnit::Pixels), 200, 100).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Serialize;
+
+/// Log lines searched after a `!` error for the `l.<n>` line that locates it.
+const LOCATION_LOOKAHEAD: usize = 20;
+
+/// One error from a TeX engine's log, located in the user's text.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct LatexError {
+    pub message: String,
+    pub line: Option<usize>,         // 1-based line in the user's text; None when the error is in the template
+    pub context: Option<String>,     // Source TeX had read on that line when it stopped
+    pub suggestion: Option<String>,
+}
+
+/// Known error messages and a fix to suggest for each, matched by substring.
+const SUGGESTIONS: &[(&str, &str)] = &[
+    ("Undefined control sequence", "Check the spelling of the command, or remove it; only standard LaTeX, amsmath and amssymb commands are available."),
+    ("Missing $ inserted", "Math such as ^, _ or \\frac must be inside $...$ or $$...$$."),
+    ("Display math should end with $$", "Close the display equation with $$."),
+    ("Extra }, or forgotten $", "Check that every { has a matching } and every $ is closed."),
+    ("Missing } inserted", "Check that every { has a matching }."),
+    ("Missing { inserted", "Wrap the command's argument in braces."),
+    ("Extra alignment tab", "A row has more & separators than its table or matrix has columns."),
+    ("Misplaced alignment tab character &", "Write \\& for a literal ampersand."),
+    ("Double superscript", "Group the exponents in braces, e.g. x^{a^b}."),
+    ("Double subscript", "Group the subscripts in braces, e.g. x_{i_j}."),
+    ("Environment", "Check the environment name and that \\begin and \\end match."),
+    ("\\begin{", "Check that every \\begin{...} has a matching \\end{...}."),
+    ("not found", "The file is not available on the server; remove the line that loads it."),
+    ("Unicode character", "This character is not supported by pdfLaTeX; the XeLaTeX engine or a LaTeX command for it is needed."),
+    ("Missing number", "A length or number was expected, e.g. \\vspace{1em}."),
+    ("Illegal unit of measure", "Add a unit such as pt, em or cm to the length."),
+];
+
+/// Extracts the `!` errors from a TeX log. `content_start` is the log line the
+/// user's text begins on (the preamble comes before it) and `content_lines` how
+/// many lines it spans, so reported lines refer to the user's own text.
+pub fn parse_latex_log(log: &str, content_start: usize, content_lines: usize) -> Vec<LatexError> {
+    let lines: Vec<&str> = log.lines().collect();
+    let mut errors: Vec<LatexError> = Vec::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        let message = match line.strip_prefix("! ") {
+            Some(message) => message.trim(),
+            None => continue,
+        };
+        if message.is_empty() || message.starts_with("Emergency stop") {
+            continue;
+        }
+
+        let mut tex_line = None;
+        let mut context = None;
+        for next in lines.iter().skip(i + 1).take(LOCATION_LOOKAHEAD) {
+            if next.starts_with("! ") {
+                break;
+            }
+            if let Some(location) = next.strip_prefix("l.") {
+                let (number, rest) = location.split_once(' ').unwrap_or((location, ""));
+                if let Ok(number) = number.parse::<usize>() {
+                    tex_line = Some(number);
+                    context = Some(rest.trim().to_string()).filter(|c| !c.is_empty());
+                    break;
+                }
+            }
+        }
+
+        let error = LatexError {
+            message: message.to_string(),
+            line: tex_line
+                .filter(|l| *l >= content_start && *l < content_start + content_lines)
+                .map(|l| l - content_start + 1),
+            context,
+            suggestion: suggest_fix(message).map(str::to_string),
+        };
+        if !errors.iter().any(|e| e.message == error.message && e.line == error.line) {
+            errors.push(error);
+        }
+    }
+    errors
+}
+
+fn suggest_fix(message: &str) -> Option<&'static str> {
+    SUGGESTIONS
+        .iter()
+        .find(|(pattern, _)| message.contains(pattern))
+        .map(|(_, suggestion)| *suggestion)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_log_maps_lines_past_the_preamble() {
+        let log = "This is XeTeX\n\
+            ! Undefined control sequence.\n\
+            l.7 Area $\\pii\n\
+            \x20              r^2$\n\
+            \n\
+            ! LaTeX Error: File `foo.sty' not found.\n\
+            \n\
+            l.3 \\usepackage\n\
+            ! Emergency stop.\n";
+        let errors = parse_latex_log(log, 5, 10);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Undefined control sequence.");
+        assert_eq!(errors[0].line, Some(3));
+        assert_eq!(errors[0].context.as_deref(), Some("Area $\\pii"));
+        assert!(errors[0].suggestion.as_deref().unwrap().starts_with("Check the spelling"));
+        // Line 3 is in the injected preamble, so it has no line in the user's text.
+        assert_eq!(errors[1].line, None);
+        assert!(errors[1].suggestion.is_some());
+    }
+}
@@ -0,0 +1,1094 @@
+use serde::Serialize;
+
+/// Characters of surrounding text kept on each side of an equation.
+const CONTEXT_CHARS: usize = 40;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum MathKind {
+    #[serde(rename = "inline")]
+    Inline,
+    #[serde(rename = "display")]
+    Display,
+}
+
+/// One math segment found in a transcription. Offsets are character (not byte)
+/// positions in the source text and span the delimiters.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct MathSegment {
+    pub index: usize,
+    pub kind: MathKind,
+    pub latex: String,
+    pub start: usize,
+    pub end: usize,
+    pub context_before: String,
+    pub context_after: String,
+}
+
+/// Splits text into its math segments using the `$...$` / `$$...$$` convention
+/// the OCR prompt enforces. `\(...\)` and `\[...\]` are accepted too, and `\$`
+/// is treated as a literal dollar sign.
+pub fn extract_math_segments(text: &str) -> Vec<MathSegment> {
+    let chars: Vec<char> = text.chars().collect();
+    let n = chars.len();
+    let mut segments = Vec::new();
+    let mut i = 0;
+
+    while i < n {
+        let found = match chars[i] {
+            '\\' if i + 1 < n => match chars[i + 1] {
+                '(' => find_closing(&chars, i + 2, &['\\', ')']).map(|close| (MathKind::Inline, 2, close)),
+                '[' => find_closing(&chars, i + 2, &['\\', ']']).map(|close| (MathKind::Display, 2, close)),
+                _ => {
+                    // Skip the escaped character, which covers `\$`.
+                    i += 2;
+                    continue;
+                }
+            },
+            '$' if i + 1 < n && chars[i + 1] == '$' => {
+                find_closing(&chars, i + 2, &['$', '$']).map(|close| (MathKind::Display, 2, close))
+            }
+            '$' => find_closing(&chars, i + 1, &['$']).map(|close| (MathKind::Inline, 1, close)),
+            _ => None,
+        };
+
+        match found {
+            Some((kind, delimiter_len, close)) => {
+                let end = close + delimiter_len;
+                let latex: String = chars[i + delimiter_len..close].iter().collect();
+                if !latex.trim().is_empty() {
+                    segments.push(MathSegment {
+                        index: segments.len(),
+                        kind,
+                        latex: latex.trim().to_string(),
+                        start: i,
+                        end,
+                        context_before: chars[i.saturating_sub(CONTEXT_CHARS)..i].iter().collect::<String>().trim().to_string(),
+                        context_after: chars[end..(end + CONTEXT_CHARS).min(n)].iter().collect::<String>().trim().to_string(),
+                    });
+                }
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+
+    segments
+}
+
+/// Finds the start of the closing delimiter at or after `from`, skipping escaped characters.
+fn find_closing(chars: &[char], from: usize, delimiter: &[char]) -> Option<usize> {
+    let mut j = from;
+    while j + delimiter.len() <= chars.len() {
+        if chars[j] == '\\' && delimiter[0] != '\\' {
+            j += 2;
+            continue;
+        }
+        if chars[j..j + delimiter.len()] == *delimiter {
+            return Some(j);
+        }
+        j += 1;
+    }
+    None
+}
+
+/// Output formats for math segments rendered in-process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MathOutputFormat {
+    MathMl,
+    Unicode,
+}
+
+impl MathOutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "mathml" => Some(MathOutputFormat::MathMl),
+            "unicode" => Some(MathOutputFormat::Unicode),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MathOutputFormat::MathMl => "mathml",
+            MathOutputFormat::Unicode => "unicode",
+        }
+    }
+
+    pub fn render(&self, node: &MathNode, display: bool) -> String {
+        match self {
+            MathOutputFormat::MathMl => to_mathml(node, display),
+            MathOutputFormat::Unicode => to_unicode(node),
+        }
+    }
+}
+
+/// Replaces every math segment in `text` with its rendering in `format`.
+/// Segments that fail to parse are left as the original LaTeX.
+pub fn render_math_in_text(text: &str, format: MathOutputFormat) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+
+    for segment in extract_math_segments(text) {
+        out.extend(&chars[last..segment.start]);
+        match parse_latex_math(&segment.latex) {
+            Ok(node) => out.push_str(&format.render(&node, segment.kind == MathKind::Display)),
+            Err(_) => out.extend(&chars[segment.start..segment.end]),
+        }
+        last = segment.end;
+    }
+
+    out.extend(&chars[last..]);
+    out
+}
+
+// ---------------------------------------------------------------------------
+// Syntax tree
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MathNode {
+    Number(String),
+    Identifier(String),
+    Operator(String),
+    Function(String),  // Upright named functions such as sin or lim
+    Text(String),
+    Space,
+    Row(Vec<MathNode>),
+    Frac(Box<MathNode>, Box<MathNode>),
+    Binom(Box<MathNode>, Box<MathNode>),
+    Sqrt { index: Option<Box<MathNode>>, body: Box<MathNode> },
+    Scripts { base: Box<MathNode>, sub: Option<Box<MathNode>>, sup: Option<Box<MathNode>> },
+    Fenced { open: String, close: String, body: Box<MathNode> },
+    Styled { variant: MathVariant, body: Box<MathNode> },
+    Accent { accent: AccentKind, body: Box<MathNode> },
+    Matrix { open: String, close: String, rows: Vec<Vec<MathNode>> },
+}
+
+impl MathNode {
+    /// Wraps a list of nodes, unwrapping single-element rows.
+    fn row(mut nodes: Vec<MathNode>) -> MathNode {
+        if nodes.len() == 1 { nodes.pop().unwrap() } else { MathNode::Row(nodes) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MathVariant {
+    Normal,
+    Italic,
+    Bold,
+    DoubleStruck,
+    Calligraphic,
+    Fraktur,
+    SansSerif,
+    Monospace,
+}
+
+impl MathVariant {
+    fn from_command(name: &str) -> Option<Self> {
+        match name {
+            "mathrm" => Some(MathVariant::Normal),
+            "mathit" => Some(MathVariant::Italic),
+            "mathbf" | "boldsymbol" | "bm" => Some(MathVariant::Bold),
+            "mathbb" => Some(MathVariant::DoubleStruck),
+            "mathcal" | "mathscr" => Some(MathVariant::Calligraphic),
+            "mathfrak" => Some(MathVariant::Fraktur),
+            "mathsf" => Some(MathVariant::SansSerif),
+            "mathtt" => Some(MathVariant::Monospace),
+            _ => None,
+        }
+    }
+
+    fn mathml_name(&self) -> &'static str {
+        match self {
+            MathVariant::Normal => "normal",
+            MathVariant::Italic => "italic",
+            MathVariant::Bold => "bold",
+            MathVariant::DoubleStruck => "double-struck",
+            MathVariant::Calligraphic => "script",
+            MathVariant::Fraktur => "fraktur",
+            MathVariant::SansSerif => "sans-serif",
+            MathVariant::Monospace => "monospace",
+        }
+    }
+
+    /// Maps a character to its Mathematical Alphanumeric Symbols form where one exists.
+    fn apply(&self, c: char) -> char {
+        let offset = |base_upper: u32, base_lower: u32| -> Option<char> {
+            match c {
+                'A'..='Z' => char::from_u32(base_upper + (c as u32 - 'A' as u32)),
+                'a'..='z' => char::from_u32(base_lower + (c as u32 - 'a' as u32)),
+                _ => None,
+            }
+        };
+        let mapped = match self {
+            MathVariant::Bold => match c {
+                '0'..='9' => char::from_u32(0x1D7CE + (c as u32 - '0' as u32)),
+                _ => offset(0x1D400, 0x1D41A),
+            },
+            MathVariant::DoubleStruck => match c {
+                'C' => Some('ℂ'), 'H' => Some('ℍ'), 'N' => Some('ℕ'), 'P' => Some('ℙ'),
+                'Q' => Some('ℚ'), 'R' => Some('ℝ'), 'Z' => Some('ℤ'),
+                '0'..='9' => char::from_u32(0x1D7D8 + (c as u32 - '0' as u32)),
+                _ => offset(0x1D538, 0x1D552),
+            },
+            MathVariant::Calligraphic => match c {
+                'B' => Some('ℬ'), 'E' => Some('ℰ'), 'F' => Some('ℱ'), 'H' => Some('ℋ'),
+                'I' => Some('ℐ'), 'L' => Some('ℒ'), 'M' => Some('ℳ'), 'R' => Some('ℛ'),
+                'a'..='z' => None,
+                _ => offset(0x1D49C, 0x1D4B6),
+            },
+            _ => None,
+        };
+        mapped.unwrap_or(c)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccentKind {
+    Hat,
+    Bar,
+    Vec,
+    Dot,
+    DoubleDot,
+    Tilde,
+}
+
+impl AccentKind {
+    fn from_command(name: &str) -> Option<Self> {
+        match name {
+            "hat" | "widehat" => Some(AccentKind::Hat),
+            "bar" | "overline" => Some(AccentKind::Bar),
+            "vec" | "overrightarrow" => Some(AccentKind::Vec),
+            "dot" => Some(AccentKind::Dot),
+            "ddot" => Some(AccentKind::DoubleDot),
+            "tilde" | "widetilde" => Some(AccentKind::Tilde),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccentKind::Hat => "hat",
+            AccentKind::Bar => "bar",
+            AccentKind::Vec => "vec",
+            AccentKind::Dot => "dot",
+            AccentKind::DoubleDot => "ddot",
+            AccentKind::Tilde => "tilde",
+        }
+    }
+
+    fn mathml_char(&self) -> &'static str {
+        match self {
+            AccentKind::Hat => "^",
+            AccentKind::Bar => "¯",
+            AccentKind::Vec => "→",
+            AccentKind::Dot => "˙",
+            AccentKind::DoubleDot => "¨",
+            AccentKind::Tilde => "~",
+        }
+    }
+
+    fn combining_char(&self) -> char {
+        match self {
+            AccentKind::Hat => '\u{0302}',
+            AccentKind::Bar => '\u{0304}',
+            AccentKind::Vec => '\u{20D7}',
+            AccentKind::Dot => '\u{0307}',
+            AccentKind::DoubleDot => '\u{0308}',
+            AccentKind::Tilde => '\u{0303}',
+        }
+    }
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "max", "min", "sup", "inf", "det", "gcd", "deg", "dim", "ker", "arg", "Pr",
+];
+
+/// Functions and operators whose limits are set above and below rather than as scripts.
+const UNDER_OVER: &[&str] = &["∑", "∏", "∐", "⋃", "⋂", "lim", "max", "min", "sup", "inf"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum SymbolClass {
+    Identifier,
+    Operator,
+}
+
+/// Command name, Unicode character and class for every supported symbol.
+const SYMBOLS: &[(&str, &str, SymbolClass)] = {
+    use SymbolClass::{Identifier as I, Operator as O};
+    &[
+        ("alpha", "α", I), ("beta", "β", I), ("gamma", "γ", I), ("delta", "δ", I), ("epsilon", "ϵ", I),
+        ("varepsilon", "ε", I), ("zeta", "ζ", I), ("eta", "η", I), ("theta", "θ", I), ("vartheta", "ϑ", I),
+        ("iota", "ι", I), ("kappa", "κ", I), ("lambda", "λ", I), ("mu", "μ", I), ("nu", "ν", I),
+        ("xi", "ξ", I), ("pi", "π", I), ("varpi", "ϖ", I), ("rho", "ρ", I), ("varrho", "ϱ", I),
+        ("sigma", "σ", I), ("varsigma", "ς", I), ("tau", "τ", I), ("upsilon", "υ", I), ("phi", "ϕ", I),
+        ("varphi", "φ", I), ("chi", "χ", I), ("psi", "ψ", I), ("omega", "ω", I),
+        ("Gamma", "Γ", I), ("Delta", "Δ", I), ("Theta", "Θ", I), ("Lambda", "Λ", I), ("Xi", "Ξ", I),
+        ("Pi", "Π", I), ("Sigma", "Σ", I), ("Upsilon", "Υ", I), ("Phi", "Φ", I), ("Psi", "Ψ", I),
+        ("Omega", "Ω", I),
+        ("infty", "∞", I), ("partial", "∂", I), ("nabla", "∇", I), ("emptyset", "∅", I), ("varnothing", "∅", I),
+        ("hbar", "ℏ", I), ("ell", "ℓ", I), ("aleph", "ℵ", I), ("Re", "ℜ", I), ("Im", "ℑ", I),
+        ("prime", "′", I), ("angle", "∠", I), ("triangle", "△", I),
+        ("forall", "∀", O), ("exists", "∃", O), ("neg", "¬", O), ("lnot", "¬", O),
+        ("pm", "±", O), ("mp", "∓", O), ("times", "×", O), ("div", "÷", O), ("cdot", "⋅", O),
+        ("ast", "∗", O), ("star", "⋆", O), ("circ", "∘", O), ("bullet", "•", O),
+        ("le", "≤", O), ("leq", "≤", O), ("ge", "≥", O), ("geq", "≥", O), ("neq", "≠", O), ("ne", "≠", O),
+        ("approx", "≈", O), ("equiv", "≡", O), ("sim", "∼", O), ("simeq", "≃", O), ("cong", "≅", O),
+        ("propto", "∝", O), ("ll", "≪", O), ("gg", "≫", O),
+        ("in", "∈", O), ("notin", "∉", O), ("ni", "∋", O), ("subset", "⊂", O), ("subseteq", "⊆", O),
+        ("supset", "⊃", O), ("supseteq", "⊇", O), ("cup", "∪", O), ("cap", "∩", O), ("setminus", "∖", O),
+        ("wedge", "∧", O), ("land", "∧", O), ("vee", "∨", O), ("lor", "∨", O), ("oplus", "⊕", O),
+        ("otimes", "⊗", O),
+        ("to", "→", O), ("rightarrow", "→", O), ("leftarrow", "←", O), ("gets", "←", O),
+        ("Rightarrow", "⇒", O), ("Leftarrow", "⇐", O), ("Leftrightarrow", "⇔", O), ("leftrightarrow", "↔", O),
+        ("iff", "⟺", O), ("implies", "⟹", O), ("mapsto", "↦", O), ("uparrow", "↑", O), ("downarrow", "↓", O),
+        ("mid", "∣", O), ("parallel", "∥", O), ("perp", "⊥", O),
+        ("sum", "∑", O), ("prod", "∏", O), ("coprod", "∐", O), ("int", "∫", O), ("iint", "∬", O),
+        ("iiint", "∭", O), ("oint", "∮", O), ("bigcup", "⋃", O), ("bigcap", "⋂", O),
+        ("ldots", "…", O), ("dots", "…", O), ("cdots", "⋯", O), ("vdots", "⋮", O), ("ddots", "⋱", O),
+        ("langle", "⟨", O), ("rangle", "⟩", O), ("lfloor", "⌊", O), ("rfloor", "⌋", O),
+        ("lceil", "⌈", O), ("rceil", "⌉", O), ("vert", "|", O), ("lvert", "|", O), ("rvert", "|", O),
+        ("Vert", "‖", O), ("lVert", "‖", O), ("rVert", "‖", O), ("colon", ":", O),
+        ("therefore", "∴", O), ("because", "∵", O),
+    ]
+};
+
+fn lookup_symbol(name: &str) -> Option<(&'static str, SymbolClass)> {
+    SYMBOLS.iter().find(|(command, _, _)| *command == name).map(|(_, symbol, class)| (*symbol, *class))
+}
+
+// ---------------------------------------------------------------------------
+// Parser
+// ---------------------------------------------------------------------------
+
+/// Parses the body of a math segment (without delimiters) into a syntax tree.
+/// Unknown commands are kept as text so that OCR noise degrades gracefully;
+/// only structural problems such as unbalanced braces are errors.
+pub fn parse_latex_math(latex: &str) -> Result<MathNode, String> {
+    let mut parser = Parser { chars: latex.chars().collect(), pos: 0, matrix_depth: 0, depth: 0 };
+    let nodes = parser.parse_row()?;
+    if parser.pos < parser.chars.len() {
+        return Err(format!("unexpected '{}' at position {}", parser.chars[parser.pos], parser.pos));
+    }
+    Ok(MathNode::row(nodes))
+}
+
+/// Deepest nesting of groups and commands the parser follows. The parser and
+/// the renderers recurse once per level, so this bounds their stack use; real
+/// formulas stay far below it.
+const MAX_NESTING_DEPTH: usize = 64;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    matrix_depth: usize,
+    depth: usize,  // Groups and commands currently open
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        let len = s.chars().count();
+        self.chars.len() >= self.pos + len && self.chars[self.pos..self.pos + len].iter().copied().eq(s.chars())
+    }
+
+    fn starts_with_command(&self, name: &str) -> bool {
+        let after = self.pos + 1 + name.chars().count();
+        self.peek() == Some('\\')
+            && self.chars[self.pos + 1..].iter().take(name.len()).copied().eq(name.chars())
+            && !self.chars.get(after).is_some_and(|c| c.is_ascii_alphabetic())
+    }
+
+    /// True at a token that ends the current row: a closing brace, `\right`,
+    /// `\end`, or a cell/row separator inside an environment.
+    fn at_stop(&self) -> bool {
+        match self.peek() {
+            None | Some('}') => true,
+            Some('&') => self.matrix_depth > 0,
+            Some('\\') => {
+                (self.matrix_depth > 0 && self.starts_with("\\\\"))
+                    || self.starts_with_command("right")
+                    || self.starts_with_command("end")
+            }
+            _ => false,
+        }
+    }
+
+    fn parse_row(&mut self) -> Result<Vec<MathNode>, String> {
+        let mut nodes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.at_stop() {
+                return Ok(nodes);
+            }
+            match self.peek() {
+                Some('^') | Some('_') => {
+                    let base = nodes.pop().unwrap_or(MathNode::Row(Vec::new()));
+                    nodes.push(self.parse_scripts(base)?);
+                }
+                _ => {
+                    if let Some(node) = self.parse_atom()? {
+                        nodes.push(node);
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_scripts(&mut self, base: MathNode) -> Result<MathNode, String> {
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            self.skip_whitespace();
+            let slot = match self.peek() {
+                Some('^') => &mut sup,
+                Some('_') => &mut sub,
+                _ => break,
+            };
+            if slot.is_some() {
+                return Err(format!("double script at position {}", self.pos));
+            }
+            self.pos += 1;
+            *slot = Some(Box::new(self.parse_argument()?));
+        }
+        Ok(MathNode::Scripts { base: Box::new(base), sub, sup })
+    }
+
+    /// Parses a single command argument: a braced group, a command or one character.
+    fn parse_argument(&mut self) -> Result<MathNode, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err("missing argument at end of input".to_string()),
+            Some(c) if c.is_ascii_digit() => {
+                self.pos += 1;
+                Ok(MathNode::Number(c.to_string()))
+            }
+            Some(_) if self.at_stop() => Err(format!("missing argument at position {}", self.pos)),
+            Some(_) => self.parse_atom()?.ok_or_else(|| format!("missing argument at position {}", self.pos)),
+        }
+    }
+
+    /// Runs `step` one nesting level deeper, failing past `MAX_NESTING_DEPTH`.
+    fn nested<T>(&mut self, step: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(format!("nested more than {} levels deep at position {}", MAX_NESTING_DEPTH, self.pos));
+        }
+        self.depth += 1;
+        let result = step(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_group(&mut self) -> Result<MathNode, String> {
+        self.pos += 1; // '{'
+        let nodes = self.parse_row()?;
+        if self.peek() != Some('}') {
+            return Err("unbalanced braces".to_string());
+        }
+        self.pos += 1;
+        Ok(MathNode::row(nodes))
+    }
+
+    /// Reads the raw contents of a braced group, used for text and environment names.
+    fn read_raw_group(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        if self.peek() != Some('{') {
+            return self.peek().map(|c| {
+                self.pos += 1;
+                c.to_string()
+            }).ok_or_else(|| "missing argument at end of input".to_string());
+        }
+        self.pos += 1;
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    let raw = self.chars[start..self.pos].iter().collect();
+                    self.pos += 1;
+                    return Ok(raw);
+                }
+                '}' => depth -= 1,
+                '\\' => self.pos += 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Err("unbalanced braces".to_string())
+    }
+
+    fn parse_atom(&mut self) -> Result<Option<MathNode>, String> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        if c == '{' {
+            return self.nested(Self::parse_group).map(Some);
+        }
+        if c == '\\' {
+            return self.nested(Self::parse_command);
+        }
+
+        let next_is_digit = self.chars.get(self.pos + 1).is_some_and(|n| n.is_ascii_digit());
+        if c.is_ascii_digit() || (c == '.' && next_is_digit) {
+            let start = self.pos;
+            while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                self.pos += 1;
+            }
+            return Ok(Some(MathNode::Number(self.chars[start..self.pos].iter().collect())));
+        }
+
+        self.pos += 1;
+        let node = match c {
+            c if c.is_alphabetic() => MathNode::Identifier(c.to_string()),
+            '~' => MathNode::Space,
+            '&' => return Ok(None),
+            '\'' => MathNode::Operator("′".to_string()),
+            '-' => MathNode::Operator("−".to_string()),
+            '*' => MathNode::Operator("∗".to_string()),
+            c => MathNode::Operator(c.to_string()),
+        };
+        Ok(Some(node))
+    }
+
+    fn read_command_name(&mut self) -> Result<String, String> {
+        self.pos += 1; // '\'
+        let start = self.pos;
+        match self.peek() {
+            None => Err("trailing backslash".to_string()),
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.pos += 1;
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+            Some(c) => {
+                self.pos += 1;
+                Ok(c.to_string())
+            }
+        }
+    }
+
+    fn parse_command(&mut self) -> Result<Option<MathNode>, String> {
+        let name = self.read_command_name()?;
+
+        let node = match name.as_str() {
+            "\\" | "," | ":" | ";" | " " | ">" | "quad" | "qquad" => MathNode::Space,
+            "!" | "displaystyle" | "textstyle" | "scriptstyle" | "limits" | "nolimits" => return Ok(None),
+            "{" | "}" | "$" | "%" | "&" | "#" | "_" => MathNode::Operator(name),
+            "|" => MathNode::Operator("‖".to_string()),
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.parse_argument()?;
+                let denominator = self.parse_argument()?;
+                MathNode::Frac(Box::new(numerator), Box::new(denominator))
+            }
+            "binom" | "dbinom" | "tbinom" => {
+                let n = self.parse_argument()?;
+                let k = self.parse_argument()?;
+                MathNode::Binom(Box::new(n), Box::new(k))
+            }
+            "sqrt" => {
+                self.skip_whitespace();
+                let index = if self.peek() == Some('[') {
+                    let start = self.pos + 1;
+                    let close = self.chars[start..].iter().position(|c| *c == ']')
+                        .ok_or_else(|| "unterminated root index".to_string())?;
+                    self.pos = start + close + 1;
+                    let raw: String = self.chars[start..start + close].iter().collect();
+                    let mut index = Parser { chars: raw.chars().collect(), pos: 0, matrix_depth: 0, depth: self.depth };
+                    let nodes = index.parse_row()?;
+                    if index.pos < index.chars.len() {
+                        return Err("malformed root index".to_string());
+                    }
+                    Some(Box::new(MathNode::row(nodes)))
+                } else {
+                    None
+                };
+                MathNode::Sqrt { index, body: Box::new(self.parse_argument()?) }
+            }
+            "text" | "textrm" | "textit" | "textbf" | "textnormal" | "mbox" => {
+                MathNode::Text(self.read_raw_group()?)
+            }
+            "operatorname" => MathNode::Function(self.read_raw_group()?.trim().to_string()),
+            "left" => {
+                let open = self.parse_delimiter()?;
+                let body = self.parse_row()?;
+                if !self.starts_with_command("right") {
+                    return Err("\\left without matching \\right".to_string());
+                }
+                self.read_command_name()?;
+                let close = self.parse_delimiter()?;
+                MathNode::Fenced { open, close, body: Box::new(MathNode::row(body)) }
+            }
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "biggl" | "biggr" | "Biggl" | "Biggr" => {
+                let delimiter = self.parse_delimiter()?;
+                if delimiter.is_empty() {
+                    return Ok(None);
+                }
+                MathNode::Operator(delimiter)
+            }
+            "begin" => self.parse_environment()?,
+            "not" => match self.parse_argument()? {
+                MathNode::Operator(op) => MathNode::Operator(match op.as_str() {
+                    "=" => "≠".to_string(),
+                    "∈" => "∉".to_string(),
+                    "⊂" => "⊄".to_string(),
+                    "≡" => "≢".to_string(),
+                    "<" => "≮".to_string(),
+                    ">" => "≯".to_string(),
+                    _ => format!("{}\u{0338}", op),
+                }),
+                other => MathNode::Row(vec![MathNode::Operator("¬".to_string()), other]),
+            },
+            name if FUNCTIONS.contains(&name) => MathNode::Function(name.to_string()),
+            name => {
+                if let Some(variant) = MathVariant::from_command(name) {
+                    MathNode::Styled { variant, body: Box::new(self.parse_argument()?) }
+                } else if let Some(accent) = AccentKind::from_command(name) {
+                    MathNode::Accent { accent, body: Box::new(self.parse_argument()?) }
+                } else if let Some((symbol, class)) = lookup_symbol(name) {
+                    match class {
+                        SymbolClass::Identifier => MathNode::Identifier(symbol.to_string()),
+                        SymbolClass::Operator => MathNode::Operator(symbol.to_string()),
+                    }
+                } else {
+                    MathNode::Text(format!("\\{}", name))
+                }
+            }
+        };
+        Ok(Some(node))
+    }
+
+    /// Reads the delimiter after `\left`, `\right` or `\big`. `.` is the empty delimiter.
+    fn parse_delimiter(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err("missing delimiter at end of input".to_string()),
+            Some('\\') => {
+                let name = self.read_command_name()?;
+                match name.as_str() {
+                    "{" | "}" => Ok(name),
+                    "|" => Ok("‖".to_string()),
+                    _ => match lookup_symbol(&name) {
+                        Some((symbol, SymbolClass::Operator)) => Ok(symbol.to_string()),
+                        _ => Err(format!("unsupported delimiter \\{}", name)),
+                    },
+                }
+            }
+            Some(c) => {
+                self.pos += 1;
+                Ok(if c == '.' { String::new() } else { c.to_string() })
+            }
+        }
+    }
+
+    fn parse_environment(&mut self) -> Result<MathNode, String> {
+        let name = self.read_raw_group()?;
+        if name == "array" {
+            self.read_raw_group()?; // Column specification
+        }
+        let (open, close) = match name.as_str() {
+            "pmatrix" => ("(", ")"),
+            "bmatrix" => ("[", "]"),
+            "Bmatrix" => ("{", "}"),
+            "vmatrix" => ("|", "|"),
+            "Vmatrix" => ("‖", "‖"),
+            "cases" => ("{", ""),
+            _ => ("", ""),
+        };
+
+        self.matrix_depth += 1;
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        loop {
+            let cell = self.parse_row()?;
+            row.push(MathNode::row(cell));
+
+            if self.peek() == Some('&') {
+                self.pos += 1;
+            } else if self.starts_with("\\\\") {
+                self.pos += 2;
+                rows.push(std::mem::take(&mut row));
+            } else if self.starts_with_command("end") {
+                self.read_command_name()?;
+                let end = self.read_raw_group()?;
+                if end != name {
+                    return Err(format!("\\begin{{{}}} closed by \\end{{{}}}", name, end));
+                }
+                // A trailing `\\` leaves one empty cell behind.
+                if !(row.len() == 1 && row[0] == MathNode::Row(Vec::new()) && !rows.is_empty()) {
+                    rows.push(row);
+                }
+                break;
+            } else {
+                return Err(format!("unterminated environment {}", name));
+            }
+        }
+        self.matrix_depth -= 1;
+
+        Ok(MathNode::Matrix { open: open.to_string(), close: close.to_string(), rows })
+    }
+}
+
+// ---------------------------------------------------------------------------
+// MathML
+// ---------------------------------------------------------------------------
+
+pub fn to_mathml(node: &MathNode, display: bool) -> String {
+    let mut out = format!(
+        "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"{}\">",
+        if display { "block" } else { "inline" }
+    );
+    write_mathml(node, None, &mut out);
+    out.push_str("</math>");
+    out
+}
+
+fn write_mathml(node: &MathNode, variant: Option<MathVariant>, out: &mut String) {
+    match node {
+        MathNode::Number(n) => out.push_str(&format!("<mn>{}</mn>", escape_xml(n))),
+        MathNode::Identifier(i) => match variant {
+            Some(v) => out.push_str(&format!("<mi mathvariant=\"{}\">{}</mi>", v.mathml_name(), escape_xml(i))),
+            None => out.push_str(&format!("<mi>{}</mi>", escape_xml(i))),
+        },
+        MathNode::Operator(op) => out.push_str(&format!("<mo>{}</mo>", escape_xml(op))),
+        MathNode::Function(name) => out.push_str(&format!("<mi>{}</mi><mo>&#x2061;</mo>", escape_xml(name))),
+        MathNode::Text(text) => out.push_str(&format!("<mtext>{}</mtext>", escape_xml(text))),
+        MathNode::Space => out.push_str("<mspace width=\"0.2em\"/>"),
+        MathNode::Row(nodes) => {
+            out.push_str("<mrow>");
+            for n in nodes {
+                write_mathml(n, variant, out);
+            }
+            out.push_str("</mrow>");
+        }
+        MathNode::Frac(num, den) => {
+            out.push_str("<mfrac>");
+            write_mathml(num, variant, out);
+            write_mathml(den, variant, out);
+            out.push_str("</mfrac>");
+        }
+        MathNode::Binom(n, k) => {
+            out.push_str("<mrow><mo>(</mo><mfrac linethickness=\"0\">");
+            write_mathml(n, variant, out);
+            write_mathml(k, variant, out);
+            out.push_str("</mfrac><mo>)</mo></mrow>");
+        }
+        MathNode::Sqrt { index: None, body } => {
+            out.push_str("<msqrt>");
+            write_mathml(body, variant, out);
+            out.push_str("</msqrt>");
+        }
+        MathNode::Sqrt { index: Some(index), body } => {
+            out.push_str("<mroot>");
+            write_mathml(body, variant, out);
+            write_mathml(index, variant, out);
+            out.push_str("</mroot>");
+        }
+        MathNode::Scripts { base, sub, sup } => {
+            // Functions carry an apply-function operator that must not end up inside the script base.
+            let base_name = match base.as_ref() {
+                MathNode::Operator(s) | MathNode::Function(s) => s.as_str(),
+                _ => "",
+            };
+            let (tag, under, over) = if UNDER_OVER.contains(&base_name) {
+                ("munderover", "munder", "mover")
+            } else {
+                ("msubsup", "msub", "msup")
+            };
+            let tag = match (sub, sup) {
+                (Some(_), Some(_)) => tag,
+                (Some(_), None) => under,
+                _ => over,
+            };
+            out.push_str(&format!("<{}>", tag));
+            match base.as_ref() {
+                MathNode::Function(name) => out.push_str(&format!("<mi>{}</mi>", escape_xml(name))),
+                other => write_mathml(other, variant, out),
+            }
+            for script in [sub, sup].into_iter().flatten() {
+                write_mathml(script, variant, out);
+            }
+            out.push_str(&format!("</{}>", tag));
+        }
+        MathNode::Fenced { open, close, body } => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                out.push_str(&format!("<mo fence=\"true\">{}</mo>", escape_xml(open)));
+            }
+            write_mathml(body, variant, out);
+            if !close.is_empty() {
+                out.push_str(&format!("<mo fence=\"true\">{}</mo>", escape_xml(close)));
+            }
+            out.push_str("</mrow>");
+        }
+        MathNode::Styled { variant, body } => write_mathml(body, Some(*variant), out),
+        MathNode::Accent { accent, body } => {
+            out.push_str("<mover accent=\"true\">");
+            write_mathml(body, variant, out);
+            out.push_str(&format!("<mo>{}</mo></mover>", accent.mathml_char()));
+        }
+        MathNode::Matrix { open, close, rows } => {
+            out.push_str("<mrow>");
+            if !open.is_empty() {
+                out.push_str(&format!("<mo fence=\"true\">{}</mo>", escape_xml(open)));
+            }
+            out.push_str("<mtable>");
+            for row in rows {
+                out.push_str("<mtr>");
+                for cell in row {
+                    out.push_str("<mtd>");
+                    write_mathml(cell, variant, out);
+                    out.push_str("</mtd>");
+                }
+                out.push_str("</mtr>");
+            }
+            out.push_str("</mtable>");
+            if !close.is_empty() {
+                out.push_str(&format!("<mo fence=\"true\">{}</mo>", escape_xml(close)));
+            }
+            out.push_str("</mrow>");
+        }
+    }
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// ---------------------------------------------------------------------------
+// Unicode
+// ---------------------------------------------------------------------------
+
+/// Operators rendered with a space on each side when used between operands.
+const SPACED_OPERATORS: &[&str] = &[
+    "=", "<", ">", "≤", "≥", "≠", "≈", "≡", "∼", "≃", "≅", "∝", "≪", "≫", "→", "←", "⇒", "⇐", "⇔", "↔",
+    "⟺", "⟹", "↦", "∈", "∉", "∋", "⊂", "⊆", "⊃", "⊇", "+", "−", "±", "∓", "×", "÷", "∪", "∩", "∧", "∨",
+];
+
+/// Operators that end an operand, so a following operator is binary rather than unary.
+const CLOSING_OPERATORS: &[&str] = &[")", "]", "}", "|", "‖", "⟩", "⌋", "⌉", "′", "!"];
+
+const SUPERSCRIPTS: &[(char, char)] = &[
+    ('0', '⁰'), ('1', '¹'), ('2', '²'), ('3', '³'), ('4', '⁴'), ('5', '⁵'), ('6', '⁶'), ('7', '⁷'),
+    ('8', '⁸'), ('9', '⁹'), ('+', '⁺'), ('−', '⁻'), ('-', '⁻'), ('=', '⁼'), ('(', '⁽'), (')', '⁾'),
+    ('a', 'ᵃ'), ('b', 'ᵇ'), ('c', 'ᶜ'), ('d', 'ᵈ'), ('e', 'ᵉ'), ('f', 'ᶠ'), ('g', 'ᵍ'), ('h', 'ʰ'),
+    ('i', 'ⁱ'), ('j', 'ʲ'), ('k', 'ᵏ'), ('l', 'ˡ'), ('m', 'ᵐ'), ('n', 'ⁿ'), ('o', 'ᵒ'), ('p', 'ᵖ'),
+    ('r', 'ʳ'), ('s', 'ˢ'), ('t', 'ᵗ'), ('u', 'ᵘ'), ('v', 'ᵛ'), ('w', 'ʷ'), ('x', 'ˣ'), ('y', 'ʸ'),
+    ('z', 'ᶻ'), ('T', 'ᵀ'), ('′', '′'), ('*', '*'), ('∗', '*'),
+];
+
+const SUBSCRIPTS: &[(char, char)] = &[
+    ('0', '₀'), ('1', '₁'), ('2', '₂'), ('3', '₃'), ('4', '₄'), ('5', '₅'), ('6', '₆'), ('7', '₇'),
+    ('8', '₈'), ('9', '₉'), ('+', '₊'), ('−', '₋'), ('-', '₋'), ('=', '₌'), ('(', '₍'), (')', '₎'),
+    ('a', 'ₐ'), ('e', 'ₑ'), ('h', 'ₕ'), ('i', 'ᵢ'), ('j', 'ⱼ'), ('k', 'ₖ'), ('l', 'ₗ'), ('m', 'ₘ'),
+    ('n', 'ₙ'), ('o', 'ₒ'), ('p', 'ₚ'), ('r', 'ᵣ'), ('s', 'ₛ'), ('t', 'ₜ'), ('u', 'ᵤ'), ('v', 'ᵥ'),
+    ('x', 'ₓ'),
+];
+
+pub fn to_unicode(node: &MathNode) -> String {
+    unicode(node).split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn unicode(node: &MathNode) -> String {
+    match node {
+        MathNode::Number(s) | MathNode::Identifier(s) | MathNode::Operator(s) | MathNode::Function(s) | MathNode::Text(s) => s.clone(),
+        MathNode::Space => " ".to_string(),
+        MathNode::Row(nodes) => {
+            let mut out = String::new();
+            for (i, n) in nodes.iter().enumerate() {
+                match n {
+                    MathNode::Operator(op) if op == "," => out.push_str(", "),
+                    MathNode::Operator(op) if SPACED_OPERATORS.contains(&op.as_str())
+                        && i > 0
+                        && !matches!(&nodes[i - 1], MathNode::Operator(prev) if !CLOSING_OPERATORS.contains(&prev.as_str())) => {
+                        out.push_str(&format!(" {} ", op));
+                    }
+                    MathNode::Function(name) => {
+                        out.push_str(name);
+                        if !matches!(nodes.get(i + 1), None | Some(MathNode::Operator(_)) | Some(MathNode::Fenced { .. })) {
+                            out.push(' ');
+                        }
+                    }
+                    other => out.push_str(&unicode(other)),
+                }
+            }
+            out
+        }
+        MathNode::Frac(num, den) => {
+            let vulgar = match (num.as_ref(), den.as_ref()) {
+                (MathNode::Number(n), MathNode::Number(d)) => match (n.as_str(), d.as_str()) {
+                    ("1", "2") => Some("½"),
+                    ("1", "3") => Some("⅓"),
+                    ("2", "3") => Some("⅔"),
+                    ("1", "4") => Some("¼"),
+                    ("3", "4") => Some("¾"),
+                    _ => None,
+                },
+                _ => None,
+            };
+            match vulgar {
+                Some(v) => v.to_string(),
+                None => format!("{}/{}", wrap_unicode(num), wrap_unicode(den)),
+            }
+        }
+        MathNode::Binom(n, k) => format!("C({}, {})", unicode(n).trim(), unicode(k).trim()),
+        MathNode::Sqrt { index, body } => {
+            let radical = match index.as_deref().map(|i| compact(&unicode(i))) {
+                None => "√".to_string(),
+                Some(i) if i == "3" => "∛".to_string(),
+                Some(i) if i == "4" => "∜".to_string(),
+                Some(i) => match map_chars(&i, SUPERSCRIPTS) {
+                    Some(sup) => format!("{}√", sup),
+                    None => format!("√[{}]", i),
+                },
+            };
+            format!("{}{}", radical, wrap_unicode(body))
+        }
+        MathNode::Scripts { base, sub, sup } => {
+            let mut out = wrap_unicode(base);
+            if let Some(sub) = sub {
+                out.push_str(&script_unicode(sub, SUBSCRIPTS, '_'));
+            }
+            if let Some(sup) = sup {
+                out.push_str(&script_unicode(sup, SUPERSCRIPTS, '^'));
+            }
+            out
+        }
+        MathNode::Fenced { open, close, body } => format!("{}{}{}", open, unicode(body).trim(), close),
+        MathNode::Styled { variant, body } => unicode(body).chars().map(|c| variant.apply(c)).collect(),
+        MathNode::Accent { accent, body } => {
+            let body = unicode(body);
+            if body.chars().count() == 1 {
+                format!("{}{}", body, accent.combining_char())
+            } else {
+                format!("{}({})", accent.name(), body.trim())
+            }
+        }
+        MathNode::Matrix { open, close, rows } => {
+            let body = rows
+                .iter()
+                .map(|row| row.iter().map(to_unicode).collect::<Vec<_>>().join(", "))
+                .collect::<Vec<_>>()
+                .join("; ");
+            format!("{}{}{}", open, body, close)
+        }
+    }
+}
+
+/// Renders a node, parenthesising it unless it reads as a single operand.
+fn wrap_unicode(node: &MathNode) -> String {
+    let atomic = matches!(
+        node,
+        MathNode::Number(_) | MathNode::Identifier(_) | MathNode::Fenced { .. } | MathNode::Matrix { .. }
+            | MathNode::Scripts { .. } | MathNode::Sqrt { .. } | MathNode::Accent { .. } | MathNode::Styled { .. }
+            | MathNode::Binom(..)
+    ) || matches!(node, MathNode::Operator(op) if op.chars().count() == 1);
+    let rendered = to_unicode(node);
+    if atomic { rendered } else { format!("({})", rendered) }
+}
+
+fn script_unicode(node: &MathNode, table: &[(char, char)], marker: char) -> String {
+    let text = compact(&unicode(node));
+    match map_chars(&text, table) {
+        Some(mapped) => mapped,
+        None if text.chars().count() == 1 => format!("{}{}", marker, text),
+        None => format!("{}({})", marker, text),
+    }
+}
+
+fn compact(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// Maps every character through `table`, or returns None if any has no entry.
+fn map_chars(s: &str, table: &[(char, char)]) -> Option<String> {
+    if s.is_empty() {
+        return None;
+    }
+    s.chars().map(|c| table.iter().find(|(from, _)| *from == c).map(|(_, to)| *to)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deep_nesting_is_an_error_not_a_stack_overflow() {
+        let braces = format!("{}x{}", "{".repeat(5000), "}".repeat(5000));
+        assert!(parse_latex_math(&braces).is_err());
+        let roots = format!("{}x", "\\sqrt".repeat(5000));
+        assert!(parse_latex_math(&roots).is_err());
+        let scripts = format!("x{}", "^{x".repeat(5000));
+        assert!(parse_latex_math(&scripts).is_err());
+
+        // The deepest accepted input still renders.
+        let deepest = format!("{}x", "\\sqrt".repeat(MAX_NESTING_DEPTH - 1));
+        let node = parse_latex_math(&deepest).unwrap();
+        assert!(!MathOutputFormat::MathMl.render(&node, true).is_empty());
+        assert!(!MathOutputFormat::Unicode.render(&node, false).is_empty());
+    }
+
+    #[test]
+    fn test_extracts_inline_and_display_math() {
+        let text = "Let $f(x) = x^2$ then\n$$ \\int_a^b f(t) dt $$ costs \\$5.";
+        let segments = extract_math_segments(text);
+        assert_eq!(segments.len(), 2);
+
+        assert_eq!(segments[0].kind, MathKind::Inline);
+        assert_eq!(segments[0].latex, "f(x) = x^2");
+        assert_eq!((segments[0].start, segments[0].end), (4, 16));
+        assert_eq!(segments[0].context_before, "Let");
+
+        assert_eq!(segments[1].kind, MathKind::Display);
+        assert_eq!(segments[1].latex, "\\int_a^b f(t) dt");
+        assert_eq!(segments[1].context_after, "costs \\$5.");
+    }
+
+    #[test]
+    fn test_bracket_delimiters_and_unclosed_dollar() {
+        let segments = extract_math_segments("A \\(a+b\\) and \\[c\\] but $ unclosed");
+        let latex: Vec<&str> = segments.iter().map(|s| s.latex.as_str()).collect();
+        assert_eq!(latex, vec!["a+b", "c"]);
+        assert_eq!(segments[1].kind, MathKind::Display);
+    }
+
+    #[test]
+    fn test_parse_and_render_mathml() {
+        let node = parse_latex_math("\\frac{a}{b} + x^2").unwrap();
+        assert_eq!(
+            to_mathml(&node, false),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"inline\"><mrow><mfrac><mi>a</mi><mi>b</mi></mfrac><mo>+</mo><msup><mi>x</mi><mn>2</mn></msup></mrow></math>"
+        );
+        assert!(parse_latex_math("\\frac{a}{b").is_err());
+        assert!(parse_latex_math("\\left( x").is_err());
+    }
+
+    #[test]
+    fn test_render_unicode() {
+        let render = |latex: &str| to_unicode(&parse_latex_math(latex).unwrap());
+        assert_eq!(render("x^2 + y_{n+1} \\le \\sqrt{z}"), "x² + yₙ₊₁ ≤ √z");
+        assert_eq!(render("\\frac{1}{2} \\alpha \\in \\mathbb{R}"), "½α ∈ ℝ");
+        assert_eq!(render("\\sin(x) = \\frac{a+b}{c}"), "sin(x) = (a + b)/c");
+        assert_eq!(render("\\begin{pmatrix} 1 & 0 \\\\ 0 & 1 \\end{pmatrix}"), "(1, 0; 0, 1)");
+    }
+
+    #[test]
+    fn test_render_math_in_text_keeps_unparseable_segments() {
+        let text = "Area $\\pi r^2$ and broken $\\frac{1}{$ done";
+        assert_eq!(render_math_in_text(text, MathOutputFormat::Unicode), "Area πr² and broken $\\frac{1}{$ done");
+    }
+}
@@ -0,0 +1,80 @@
+use crate::services::document_converter::{escape_latex_text, escape_stray_specials};
+
+/// One conversion as a file under `sections/` of an exported LaTeX project.
+pub struct ProjectSection {
+    pub file_stem: String,       // e.g. `001-page_1`, shared by the section and its figure
+    pub heading: String,         // Plain text, escaped when written
+    pub content: String,         // LaTeX body; empty when nothing was extracted
+    pub figure: Option<String>,  // Filename under `figures/` of the original image
+}
+
+/// `latexmkrc` that makes Overleaf (and local latexmk) build with XeLaTeX,
+/// which handles the Unicode the OCR output often contains.
+pub const LATEXMKRC: &str = "$pdf_mode = 5;\n";
+
+/// Builds `main.tex`, which pulls in the optional `preamble.tex` and one
+/// `\input` per section, in order.
+pub fn main_tex(title: &str, class_name: Option<&str>, has_preamble: bool, sections: &[ProjectSection]) -> String {
+    let mut tex = format!("\\documentclass{{{}}}\n", class_name.unwrap_or("article"));
+    tex.push_str("\\usepackage{amsmath}\n\\usepackage{amssymb}\n\\usepackage{graphicx}\n\\graphicspath{{figures/}}\n");
+    if has_preamble {
+        tex.push_str("\\input{preamble}\n");
+    }
+    tex.push_str(&format!("\n\\title{{{}}}\n\\date{{}}\n\n\\begin{{document}}\n\\maketitle\n\n", escape_latex_text(title)));
+    for section in sections {
+        tex.push_str(&format!("\\input{{sections/{}}}\n", section.file_stem));
+    }
+    tex.push_str("\n\\end{document}\n");
+    tex
+}
+
+/// Builds one section file: the heading, the original image as a figure and
+/// the text, with characters TeX would reject outside math escaped.
+pub fn section_tex(section: &ProjectSection) -> String {
+    let mut tex = format!("\\section*{{{}}}\n", escape_latex_text(&section.heading));
+    if let Some(figure) = &section.figure {
+        tex.push_str(&format!(
+            "\\begin{{figure}}[htbp]\n\\centering\n\\includegraphics[width=\\linewidth,height=0.8\\textheight,keepaspectratio]{{{}}}\n\\end{{figure}}\n",
+            figure
+        ));
+    }
+    tex.push('\n');
+    if section.content.trim().is_empty() {
+        tex.push_str("% No text was extracted from this image.\n");
+    } else {
+        tex.push_str(&escape_stray_specials(section.content.trim()));
+        tex.push('\n');
+    }
+    tex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_main_tex_inputs_each_section() {
+        let sections = vec![
+            ProjectSection {
+                file_stem: "001-page_1".to_string(),
+                heading: "page_1.png".to_string(),
+                content: "R&D notes_1: $x_1^2$".to_string(),
+                figure: Some("001-page_1.png".to_string()),
+            },
+            ProjectSection { file_stem: "002-notes".to_string(), heading: "notes.jpg".to_string(), content: String::new(), figure: None },
+        ];
+        let main = main_tex("Thesis & Notes", Some("thesis"), true, &sections);
+        assert!(main.starts_with("\\documentclass{thesis}\n"));
+        assert!(main.contains("\\input{preamble}\n"));
+        assert!(main.contains("\\title{Thesis \\& Notes}"));
+        assert!(main.contains("\\input{sections/001-page_1}\n\\input{sections/002-notes}\n"));
+        assert!(main.contains("\\usepackage{graphicx}\n\\graphicspath{{figures/}}\n"));
+
+        let first = section_tex(&sections[0]);
+        assert_eq!(
+            first,
+            "\\section*{page\\_1.png}\n\\begin{figure}[htbp]\n\\centering\n\\includegraphics[width=\\linewidth,height=0.8\\textheight,keepaspectratio]{001-page_1.png}\n\\end{figure}\n\nR\\&D notes\\_1: $x_1^2$\n"
+        );
+        assert!(section_tex(&sections[1]).contains("% No text was extracted"));
+    }
+}
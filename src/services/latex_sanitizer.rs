@@ -0,0 +1,269 @@
+use serde::Serialize;
+
+const RUNS_SHELL: &str = "runs shell commands";
+const READS_FILES: &str = "reads files from the server";
+const WRITES_FILES: &str = "writes files on the server";
+const RETOKENIZES: &str = "changes how TeX reads its input";
+
+/// Control words that reach outside the document, or that change tokenisation
+/// and would let the others through in disguise.
+const BLOCKED_COMMANDS: &[(&str, &str)] = &[
+    ("write18", RUNS_SHELL),
+    ("directlua", RUNS_SHELL),
+    ("luaexec", RUNS_SHELL),
+    ("immediate", WRITES_FILES),
+    ("write", WRITES_FILES),
+    ("openout", WRITES_FILES),
+    ("closeout", WRITES_FILES),
+    ("input", READS_FILES),
+    ("include", READS_FILES),
+    ("includeonly", READS_FILES),
+    ("InputIfFileExists", READS_FILES),
+    ("openin", READS_FILES),
+    ("closein", READS_FILES),
+    ("read", READS_FILES),
+    ("readline", READS_FILES),
+    ("includegraphics", READS_FILES),
+    ("lstinputlisting", READS_FILES),
+    ("verbatiminput", READS_FILES),
+    ("inputminted", READS_FILES),
+    ("subfile", READS_FILES),
+    ("subfileinclude", READS_FILES),
+    ("import", READS_FILES),
+    ("subimport", READS_FILES),
+    ("@input", READS_FILES),
+    ("@@input", READS_FILES),
+    ("@iinput", READS_FILES),
+    ("@input@", READS_FILES),
+    ("catcode", RETOKENIZES),
+    ("csname", RETOKENIZES),
+    ("scantokens", RETOKENIZES),
+    ("endlinechar", RETOKENIZES),
+];
+
+/// Commands that load a package or class by name. A name is fine; a path would
+/// read an arbitrary file as code.
+const LOADS_BY_NAME: &[&str] = &[
+    "usepackage",
+    "RequirePackage",
+    "RequirePackageWithOptions",
+    "documentclass",
+    "LoadClass",
+    "LoadClassWithOptions",
+];
+
+/// Which checks apply. Class files are TeX programs that legitimately change
+/// catcodes, build control sequences and write to the log, so they only lose
+/// direct shell and file access; anything disguised beyond that is still
+/// confined by the restricted engine in `run_bounded`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Policy {
+    Document,
+    Class,
+}
+
+impl Policy {
+    fn blocks(&self, reason: &str, name: &str) -> bool {
+        match self {
+            Policy::Document => true,
+            Policy::Class => reason != RETOKENIZES && name != "write" && name != "immediate",
+        }
+    }
+}
+
+/// A construct refused before the text reaches pandoc or xelatex.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct LatexViolation {
+    pub construct: String,  // As written, e.g. `\write18` or `^^`
+    pub line: usize,        // 1-based line in the checked text
+    pub reason: &'static str,
+}
+
+/// Finds every blocked construct in `text`. Comments are skipped, since TeX
+/// never reads them, and `^^` is refused because `^^5cinput` spells `\input`.
+/// Control words are matched both as plain letters and with `@`, which is a
+/// letter after `\makeatletter`, so neither `\@input` nor `\input@x` slips by.
+pub fn find_blocked_constructs(text: &str) -> Vec<LatexViolation> {
+    scan(text, Policy::Document)
+}
+
+/// The checks for uploaded class files: no shell commands, no file access
+/// beyond loading packages and classes by name.
+pub fn find_blocked_class_constructs(text: &str) -> Vec<LatexViolation> {
+    scan(text, Policy::Class)
+}
+
+fn scan(text: &str, policy: Policy) -> Vec<LatexViolation> {
+    let chars: Vec<char> = text.chars().collect();
+    let n = chars.len();
+    let mut violations = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+
+    while i < n {
+        match chars[i] {
+            '\n' => line += 1,
+            '%' => {
+                while i + 1 < n && chars[i + 1] != '\n' {
+                    i += 1;
+                }
+            }
+            '\\' if i + 1 < n && (chars[i + 1].is_ascii_alphabetic() || chars[i + 1] == '@') => {
+                let start = i + 1;
+                let letters_end = control_word_end(&chars, start, false);
+                let at_end = control_word_end(&chars, start, true);
+
+                let mut readings: Vec<String> = Vec::new();
+                for end in [letters_end, at_end] {
+                    if end == start {
+                        continue;  // `\@` without `\makeatletter` is a control symbol
+                    }
+                    let mut name: String = chars[start..end].iter().collect();
+                    if name == "write" && chars[end..].starts_with(&['1', '8']) {
+                        name.push_str("18");
+                    }
+                    if !readings.contains(&name) {
+                        readings.push(name);
+                    }
+                }
+
+                for name in &readings {
+                    if let Some((_, reason)) = BLOCKED_COMMANDS.iter().find(|(blocked, _)| blocked == name) {
+                        if policy.blocks(reason, name) {
+                            violations.push(LatexViolation { construct: format!("\\{}", name), line, reason });
+                        }
+                    } else if LOADS_BY_NAME.contains(&name.as_str()) {
+                        let end = start + name.chars().count();
+                        if loaded_names(&chars, end).iter().any(|loaded| is_path(loaded)) {
+                            violations.push(LatexViolation { construct: format!("\\{}", name), line, reason: READS_FILES });
+                        }
+                    }
+                }
+                i = letters_end.max(start + 1);
+                continue;
+            }
+            '\\' => {
+                // Escaped character such as `\%` or `\\`; a line break still counts.
+                if i + 1 < n && chars[i + 1] == '\n' {
+                    line += 1;
+                }
+                i += 2;
+                continue;
+            }
+            '^' if i + 1 < n && chars[i + 1] == '^' => {
+                if policy == Policy::Document {
+                    violations.push(LatexViolation { construct: "^^".to_string(), line, reason: RETOKENIZES });
+                }
+                i += 2;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    violations
+}
+
+fn control_word_end(chars: &[char], start: usize, at_is_letter: bool) -> usize {
+    let mut end = start;
+    while end < chars.len() && (chars[end].is_ascii_alphabetic() || (at_is_letter && chars[end] == '@')) {
+        end += 1;
+    }
+    end
+}
+
+/// The comma-separated names in the mandatory argument of a package or class
+/// command starting at `pos`, after any `[options]`.
+fn loaded_names(chars: &[char], mut pos: usize) -> Vec<String> {
+    let skip_blank = |pos: &mut usize| {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    };
+    skip_blank(&mut pos);
+    if chars.get(pos) == Some(&'[') {
+        while pos < chars.len() && chars[pos] != ']' {
+            pos += 1;
+        }
+        pos += 1;
+        skip_blank(&mut pos);
+    }
+    if chars.get(pos) != Some(&'{') {
+        return Vec::new();
+    }
+    let argument: String = chars[pos + 1..].iter().take_while(|c| **c != '}').collect();
+    argument.split(',').map(|name| name.trim().to_string()).collect()
+}
+
+fn is_path(name: &str) -> bool {
+    name.contains(['/', '\\', ':']) || name.starts_with(['.', '~'])
+}
+
+/// One line per violation, for error messages and logs.
+pub fn describe_violations(violations: &[LatexViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| format!("{} on line {} ({})", v.construct, v.line, v.reason))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_dangerous_primitives_but_not_ordinary_latex() {
+        let text = "Area $\\pi r^2$ and \\textbf{bold} \\\\ 50\\% % \\input{ignored}\n\\immediate\\write18{ls}\n\\inputenc \\input{/etc/passwd} x^^5c";
+        let found: Vec<(String, usize)> = find_blocked_constructs(text)
+            .into_iter()
+            .map(|v| (v.construct, v.line))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("\\immediate".to_string(), 2),
+                ("\\write18".to_string(), 2),
+                ("\\input".to_string(), 3),
+                ("^^".to_string(), 3),
+            ]
+        );
+        assert!(find_blocked_constructs("$\\frac{a}{b}$ \\section*{Intro}").is_empty());
+    }
+
+    fn constructs(violations: Vec<LatexViolation>) -> Vec<String> {
+        violations.into_iter().map(|v| v.construct).collect()
+    }
+
+    #[test]
+    fn test_blocks_internal_at_commands() {
+        assert_eq!(constructs(find_blocked_constructs("\\makeatletter\\@input{/etc/passwd}")), vec!["\\@input"]);
+        assert_eq!(constructs(find_blocked_constructs("\\makeatletter \\@@input secret.tex")), vec!["\\@@input"]);
+        // Without \makeatletter TeX reads `\input` followed by `@x`
+        assert_eq!(constructs(find_blocked_constructs("\\input@x")), vec!["\\input"]);
+        assert!(find_blocked_constructs("\\makeatletter\\@ifnextchar x").is_empty());
+    }
+
+    #[test]
+    fn test_blocks_subfile_and_inputminted() {
+        assert_eq!(constructs(find_blocked_constructs("\\subfile{chapters/one}")), vec!["\\subfile"]);
+        assert_eq!(constructs(find_blocked_constructs("\\inputminted{python}{/etc/passwd}")), vec!["\\inputminted"]);
+    }
+
+    #[test]
+    fn test_blocks_packages_and_classes_loaded_by_path() {
+        assert_eq!(constructs(find_blocked_constructs("\\usepackage{/tmp/evil}")), vec!["\\usepackage"]);
+        assert_eq!(constructs(find_blocked_constructs("\\usepackage[utf8]{inputenc, ../evil}")), vec!["\\usepackage"]);
+        assert_eq!(constructs(find_blocked_constructs("\\RequirePackage{~/evil}")), vec!["\\RequirePackage"]);
+        assert!(find_blocked_constructs("\\usepackage[margin=1in]{geometry}\\RequirePackage{amsmath,amssymb}").is_empty());
+    }
+
+    #[test]
+    fn test_class_files_keep_catcodes_but_lose_file_access() {
+        let class = "\\NeedsTeXFormat{LaTeX2e}\\LoadClass{article}\\RequirePackage{graphicx}\n\\catcode`\\^^M=13 \\csname foo\\endcsname\\immediate\\write16{loaded}";
+        assert!(find_blocked_class_constructs(class).is_empty());
+
+        let found = find_blocked_class_constructs("\\makeatletter\\@@input /etc/passwd\n\\immediate\\write18{ls}\n\\LoadClass{../other}\n\\openout1=x");
+        assert_eq!(constructs(found), vec!["\\@@input", "\\write18", "\\LoadClass", "\\openout"]);
+    }
+}
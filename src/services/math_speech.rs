@@ -0,0 +1,345 @@
+use crate::services::latex_math::{extract_math_segments, parse_latex_math, AccentKind, MathNode, MathVariant};
+
+/// Single letters that are read as functions when followed by parentheses, so
+/// `g(t)` is spoken "g of t" rather than "g open paren t close paren".
+const FUNCTION_LETTERS: &[&str] = &["f", "g", "h", "F", "G", "H", "φ", "ϕ", "ψ"];
+
+/// Generates natural-language alt text for a LaTeX math segment, or None if
+/// the segment cannot be parsed.
+pub fn speak_latex(latex: &str) -> Option<String> {
+    parse_latex_math(latex).ok().map(|node| speak(&node)).filter(|s| !s.is_empty())
+}
+
+/// Alt text for every parseable math segment in `text`, keyed by the segment's LaTeX.
+pub fn alt_texts(text: &str) -> Vec<(String, String)> {
+    extract_math_segments(text)
+        .into_iter()
+        .filter_map(|segment| speak_latex(&segment.latex).map(|alt| (segment.latex, alt)))
+        .collect()
+}
+
+/// Reads a parsed math tree aloud, e.g. `\int_a^b g(t)\,dt` becomes
+/// "integral from a to b of g of t, d t".
+pub fn speak(node: &MathNode) -> String {
+    let spoken = words(node).split_whitespace().collect::<Vec<_>>().join(" ");
+    spoken.replace(" ,", ",").trim_matches(|c: char| c == ',' || c.is_whitespace()).to_string()
+}
+
+fn words(node: &MathNode) -> String {
+    match node {
+        MathNode::Number(n) => n.clone(),
+        MathNode::Identifier(i) => i.chars().map(speak_symbol).collect::<Vec<_>>().join(" "),
+        MathNode::Operator(op) => speak_operator(op).to_string(),
+        MathNode::Function(name) => speak_function(name).to_string(),
+        MathNode::Text(text) => text.trim().to_string(),
+        MathNode::Space => String::new(),
+        MathNode::Row(nodes) => speak_row(nodes),
+        MathNode::Frac(num, den) => {
+            if is_simple(num) && is_simple(den) {
+                format!("{} over {}", words(num), words(den))
+            } else {
+                format!("the fraction with numerator {} and denominator {},", words(num), words(den))
+            }
+        }
+        MathNode::Binom(n, k) => format!("{} choose {}", words(n), words(k)),
+        MathNode::Sqrt { index, body } => match index.as_deref().map(words).as_deref() {
+            None | Some("2") => format!("the square root of {},", words(body)),
+            Some("3") => format!("the cube root of {},", words(body)),
+            Some(n) => format!("the {}th root of {},", n, words(body)),
+        },
+        MathNode::Scripts { base, sub, sup } => speak_scripts(base, sub.as_deref(), sup.as_deref()),
+        MathNode::Fenced { open, close, body } => speak_fenced(open, close, &words(body)),
+        MathNode::Styled { variant, body } => match (variant, body.as_ref()) {
+            (MathVariant::DoubleStruck, MathNode::Identifier(set)) => match set.as_str() {
+                "R" => "the real numbers".to_string(),
+                "N" => "the natural numbers".to_string(),
+                "Z" => "the integers".to_string(),
+                "Q" => "the rational numbers".to_string(),
+                "C" => "the complex numbers".to_string(),
+                _ => words(body),
+            },
+            _ => words(body),
+        },
+        MathNode::Accent { accent, body } => match accent {
+            AccentKind::Vec => format!("vector {}", words(body)),
+            AccentKind::DoubleDot => format!("{} double dot", words(body)),
+            _ => format!("{} {}", words(body), accent.name()),
+        },
+        MathNode::Matrix { open, rows, .. } if open == "{" => {
+            let cases = rows
+                .iter()
+                .map(|row| row.iter().map(words).collect::<Vec<_>>().join(" "))
+                .collect::<Vec<_>>()
+                .join("; ");
+            format!("cases: {};", cases)
+        }
+        MathNode::Matrix { rows, .. } => {
+            let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+            let body = rows
+                .iter()
+                .enumerate()
+                .map(|(i, row)| format!("row {}: {}", i + 1, row.iter().map(words).collect::<Vec<_>>().join(", ")))
+                .collect::<Vec<_>>()
+                .join("; ");
+            format!("the {} by {} matrix, {};", rows.len(), columns, body)
+        }
+    }
+}
+
+fn speak_row(nodes: &[MathNode]) -> String {
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < nodes.len() {
+        let node = &nodes[i];
+        let applies = is_function_like(node);
+
+        // Plain `(` ... `)` pairs are flat in the row, so find the matching close here.
+        if let MathNode::Operator(open) = node
+            && (open == "(" || open == "[")
+            && let Some(close) = matching_close(nodes, i)
+        {
+            let inner = speak_row(&nodes[i + 1..close]);
+            let after_function = i > 0 && is_function_like(&nodes[i - 1]);
+            if after_function && close == i + 2 {
+                parts.push(inner);
+            } else {
+                parts.push(format!("open paren {} close paren", inner));
+            }
+            i = close + 1;
+            continue;
+        }
+
+        parts.push(words(node));
+
+        match (node, nodes.get(i + 1)) {
+            // "d t" after a thin space reads as the differential.
+            (MathNode::Space, Some(MathNode::Identifier(d))) if d == "d" => parts.push(",".to_string()),
+            (_, Some(next)) if applies && !matches!(next, MathNode::Operator(op) if op != "(" && op != "[") => {
+                parts.push("of".to_string())
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.join(" ")
+}
+
+fn matching_close(nodes: &[MathNode], open_index: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, node) in nodes.iter().enumerate().skip(open_index) {
+        match node {
+            MathNode::Operator(op) if op == "(" || op == "[" => depth += 1,
+            MathNode::Operator(op) if op == ")" || op == "]" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_function_like(node: &MathNode) -> bool {
+    match node {
+        MathNode::Function(_) => true,
+        MathNode::Identifier(i) => FUNCTION_LETTERS.contains(&i.as_str()),
+        MathNode::Scripts { base, .. } => is_function_like(base),
+        _ => false,
+    }
+}
+
+fn is_simple(node: &MathNode) -> bool {
+    matches!(node, MathNode::Number(_) | MathNode::Identifier(_))
+}
+
+fn speak_scripts(base: &MathNode, sub: Option<&MathNode>, sup: Option<&MathNode>) -> String {
+    let name = match base {
+        MathNode::Operator(op) | MathNode::Function(op) => op.as_str(),
+        _ => "",
+    };
+
+    match name {
+        "∫" | "∬" | "∭" | "∮" | "∑" | "∏" | "⋃" | "⋂" => {
+            let mut spoken = speak_operator(name).to_string();
+            if let Some(sub) = sub {
+                spoken.push_str(&format!(" from {}", words(sub)));
+            }
+            if let Some(sup) = sup {
+                spoken.push_str(&format!(" to {}", words(sup)));
+            }
+            spoken.push_str(" of");
+            return spoken;
+        }
+        "lim" => {
+            return match sub {
+                Some(sub) => format!("the limit as {} of", words(sub)),
+                None => "the limit of".to_string(),
+            };
+        }
+        _ => {}
+    }
+
+    let mut spoken = words(base);
+    if !is_simple(base) && !matches!(base, MathNode::Fenced { .. } | MathNode::Function(_) | MathNode::Styled { .. }) {
+        spoken = format!("open paren {} close paren", spoken);
+    }
+    if let Some(sub) = sub {
+        spoken.push_str(&format!(" sub {}", words(sub)));
+    }
+    if let Some(sup) = sup {
+        let exponent = words(sup);
+        spoken.push_str(&match exponent.as_str() {
+            "2" => " squared".to_string(),
+            "3" => " cubed".to_string(),
+            "prime" => " prime".to_string(),
+            "prime prime" => " double prime".to_string(),
+            _ if is_simple(sup) => format!(" to the {}", exponent),
+            _ => format!(" to the power of {},", exponent),
+        });
+    }
+    spoken
+}
+
+fn speak_fenced(open: &str, close: &str, body: &str) -> String {
+    match (open, close) {
+        ("|", "|") => format!("the absolute value of {},", body),
+        ("‖", "‖") => format!("the norm of {},", body),
+        ("⌊", "⌋") => format!("the floor of {},", body),
+        ("⌈", "⌉") => format!("the ceiling of {},", body),
+        ("{", "}") => format!("the set {},", body),
+        ("", "") => body.to_string(),
+        _ => format!("open paren {} close paren", body),
+    }
+}
+
+fn speak_function(name: &str) -> &str {
+    match name {
+        "sin" => "sine",
+        "cos" => "cosine",
+        "tan" => "tangent",
+        "cot" => "cotangent",
+        "sec" => "secant",
+        "csc" => "cosecant",
+        "arcsin" => "arc sine",
+        "arccos" => "arc cosine",
+        "arctan" => "arc tangent",
+        "sinh" => "hyperbolic sine",
+        "cosh" => "hyperbolic cosine",
+        "tanh" => "hyperbolic tangent",
+        "ln" => "natural log",
+        "exp" => "exponential",
+        "lim" => "the limit",
+        "max" => "the maximum",
+        "min" => "the minimum",
+        "sup" => "the supremum",
+        "inf" => "the infimum",
+        "det" => "the determinant",
+        "gcd" => "the greatest common divisor",
+        other => other,
+    }
+}
+
+fn speak_operator(op: &str) -> &str {
+    match op {
+        "+" => "plus",
+        "−" => "minus",
+        "±" => "plus or minus",
+        "∓" => "minus or plus",
+        "×" | "⋅" | "∗" => "times",
+        "÷" | "/" => "divided by",
+        "=" => "equals",
+        "≠" => "is not equal to",
+        "<" => "is less than",
+        ">" => "is greater than",
+        "≤" => "is less than or equal to",
+        "≥" => "is greater than or equal to",
+        "≈" => "is approximately equal to",
+        "≡" => "is equivalent to",
+        "∼" | "≃" => "is similar to",
+        "≅" => "is congruent to",
+        "∝" => "is proportional to",
+        "≪" => "is much less than",
+        "≫" => "is much greater than",
+        "→" => "goes to",
+        "←" => "gets",
+        "⇒" | "⟹" => "implies",
+        "⇐" => "is implied by",
+        "⇔" | "⟺" | "↔" => "if and only if",
+        "↦" => "maps to",
+        "∈" => "is in",
+        "∉" => "is not in",
+        "∋" => "contains",
+        "⊂" => "is a subset of",
+        "⊆" => "is a subset of or equal to",
+        "⊃" => "is a superset of",
+        "⊇" => "is a superset of or equal to",
+        "∪" => "union",
+        "∩" => "intersection",
+        "∖" => "minus",
+        "∀" => "for all",
+        "∃" => "there exists",
+        "¬" => "not",
+        "∧" => "and",
+        "∨" => "or",
+        "∑" => "the sum",
+        "∏" => "the product",
+        "∫" => "integral",
+        "∬" => "double integral",
+        "∭" => "triple integral",
+        "∮" => "contour integral",
+        "⋃" => "the union",
+        "⋂" => "the intersection",
+        "…" | "⋯" | "⋮" | "⋱" => "dot dot dot",
+        "′" => "prime",
+        "!" => "factorial",
+        "∣" => "divides",
+        "∥" => "is parallel to",
+        "⊥" => "is perpendicular to",
+        "∘" => "composed with",
+        "⊕" => "direct sum",
+        "⊗" => "tensor",
+        "∴" => "therefore",
+        "∵" => "because",
+        "(" | "[" => "open paren",
+        ")" | "]" => "close paren",
+        "|" => "vertical bar",
+        "," => ",",
+        ":" => "such that",
+        other => other,
+    }
+}
+
+fn speak_symbol(c: char) -> String {
+    let name = match c {
+        'α' => "alpha", 'β' => "beta", 'γ' => "gamma", 'δ' => "delta", 'ϵ' | 'ε' => "epsilon",
+        'ζ' => "zeta", 'η' => "eta", 'θ' | 'ϑ' => "theta", 'ι' => "iota", 'κ' => "kappa",
+        'λ' => "lambda", 'μ' => "mu", 'ν' => "nu", 'ξ' => "xi", 'π' | 'ϖ' => "pi", 'ρ' | 'ϱ' => "rho",
+        'σ' | 'ς' => "sigma", 'τ' => "tau", 'υ' => "upsilon", 'ϕ' | 'φ' => "phi", 'χ' => "chi",
+        'ψ' => "psi", 'ω' => "omega",
+        'Γ' => "capital gamma", 'Δ' => "capital delta", 'Θ' => "capital theta", 'Λ' => "capital lambda",
+        'Ξ' => "capital xi", 'Π' => "capital pi", 'Σ' => "capital sigma", 'Υ' => "capital upsilon",
+        'Φ' => "capital phi", 'Ψ' => "capital psi", 'Ω' => "capital omega",
+        '∞' => "infinity", '∂' => "partial", '∇' => "nabla", '∅' => "the empty set", 'ℏ' => "h bar",
+        'ℓ' => "ell", 'ℵ' => "aleph", '′' => "prime", '∠' => "angle", '△' => "triangle",
+        _ => return c.to_string(),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speaks_integrals_and_function_application() {
+        assert_eq!(speak_latex("\\int_a^b g(t)\\,dt").unwrap(), "integral from a to b of g of t, d t");
+        assert_eq!(speak_latex("x^2 + \\frac{1}{2}").unwrap(), "x squared plus 1 over 2");
+        assert_eq!(
+            speak_latex("\\sum_{i=1}^{n} \\alpha_i").unwrap(),
+            "the sum from i equals 1 to n of alpha sub i"
+        );
+        assert_eq!(speak_latex("\\sqrt{x+1} \\in \\mathbb{R}").unwrap(), "the square root of x plus 1, is in the real numbers");
+    }
+}
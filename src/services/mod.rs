@@ -1,4 +1,19 @@
 pub mod cloudinary;
+pub mod consensus;
 pub mod document_converter;
+pub mod document_stats;
 pub mod email;
-pub mod jwt; 
\ No newline at end of file
+pub mod export_cache;
+pub mod export_capabilities;
+pub mod image_crop;
+pub mod jwt;
+pub mod latex_log;
+pub mod latex_math;
+pub mod latex_project;
+pub mod latex_sanitizer;
+pub mod math_speech;
+pub mod native_renderer;
+pub mod ocr;
+pub mod office_watermark;
+pub mod remote_fetch;
+pub mod zip_archive; 
\ No newline at end of file
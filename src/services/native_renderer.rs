@@ -0,0 +1,660 @@
+use std::cell::{Cell, RefCell};
+
+use crate::services::latex_math::{extract_math_segments, parse_latex_math, MathKind, MathOutputFormat, MathSegment};
+
+/// Formats the in-process renderer writes, used when pandoc is not installed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NativeFormat {
+    Html,
+    Markdown,
+    Text,
+}
+
+/// Text commands replaced by a fixed string; a following `{}` is dropped with them.
+const SYMBOLS: &[(&str, &str)] = &[
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("LaTeX", "LaTeX"),
+    ("TeX", "TeX"),
+    ("textbackslash", "\\"),
+    ("textasciicircum", "^"),
+    ("textasciitilde", "~"),
+    ("S", "§"),
+    ("copyright", "©"),
+    ("quad", " "),
+    ("qquad", "  "),
+];
+
+/// Commands that only affect layout, dropped together with their arguments.
+const DROPPED_WITH_ARGUMENTS: &[&str] = &["label", "ref", "vspace", "hspace", "setlength", "addtolength", "newpage", "pagebreak", "clearpage"];
+
+/// Deepest nesting of command arguments that is interpreted, such as
+/// `\textbf{\emph{...}}`. Arguments below it keep their text but lose their
+/// formatting, so hostile input can't overflow the stack.
+const MAX_COMMAND_NESTING: usize = 64;
+
+/// Display math environments, rendered like `\[...\]`.
+const DISPLAY_ENVIRONMENTS: &[&str] = &["equation", "equation*", "align", "align*", "gather", "gather*", "multline", "multline*", "displaymath"];
+
+/// Renders a LaTeX body as a standalone HTML page, Markdown or plain text. It
+/// understands the subset the OCR produces: sections, paragraphs, lists, text
+/// styles, escaped characters and math. Anything else keeps its text and loses
+/// its formatting.
+pub fn render_native(latex_content: &str, format: NativeFormat, title: Option<&str>) -> String {
+    let writer = Writer::new(format, 1);
+    let blocks = writer.blocks(latex_content);
+    match format {
+        NativeFormat::Html => {
+            let title = escape_html(title.map(str::trim).filter(|t| !t.is_empty()).unwrap_or("Document"));
+            format!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>body {{ max-width: 48em; margin: 2em auto; padding: 0 1em; font-family: serif; line-height: 1.5 }} hr.page-break {{ border: 0; page-break-after: always }}</style>\n</head>\n<body>\n{}\n</body>\n</html>\n",
+                title,
+                blocks.join("\n")
+            )
+        }
+        NativeFormat::Markdown => format!("{}\n", blocks.join("\n\n")),
+        NativeFormat::Text => blocks.join("\n\n"),
+    }
+}
+
+/// Renders a LaTeX body as an HTML fragment, without the page around it.
+/// Display equations are wrapped in `<span class="equation" id="eq-N">`,
+/// numbered from `first_equation`. Returns the HTML and the LaTeX of each
+/// display equation, in order.
+pub fn render_html_fragment(latex_content: &str, first_equation: usize) -> (String, Vec<String>) {
+    let writer = Writer::new(NativeFormat::Html, first_equation);
+    let html = writer.blocks(latex_content).join("\n");
+    (html, writer.equations.into_inner())
+}
+
+/// What a LaTeX body contains, as the in-process renderer reads it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TextCounts {
+    pub words: usize,  // Words of running text; math is not counted
+    pub inline_equations: usize,
+    pub display_equations: usize,
+}
+
+pub fn count_text(latex_content: &str) -> TextCounts {
+    let writer = counting_writer();
+    let words = writer.blocks(latex_content).iter().map(|block| block.split_whitespace().count()).sum();
+    TextCounts {
+        words,
+        inline_equations: writer.inline_equations.get(),
+        display_equations: writer.equations.borrow().len(),
+    }
+}
+
+/// LaTeX of each display equation in `latex_content`, in the order
+/// `render_html_fragment` numbers them, without rendering any math.
+pub fn display_equations(latex_content: &str) -> Vec<String> {
+    let writer = counting_writer();
+    writer.blocks(latex_content);
+    writer.equations.into_inner()
+}
+
+fn counting_writer() -> Writer {
+    Writer { omit_math: true, ..Writer::new(NativeFormat::Text, 1) }
+}
+
+/// One part of a document split at its sectioning commands.
+pub struct DocumentSection {
+    pub heading: Option<String>,  // Plain text; None for text before the first heading
+    pub level: usize,             // 1 for \chapter down to 4 for \subsubsection; 0 without a heading
+    pub content: String,          // LaTeX of the section, starting with its heading
+}
+
+/// Splits a LaTeX body before every `\chapter`, `\section`, `\subsection`
+/// and `\subsubsection` that starts a line outside display math. Text before
+/// the first heading becomes a section of its own when it is not blank.
+pub fn split_sections(latex_content: &str) -> Vec<DocumentSection> {
+    let mut sections = Vec::new();
+    let mut current = DocumentSection { heading: None, level: 0, content: String::new() };
+    let mut display: Option<(String, String)> = None;
+
+    for line in latex_content.lines() {
+        let trimmed = line.trim();
+        if display.is_some() {
+            continue_display(&mut display, line);
+        } else if let Some((environment, rest)) = environment_marker(trimmed, "begin").filter(|(e, _)| DISPLAY_ENVIRONMENTS.contains(e)) {
+            display = Some((environment.to_string(), String::new()));
+            continue_display(&mut display, rest);
+        } else if let Some((level, heading, _)) = heading(trimmed) {
+            if current.heading.is_some() || !current.content.trim().is_empty() {
+                sections.push(current);
+            }
+            let heading = render_native(&heading, NativeFormat::Text, None).trim().to_string();
+            current = DocumentSection { heading: Some(heading), level, content: String::new() };
+        }
+        current.content.push_str(line);
+        current.content.push('\n');
+    }
+    if current.heading.is_some() || !current.content.trim().is_empty() {
+        sections.push(current);
+    }
+    sections
+}
+
+/// A list being read: its finished items and the text of the current one.
+struct ListFrame {
+    ordered: bool,
+    items: Vec<String>,
+    current: String,
+    nested: String,  // Already rendered lists inside the current item
+}
+
+impl ListFrame {
+    fn new(ordered: bool) -> Self {
+        Self { ordered, items: Vec::new(), current: String::new(), nested: String::new() }
+    }
+}
+
+struct Writer {
+    format: NativeFormat,
+    first_equation: usize,
+    equations: RefCell<Vec<String>>,  // LaTeX of the display equations rendered so far
+    inline_equations: Cell<usize>,
+    omit_math: bool,                   // Leave math out of the output, for counting words
+    nesting: Cell<usize>,              // Command arguments currently being rendered
+}
+
+impl Writer {
+    fn new(format: NativeFormat, first_equation: usize) -> Self {
+        Self {
+            format,
+            first_equation,
+            equations: RefCell::new(Vec::new()),
+            inline_equations: Cell::new(0),
+            omit_math: false,
+            nesting: Cell::new(0),
+        }
+    }
+
+    /// Splits the body into rendered blocks: headings, paragraphs, lists and page breaks.
+    fn blocks(&self, latex_content: &str) -> Vec<String> {
+        let mut blocks = Vec::new();
+        let mut paragraph = String::new();
+        let mut lists: Vec<ListFrame> = Vec::new();
+        let mut display: Option<(String, String)> = None;
+
+        for line in latex_content.lines() {
+            let trimmed = line.trim();
+
+            if display.is_some() {
+                if let Some(math) = continue_display(&mut display, line) {
+                    match lists.last_mut() {
+                        Some(frame) => frame.current.push_str(&math),
+                        None => paragraph.push_str(&math),
+                    }
+                }
+                continue;
+            }
+
+            if let Some((environment, rest)) = environment_marker(trimmed, "begin") {
+                match environment {
+                    "itemize" | "enumerate" => {
+                        self.flush_paragraph(&mut paragraph, &mut blocks);
+                        lists.push(ListFrame::new(environment == "enumerate"));
+                    }
+                    _ if DISPLAY_ENVIRONMENTS.contains(&environment) => {
+                        // The environment may be closed on the same line.
+                        display = Some((environment.to_string(), String::new()));
+                        if let Some(math) = continue_display(&mut display, rest) {
+                            match lists.last_mut() {
+                                Some(frame) => frame.current.push_str(&math),
+                                None => paragraph.push_str(&math),
+                            }
+                        }
+                    }
+                    _ => {}  // center, flushleft and the like only change alignment
+                }
+                continue;
+            }
+
+            if let Some((environment, _)) = environment_marker(trimmed, "end") {
+                if matches!(environment, "itemize" | "enumerate") {
+                    self.close_list(&mut lists, &mut blocks);
+                }
+                continue;
+            }
+
+            if let Some(frame) = lists.last_mut() {
+                match trimmed.strip_prefix("\\item") {
+                    Some(rest) => {
+                        self.finish_item(frame);
+                        frame.current = skip_optional_argument(rest).to_string();
+                        frame.current.push('\n');
+                    }
+                    None => {
+                        frame.current.push_str(line);
+                        frame.current.push('\n');
+                    }
+                }
+                continue;
+            }
+
+            if let Some((level, heading, rest)) = heading(trimmed) {
+                self.flush_paragraph(&mut paragraph, &mut blocks);
+                blocks.push(self.heading(level, &heading));
+                if !rest.trim().is_empty() {
+                    paragraph.push_str(rest.trim());
+                    paragraph.push('\n');
+                }
+            } else if is_page_break(trimmed) {
+                self.flush_paragraph(&mut paragraph, &mut blocks);
+                match self.format {
+                    NativeFormat::Html => blocks.push("<hr class=\"page-break\">".to_string()),
+                    NativeFormat::Markdown => blocks.push("* * *".to_string()),
+                    NativeFormat::Text => {}
+                }
+            } else if trimmed.is_empty() {
+                self.flush_paragraph(&mut paragraph, &mut blocks);
+            } else {
+                paragraph.push_str(line);
+                paragraph.push('\n');
+            }
+        }
+
+        // Unclosed environments still keep their text.
+        if let Some((_, body)) = display {
+            paragraph.push_str(&format!("\\[{}\\]\n", body.trim()));
+        }
+        while !lists.is_empty() {
+            self.close_list(&mut lists, &mut blocks);
+        }
+        self.flush_paragraph(&mut paragraph, &mut blocks);
+        blocks
+    }
+
+    fn flush_paragraph(&self, paragraph: &mut String, blocks: &mut Vec<String>) {
+        let text = self.inline(paragraph.trim_end_matches('\n'));
+        paragraph.clear();
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        blocks.push(match self.format {
+            NativeFormat::Html => format!("<p>{}</p>", text),
+            _ => text.to_string(),
+        });
+    }
+
+    /// Renders the innermost open list into its parent item, or as a block.
+    fn close_list(&self, lists: &mut Vec<ListFrame>, blocks: &mut Vec<String>) {
+        let Some(mut frame) = lists.pop() else {
+            return;
+        };
+        self.finish_item(&mut frame);
+        let rendered = self.list(&frame, lists.len());
+        match lists.last_mut() {
+            Some(parent) => {
+                parent.nested.push('\n');
+                parent.nested.push_str(&rendered);
+            }
+            None => blocks.push(rendered),
+        }
+    }
+
+    fn finish_item(&self, frame: &mut ListFrame) {
+        let text = self.inline(frame.current.trim()).split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() || !frame.nested.is_empty() {
+            frame.items.push(format!("{}{}", text, frame.nested));
+        }
+        frame.current.clear();
+        frame.nested.clear();
+    }
+
+    fn list(&self, frame: &ListFrame, depth: usize) -> String {
+        match self.format {
+            NativeFormat::Html => {
+                let tag = if frame.ordered { "ol" } else { "ul" };
+                let items: String = frame.items.iter().map(|item| format!("<li>{}</li>\n", item)).collect();
+                format!("<{}>\n{}</{}>", tag, items, tag)
+            }
+            NativeFormat::Markdown | NativeFormat::Text => {
+                let indent = if self.format == NativeFormat::Markdown { "    " } else { "  " }.repeat(depth);
+                frame
+                    .items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| {
+                        let marker = match (frame.ordered, self.format) {
+                            (true, _) => format!("{}.", i + 1),
+                            (false, NativeFormat::Markdown) => "-".to_string(),
+                            (false, _) => "•".to_string(),
+                        };
+                        format!("{}{} {}", indent, marker, item)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+    }
+
+    fn heading(&self, level: usize, heading: &str) -> String {
+        let text = self.inline(heading);
+        match self.format {
+            NativeFormat::Html => format!("<h{}>{}</h{}>", level, text, level),
+            NativeFormat::Markdown => format!("{} {}", "#".repeat(level), text),
+            NativeFormat::Text => text,
+        }
+    }
+
+    /// Renders running text with its math.
+    fn inline(&self, text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let segments = extract_math_segments(text);
+        let mut out = String::with_capacity(text.len());
+        self.inline_range(&chars, 0, chars.len(), &segments, &mut out);
+        out
+    }
+
+    fn inline_range(&self, chars: &[char], start: usize, end: usize, segments: &[MathSegment], out: &mut String) {
+        let mut i = start;
+        while i < end {
+            if let Some(segment) = segments.iter().find(|s| s.start == i && s.end <= end) {
+                out.push_str(&self.math(segment, &chars[segment.start..segment.end]));
+                i = segment.end;
+                continue;
+            }
+
+            match chars[i] {
+                '\\' if i + 1 < end && chars[i + 1] == '\\' => {
+                    out.push_str(match self.format {
+                        NativeFormat::Html => "<br>",
+                        NativeFormat::Markdown => "\\\n",
+                        NativeFormat::Text => "\n",
+                    });
+                    i += 2;
+                    // `\\[2pt]` spacing and the line break that usually follows.
+                    if i < end && chars[i] == '[' {
+                        i = chars[i..end].iter().position(|c| *c == ']').map(|p| i + p + 1).unwrap_or(i);
+                    }
+                    while i < end && chars[i] == '\n' {
+                        i += 1;
+                    }
+                }
+                '\\' if i + 1 < end && chars[i + 1].is_ascii_alphabetic() => {
+                    let name_start = i + 1;
+                    let mut name_end = name_start;
+                    while name_end < end && chars[name_end].is_ascii_alphabetic() {
+                        name_end += 1;
+                    }
+                    let name: String = chars[name_start..name_end].iter().collect();
+                    i = name_end;
+                    if i < end && chars[i] == '*' {
+                        i += 1;
+                    }
+                    i = self.command(&name, chars, i, end, segments, out);
+                }
+                '\\' if i + 1 < end => {
+                    match chars[i + 1] {
+                        c @ ('%' | '&' | '#' | '_' | '$' | '{' | '}') => self.literal(c, out),
+                        ' ' | ',' | ';' | ':' => out.push(' '),
+                        _ => {}
+                    }
+                    i += 2;
+                }
+                '%' => {
+                    while i < end && chars[i] != '\n' {
+                        i += 1;
+                    }
+                }
+                '~' => {
+                    out.push_str(if self.format == NativeFormat::Html { "&nbsp;" } else { " " });
+                    i += 1;
+                }
+                '{' | '}' => i += 1,
+                c => {
+                    self.literal(c, out);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Renders the command `name` whose arguments start at `i`, returning the
+    /// position after everything it consumed.
+    fn command(&self, name: &str, chars: &[char], mut i: usize, end: usize, segments: &[MathSegment], out: &mut String) -> usize {
+        if let Some((_, symbol)) = SYMBOLS.iter().find(|(command, _)| *command == name) {
+            out.push_str(&match self.format {
+                NativeFormat::Html => escape_html(symbol),
+                _ => symbol.to_string(),
+            });
+            if chars[i..end].starts_with(&['{', '}']) {
+                i += 2;
+            }
+            return i;
+        }
+        if DROPPED_WITH_ARGUMENTS.contains(&name) {
+            while i < end && (chars[i] == '{' || chars[i] == '[') {
+                i = matching_close(chars, i, end).map(|close| close + 1).unwrap_or(end);
+            }
+            return i;
+        }
+
+        let argument_start = (i..end).find(|&j| !chars[j].is_whitespace()).filter(|&j| chars[j] == '{');
+        let Some(open) = argument_start else {
+            // A declaration such as `\noindent` or `\Large`; TeX skips the spaces after it.
+            while i < end && chars[i] == ' ' {
+                i += 1;
+            }
+            return i;
+        };
+        let close = matching_close(chars, open, end).unwrap_or(end);
+        let (before, after) = match (name, self.format) {
+            ("textbf", NativeFormat::Html) => ("<strong>", "</strong>"),
+            ("textit" | "emph" | "textsl", NativeFormat::Html) => ("<em>", "</em>"),
+            ("underline", NativeFormat::Html) => ("<u>", "</u>"),
+            ("texttt", NativeFormat::Html) => ("<code>", "</code>"),
+            ("textbf", NativeFormat::Markdown) => ("**", "**"),
+            ("textit" | "emph" | "textsl", NativeFormat::Markdown) => ("*", "*"),
+            ("texttt", NativeFormat::Markdown) => ("`", "`"),
+            _ => ("", ""),
+        };
+        out.push_str(before);
+        let nesting = self.nesting.get();
+        if nesting < MAX_COMMAND_NESTING {
+            self.nesting.set(nesting + 1);
+            self.inline_range(chars, open + 1, close, segments, out);
+            self.nesting.set(nesting);
+        } else {
+            for c in chars[open + 1..close].iter().filter(|c| !matches!(c, '{' | '}')) {
+                self.literal(*c, out);
+            }
+        }
+        out.push_str(after);
+        (close + 1).min(end)
+    }
+
+    fn math(&self, segment: &MathSegment, source: &[char]) -> String {
+        let display = segment.kind == MathKind::Display;
+        let number = if display {
+            let mut equations = self.equations.borrow_mut();
+            equations.push(segment.latex.clone());
+            self.first_equation + equations.len() - 1
+        } else {
+            self.inline_equations.set(self.inline_equations.get() + 1);
+            0
+        };
+        if self.omit_math {
+            return " ".to_string();
+        }
+
+        match self.format {
+            NativeFormat::Markdown if display => format!("$${}$$", segment.latex),
+            NativeFormat::Markdown => format!("${}$", segment.latex),
+            NativeFormat::Html => {
+                let math = match parse_latex_math(&segment.latex) {
+                    Ok(node) => MathOutputFormat::MathMl.render(&node, display),
+                    Err(_) => escape_html(&source.iter().collect::<String>()),
+                };
+                if display {
+                    format!("<span class=\"equation\" id=\"eq-{}\">{}</span>", number, math)
+                } else {
+                    math
+                }
+            }
+            NativeFormat::Text => match parse_latex_math(&segment.latex) {
+                Ok(node) => MathOutputFormat::Unicode.render(&node, display),
+                Err(_) => source.iter().collect(),
+            },
+        }
+    }
+
+    fn literal(&self, c: char, out: &mut String) {
+        match (self.format, c) {
+            (NativeFormat::Html, '&') => out.push_str("&amp;"),
+            (NativeFormat::Html, '<') => out.push_str("&lt;"),
+            (NativeFormat::Html, '>') => out.push_str("&gt;"),
+            (NativeFormat::Markdown, '*' | '_' | '`' | '#' | '$') => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+}
+
+/// Adds a line to the open display math environment. At its `\end` the
+/// environment is closed and returned as `\[...\]` text.
+fn continue_display(display: &mut Option<(String, String)>, line: &str) -> Option<String> {
+    let (environment, body) = display.as_mut()?;
+    match line.find(&format!("\\end{{{}}}", environment)) {
+        Some(end) => {
+            body.push_str(&line[..end]);
+            let math = format!("\\[{}\\]\n", body.trim());
+            *display = None;
+            Some(math)
+        }
+        None => {
+            body.push_str(line);
+            body.push('\n');
+            None
+        }
+    }
+}
+
+/// Reads `\begin{name}` or `\end{name}` at the start of a line, returning the
+/// name and the rest of the line.
+fn environment_marker<'a>(line: &'a str, marker: &str) -> Option<(&'a str, &'a str)> {
+    let rest = line.strip_prefix('\\')?.strip_prefix(marker)?.trim_start().strip_prefix('{')?;
+    let (name, rest) = rest.split_once('}')?;
+    Some((name.trim(), rest))
+}
+
+/// Reads a sectioning command at the start of a line: its level (2 for
+/// `\section`), its title and the rest of the line.
+fn heading(line: &str) -> Option<(usize, String, &str)> {
+    let command = line.strip_prefix('\\')?;
+    let (level, rest) = [("subsubsection", 4), ("subsection", 3), ("section", 2), ("chapter", 1)]
+        .iter()
+        .find_map(|(name, level)| command.strip_prefix(name).map(|rest| (*level, rest)))?;
+    let rest = rest.strip_prefix('*').unwrap_or(rest).trim_start();
+    if !rest.starts_with('{') {
+        return None;
+    }
+    let chars: Vec<char> = rest.chars().collect();
+    let close = matching_close(&chars, 0, chars.len())?;
+    let title: String = chars[1..close].iter().collect();
+    let consumed: usize = chars[..=close].iter().map(|c| c.len_utf8()).sum();
+    Some((level, title, &rest[consumed..]))
+}
+
+/// Whether a line is only a page break command.
+pub fn is_page_break(line: &str) -> bool {
+    matches!(line, "\\newpage" | "\\clearpage" | "\\pagebreak")
+}
+
+/// Drops an `\item[label]` label, keeping the item text.
+fn skip_optional_argument(text: &str) -> &str {
+    let text = text.trim_start();
+    match text.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((_, rest)) => rest.trim_start(),
+        None => text,
+    }
+}
+
+/// Finds the bracket closing the `{` or `[` at `open`, skipping escaped characters.
+fn matching_close(chars: &[char], open: usize, end: usize) -> Option<usize> {
+    let (opening, closing) = if chars[open] == '[' { ('[', ']') } else { ('{', '}') };
+    let mut depth = 0;
+    let mut i = open;
+    while i < end {
+        match chars[i] {
+            '\\' => i += 1,
+            c if c == opening => depth += 1,
+            c if c == closing => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_native_formats() {
+        let latex = "\\section*{Results \\& notes}\n\\noindent The \\textbf{area} is $\\pi r^2$, 50\\% of it.\n\n\\begin{itemize}\n\\item First\n\\item Second \\emph{point}\n\\end{itemize}\n\\newpage\n\\begin{equation}\nx = 1\n\\end{equation}";
+
+        let markdown = render_native(latex, NativeFormat::Markdown, None);
+        assert_eq!(
+            markdown,
+            "## Results & notes\n\nThe **area** is $\\pi r^2$, 50% of it.\n\n- First\n- Second *point*\n\n* * *\n\n$$x = 1$$\n"
+        );
+
+        let html = render_native(latex, NativeFormat::Html, Some("A < B"));
+        assert!(html.contains("<title>A &lt; B</title>"));
+        assert!(html.contains("<h2>Results &amp; notes</h2>\n<p>The <strong>area</strong> is <math"));
+        assert!(html.contains("<ul>\n<li>First</li>\n<li>Second <em>point</em></li>\n</ul>\n<hr class=\"page-break\">"));
+
+        let text = render_native(latex, NativeFormat::Text, None);
+        assert_eq!(text, "Results & notes\n\nThe area is πr², 50% of it.\n\n• First\n• Second point\n\nx = 1");
+        assert_eq!(render_native("Area $\\pi r^2$", NativeFormat::Text, None), "Area πr²");
+    }
+
+    #[test]
+    fn test_deeply_nested_commands_do_not_overflow_the_stack() {
+        let depth = 20_000;
+        let latex = format!("{}deep{}", "\\textbf{\\emph{".repeat(depth), "}}".repeat(depth));
+        for format in [NativeFormat::Html, NativeFormat::Markdown, NativeFormat::Text] {
+            let rendered = render_native(&latex, format, None);
+            assert!(rendered.contains("deep"));
+        }
+        let html = render_native(&latex, NativeFormat::Html, None);
+        // Levels 0, 2, .., 64 are bold; below that the text is kept unformatted
+        assert_eq!(html.matches("<strong>").count(), MAX_COMMAND_NESTING / 2 + 1);
+    }
+
+    #[test]
+    fn test_split_sections_and_anchor_equations() {
+        let latex = "Intro $$a$$\n\\section*{One \\& two}\n$$b = 1$$\n\\begin{align}\n\\section{x}\n\\end{align}\n\\subsection{Two}\nText";
+        let sections = split_sections(latex);
+        let headings: Vec<(Option<&str>, usize)> = sections.iter().map(|s| (s.heading.as_deref(), s.level)).collect();
+        assert_eq!(headings, vec![(None, 0), (Some("One & two"), 2), (Some("Two"), 3)]);
+        assert!(sections[1].content.starts_with("\\section*{One"));
+        assert!(sections[1].content.contains("\\section{x}"));
+
+        let (html, equations) = render_html_fragment(&sections[1].content, 2);
+        assert_eq!(equations, vec!["b = 1".to_string(), "\\section{x}".to_string()]);
+        assert!(html.starts_with("<h2>One &amp; two</h2>\n<p><span class=\"equation\" id=\"eq-2\"><math"));
+        assert!(html.contains("id=\"eq-3\""));
+
+        // Counting without rendering agrees with the rendered numbering
+        assert_eq!(display_equations(&sections[1].content), equations);
+        assert_eq!(count_text(&sections[1].content).display_equations, equations.len());
+        assert_eq!(display_equations(&sections[0].content), vec!["a".to_string()]);
+    }
+}
@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use base64::{Engine as _, engine::general_purpose};
+
+use crate::config::environment::Config;
+
+const OPENROUTER_CHAT_URL: &str = "https://openrouter.ai/api/v1/chat/completions";
+
+pub const OCR_PROMPT: &str = "You are an expert mathematician and a LaTeX specialist. Your task is to accurately transcribe all text and mathematical expressions from the provided image.
+
+            **CRITICAL INSTRUCTIONS:**
+            1.  **Validate and Correct:** Do not just blindly transcribe. Interpret the content. If a mathematical expression seems syntactically incorrect or a symbol is ambiguous, infer the most likely correct version. Your goal is to produce semantically sound mathematics.
+            2.  **Strict Formatting:** Adhere to the following formatting rules without exception.
+                *   **Math Delimiters:** Use ONLY `$...$` for inline math and `$$...$$` for display/block math.
+                *   **No Structural LaTeX:** Do NOT use commands like `\\textbf`, `\\textit`, `\\section`, `\\begin{itemize}`, etc. Use plain text for emphasis and structure.
+                *   **No Code Blocks:** The entire output must be plain text. Do NOT wrap it in Markdown code blocks (```).
+                *   **Plain Lists:** For lists, use simple numbering like `1.`, `2.`, or plain hyphens `-`.
+
+            **Example of Correct Output:**
+            Here is some text with an inline formula $f(x) = x^2 + 1$.
+
+            1. A list item with a display formula:
+            $$ \\int_a^b g(t) dt = G(b) - G(a) $$
+            2. Another list item.
+
+            **Your Goal:** Return ONLY the clean, corrected, and properly formatted transcription from the image.";
+
+// --- Structs for the OpenRouter vision chat API ---
+#[derive(Serialize)]
+struct VisionRequestBody<'a> {
+    model: &'a str,
+    messages: Vec<VisionMessage<'a>>,
+    max_tokens: u32,
+}
+
+#[derive(Serialize)]
+struct VisionMessage<'a> {
+    role: &'a str,
+    content: Vec<VisionContentPart<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum VisionContentPart<'a> {
+    #[serde(rename = "text")]
+    Text { text: &'a str },
+    #[serde(rename = "image_url")]
+    ImageUrl { image_url: VisionImageUrl },
+}
+
+#[derive(Serialize)]
+struct VisionImageUrl {
+    url: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatCompletionResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatChoice {
+    message: ChatResponseMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatResponseMessage {
+    content: String,
+}
+
+/// Text produced by a successful OCR call, along with the model that produced it.
+#[derive(Debug, Clone)]
+pub struct OcrOutput {
+    pub text: String,
+    pub model: String,
+    pub low_quality: bool,  // Every model's output failed `is_low_quality`; this is the first usable one
+}
+
+pub struct OcrService {
+    config: Config,
+    client: reqwest::Client,
+}
+
+impl OcrService {
+    pub fn new(config: Config) -> Self {
+        Self { config, client: reqwest::Client::new() }
+    }
+
+    /// Builds the ordered list of models to try: the requested model first (if any),
+    /// then the configured fallback chain, without duplicates.
+    pub fn model_chain(&self, requested: Option<&str>) -> Vec<String> {
+        let mut chain: Vec<String> = Vec::new();
+        for model in requested.into_iter().map(str::to_string).chain(self.config.ocr_model_chain.iter().cloned()) {
+            if !chain.contains(&model) {
+                chain.push(model);
+            }
+        }
+        chain
+    }
+
+    /// Transcribes an image by trying each model in `models` in order until one
+    /// returns usable text. Failed and low-quality attempts fall through to the next model;
+    /// if every attempt is low quality, the first one with any text is returned, flagged.
+    pub async fn transcribe(&self, image_bytes: &[u8], mime_type: &str, models: &[String]) -> Result<OcrOutput, String> {
+        self.transcribe_with_prompt(image_bytes, mime_type, models, OCR_PROMPT).await
+    }
+
+    /// Runs the image through two models concurrently for consensus mode.
+    /// Both must succeed; there is no fallback since the point is an independent second opinion.
+    pub async fn transcribe_pair(
+        &self,
+        image_bytes: &[u8],
+        mime_type: &str,
+        primary_model: &str,
+        secondary_model: &str,
+    ) -> Result<(OcrOutput, OcrOutput), String> {
+        let primary_chain = [primary_model.to_string()];
+        let secondary_chain = [secondary_model.to_string()];
+        let (primary, secondary) = futures::join!(
+            self.transcribe(image_bytes, mime_type, &primary_chain),
+            self.transcribe(image_bytes, mime_type, &secondary_chain),
+        );
+        Ok((primary?, secondary?))
+    }
+
+    pub async fn transcribe_with_prompt(
+        &self,
+        image_bytes: &[u8],
+        mime_type: &str,
+        models: &[String],
+        prompt: &str,
+    ) -> Result<OcrOutput, String> {
+        let image_uri = format!("data:{};base64,{}", mime_type, general_purpose::STANDARD.encode(image_bytes));
+        let mut failures: Vec<String> = Vec::new();
+        let mut best_low_quality: Option<OcrOutput> = None;
+
+        for model in models {
+            println!("OCR: Trying model {}...", model);
+            match self.call_model(model, &image_uri, prompt).await {
+                Ok(text) if !is_low_quality(&text) => {
+                    println!("OCR: Model {} succeeded.", model);
+                    return Ok(OcrOutput { text, model: model.clone(), low_quality: false });
+                }
+                Ok(text) => {
+                    println!("OCR: Model {} returned low-quality output, falling back.", model);
+                    failures.push(format!("{}: low-quality output", model));
+                    if best_low_quality.is_none() && !is_unusable(&text) {
+                        best_low_quality = Some(OcrOutput { text, model: model.clone(), low_quality: true });
+                    }
+                }
+                Err(e) => {
+                    println!("OCR: Model {} failed: {}", model, e);
+                    failures.push(format!("{}: {}", model, e));
+                }
+            }
+        }
+
+        if let Some(output) = best_low_quality {
+            println!("OCR: WARNING - No model gave clean output, keeping low-quality output from {}.", output.model);
+            Ok(output)
+        } else if failures.is_empty() {
+            Err("No OCR models are configured.".to_string())
+        } else {
+            Err(format!("All OCR models failed. {}", failures.join("; ")))
+        }
+    }
+
+    async fn call_model(&self, model: &str, image_uri: &str, prompt: &str) -> Result<String, String> {
+        let request_body = VisionRequestBody {
+            model,
+            messages: vec![VisionMessage {
+                role: "user",
+                content: vec![
+                    VisionContentPart::Text { text: prompt },
+                    VisionContentPart::ImageUrl { image_url: VisionImageUrl { url: image_uri.to_string() } },
+                ],
+            }],
+            max_tokens: 2048,
+        };
+
+        let response_result = self.client
+            .post(OPENROUTER_CHAT_URL)
+            .bearer_auth(&self.config.open_router_api_key)
+            .json(&request_body)
+            .send()
+            .await;
+
+        match response_result {
+            Ok(res) if res.status().is_success() => {
+                match res.json::<ChatCompletionResponse>().await {
+                    Ok(res_body) => match res_body.choices.first() {
+                        Some(choice) => Ok(choice.message.content.clone()),
+                        None => Err("API returned no choices.".to_string()),
+                    },
+                    Err(e) => Err(format!("Failed to parse API response: {}", e)),
+                }
+            }
+            Ok(res) => {
+                let status = res.status();
+                let error_text = res.text().await.unwrap_or_else(|_| "Could not read error body".to_string());
+                Err(format!("API call failed with status {}: {}", status, error_text))
+            }
+            Err(e) => Err(format!("Failed to send request: {}", e)),
+        }
+    }
+}
+
+/// Heuristic check for responses that should trigger a fallback: empty text,
+/// refusals, or output whose math delimiters don't balance.
+pub fn is_low_quality(text: &str) -> bool {
+    if is_unusable(text) {
+        return true;
+    }
+    let trimmed = text.trim();
+
+    // Every `$` that isn't escaped should be paired.
+    let mut dollars = 0;
+    let mut previous = '\0';
+    for c in trimmed.chars() {
+        if c == '$' && previous != '\\' {
+            dollars += 1;
+        }
+        previous = c;
+    }
+    dollars % 2 != 0
+}
+
+/// Empty output or a refusal, which is never worth keeping even as a last resort.
+fn is_unusable(text: &str) -> bool {
+    let lowered = text.trim().to_lowercase();
+    const REFUSALS: [&str; 4] = ["i'm sorry", "i am sorry", "i cannot", "i can't"];
+    lowered.is_empty() || REFUSALS.iter().any(|r| lowered.starts_with(r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_low_quality_detection() {
+        assert!(is_low_quality("   "));
+        assert!(is_low_quality("I'm sorry, I can't read this image."));
+        assert!(is_low_quality("Unbalanced $x + 1"));
+        assert!(!is_low_quality("Price is \\$5 and $x^2$ holds."));
+        assert!(!is_low_quality("$$ \\int_a^b g(t) dt $$"));
+
+        // Unbalanced math is still text worth keeping; empty output and refusals are not.
+        assert!(!is_unusable("It costs $5"));
+        assert!(is_unusable("  "));
+        assert!(is_unusable("I cannot transcribe this."));
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::services::document_converter::{TargetFormat, Watermark, WatermarkPosition};
+use crate::services::latex_math::escape_xml;
+
+/// Relationship id and part of the DOCX header or footer holding the watermark.
+const DOCX_RELATIONSHIP_ID: &str = "rIdWatermark";
+const DOCX_PART: &str = "word/watermark.xml";
+
+const WORDPROCESSING_NAMESPACE: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const RELATIONSHIP_NAMESPACE: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+
+/// Word's own WordArt shape type, which its built-in text watermarks use.
+const DOCX_TEXT_SHAPE_TYPE: &str = concat!(
+    r##"<v:shapetype id="_x0000_t136" coordsize="21600,21600" o:spt="136" adj="10800" path="m@7,l@8,m@5,21600l@6,21600e">"##,
+    r##"<v:formulas><v:f eqn="sum #0 0 10800"/><v:f eqn="prod #0 2 1"/><v:f eqn="sum 21600 0 @1"/><v:f eqn="sum 0 0 @2"/>"##,
+    r##"<v:f eqn="sum 21600 0 @3"/><v:f eqn="if @0 @3 0"/><v:f eqn="if @0 21600 @1"/><v:f eqn="if @0 0 @2"/><v:f eqn="if @0 @4 21600"/>"##,
+    r##"<v:f eqn="mid @5 @6"/><v:f eqn="mid @8 @5"/><v:f eqn="mid @7 @8"/><v:f eqn="mid @6 @7"/><v:f eqn="sum @6 0 @5"/></v:formulas>"##,
+    r##"<v:path textpathok="t" o:connecttype="custom" o:connectlocs="@9,0;@10,10800;@11,21600;@12,10800" o:connectangles="270,180,90,0"/>"##,
+    r##"<v:textpath on="t" fitshape="t"/><v:handles><v:h position="#0,bottomRight" xrange="6629,14971"/></v:handles>"##,
+    r##"<o:lock v:ext="edit" text="t" shapetype="t"/></v:shapetype>"##,
+);
+
+/// Puts the watermark on every page of a DOCX or ODT file written by pandoc:
+/// as the page footer or header, or for `Diagonal` as a large pale mark drawn
+/// behind the text from the page header. Other formats are returned unchanged.
+pub fn watermark_package(package: &[u8], format: TargetFormat, watermark: &Watermark) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(package)).map_err(|e| format!("Failed to read {} package: {}", format.name(), e))?;
+    let mut parts = HashMap::new();
+    let mut added = Vec::new();
+    match format {
+        TargetFormat::Docx => {
+            let header = watermark.position != WatermarkPosition::Footer;
+            let kind = if header { "header" } else { "footer" };
+            let document = read_part(&mut archive, "word/document.xml")?;
+            let relationships = read_part(&mut archive, "word/_rels/document.xml.rels")?;
+            let content_types = read_part(&mut archive, "[Content_Types].xml")?;
+
+            parts.insert("word/document.xml", reference_watermark_part(&document, kind));
+            parts.insert(
+                "word/_rels/document.xml.rels",
+                insert_before(&relationships, "</Relationships>", &format!(
+                    r##"<Relationship Id="{}" Type="{}/{}" Target="watermark.xml"/>"##,
+                    DOCX_RELATIONSHIP_ID, RELATIONSHIP_NAMESPACE, kind
+                ))?,
+            );
+            parts.insert(
+                "[Content_Types].xml",
+                insert_before(&content_types, "</Types>", &format!(
+                    r##"<Override PartName="/{}" ContentType="application/vnd.openxmlformats-officedocument.wordprocessingml.{}+xml"/>"##,
+                    DOCX_PART, kind
+                ))?,
+            );
+            added.push((DOCX_PART, docx_watermark_part(watermark)));
+        }
+        TargetFormat::Odt => {
+            let styles = read_part(&mut archive, "styles.xml")?;
+            parts.insert("styles.xml", odt_watermark_styles(&styles, watermark)?);
+        }
+        _ => return Ok(package.to_vec()),
+    }
+
+    // Unchanged entries are copied as they are, which keeps ODT's stored `mimetype` first.
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index).map_err(|e| format!("Failed to read package entry: {}", e))?;
+        match parts.remove(entry.name()) {
+            Some(content) => {
+                let name = entry.name().to_string();
+                drop(entry);
+                write_part(&mut writer, &name, &content, options)?;
+            }
+            None => writer.raw_copy_file(entry).map_err(|e| format!("Failed to copy package entry: {}", e))?,
+        }
+    }
+    for (name, content) in added {
+        write_part(&mut writer, name, &content, options)?;
+    }
+    writer
+        .finish()
+        .map(Cursor::into_inner)
+        .map_err(|e| format!("Failed to finish {} package: {}", format.name(), e))
+}
+
+fn read_part(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<String, String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .map_err(|e| format!("The package has no {}: {}", name, e))?
+        .read_to_string(&mut content)
+        .map_err(|e| format!("Failed to read {}: {}", name, e))?;
+    Ok(content)
+}
+
+fn write_part(writer: &mut ZipWriter<Cursor<Vec<u8>>>, name: &str, content: &str, options: SimpleFileOptions) -> Result<(), String> {
+    writer.start_file(name, options).map_err(|e| format!("Failed to add {}: {}", name, e))?;
+    writer.write_all(content.as_bytes()).map_err(|e| format!("Failed to write {}: {}", name, e))
+}
+
+fn insert_before(xml: &str, marker: &str, insertion: &str) -> Result<String, String> {
+    let at = xml.rfind(marker).ok_or_else(|| format!("Missing {} in package part", marker))?;
+    Ok(format!("{}{}{}", &xml[..at], insertion, &xml[at..]))
+}
+
+/// The DOCX header or footer part: a small grey line, or a rotated pale text
+/// shape centred on the page behind the text.
+fn docx_watermark_part(watermark: &Watermark) -> String {
+    let text = escape_xml(&watermark.text);
+    let (root, content) = match watermark.position {
+        WatermarkPosition::Footer | WatermarkPosition::Header => (
+            if watermark.position == WatermarkPosition::Footer { "w:ftr" } else { "w:hdr" },
+            format!(
+                r##"<w:p><w:pPr><w:jc w:val="center"/></w:pPr><w:r><w:rPr><w:color w:val="999999"/><w:sz w:val="16"/></w:rPr><w:t xml:space="preserve">{}</w:t></w:r></w:p>"##,
+                text
+            ),
+        ),
+        WatermarkPosition::Diagonal => (
+            "w:hdr",
+            format!(
+                concat!(
+                    r##"<w:p><w:pPr><w:pStyle w:val="Header"/></w:pPr><w:r><w:pict>{}"##,
+                    r##"<v:shape id="Watermark" o:spid="_x0000_s2049" type="#_x0000_t136" style="position:absolute;margin-left:0;margin-top:0;width:468pt;height:117pt;rotation:315;z-index:-251657216;"##,
+                    r##"mso-position-horizontal:center;mso-position-horizontal-relative:margin;mso-position-vertical:center;mso-position-vertical-relative:margin" fillcolor="silver" stroked="f">"##,
+                    r##"<v:fill opacity=".3"/><v:textpath style="font-family:&quot;Calibri&quot;;font-size:1pt" string="{}"/></v:shape></w:pict></w:r></w:p>"##,
+                ),
+                DOCX_TEXT_SHAPE_TYPE, text
+            ),
+        ),
+    };
+    format!(
+        r##"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>{}<{} xmlns:w="{}" xmlns:r="{}" xmlns:v="urn:schemas-microsoft-com:vml" xmlns:o="urn:schemas-microsoft-com:office:office">{}</{}>"##,
+        "\n", root, WORDPROCESSING_NAMESPACE, RELATIONSHIP_NAMESPACE, content, root
+    )
+}
+
+/// Points every section of `document` at the watermark as its default header
+/// or footer (`kind`), replacing any default one the reference document gave it.
+fn reference_watermark_part(document: &str, kind: &str) -> String {
+    let reference = format!(
+        r##"<w:{}Reference xmlns:r="{}" w:type="default" r:id="{}"/>"##,
+        kind, RELATIONSHIP_NAMESPACE, DOCX_RELATIONSHIP_ID
+    );
+    let mut result = String::with_capacity(document.len() + reference.len());
+    let mut rest = document;
+    let mut found = false;
+    while let Some(start) = find_element(rest, "w:sectPr") {
+        found = true;
+        let open_end = start + rest[start..].find('>').map_or(rest.len() - start, |i| i + 1);
+        result.push_str(&rest[..start]);
+        let open = &rest[start..open_end];
+        if let Some(attributes) = open.strip_suffix("/>") {
+            result.push_str(&format!("{}>{}</w:sectPr>", attributes.trim_end(), reference));
+            rest = &rest[open_end..];
+            continue;
+        }
+        let close = rest[open_end..].find("</w:sectPr>").map_or(rest.len(), |i| open_end + i);
+        result.push_str(open);
+        result.push_str(&reference);
+        result.push_str(&without_default_references(&rest[open_end..close], kind));
+        rest = &rest[close..];
+    }
+    result.push_str(rest);
+    if let Some(at) = result.rfind("</w:body>").filter(|_| !found) {
+        result.insert_str(at, &format!("<w:sectPr>{}</w:sectPr>", reference));
+    }
+    result
+}
+
+/// Start of the next `<name` element in `xml`, not counting longer names that begin the same way.
+fn find_element(xml: &str, name: &str) -> Option<usize> {
+    let tag = format!("<{}", name);
+    let mut from = 0;
+    while let Some(offset) = xml[from..].find(&tag) {
+        let start = from + offset;
+        if matches!(xml[start + tag.len()..].chars().next(), Some('>' | '/' | ' ' | '\t' | '\r' | '\n')) {
+            return Some(start);
+        }
+        from = start + tag.len();
+    }
+    None
+}
+
+/// Drops the self-closing `<w:{kind}Reference w:type="default" .../>` elements from a section.
+fn without_default_references(section: &str, kind: &str) -> String {
+    let mut result = String::with_capacity(section.len());
+    let mut rest = section;
+    while let Some(start) = find_element(rest, &format!("w:{}Reference", kind)) {
+        let end = rest[start..].find("/>").map_or(rest.len(), |i| start + i + 2);
+        result.push_str(&rest[..start]);
+        if !rest[start..end].contains(r##"w:type="default""##) {
+            result.push_str(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Adds the watermark styles to the ODT `styles.xml` and the watermark to the
+/// header or footer of every master page.
+fn odt_watermark_styles(styles: &str, watermark: &Watermark) -> Result<String, String> {
+    let text = escape_xml(&watermark.text);
+    let automatic_styles = concat!(
+        r##"<style:style style:name="WatermarkText" style:family="paragraph"><style:paragraph-properties fo:text-align="center"/>"##,
+        r##"<style:text-properties fo:color="#999999" fo:font-size="8pt"/></style:style>"##,
+        r##"<style:style style:name="WatermarkDiagonal" style:family="paragraph"><style:paragraph-properties fo:text-align="center"/>"##,
+        r##"<style:text-properties fo:color="#d9d9d9" fo:font-size="40pt"/></style:style>"##,
+        r##"<style:style style:name="WatermarkShape" style:family="graphic"><style:graphic-properties draw:stroke="none" draw:fill="none" "##,
+        r##"style:wrap="run-through" style:run-through="background" draw:textarea-horizontal-align="center" draw:textarea-vertical-align="middle"/></style:style>"##,
+    );
+    let (kind, content) = match watermark.position {
+        WatermarkPosition::Footer => ("footer", format!(r##"<text:p text:style-name="WatermarkText">{}</text:p>"##, text)),
+        WatermarkPosition::Header => ("header", format!(r##"<text:p text:style-name="WatermarkText">{}</text:p>"##, text)),
+        // A shape anchored in the header, rotated 45 degrees across the middle of the page
+        WatermarkPosition::Diagonal => (
+            "header",
+            format!(
+                concat!(
+                    r##"<text:p text:style-name="WatermarkText"><draw:custom-shape text:anchor-type="paragraph" draw:z-index="0" draw:style-name="WatermarkShape" "##,
+                    r##"svg:width="16cm" svg:height="3cm" draw:transform="rotate (0.785398163397448) translate (1.5cm 16cm)">"##,
+                    r##"<text:p text:style-name="WatermarkDiagonal">{}</text:p><draw:enhanced-geometry svg:viewBox="0 0 21600 21600" draw:type="rectangle"/>"##,
+                    r##"</draw:custom-shape></text:p>"##,
+                ),
+                text
+            ),
+        ),
+    };
+    let region = format!("<style:{}>{}</style:{}>", kind, content, kind);
+
+    let styles = if let Some(start) = find_element(styles, "office:automatic-styles") {
+        let open_end = start + styles[start..].find('>').ok_or("Unterminated office:automatic-styles")? + 1;
+        if styles[start..open_end].ends_with("/>") {
+            format!("{}<office:automatic-styles>{}</office:automatic-styles>{}", &styles[..start], automatic_styles, &styles[open_end..])
+        } else {
+            format!("{}{}{}", &styles[..open_end], automatic_styles, &styles[open_end..])
+        }
+    } else {
+        insert_before(styles, "<office:master-styles", &format!("<office:automatic-styles>{}</office:automatic-styles>", automatic_styles))?
+    };
+
+    let mut result = String::with_capacity(styles.len() + region.len());
+    let mut rest = styles.as_str();
+    let mut found = false;
+    while let Some(start) = find_element(rest, "style:master-page") {
+        found = true;
+        let open_end = start + rest[start..].find('>').ok_or("Unterminated style:master-page")? + 1;
+        result.push_str(&rest[..start]);
+        let open = &rest[start..open_end];
+        if let Some(attributes) = open.strip_suffix("/>") {
+            result.push_str(&format!("{}>{}</style:master-page>", attributes.trim_end(), region));
+            rest = &rest[open_end..];
+            continue;
+        }
+        let close = rest[open_end..].find("</style:master-page>").map_or(rest.len(), |i| open_end + i);
+        let page = without_region(&rest[open_end..close], kind);
+        // The schema orders a master page's children header, header-left, footer, footer-left, then drawings.
+        let at = if kind == "header" {
+            0
+        } else {
+            ["<style:footer-left", "<draw:", "<presentation:notes"]
+                .iter()
+                .filter_map(|marker| page.find(marker))
+                .min()
+                .unwrap_or(page.len())
+        };
+        result.push_str(open);
+        result.push_str(&page[..at]);
+        result.push_str(&region);
+        result.push_str(&page[at..]);
+        rest = &rest[close..];
+    }
+    if !found {
+        return Err("The package has no master page".to_string());
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Drops an existing `<style:{kind}>` region (not `-left`) from a master page.
+fn without_region(page: &str, kind: &str) -> String {
+    let name = format!("style:{}", kind);
+    let Some(start) = find_element(page, &name) else {
+        return page.to_string();
+    };
+    let open_end = page[start..].find('>').map_or(page.len(), |i| start + i + 1);
+    let end = if page[start..open_end].ends_with("/>") {
+        open_end
+    } else {
+        let close = format!("</{}>", name);
+        page[open_end..].find(&close).map_or(page.len(), |i| open_end + i + close.len())
+    };
+    format!("{}{}", &page[..start], &page[end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::zip_archive::{build_zip, ArchiveEntry};
+
+    fn read(package: &[u8], name: &str) -> String {
+        let mut archive = ZipArchive::new(Cursor::new(package)).unwrap();
+        let mut content = String::new();
+        archive.by_name(name).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    fn watermark(position: WatermarkPosition) -> Watermark {
+        Watermark { text: "Made with <OCR> & co".to_string(), position }
+    }
+
+    #[test]
+    fn test_docx_watermark_is_a_footer_of_every_section() {
+        let docx = build_zip(&[
+            ArchiveEntry::new("[Content_Types].xml", "<Types></Types>"),
+            ArchiveEntry::new("word/_rels/document.xml.rels", "<Relationships></Relationships>"),
+            ArchiveEntry::new(
+                "word/document.xml",
+                r##"<w:document><w:body><w:p><w:pPr><w:sectPr/></w:pPr></w:p><w:sectPr w:rsidR="1"><w:footerReference w:type="default" r:id="rId9"/><w:footerReference w:type="even" r:id="rId8"/><w:pgSz/></w:sectPr></w:body></w:document>"##,
+            ),
+        ])
+        .unwrap();
+        let marked = watermark_package(&docx, TargetFormat::Docx, &watermark(WatermarkPosition::Footer)).unwrap();
+
+        let document = read(&marked, "word/document.xml");
+        assert_eq!(document.matches(r##"r:id="rIdWatermark""##).count(), 2);
+        assert!(document.contains(r##"<w:sectPr><w:footerReference xmlns:r="##));
+        assert!(!document.contains("rId9") && document.contains(r##"w:type="even" r:id="rId8""##));
+        assert!(read(&marked, "word/_rels/document.xml.rels").contains(r##"relationships/footer" Target="watermark.xml""##));
+        assert!(read(&marked, "[Content_Types].xml").contains("wordprocessingml.footer+xml"));
+        let footer = read(&marked, "word/watermark.xml");
+        assert!(footer.contains("<w:ftr ") && footer.contains("Made with &lt;OCR&gt; &amp; co"));
+
+        let diagonal = watermark_package(&docx, TargetFormat::Docx, &watermark(WatermarkPosition::Diagonal)).unwrap();
+        let header = read(&diagonal, "word/watermark.xml");
+        assert!(header.contains("<w:hdr ") && header.contains("rotation:315") && header.contains(r##"string="Made with &lt;OCR&gt; &amp; co""##));
+        assert!(read(&diagonal, "word/document.xml").contains("<w:headerReference "));
+    }
+
+    #[test]
+    fn test_odt_watermark_goes_on_every_master_page() {
+        let odt = build_zip(&[
+            ArchiveEntry::new("mimetype", "application/vnd.oasis.opendocument.text"),
+            ArchiveEntry::new(
+                "styles.xml",
+                r##"<office:document-styles><office:automatic-styles/><office:master-styles><style:master-page style:name="Standard"/><style:master-page style:name="Index"><style:header><text:p>Old</text:p></style:header><style:footer><text:p>Old</text:p></style:footer><style:footer-left/></style:master-page></office:master-styles></office:document-styles>"##,
+            ),
+        ])
+        .unwrap();
+        let marked = watermark_package(&odt, TargetFormat::Odt, &watermark(WatermarkPosition::Footer)).unwrap();
+
+        let mut archive = ZipArchive::new(Cursor::new(marked.as_slice())).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let styles = read(&marked, "styles.xml");
+        assert!(styles.contains(r##"<office:automatic-styles><style:style style:name="WatermarkText""##));
+        assert!(styles.contains(r##"<style:master-page style:name="Standard"><style:footer><text:p text:style-name="WatermarkText">Made with &lt;OCR&gt;"##));
+        assert!(styles.contains(r##"<style:header><text:p>Old</text:p></style:header><style:footer><text:p text:style-name="WatermarkText">"##));
+        assert!(styles.contains("</style:footer><style:footer-left/></style:master-page>"));
+        assert_eq!(styles.matches("<style:footer>").count(), 2);
+
+        let diagonal = read(&watermark_package(&odt, TargetFormat::Odt, &watermark(WatermarkPosition::Diagonal)).unwrap(), "styles.xml");
+        assert!(diagonal.contains(r##"<style:master-page style:name="Index"><style:header><text:p text:style-name="WatermarkText"><draw:custom-shape "##));
+        assert!(!diagonal.contains("<text:p>Old</text:p></style:header>") && diagonal.contains("rotate (0.785398163397448)"));
+    }
+}
@@ -0,0 +1,214 @@
+use reqwest::{redirect::Policy, Url};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// An image downloaded from a user supplied URL.
+#[derive(Debug)]
+pub struct FetchedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    pub final_url: Url,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    Blocked(String),
+    TooLarge(u64),
+    UnsupportedType(String),
+    Timeout,
+    Upstream(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(e) => write!(f, "Invalid URL: {}", e),
+            FetchError::Blocked(e) => write!(f, "URL is not allowed: {}", e),
+            FetchError::TooLarge(max) => write!(f, "Remote file exceeds the maximum size of {} bytes", max),
+            FetchError::UnsupportedType(t) => write!(f, "Remote file has unsupported content type: {}", t),
+            FetchError::Timeout => write!(f, "Timed out fetching the remote file"),
+            FetchError::Upstream(e) => write!(f, "Failed to fetch the remote file: {}", e),
+        }
+    }
+}
+
+/// Downloads remote images with size, time and SSRF limits. Every hop of a
+/// redirect chain is resolved and checked against private address ranges, and
+/// the connection is pinned to the checked address to defeat DNS rebinding.
+pub struct RemoteImageFetcher {
+    max_bytes: u64,
+    timeout: Duration,
+    max_redirects: usize,
+}
+
+impl RemoteImageFetcher {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            timeout: Duration::from_secs(10),
+            max_redirects: 3,
+        }
+    }
+
+    pub async fn fetch(&self, url: &str, accept_content_type: impl Fn(&str) -> bool) -> Result<FetchedImage, FetchError> {
+        let mut current = Url::parse(url.trim()).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+
+        for _ in 0..=self.max_redirects {
+            let addr = resolve_public_addr(&current).await?;
+            let host = current.host_str().unwrap_or_default().to_string();
+
+            let client = reqwest::Client::builder()
+                .redirect(Policy::none())
+                // HTTP(S)_PROXY must not send server-side fetches around the address checks.
+                .no_proxy()
+                .timeout(self.timeout)
+                .connect_timeout(Duration::from_secs(5))
+                .resolve(&host, addr)
+                .build()
+                .map_err(|e| FetchError::Upstream(e.to_string()))?;
+
+            let mut response = client.get(current.clone()).send().await.map_err(map_reqwest_error)?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .ok_or_else(|| FetchError::Upstream("redirect without a Location header".to_string()))?;
+                current = current.join(location).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+                continue;
+            }
+
+            if !response.status().is_success() {
+                return Err(FetchError::Upstream(format!("status {}", response.status())));
+            }
+
+            let content_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|c| c.to_str().ok())
+                .map(|c| c.split(';').next().unwrap_or_default().trim().to_lowercase())
+                .unwrap_or_default();
+            if !accept_content_type(&content_type) {
+                return Err(FetchError::UnsupportedType(content_type));
+            }
+
+            if response.content_length().is_some_and(|len| len > self.max_bytes) {
+                return Err(FetchError::TooLarge(self.max_bytes));
+            }
+
+            // Content-Length can be absent or wrong, so enforce the limit while streaming.
+            let mut bytes = Vec::new();
+            while let Some(chunk) = response.chunk().await.map_err(map_reqwest_error)? {
+                if (bytes.len() + chunk.len()) as u64 > self.max_bytes {
+                    return Err(FetchError::TooLarge(self.max_bytes));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+
+            return Ok(FetchedImage { bytes, content_type, final_url: current });
+        }
+
+        Err(FetchError::Blocked("too many redirects".to_string()))
+    }
+}
+
+fn map_reqwest_error(e: reqwest::Error) -> FetchError {
+    if e.is_timeout() {
+        FetchError::Timeout
+    } else {
+        FetchError::Upstream(e.to_string())
+    }
+}
+
+/// Resolves the URL's host and returns an address only if every resolved
+/// address is publicly routable.
+async fn resolve_public_addr(url: &Url) -> Result<SocketAddr, FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::InvalidUrl("only http and https URLs are supported".to_string()));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(FetchError::InvalidUrl("URLs with credentials are not supported".to_string()));
+    }
+    let host = url.host_str().ok_or_else(|| FetchError::InvalidUrl("missing host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addrs: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| FetchError::InvalidUrl(format!("could not resolve host: {}", e)))?
+            .collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err(FetchError::InvalidUrl("host did not resolve to any address".to_string()));
+    }
+    if let Some(addr) = addrs.iter().find(|a| !is_public_ip(a.ip())) {
+        return Err(FetchError::Blocked(format!("{} resolves to non-public address {}", host, addr.ip())));
+    }
+    Ok(addrs[0])
+}
+
+/// Returns false for loopback, private, link-local, shared, reserved and other
+/// non-internet-routable ranges.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_public_ipv4(v4),
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_ipv4(v4);
+            }
+            is_public_ipv6(v6)
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || a == 0                                 // 0.0.0.0/8
+        || (a == 100 && (64..128).contains(&b))   // 100.64.0.0/10 carrier-grade NAT
+        || (a == 192 && b == 0 && c == 0)         // 192.0.0.0/24 protocol assignments
+        || (a == 198 && (b == 18 || b == 19))     // 198.18.0.0/15 benchmarking
+        || a >= 240)                              // 240.0.0.0/4 reserved
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (segments[0] & 0xfe00) == 0xfc00       // fc00::/7 unique local
+        || (segments[0] & 0xffc0) == 0xfe80       // fe80::/10 link-local
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8) // 2001:db8::/32 documentation
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b) // 64:ff9b::/96 NAT64
+        || ip.to_ipv4().is_some())                // IPv4-compatible addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_ranges_are_blocked() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should be blocked", ip);
+        }
+    }
+
+    #[test]
+    fn test_public_addresses_are_allowed() {
+        for ip in ["8.8.8.8", "151.101.1.69", "2606:4700:4700::1111"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be allowed", ip);
+        }
+    }
+}
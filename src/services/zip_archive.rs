@@ -0,0 +1,137 @@
+use std::fs::{self, File};
+use std::io::{Cursor, Seek, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// One file in an archive, at a `/`-separated path relative to the archive root.
+pub struct ArchiveEntry {
+    pub path: String,
+    pub content: Vec<u8>,
+}
+
+impl ArchiveEntry {
+    pub fn new(path: impl Into<String>, content: impl Into<Vec<u8>>) -> Self {
+        Self { path: path.into(), content: content.into() }
+    }
+}
+
+/// Builds a deflate-compressed ZIP in memory. Already-compressed files (images,
+/// PDFs and Office documents) are stored as-is.
+pub fn build_zip(entries: &[ArchiveEntry]) -> Result<Vec<u8>, String> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for entry in entries {
+        write_entry(&mut writer, &entry.path, &entry.content)?;
+    }
+    writer
+        .finish()
+        .map(Cursor::into_inner)
+        .map_err(|e| format!("Failed to finish archive: {}", e))
+}
+
+/// A ZIP written to the system temp dir one entry at a time, so only the entry
+/// being added is held in memory however large the archive grows. The file is
+/// removed on drop.
+pub struct TempArchive {
+    path: PathBuf,
+    writer: Option<ZipWriter<File>>,  // `None` once finished
+}
+
+impl TempArchive {
+    pub fn create() -> Result<Self, String> {
+        let path = std::env::temp_dir().join(format!("archive-{}.zip", Uuid::new_v4()));
+        let file = File::create(&path).map_err(|e| format!("Failed to create archive file: {}", e))?;
+        Ok(Self { path, writer: Some(ZipWriter::new(file)) })
+    }
+
+    pub fn add(&mut self, path: &str, content: &[u8]) -> Result<(), String> {
+        let writer = self.writer.as_mut().ok_or("The archive is already finished")?;
+        write_entry(writer, path, content)
+    }
+
+    /// Writes the central directory and returns the archive size in bytes.
+    pub fn finish(&mut self) -> Result<u64, String> {
+        let writer = self.writer.take().ok_or("The archive is already finished")?;
+        let file = writer.finish().map_err(|e| format!("Failed to finish archive: {}", e))?;
+        file.metadata().map(|m| m.len()).map_err(|e| format!("Failed to read archive size: {}", e))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempArchive {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn write_entry<W: Write + Seek>(writer: &mut ZipWriter<W>, path: &str, content: &[u8]) -> Result<(), String> {
+    let method = if is_compressed(path) { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+    writer
+        .start_file(path, SimpleFileOptions::default().compression_method(method))
+        .map_err(|e| format!("Failed to add {} to archive: {}", path, e))?;
+    writer
+        .write_all(content)
+        .map_err(|e| format!("Failed to write {} to archive: {}", path, e))
+}
+
+fn is_compressed(path: &str) -> bool {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp" | "pdf" | "docx" | "odt" | "epub" | "zip")
+}
+
+/// Reduces a user-supplied name to something safe as a single archive path component.
+pub fn archive_file_stem(name: &str) -> String {
+    let stem = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name);
+    let cleaned: String = stem
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let cleaned = cleaned.trim_matches('_');
+    if cleaned.is_empty() { "file".to_string() } else { cleaned.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_build_zip_round_trip() {
+        let entries = vec![
+            ArchiveEntry::new("manifest.json", "{}"),
+            ArchiveEntry::new("images/001-page.png", vec![0x89, b'P', b'N', b'G']),
+        ];
+        let bytes = build_zip(&entries).unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut manifest = String::new();
+        archive.by_name("manifest.json").unwrap().read_to_string(&mut manifest).unwrap();
+        assert_eq!(manifest, "{}");
+        assert_eq!(archive.by_name("images/001-page.png").unwrap().compression(), CompressionMethod::Stored);
+        assert_eq!(archive_file_stem("../Page 1 (scan).PNG"), "Page_1__scan");
+    }
+
+    #[test]
+    fn test_temp_archive_writes_to_disk_and_cleans_up() {
+        let mut archive = TempArchive::create().unwrap();
+        archive.add("documents/001-page.tex", b"$x$").unwrap();
+        archive.add("manifest.json", b"{}").unwrap();
+        let size = archive.finish().unwrap();
+        assert!(archive.add("late.txt", b"").is_err());
+
+        let path = archive.path().to_path_buf();
+        assert_eq!(fs::metadata(&path).unwrap().len(), size);
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut tex = String::new();
+        zip.by_name("documents/001-page.tex").unwrap().read_to_string(&mut tex).unwrap();
+        assert_eq!(tex, "$x$");
+
+        drop(archive);
+        assert!(!path.exists());
+    }
+}